@@ -1,72 +1,1066 @@
-use parquet::file::reader::{FileReader, SerializedFileReader};
-use parquet::record::Field;
-use std::collections::HashSet;
-use std::{fs::File, path::Path};
+use arrow_array::{
+    Array, ArrayRef, FixedSizeListArray, Float64Array, LargeListArray, ListArray, RecordBatch,
+};
+use arrow_ipc::reader::{FileReader as IpcFileReader, StreamReader};
+use arrow_schema::{ArrowError, DataType};
+use parquet::arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ProjectionMask};
+use parquet::file::reader::SerializedFileReader;
+use parquet::record::{reader::RowIter, Field, Row};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::{
+    fs::{self, File},
+    io::Cursor,
+    ops::Deref,
+    path::Path,
+};
 
-pub struct Mnist {
+use crate::{io, loss, tensor::Tensor, util::Util};
+
+/// Copy of the digits in this repository, to pass to `Mnist::download`.
+#[cfg(feature = "http")]
+pub const MNIST_URL: &str = "https://github.com/guigzzz/micrograd-rs/raw/main/mnist.parquet";
+/// SHA-256 of the file at `MNIST_URL`.
+#[cfg(feature = "http")]
+pub const MNIST_SHA256: &str = "e33f76031ea751b9f093a3d330f5d6667a722376d823d6af4e53e03251041925";
+
+#[cfg(feature = "http")]
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Path of the file at `url` in `cache_dir`, named after the last segment of the url, which is
+/// only downloaded if it isn't there yet with the expected hex `sha256`.
+#[cfg(feature = "http")]
+pub fn download(url: &str, sha256: &str, cache_dir: &Path) -> std::path::PathBuf {
+    use std::io::Read;
+
+    let name = url.rsplit('/').next().filter(|n| !n.is_empty());
+    let path = cache_dir.join(name.unwrap_or_else(|| panic!("No file name in url: {url}")));
+    if fs::read(&path).is_ok_and(|bytes| sha256_hex(&bytes) == sha256) {
+        return path;
+    }
+
+    let mut bytes = vec![];
+    ureq::get(url)
+        .call()
+        .unwrap_or_else(|e| panic!("Failed to download {url}: {e}"))
+        .into_reader()
+        .read_to_end(&mut bytes)
+        .unwrap_or_else(|e| panic!("Failed to download {url}: {e}"));
+    let actual = sha256_hex(&bytes);
+    if actual != sha256 {
+        panic!("Expected SHA-256 {sha256} for {url}, but got {actual}")
+    }
+
+    // Written under another name first, so that an interrupted write is never taken as cached.
+    fs::create_dir_all(cache_dir)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {e}", cache_dir.display()));
+    let partial = path.with_extension("partial");
+    fs::write(&partial, bytes)
+        .and_then(|_| fs::rename(&partial, &path))
+        .unwrap_or_else(|e| panic!("Failed to write {}: {e}", path.display()));
+    path
+}
+
+/// Samples of features and a label, which a `DataLoader` batches.
+pub trait Dataset {
+    type Label;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Features and label of the sample at `index`.
+    fn get(&self, index: usize) -> (Vec<f64>, Self::Label);
+
+    /// Label of the sample at `index`, which datasets override to skip copying the features.
+    fn label(&self, index: usize) -> Self::Label {
+        self.get(index).1
+    }
+}
+
+impl<L: Clone> Dataset for Vec<(Vec<f64>, L)> {
+    type Label = L;
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, L) {
+        self[index].clone()
+    }
+
+    fn label(&self, index: usize) -> L {
+        self[index].1.clone()
+    }
+}
+
+/// Images flattened into features in `[0, 1]`, each labelled with its class, which `Mnist`,
+/// `FashionMnist` and `Cifar10` dereference to.
+pub struct LabelledImages {
     images: Vec<Vec<f64>>,
     labels: Vec<u32>,
     pub x_dim: usize,
     pub y_dim: usize,
 }
 
+impl LabelledImages {
+    /// Reads the images and labels files in the IDX format of the original MNIST, gzipped or
+    /// not, scaling the pixels from bytes to `[0, 1]`.
+    fn from_idx(images: &Path, labels: &Path) -> LabelledImages {
+        let read = |path: &Path| {
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()))
+        };
+        LabelledImages::from_idx_bytes(&read(images), &read(labels))
+    }
+
+    fn from_idx_bytes(images: &[u8], labels: &[u8]) -> LabelledImages {
+        let images = io::read_idx(images);
+        let labels = io::read_idx(labels);
+        if images.shape()[0] != labels.shape()[0] {
+            panic!(
+                "Expected {} labels, but got {}",
+                images.shape()[0],
+                labels.shape()[0]
+            )
+        }
+
+        let x_dim = images.shape()[1..].iter().product();
+        let images: Vec<Vec<f64>> = images
+            .data()
+            .chunks(x_dim)
+            .map(|image| image.iter().map(|p| p / 255.).collect())
+            .collect();
+        let labels: Vec<u32> = labels.data().iter().map(|l| *l as u32).collect();
+        let y_dim = labels.iter().collect::<HashSet<_>>().len();
+
+        LabelledImages {
+            images,
+            labels,
+            x_dim,
+            y_dim,
+        }
+    }
+
+    /// Weights of the classes inversely proportional to their frequencies, for
+    /// `loss::weighted_cross_entropy_with_logits`.
+    pub fn class_weights(&self) -> Vec<f64> {
+        loss::class_weights(self.labels.iter().map(|l| *l as usize), self.y_dim)
+    }
+
+    pub fn as_xy(&self) -> Vec<(&Vec<f64>, u32)> {
+        self.images
+            .iter()
+            .zip(self.labels.iter().cloned())
+            .collect()
+    }
+}
+
+impl Dataset for LabelledImages {
+    type Label = u32;
+
+    fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, u32) {
+        (self.images[index].clone(), self.labels[index])
+    }
+
+    fn label(&self, index: usize) -> u32 {
+        self.labels[index]
+    }
+}
+
+/// Handwritten digits.
+pub struct Mnist(LabelledImages);
+
 impl Mnist {
+    /// Reads the `data` and `labels` columns of a parquet file, decoding its row groups in
+    /// parallel.
     pub fn from_parquet(path: &Path) -> Mnist {
-        if let Ok(file) = File::open(path) {
-            let reader = SerializedFileReader::new(file).unwrap();
-
-            let iter = reader.get_row_iter(None).unwrap();
-
-            let mut images: Vec<Vec<f64>> = vec![];
-            let mut labels: Vec<u32> = vec![];
-            for record in iter {
-                for (name, field) in record.get_column_iter() {
-                    match name.as_str() {
-                        "data" => match field {
-                            Field::ListInternal(l) => {
-                                let vals: Vec<f64> = l
-                                    .elements()
-                                    .iter()
-                                    .map(|f| match f {
-                                        Field::Double(f) => *f,
-                                        f => panic!("Unexpected array value type: {:?}", f),
-                                    })
-                                    .collect();
-                                images.push(vals);
-                            }
-                            f => panic!("Unexpcted type for data field: {:?}", f),
-                        },
-                        "labels" => match field {
-                            Field::Long(i) => labels.push(*i as u32),
-                            f => panic!("Unexpcted type for labels field: {:?}", f),
-                        },
-                        n => panic!("Unexpected column: {:?}", n),
+        let open = || {
+            let file = File::open(path)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+            ParquetRecordBatchReaderBuilder::try_new(file)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()))
+        };
+        let builder = open();
+        let schema = builder.parquet_schema();
+        let columns: Vec<usize> = ["data", "labels"]
+            .iter()
+            .map(|name| {
+                schema
+                    .root_schema()
+                    .get_fields()
+                    .iter()
+                    .position(|f| f.name() == *name)
+                    .unwrap_or_else(|| panic!("Missing column {name}"))
+            })
+            .collect();
+        let projection = ProjectionMask::roots(schema, columns);
+
+        let row_groups: Vec<(Vec<Vec<f64>>, Vec<u32>)> = (0..builder.metadata().num_row_groups())
+            .into_par_iter()
+            .map(|row_group| {
+                let reader = open()
+                    .with_projection(projection.clone())
+                    .with_row_groups(vec![row_group])
+                    .build()
+                    .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+                let mut images = vec![];
+                let mut labels = vec![];
+                reader.for_each(|batch| {
+                    let batch =
+                        batch.unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+                    images.extend(column_rows(batch.column(0), "data"));
+                    labels.extend(
+                        column_rows(batch.column(1), "labels")
+                            .iter()
+                            .map(|l| l[0] as u32),
+                    );
+                });
+                (images, labels)
+            })
+            .collect();
+        let (images, labels): (Vec<Vec<f64>>, Vec<u32>) =
+            row_groups
+                .into_iter()
+                .fold((vec![], vec![]), |(mut images, mut labels), (i, l)| {
+                    images.extend(i);
+                    labels.extend(l);
+                    (images, labels)
+                });
+
+        let x_dim = images[0].len();
+        let y_dim = labels.iter().collect::<HashSet<_>>().len();
+
+        Mnist(LabelledImages {
+            images,
+            labels,
+            x_dim,
+            y_dim,
+        })
+    }
+
+    /// Same as `from_parquet`, reading the file lazily as batches of at most `batch_size`
+    /// samples, in the order of the file, so that files larger than memory can be trained on.
+    pub fn stream_parquet(
+        path: &Path,
+        batch_size: usize,
+    ) -> impl Iterator<Item = Vec<(Vec<f64>, u32)>> {
+        if batch_size == 0 {
+            panic!("Expected a batch size of at least 1")
+        }
+        let file =
+            File::open(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        let reader = SerializedFileReader::new(file)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        let mut rows = RowIter::from_file_into(Box::new(reader));
+        std::iter::from_fn(move || {
+            let batch: Vec<(Vec<f64>, u32)> = rows
+                .by_ref()
+                .take(batch_size)
+                .map(|record| Mnist::parse_row(&record))
+                .collect();
+            (!batch.is_empty()).then_some(batch)
+        })
+    }
+
+    fn parse_row(record: &Row) -> (Vec<f64>, u32) {
+        let mut image = None;
+        let mut label = None;
+        for (name, field) in record.get_column_iter() {
+            match name.as_str() {
+                "data" => match field {
+                    Field::ListInternal(l) => {
+                        let vals: Vec<f64> = l
+                            .elements()
+                            .iter()
+                            .map(|f| match f {
+                                Field::Double(f) => *f,
+                                f => panic!("Unexpected array value type: {:?}", f),
+                            })
+                            .collect();
+                        image = Some(vals);
                     }
+                    f => panic!("Unexpcted type for data field: {:?}", f),
+                },
+                "labels" => match field {
+                    Field::Long(i) => label = Some(*i as u32),
+                    f => panic!("Unexpcted type for labels field: {:?}", f),
+                },
+                n => panic!("Unexpected column: {:?}", n),
+            }
+        }
+        match (image, label) {
+            (Some(image), Some(label)) => (image, label),
+            _ => panic!("Expected data and labels columns"),
+        }
+    }
+
+    /// Reads the images and labels files in the IDX format of the original MNIST, e.g.
+    /// `train-images-idx3-ubyte.gz` and `train-labels-idx1-ubyte.gz`, gzipped or not. The pixels
+    /// are scaled from bytes to `[0, 1]`.
+    pub fn from_idx(images: &Path, labels: &Path) -> Mnist {
+        Mnist(LabelledImages::from_idx(images, labels))
+    }
+
+    /// Loads the digits from `cache_dir`, downloading them there first from `url` if they aren't
+    /// cached yet with the expected hex `sha256`, see `download`. `MNIST_URL` and `MNIST_SHA256`
+    /// point to the copy in this repository.
+    #[cfg(feature = "http")]
+    pub fn download(url: &str, sha256: &str, cache_dir: &Path) -> Mnist {
+        Mnist::from_parquet(&download(url, sha256, cache_dir))
+    }
+}
+
+impl Deref for Mnist {
+    type Target = LabelledImages;
+
+    fn deref(&self) -> &LabelledImages {
+        &self.0
+    }
+}
+
+impl Dataset for Mnist {
+    type Label = u32;
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, u32) {
+        self.0.get(index)
+    }
+
+    fn label(&self, index: usize) -> u32 {
+        self.0.label(index)
+    }
+}
+
+/// Fashion-MNIST, greyscale images of clothes in place of the digits of MNIST.
+pub struct FashionMnist(LabelledImages);
+
+impl FashionMnist {
+    /// Names of the classes, by label.
+    pub const CLASSES: [&'static str; 10] = [
+        "T-shirt/top",
+        "Trouser",
+        "Pullover",
+        "Dress",
+        "Coat",
+        "Sandal",
+        "Shirt",
+        "Sneaker",
+        "Bag",
+        "Ankle boot",
+    ];
+
+    /// Reads the images and labels files, which have the same IDX layout as the original MNIST,
+    /// e.g. `train-images-idx3-ubyte.gz` and `train-labels-idx1-ubyte.gz`, gzipped or not. The
+    /// pixels are scaled from bytes to `[0, 1]`.
+    pub fn from_idx(images: &Path, labels: &Path) -> FashionMnist {
+        FashionMnist(LabelledImages::from_idx(images, labels))
+    }
+}
+
+impl Deref for FashionMnist {
+    type Target = LabelledImages;
+
+    fn deref(&self) -> &LabelledImages {
+        &self.0
+    }
+}
+
+impl Dataset for FashionMnist {
+    type Label = u32;
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, u32) {
+        self.0.get(index)
+    }
+
+    fn label(&self, index: usize) -> u32 {
+        self.0.label(index)
+    }
+}
+
+/// CIFAR-10 colour images of 32x32 pixels, as the red, green and blue planes one after the other.
+pub struct Cifar10(LabelledImages);
+
+impl Cifar10 {
+    /// Names of the classes, by label.
+    pub const CLASSES: [&'static str; 10] = [
+        "airplane",
+        "automobile",
+        "bird",
+        "cat",
+        "deer",
+        "dog",
+        "frog",
+        "horse",
+        "ship",
+        "truck",
+    ];
+    const IMAGE_SIZE: usize = 3 * 32 * 32;
+
+    /// Reads batches in the binary format, e.g. `data_batch_1.bin` to `data_batch_5.bin` for
+    /// training. The pixels are scaled from bytes to `[0, 1]`.
+    pub fn from_binary(paths: &[&Path]) -> Cifar10 {
+        let batches: Vec<Vec<u8>> = paths
+            .iter()
+            .map(|path| {
+                fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()))
+            })
+            .collect();
+        Cifar10::from_binary_bytes(&batches.iter().map(|b| &b[..]).collect::<Vec<&[u8]>>())
+    }
+
+    fn from_binary_bytes(batches: &[&[u8]]) -> Cifar10 {
+        let record = 1 + Cifar10::IMAGE_SIZE;
+        let (images, labels) = batches
+            .iter()
+            .flat_map(|batch| {
+                if batch.len() % record != 0 {
+                    panic!(
+                        "Expected records of {} bytes, but got {} bytes",
+                        record,
+                        batch.len()
+                    )
                 }
+                batch.chunks(record)
+            })
+            .map(|r| {
+                let image: Vec<f64> = r[1..].iter().map(|p| *p as f64 / 255.).collect();
+                (image, r[0] as u32)
+            })
+            .unzip();
+        Cifar10(LabelledImages {
+            images,
+            labels,
+            x_dim: Cifar10::IMAGE_SIZE,
+            y_dim: Cifar10::CLASSES.len(),
+        })
+    }
+}
+
+impl Deref for Cifar10 {
+    type Target = LabelledImages;
+
+    fn deref(&self) -> &LabelledImages {
+        &self.0
+    }
+}
+
+impl Dataset for Cifar10 {
+    type Label = u32;
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, u32) {
+        self.0.get(index)
+    }
+
+    fn label(&self, index: usize) -> u32 {
+        self.0.label(index)
+    }
+}
+
+/// Samples from arrays of features and targets with one row per sample, e.g. saved with NumPy.
+/// The targets are rows of values, see `into_classes` for class labels.
+pub struct ArrayDataset<L> {
+    features: Vec<Vec<f64>>,
+    labels: Vec<L>,
+    pub x_dim: usize,
+}
+
+impl ArrayDataset<Vec<f64>> {
+    /// Arrays with as many rows, where every row of `features` is flattened into the features of
+    /// a sample. One-dimensional arrays hold one value per sample.
+    pub fn from_arrays(features: &Tensor, targets: &Tensor) -> ArrayDataset<Vec<f64>> {
+        let rows = |tensor: &Tensor| -> Vec<Vec<f64>> {
+            match tensor.shape().first() {
+                Some(n) if *n > 0 => tensor
+                    .data()
+                    .chunks(tensor.data().len() / n)
+                    .map(|row| row.to_vec())
+                    .collect(),
+                _ => vec![],
             }
+        };
+        let features = rows(features);
+        let labels = rows(targets);
+        if labels.len() != features.len() {
+            panic!(
+                "Expected {} targets, but got {}",
+                features.len(),
+                labels.len()
+            )
+        }
+        let x_dim = features.first().map_or(0, |x| x.len());
+        ArrayDataset {
+            features,
+            labels,
+            x_dim,
+        }
+    }
 
-            let x_dim = images[0].len();
-            let y_dim = labels.iter().collect::<HashSet<_>>().len();
+    /// Reads the features and targets from two `.npy` files, see `io::read_npy`.
+    pub fn from_npy(features: &Path, targets: &Path) -> ArrayDataset<Vec<f64>> {
+        let read = |path: &Path| {
+            io::read_npy(
+                &fs::read(path)
+                    .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display())),
+            )
+        };
+        ArrayDataset::from_arrays(&read(features), &read(targets))
+    }
 
-            return Mnist {
-                images,
-                labels,
-                x_dim,
-                y_dim,
-            };
+    /// Reads the arrays named `features` and `targets` from an `.npz` archive, e.g. saved with
+    /// `numpy.savez(path, x=x, y=y)`.
+    pub fn from_npz(path: &Path, features: &str, targets: &str) -> ArrayDataset<Vec<f64>> {
+        let arrays = io::read_npz(
+            &fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display())),
+        );
+        let array = |name: &str| -> &Tensor {
+            arrays
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, tensor)| tensor)
+                .unwrap_or_else(|| panic!("Missing array {name}"))
+        };
+        ArrayDataset::from_arrays(array(features), array(targets))
+    }
+
+    /// Reads a table in the Arrow IPC file format, e.g. a Feather file written by pandas or
+    /// polars, or in the Arrow IPC stream format. The `target` column holds the targets and the
+    /// other columns the features, which may be numbers or lists of numbers.
+    pub fn from_arrow_ipc(path: &Path, target: &str) -> ArrayDataset<Vec<f64>> {
+        let bytes =
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        ArrayDataset::from_arrow_ipc_bytes(bytes, target)
+    }
+
+    fn from_arrow_ipc_bytes(bytes: Vec<u8>, target: &str) -> ArrayDataset<Vec<f64>> {
+        fn failed<T>(e: ArrowError) -> T {
+            panic!("Failed to read Arrow IPC: {e}")
+        }
+        let batches: Vec<RecordBatch> = match bytes.starts_with(b"ARROW1") {
+            true => IpcFileReader::try_new(Cursor::new(bytes), None)
+                .unwrap_or_else(failed)
+                .collect::<Result<_, _>>(),
+            false => StreamReader::try_new(Cursor::new(bytes), None)
+                .unwrap_or_else(failed)
+                .collect::<Result<_, _>>(),
         }
+        .unwrap_or_else(failed);
+
+        let mut features: Vec<Vec<f64>> = vec![];
+        let mut labels: Vec<Vec<f64>> = vec![];
+        batches.iter().for_each(|batch| {
+            let schema = batch.schema();
+            let index = schema
+                .index_of(target)
+                .unwrap_or_else(|_| panic!("Missing column {target}"));
+            let mut rows = vec![vec![]; batch.num_rows()];
+            batch
+                .columns()
+                .iter()
+                .zip(schema.fields())
+                .enumerate()
+                .filter(|(i, _)| *i != index)
+                .for_each(|(_, (column, field))| {
+                    rows.iter_mut()
+                        .zip(column_rows(column, field.name()))
+                        .for_each(|(row, values)| row.extend(values));
+                });
+            features.extend(rows);
+            labels.extend(column_rows(batch.column(index), target));
+        });
 
-        panic!()
+        let x_dim = features.first().map_or(0, |x| x.len());
+        ArrayDataset {
+            features,
+            labels,
+            x_dim,
+        }
     }
 
-    pub fn as_xy(&self) -> Vec<(&Vec<f64>, u32)> {
-        self.images
+    /// Takes the targets as class labels, which have to be single non-negative integers.
+    pub fn into_classes(self) -> ArrayDataset<u32> {
+        let labels = self
+            .labels
             .iter()
-            .zip(self.labels.iter().cloned())
+            .map(|target| match target[..] {
+                [class] if class >= 0. && class.fract() == 0. => class as u32,
+                _ => panic!("Expected a class label, but got {:?}", target),
+            })
+            .collect();
+        ArrayDataset {
+            features: self.features,
+            labels,
+            x_dim: self.x_dim,
+        }
+    }
+}
+
+/// Values of each row of an Arrow column of numbers or of lists of numbers.
+fn column_rows(column: &ArrayRef, name: &str) -> Vec<Vec<f64>> {
+    let values = |array: &dyn Array| -> Vec<f64> {
+        if array.null_count() > 0 {
+            panic!("Unexpected null in column {name}")
+        }
+        let array = arrow_cast::cast(array, &DataType::Float64)
+            .unwrap_or_else(|e| panic!("Unexpected type for column {name}: {e}"));
+        let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+        array.values().to_vec()
+    };
+    if column.null_count() > 0 {
+        panic!("Unexpected null in column {name}")
+    }
+
+    // The values of all the lists are cast at once, then split at the offsets of the lists.
+    let any = column.as_any();
+    let (flat, offsets): (Vec<f64>, Vec<usize>) = match column.data_type() {
+        DataType::List(_) => {
+            let lists = any.downcast_ref::<ListArray>().unwrap();
+            let offsets = lists.value_offsets().iter().map(|o| *o as usize);
+            (values(lists.values()), offsets.collect())
+        }
+        DataType::LargeList(_) => {
+            let lists = any.downcast_ref::<LargeListArray>().unwrap();
+            let offsets = lists.value_offsets().iter().map(|o| *o as usize);
+            (values(lists.values()), offsets.collect())
+        }
+        DataType::FixedSizeList(_, _) => {
+            let lists = any.downcast_ref::<FixedSizeListArray>().unwrap();
+            let offsets = (0..=lists.len())
+                .map(|i| (lists.value_offset(0) + i as i32 * lists.value_length()) as usize);
+            (values(lists.values()), offsets.collect())
+        }
+        _ => return values(column).into_iter().map(|v| vec![v]).collect(),
+    };
+    offsets
+        .windows(2)
+        .map(|w| flat[w[0]..w[1]].to_vec())
+        .collect()
+}
+
+impl<L: Clone> Dataset for ArrayDataset<L> {
+    type Label = L;
+
+    fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, L) {
+        (self.features[index].clone(), self.labels[index].clone())
+    }
+
+    fn label(&self, index: usize) -> L {
+        self.labels[index].clone()
+    }
+}
+
+/// Transformation of the features of every sample, see `Transformed`.
+pub trait Transform {
+    fn apply(&self, features: Vec<f64>) -> Vec<f64>;
+}
+
+/// Scales every feature to a mean of 0 and a standard deviation of 1 over the dataset it was
+/// fitted on, e.g. the training set, which can then be applied to the validation set as well.
+#[derive(Debug, Clone)]
+pub struct Standardise {
+    pub mean: Vec<f64>,
+    pub std: Vec<f64>,
+}
+
+impl Standardise {
+    /// Constant features are only centred.
+    pub fn fit(dataset: &impl Dataset) -> Standardise {
+        let n = dataset.len() as f64;
+        let features: Vec<Vec<f64>> = (0..dataset.len()).map(|i| dataset.get(i).0).collect();
+        let x_dim = features.first().map_or(0, |x| x.len());
+        let mean: Vec<f64> = (0..x_dim)
+            .map(|j| features.iter().map(|x| x[j]).sum::<f64>() / n)
+            .collect();
+        let std = (0..x_dim)
+            .map(|j| {
+                let variance = features
+                    .iter()
+                    .map(|x| (x[j] - mean[j]).powf(2.))
+                    .sum::<f64>()
+                    / n;
+                match variance > 0. {
+                    true => variance.sqrt(),
+                    false => 1.,
+                }
+            })
+            .collect();
+        Standardise { mean, std }
+    }
+}
+
+impl Transform for Standardise {
+    fn apply(&self, features: Vec<f64>) -> Vec<f64> {
+        if features.len() != self.mean.len() {
+            panic!(
+                "Expected {} features, but got {}",
+                self.mean.len(),
+                features.len()
+            )
+        }
+        features
+            .iter()
+            .zip(self.mean.iter().zip(&self.std))
+            .map(|(x, (mean, std))| (x - mean) / std)
             .collect()
     }
 }
 
+/// Scales every feature linearly to `[0, 1]` over the dataset it was fitted on.
+#[derive(Debug, Clone)]
+pub struct MinMaxScale {
+    pub min: Vec<f64>,
+    pub max: Vec<f64>,
+}
+
+impl MinMaxScale {
+    /// Constant features are mapped to 0.
+    pub fn fit(dataset: &impl Dataset) -> MinMaxScale {
+        let x_dim = match dataset.is_empty() {
+            true => 0,
+            false => dataset.get(0).0.len(),
+        };
+        let (min, max) = (0..dataset.len()).map(|i| dataset.get(i).0).fold(
+            (vec![f64::INFINITY; x_dim], vec![f64::NEG_INFINITY; x_dim]),
+            |(min, max), x| {
+                (
+                    min.iter().zip(&x).map(|(m, x)| m.min(*x)).collect(),
+                    max.iter().zip(&x).map(|(m, x)| m.max(*x)).collect(),
+                )
+            },
+        );
+        MinMaxScale { min, max }
+    }
+}
+
+impl Transform for MinMaxScale {
+    fn apply(&self, features: Vec<f64>) -> Vec<f64> {
+        if features.len() != self.min.len() {
+            panic!(
+                "Expected {} features, but got {}",
+                self.min.len(),
+                features.len()
+            )
+        }
+        features
+            .iter()
+            .zip(self.min.iter().zip(&self.max))
+            .map(|(x, (min, max))| match max > min {
+                true => (x - min) / (max - min),
+                false => 0.,
+            })
+            .collect()
+    }
+}
+
+/// Dataset whose features go through `transform` whenever a sample is read, e.g. by a
+/// `DataLoader`.
+pub struct Transformed<'a, D: Dataset, T: Transform> {
+    dataset: &'a D,
+    transform: T,
+}
+
+impl<'a, D: Dataset, T: Transform> Transformed<'a, D, T> {
+    pub fn new(dataset: &'a D, transform: T) -> Transformed<'a, D, T> {
+        Transformed { dataset, transform }
+    }
+}
+
+impl<D: Dataset, T: Transform> Dataset for Transformed<'_, D, T> {
+    type Label = D::Label;
+
+    fn len(&self) -> usize {
+        self.dataset.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, D::Label) {
+        let (features, label) = self.dataset.get(index);
+        (self.transform.apply(features), label)
+    }
+
+    fn label(&self, index: usize) -> D::Label {
+        self.dataset.label(index)
+    }
+}
+
+/// Dataset whose class labels are turned into one-hot targets of `num_classes` values, e.g.
+/// for `loss::mse`.
+pub struct OneHot<'a, D: Dataset> {
+    dataset: &'a D,
+    num_classes: usize,
+}
+
+impl<'a, D: Dataset> OneHot<'a, D> {
+    pub fn new(dataset: &'a D, num_classes: usize) -> OneHot<'a, D> {
+        OneHot {
+            dataset,
+            num_classes,
+        }
+    }
+}
+
+impl<D: Dataset> OneHot<'_, D>
+where
+    D::Label: TryInto<usize>,
+{
+    fn one_hot(&self, label: D::Label) -> Vec<f64> {
+        let label = label
+            .try_into()
+            .unwrap_or_else(|_| panic!("Expected a label below {}", self.num_classes));
+        Util::one_hot(label, self.num_classes)
+    }
+}
+
+impl<D: Dataset> Dataset for OneHot<'_, D>
+where
+    D::Label: TryInto<usize>,
+{
+    type Label = Vec<f64>;
+
+    fn len(&self) -> usize {
+        self.dataset.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, Vec<f64>) {
+        let (features, label) = self.dataset.get(index);
+        (features, self.one_hot(label))
+    }
+
+    fn label(&self, index: usize) -> Vec<f64> {
+        self.one_hot(self.dataset.label(index))
+    }
+}
+
+/// Samples of a dataset at the given indices, e.g. one side of a split.
+pub struct Subset<'a, D: Dataset> {
+    dataset: &'a D,
+    indices: Vec<usize>,
+}
+
+impl<'a, D: Dataset> Subset<'a, D> {
+    pub fn new(dataset: &'a D, indices: Vec<usize>) -> Subset<'a, D> {
+        if let Some(i) = indices.iter().find(|i| **i >= dataset.len()) {
+            panic!("Expected indices below {}, but got {}", dataset.len(), i)
+        }
+        Subset { dataset, indices }
+    }
+
+    /// Indices of the samples in the underlying dataset.
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+}
+
+impl<D: Dataset> Dataset for Subset<'_, D> {
+    type Label = D::Label;
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn get(&self, index: usize) -> (Vec<f64>, D::Label) {
+        self.dataset.get(self.indices[index])
+    }
+
+    fn label(&self, index: usize) -> D::Label {
+        self.dataset.label(self.indices[index])
+    }
+}
+
+fn seeded(seed: Option<u64>) -> StdRng {
+    seed.map(StdRng::seed_from_u64)
+        .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap())
+}
+
+fn check_fraction(fraction: f64) {
+    if !(0. ..=1.).contains(&fraction) {
+        panic!("Expected a fraction between 0 and 1, but got {}", fraction)
+    }
+}
+
+/// Randomly splits `dataset` into a part with `fraction` of the samples, e.g. for training, and
+/// a part with the rest, e.g. for validation.
+pub fn split<D: Dataset>(
+    dataset: &D,
+    fraction: f64,
+    seed: Option<u64>,
+) -> (Subset<'_, D>, Subset<'_, D>) {
+    check_fraction(fraction);
+    let mut indices: Vec<usize> = (0..dataset.len()).collect();
+    indices.shuffle(&mut seeded(seed));
+    let rest = indices.split_off((fraction * dataset.len() as f64).round() as usize);
+    (Subset::new(dataset, indices), Subset::new(dataset, rest))
+}
+
+/// Shuffled indices of the samples of each class, in the order the classes first appear.
+fn indices_by_class<D: Dataset>(dataset: &D, rng: &mut StdRng) -> Vec<Vec<usize>>
+where
+    D::Label: Hash + Eq,
+{
+    let mut classes: HashMap<D::Label, usize> = HashMap::new();
+    let mut indices: Vec<Vec<usize>> = vec![];
+    (0..dataset.len()).for_each(|i| {
+        let class = *classes.entry(dataset.label(i)).or_insert_with(|| {
+            indices.push(vec![]);
+            indices.len() - 1
+        });
+        indices[class].push(i);
+    });
+    indices.iter_mut().for_each(|class| class.shuffle(rng));
+    indices
+}
+
+/// Same as `split`, with `fraction` of the samples of every class in the first part, so that
+/// both parts keep the class balance of `dataset`, even for rare classes.
+pub fn stratified_split<D: Dataset>(
+    dataset: &D,
+    fraction: f64,
+    seed: Option<u64>,
+) -> (Subset<'_, D>, Subset<'_, D>)
+where
+    D::Label: Hash + Eq,
+{
+    check_fraction(fraction);
+    let (first, rest) = indices_by_class(dataset, &mut seeded(seed))
+        .into_iter()
+        .map(|mut class| {
+            let rest = class.split_off((fraction * class.len() as f64).round() as usize);
+            (class, rest)
+        })
+        .fold((vec![], vec![]), |(mut first, mut rest), (f, r)| {
+            first.extend(f);
+            rest.extend(r);
+            (first, rest)
+        });
+    (Subset::new(dataset, first), Subset::new(dataset, rest))
+}
+
+/// Splits `dataset` into `k` folds where every class has the same number of samples, give or
+/// take one, and returns for each fold the other folds for training and the fold itself for
+/// validation, for k-fold cross-validation.
+pub fn stratified_k_fold<D: Dataset>(
+    dataset: &D,
+    k: usize,
+    seed: Option<u64>,
+) -> Vec<(Subset<'_, D>, Subset<'_, D>)>
+where
+    D::Label: Hash + Eq,
+{
+    if k < 2 {
+        panic!("Expected at least 2 folds, but got {}", k)
+    }
+    // Dealing the samples class after class to the folds in turn balances both the classes
+    // and the sizes of the folds.
+    let mut folds: Vec<Vec<usize>> = vec![vec![]; k];
+    indices_by_class(dataset, &mut seeded(seed))
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .for_each(|(i, index)| folds[i % k].push(index));
+    (0..k)
+        .map(|f| {
+            let train = (0..k)
+                .filter(|other| *other != f)
+                .flat_map(|other| folds[other].iter().cloned())
+                .collect();
+            (
+                Subset::new(dataset, train),
+                Subset::new(dataset, folds[f].clone()),
+            )
+        })
+        .collect()
+}
+
+/// Iterates over a dataset in mini-batches of samples, reshuffled at every pass by default.
+pub struct DataLoader<'a, D: Dataset> {
+    dataset: &'a D,
+    batch_size: usize,
+    shuffle: bool,
+    drop_last: bool,
+    rng: StdRng,
+}
+
+impl<'a, D: Dataset> DataLoader<'a, D> {
+    /// Shuffles with a generator seeded with `seed`, or from entropy if `None`.
+    pub fn new(dataset: &'a D, batch_size: usize, seed: Option<u64>) -> DataLoader<'a, D> {
+        if batch_size == 0 {
+            panic!("Expected a batch size of at least 1")
+        }
+        DataLoader {
+            dataset,
+            batch_size,
+            shuffle: true,
+            drop_last: false,
+            rng: seeded(seed),
+        }
+    }
+
+    /// Whether to go through the samples in a new random order at every pass, true by default.
+    pub fn with_shuffle(mut self, shuffle: bool) -> DataLoader<'a, D> {
+        self.shuffle = shuffle;
+        self
+    }
+
+    /// Whether to skip the last batch when it is smaller than the others, false by default.
+    pub fn with_drop_last(mut self, drop_last: bool) -> DataLoader<'a, D> {
+        self.drop_last = drop_last;
+        self
+    }
+
+    /// Number of batches in a pass over the dataset.
+    pub fn num_batches(&self) -> usize {
+        match self.drop_last {
+            true => self.dataset.len() / self.batch_size,
+            false => self.dataset.len().div_ceil(self.batch_size),
+        }
+    }
+
+    /// One pass over the dataset, as batches of samples. Use `flatten` to go through the
+    /// samples one at a time.
+    pub fn batches(&mut self) -> impl Iterator<Item = Vec<(Vec<f64>, D::Label)>> + 'a {
+        let mut order: Vec<usize> = (0..self.dataset.len()).collect();
+        if self.shuffle {
+            order.shuffle(&mut self.rng);
+        }
+        let dataset = self.dataset;
+        let batch_size = self.batch_size;
+        (0..self.num_batches()).map(move |b| {
+            order[b * batch_size..order.len().min((b + 1) * batch_size)]
+                .iter()
+                .map(|i| dataset.get(*i))
+                .collect()
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -83,4 +1077,290 @@ mod tests {
         assert_eq!(mnist.x_dim, 64);
         assert_eq!(mnist.y_dim, 10)
     }
+
+    #[test]
+    fn test_mnist_dataset() {
+        let mnist = Mnist::from_parquet(Path::new("mnist.parquet"));
+        assert_eq!(mnist.len(), 1797);
+        let (x, y) = mnist.get(3);
+        assert_eq!((x.len(), y), (64, mnist.labels[3]));
+    }
+
+    #[test]
+    fn test_mnist_class_weights() {
+        let mnist = Mnist::from_parquet(Path::new("mnist.parquet"));
+        let weights = mnist.class_weights();
+        assert_eq!(weights.len(), 10);
+        assert!(weights.iter().all(|w| (w - 1.).abs() < 0.05));
+    }
+
+    #[test]
+    fn test_stream_parquet() {
+        let path = Path::new("mnist.parquet");
+        let mnist = Mnist::from_parquet(path);
+
+        let batches: Vec<Vec<(Vec<f64>, u32)>> = Mnist::stream_parquet(path, 500).collect();
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<usize>>(),
+            vec![500, 500, 500, 297]
+        );
+        batches
+            .iter()
+            .flatten()
+            .enumerate()
+            .for_each(|(i, sample)| assert_eq!(*sample, mnist.get(i)));
+    }
+
+    #[test]
+    fn test_mnist_idx() {
+        let mut images = vec![0, 0, 0x08, 3];
+        [3u32, 2, 2]
+            .iter()
+            .for_each(|d| images.extend(d.to_be_bytes()));
+        images.extend([0, 255, 51, 102, 255, 0, 0, 0, 1, 2, 3, 4]);
+        let mut labels = vec![0, 0, 0x08, 1];
+        labels.extend(3u32.to_be_bytes());
+        labels.extend([7, 1, 7]);
+
+        let mnist = LabelledImages::from_idx_bytes(&images, &labels);
+        assert_eq!((mnist.len(), mnist.x_dim, mnist.y_dim), (3, 4, 2));
+        assert_eq!(mnist.get(0), (vec![0., 1., 0.2, 0.4], 7));
+        assert_eq!(mnist.get(1).1, 1);
+        assert_eq!(FashionMnist::CLASSES[mnist.label(0) as usize], "Sneaker");
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_download_cached() {
+        let name = format!("micrograd_rs_test_download_{}", std::process::id());
+        let cache_dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&cache_dir).unwrap();
+        fs::copy("mnist.parquet", cache_dir.join("mnist.parquet")).unwrap();
+
+        // Nothing listens on the discard port, so this only passes without downloading.
+        let url = "http://127.0.0.1:9/mnist.parquet";
+        let path = download(url, MNIST_SHA256, &cache_dir);
+        assert_eq!(path, cache_dir.join("mnist.parquet"));
+        assert_eq!(Mnist::download(url, MNIST_SHA256, &cache_dir).len(), 1797);
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_cifar10() {
+        let record = |label: u8, pixel: u8| {
+            let mut bytes = vec![label];
+            bytes.extend(vec![pixel; Cifar10::IMAGE_SIZE]);
+            bytes
+        };
+        let first = [record(3, 0), record(9, 255)].concat();
+        let second = record(0, 51);
+
+        let cifar = Cifar10::from_binary_bytes(&[&first, &second]);
+        assert_eq!((cifar.len(), cifar.x_dim, cifar.y_dim), (3, 3072, 10));
+        assert_eq!(
+            (0..3).map(|i| cifar.get(i).1).collect::<Vec<u32>>(),
+            vec![3, 9, 0]
+        );
+        assert!(cifar.get(1).0.iter().all(|p| *p == 1.));
+        assert!(cifar.get(2).0.iter().all(|p| (p - 0.2).abs() < 1e-12));
+        assert_eq!(Cifar10::CLASSES[cifar.get(0).1 as usize], "cat");
+    }
+
+    #[test]
+    fn test_array_dataset() {
+        let features = Tensor::new(vec![3, 2, 2], (0..12).map(|v| v as f64).collect());
+        let targets = Tensor::new(vec![3], vec![1., 0., 2.]);
+
+        let dataset = ArrayDataset::from_arrays(&features, &targets);
+        assert_eq!((dataset.len(), dataset.x_dim), (3, 4));
+        assert_eq!(dataset.get(1), (vec![4., 5., 6., 7.], vec![0.]));
+
+        let classes = dataset.into_classes();
+        assert_eq!(classes.get(2), (vec![8., 9., 10., 11.], 2));
+        let (train, _) = stratified_split(&classes, 0.5, Some(0));
+        assert_eq!(train.len(), 3);
+    }
+
+    #[test]
+    fn test_array_dataset_files() {
+        let features = Tensor::new(vec![3, 2], (0..6).map(|v| v as f64).collect());
+        let targets = Tensor::new(vec![3], vec![1., 0., 2.]);
+        let dir =
+            std::env::temp_dir().join(format!("micrograd_rs_test_arrays_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("dataset.npz");
+        let arrays = [
+            ("x".to_string(), features.clone()),
+            ("y".to_string(), targets.clone()),
+        ];
+        fs::write(&path, io::write_npz(&arrays)).unwrap();
+        let dataset = ArrayDataset::from_npz(&path, "x", "y");
+        assert_eq!(dataset.get(1), (vec![2., 3.], vec![0.]));
+
+        let (x, y) = (dir.join("x.npy"), dir.join("y.npy"));
+        fs::write(&x, io::write_npy(&features)).unwrap();
+        fs::write(&y, io::write_npy(&targets)).unwrap();
+        let dataset = ArrayDataset::from_npy(&x, &y);
+        assert_eq!(dataset.get(2), (vec![4., 5.], vec![2.]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_arrow_ipc() {
+        use arrow_array::{types::Float32Type, Int64Array};
+        use arrow_ipc::writer::{FileWriter, StreamWriter};
+        use std::sync::Arc;
+
+        let pixels = ListArray::from_iter_primitive::<Float32Type, _, _>(vec![
+            Some(vec![Some(0.5), Some(1.)]),
+            Some(vec![Some(0.), Some(0.25)]),
+        ]);
+        let bias = Int64Array::from(vec![3, 4]);
+        let labels = Int64Array::from(vec![7, 1]);
+        let batch = RecordBatch::try_from_iter(vec![
+            ("data", Arc::new(pixels) as ArrayRef),
+            ("labels", Arc::new(labels) as ArrayRef),
+            ("bias", Arc::new(bias) as ArrayRef),
+        ])
+        .unwrap();
+
+        let mut writer = FileWriter::try_new(vec![], &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        let dataset = ArrayDataset::from_arrow_ipc_bytes(writer.into_inner().unwrap(), "labels");
+        assert_eq!((dataset.len(), dataset.x_dim), (4, 3));
+        assert_eq!(dataset.get(3), (vec![0., 0.25, 4.], vec![1.]));
+        assert_eq!(dataset.into_classes().get(0).1, 7);
+
+        let mut writer = StreamWriter::try_new(vec![], &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        let dataset = ArrayDataset::from_arrow_ipc_bytes(writer.into_inner().unwrap(), "bias");
+        assert_eq!(dataset.get(0), (vec![0.5, 1., 7.], vec![3.]));
+    }
+
+    #[test]
+    fn test_normalisation() {
+        let samples: Vec<(Vec<f64>, u32)> = vec![
+            (vec![1., 10., 5.], 0),
+            (vec![2., 30., 5.], 1),
+            (vec![3., 20., 5.], 0),
+            (vec![6., 40., 5.], 1),
+        ];
+        let (train, validation) = (
+            Subset::new(&samples, vec![0, 1, 2]),
+            Subset::new(&samples, vec![3]),
+        );
+
+        let standardise = Standardise::fit(&train);
+        assert_eq!(standardise.mean, vec![2., 20., 5.]);
+        assert_eq!(standardise.std[2], 1.);
+        let train = Transformed::new(&train, standardise.clone());
+        let x: Vec<Vec<f64>> = (0..3).map(|i| train.get(i).0).collect();
+        (0..3).for_each(|j| {
+            assert!(x.iter().map(|x| x[j]).sum::<f64>().abs() < 1e-12);
+        });
+        assert!((x.iter().map(|x| x[0] * x[0]).sum::<f64>() / 3. - 1.).abs() < 1e-12);
+        assert_eq!(train.get(1).1, 1);
+        let validation = Transformed::new(&validation, standardise);
+        assert!((validation.get(0).0[0] - 4. / (2f64 / 3.).sqrt()).abs() < 1e-12);
+
+        let scale = MinMaxScale::fit(&samples);
+        let scaled = Transformed::new(&samples, scale);
+        assert_eq!(scaled.get(1).0, vec![0.2, 2. / 3., 0.]);
+        let batches: Vec<Vec<(Vec<f64>, u32)>> =
+            DataLoader::new(&scaled, 4, Some(0)).batches().collect();
+        assert!(batches[0]
+            .iter()
+            .all(|(x, _)| x.iter().all(|v| (0. ..=1.).contains(v))));
+    }
+
+    #[test]
+    fn test_one_hot() {
+        assert_eq!(Util::one_hot(2, 4), vec![0., 0., 1., 0.]);
+
+        let samples: Vec<(Vec<f64>, u32)> = vec![(vec![0.5], 1), (vec![0.], 0)];
+        let one_hot = OneHot::new(&samples, 3);
+        assert_eq!(one_hot.len(), 2);
+        assert_eq!(one_hot.get(0), (vec![0.5], vec![0., 1., 0.]));
+        assert_eq!(one_hot.get(1).1, vec![1., 0., 0.]);
+        assert_eq!(one_hot.label(0), vec![0., 1., 0.]);
+    }
+
+    #[test]
+    fn test_data_loader() {
+        let samples: Vec<(Vec<f64>, usize)> = (0..10).map(|i| (vec![i as f64], i)).collect();
+
+        let mut loader = DataLoader::new(&samples, 4, Some(0));
+        assert_eq!(loader.num_batches(), 3);
+        let batches: Vec<Vec<(Vec<f64>, usize)>> = loader.batches().collect();
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<usize>>(),
+            vec![4, 4, 2]
+        );
+        let mut labels: Vec<usize> = batches.iter().flatten().map(|(_, y)| *y).collect();
+        assert_ne!(labels, (0..10).collect::<Vec<usize>>());
+        labels.sort();
+        assert_eq!(labels, (0..10).collect::<Vec<usize>>());
+        batches
+            .iter()
+            .flatten()
+            .for_each(|(x, y)| assert_eq!(x[0], *y as f64));
+
+        // Every pass is shuffled anew.
+        let first: Vec<usize> = loader.batches().flatten().map(|(_, y)| y).collect();
+        let second: Vec<usize> = loader.batches().flatten().map(|(_, y)| y).collect();
+        assert_ne!(first, second);
+
+        let mut loader = DataLoader::new(&samples, 4, None)
+            .with_shuffle(false)
+            .with_drop_last(true);
+        assert_eq!(loader.num_batches(), 2);
+        let labels: Vec<usize> = loader.batches().flatten().map(|(_, y)| y).collect();
+        assert_eq!(labels, (0..8).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_split() {
+        let samples: Vec<(Vec<f64>, usize)> = (0..10).map(|i| (vec![i as f64], i)).collect();
+        let (train, validation) = split(&samples, 0.7, Some(0));
+        assert_eq!((train.len(), validation.len()), (7, 3));
+        let mut indices = [train.indices(), validation.indices()].concat();
+        indices.sort();
+        assert_eq!(indices, (0..10).collect::<Vec<usize>>());
+        assert_eq!(validation.get(1).1, validation.indices()[1]);
+        assert_eq!(validation.label(2), validation.indices()[2]);
+    }
+
+    #[test]
+    fn test_stratified_split() {
+        // A rare class of 5 samples among 100.
+        let samples: Vec<(Vec<f64>, u32)> = (0..100)
+            .map(|i| (vec![i as f64], if i % 20 == 0 { 1 } else { 0 }))
+            .collect();
+        let count = |subset: &Subset<Vec<(Vec<f64>, u32)>>, class: u32| {
+            (0..subset.len())
+                .filter(|i| subset.get(*i).1 == class)
+                .count()
+        };
+
+        let (train, validation) = stratified_split(&samples, 0.6, Some(1));
+        assert_eq!((train.len(), validation.len()), (60, 40));
+        assert_eq!((count(&train, 1), count(&validation, 1)), (3, 2));
+
+        let folds = stratified_k_fold(&samples, 5, Some(2));
+        assert_eq!(folds.len(), 5);
+        folds.iter().for_each(|(train, validation)| {
+            assert_eq!((train.len(), validation.len()), (80, 20));
+            assert_eq!((count(train, 1), count(validation, 1)), (4, 1));
+        });
+        let mut validated: Vec<usize> = folds
+            .iter()
+            .flat_map(|(_, validation)| validation.indices().to_vec())
+            .collect();
+        validated.sort();
+        assert_eq!(validated, (0..100).collect::<Vec<usize>>());
+    }
 }
@@ -1,13 +1,18 @@
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Add, Div, Mul, Neg, Sub},
     rc::Rc,
 };
 
 use num::traits::Pow;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use rayon::prelude::*;
 
-use crate::optimiser::Optimiser;
+use crate::{
+    optimiser::{self, Optimiser},
+    util::{Mean, Util},
+};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Operation {
@@ -17,10 +22,81 @@ pub enum Operation {
     Div,
     Pow,
     Relu,
+    /// The left operand is the slope applied to negative inputs.
+    LeakyRelu,
+    Tanh,
+    Sigmoid,
+    Exp,
+    Max,
+    /// Natural logarithm.
+    Ln,
+}
+
+impl Operation {
+    pub(crate) fn apply(&self, left_val: f64, right_val: f64) -> f64 {
+        match self {
+            Operation::Mul => left_val * right_val,
+            Operation::Add => left_val + right_val,
+            Operation::Sub => left_val - right_val,
+            Operation::Div => right_val / left_val,
+            Operation::Pow => right_val.pow(left_val),
+            Operation::Relu => {
+                if right_val < 0. {
+                    0.
+                } else {
+                    right_val
+                }
+            }
+            Operation::LeakyRelu => {
+                if right_val < 0. {
+                    left_val * right_val
+                } else {
+                    right_val
+                }
+            }
+            Operation::Tanh => right_val.tanh(),
+            Operation::Sigmoid => 1. / (1. + (-right_val).exp()),
+            Operation::Exp => right_val.exp(),
+            Operation::Max => left_val.max(right_val),
+            Operation::Ln => right_val.ln(),
+        }
+    }
+
+    /// Local derivatives of the operation output with respect to its (left, right) operands.
+    fn partials(&self, left_val: f64, right_val: f64, value: f64) -> (f64, f64) {
+        match self {
+            Operation::Mul => (right_val, left_val),
+            Operation::Add => (1., 1.),
+            Operation::Sub => (1., -1.),
+            Operation::Div => (-right_val / (left_val * left_val), 1. / left_val),
+            Operation::Pow => {
+                let d_exponent = if right_val > 0. {
+                    value * right_val.ln()
+                } else {
+                    0.
+                };
+                (d_exponent, left_val * right_val.pow(left_val - 1.))
+            }
+            Operation::Relu => (0., if value > 0. { 1. } else { 0. }),
+            Operation::LeakyRelu => (0., if right_val < 0. { left_val } else { 1. }),
+            Operation::Tanh => (0., 1. - value * value),
+            Operation::Sigmoid => (0., value * (1. - value)),
+            Operation::Exp => (0., value),
+            Operation::Ln => (0., 1. / right_val),
+            // Ties route the gradient to the left operand only.
+            Operation::Max => {
+                if left_val >= right_val {
+                    (1., 0.)
+                } else {
+                    (0., 1.)
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct NodeId(usize);
+pub struct NodeId(pub(crate) usize);
 
 #[derive(Debug, Clone, Copy)]
 pub struct GraphBuilderNode {
@@ -29,11 +105,77 @@ pub struct GraphBuilderNode {
     right_id: NodeId,
 }
 
+/// Entry of a table of leaf nodes picked by the value of `index`, rounded to the nearest integer.
+/// The table holds `rows` nodes whose ids are `stride` apart, starting from `first`.
 #[derive(Debug, Clone, Copy)]
+pub struct GatherNode {
+    index: NodeId,
+    first: NodeId,
+    rows: usize,
+    stride: usize,
+}
+
+impl GatherNode {
+    /// Entry picked by an index of `value`.
+    fn selected(&self, value: f64) -> NodeId {
+        let row = value.round();
+        if !(0. ..self.rows as f64).contains(&row) {
+            panic!("Expected an index in 0..{}, but got {}", self.rows, value)
+        }
+        NodeId(self.first.0 + row as usize * self.stride)
+    }
+}
+
+/// Sum of the pairwise products of `weights` and `inputs`, e.g. the pre-activation of a neuron,
+/// held in a single node instead of a chain of Mul and Add nodes.
+#[derive(Debug, Clone)]
+pub struct DotNode {
+    weights: Vec<NodeId>,
+    inputs: Vec<NodeId>,
+}
+
+#[derive(Debug, Clone)]
 pub enum Node {
     Operation(GraphBuilderNode),
+    Gather(GatherNode),
+    Dot(DotNode),
     Immediate(f64),
+    /// Trainable value, the only kind of node `update_weights` hands to the optimiser.
+    Parameter(f64),
+    /// Parameter left untouched by `update_weights` on steps where it received no gradient, e.g.
+    /// embedding rows that were not looked up.
+    SparseParameter(f64),
     Input,
+    /// Standard normal sample, redrawn from the graph's RNG on every evaluation.
+    Normal,
+    /// Inverted dropout mask keeping the value with the given probability: `1 / keep` or 0,
+    /// redrawn from the graph's RNG on every evaluation.
+    Mask(f64),
+}
+
+impl Node {
+    /// Value of an Operation, Gather or Dot node, given the values of the nodes it reads from.
+    fn compute(&self, value: impl Fn(NodeId) -> f64) -> f64 {
+        match self {
+            Node::Operation(n) => n.operation.apply(value(n.left_id), value(n.right_id)),
+            Node::Gather(g) => value(g.selected(value(g.index))),
+            Node::Dot(d) => d
+                .weights
+                .iter()
+                .zip(d.inputs.iter())
+                .map(|(w, x)| value(*w) * value(*x))
+                .sum(),
+            _ => panic!(
+                "Expected an Operation, Gather or Dot node, but got {:?}",
+                self
+            ),
+        }
+    }
+
+    /// Whether the node is computed from other nodes, as opposed to being a leaf.
+    fn is_computed(&self) -> bool {
+        matches!(self, Node::Operation(_) | Node::Gather(_) | Node::Dot(_))
+    }
 }
 
 #[derive(Debug)]
@@ -41,8 +183,14 @@ pub struct IdGenerator {
     current_id: usize,
 }
 
+impl Default for IdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl IdGenerator {
-    fn get_id(&mut self) -> NodeId {
+    pub(crate) fn get_id(&mut self) -> NodeId {
         let id = self.current_id;
         self.current_id += 1;
         NodeId(id)
@@ -57,10 +205,11 @@ impl IdGenerator {
 pub struct GraphBuilder<'a> {
     pub root: NodeId,
     nodes: HashMap<NodeId, Node>,
+    labels: HashMap<NodeId, String>,
     ids: Rc<RefCell<&'a mut IdGenerator>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Data {
     pub value: f64,
     pub gradient: f64,
@@ -75,18 +224,84 @@ impl Data {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradientFlow {
+    Healthy,
+    Vanishing,
+    Exploding,
+}
+
+/// Gradient magnitudes of all nodes sitting at the same topological depth of the graph.
+#[derive(Debug)]
+pub struct DepthGradientStats {
+    pub depth: usize,
+    pub num_nodes: usize,
+    pub mean_abs_gradient: f64,
+    pub max_abs_gradient: f64,
+    pub flow: GradientFlow,
+}
+
 #[derive(Debug)]
 pub struct RunnableGraph {
     nodes: Vec<(NodeId, Node)>,
     data: Vec<Data>,
+    parameters: Vec<NodeId>,
+    frozen: HashSet<NodeId>,
+    labels: HashMap<NodeId, String>,
+    rng: StdRng,
+    training: bool,
+    /// Whether dropout masks are sampled, which follows `training` unless set on its own.
+    dropout: bool,
+    /// Bumped whenever a parameter or immediate is overwritten, see `revision`.
+    revision: u64,
 }
 
 impl RunnableGraph {
+    const VANISHING_GRADIENT: f64 = 1e-7;
+    const EXPLODING_GRADIENT: f64 = 1e3;
+
     pub fn set_input(&mut self, inp: NodeId, val: f64) {
+        if !matches!(self.nodes.get(inp.0), Some((_, Node::Input))) {
+            panic!("This is not an Input node: {}", self.describe(inp))
+        }
         let data = self.data.get_mut(inp.0).unwrap();
         data.value = val;
     }
 
+    /// Overwrites the value of an Immediate node, e.g. to update non-trainable state such as
+    /// running statistics between evaluations.
+    pub fn set_immediate(&mut self, id: NodeId, val: f64) {
+        if !matches!(self.nodes.get(id.0), Some((_, Node::Immediate(_)))) {
+            panic!("This is not an Immediate node: {}", self.describe(id))
+        }
+        self.data[id.0].value = val;
+        self.revision += 1;
+    }
+
+    /// Overwrites the value of a Parameter or Immediate node, e.g. when loading saved weights.
+    pub fn set_state(&mut self, id: NodeId, val: f64) {
+        if !matches!(
+            self.nodes.get(id.0),
+            Some((
+                _,
+                Node::Parameter(_) | Node::SparseParameter(_) | Node::Immediate(_)
+            ))
+        ) {
+            panic!(
+                "This is not a Parameter or Immediate node: {}",
+                self.describe(id)
+            )
+        }
+        self.data[id.0].value = val;
+        self.revision += 1;
+    }
+
+    /// Counter that changes whenever the value of a parameter or immediate does, so that copies
+    /// of those values, e.g. in a batched tensor graph, can tell when they are stale.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
     fn update_data_value(&mut self, id: NodeId, v: f64) {
         match self.data.get_mut(id.0) {
             None => {
@@ -96,35 +311,221 @@ impl RunnableGraph {
         }
     }
 
+    /// Reseeds the RNG used to draw samples for random nodes.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// In eval mode random nodes stop sampling and take their expected value instead, e.g. to
+    /// turn off dropout at inference time.
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+        self.dropout = training;
+        self.sample_random_nodes();
+    }
+
+    /// Turns the sampling of dropout masks on or off without touching the other random nodes,
+    /// e.g. to keep dropout active in eval mode. Reset by the next `set_training`.
+    pub fn set_dropout(&mut self, dropout: bool) {
+        self.dropout = dropout;
+        self.sample_random_nodes();
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn sample_random_nodes(&mut self) {
+        for id in 0..self.nodes.len() {
+            self.data[id].value = match self.nodes[id].1 {
+                Node::Normal if self.training => Util::standard_normal(&mut self.rng),
+                Node::Normal => 0.,
+                Node::Mask(keep) if self.dropout => {
+                    if self.rng.gen::<f64>() < keep {
+                        1. / keep
+                    } else {
+                        0.
+                    }
+                }
+                Node::Mask(_) => 1.,
+                _ => continue,
+            };
+        }
+    }
+
+    /// Evaluates the part of the graph that `outputs` depend on, leaving the other nodes with
+    /// the values of their last evaluation.
     pub fn evaluate(&mut self, outputs: &[NodeId]) -> Vec<f64> {
+        self.sample_random_nodes();
+
+        self.operations_for(outputs).into_iter().for_each(|id| {
+            let value = self.nodes[id].1.compute(|id| self.value_for_id(id));
+            self.update_data_value(NodeId(id), value);
+        });
+
+        outputs.iter().map(|id| self.value_for_id(*id)).collect()
+    }
+
+    /// Ids of the computed nodes that `outputs` depend on, in evaluation order.
+    fn operations_for(&self, outputs: &[NodeId]) -> Vec<usize> {
+        let mut needed = vec![false; self.nodes.len()];
+        outputs.iter().for_each(|id| needed[id.0] = true);
+
+        let mut operations = vec![];
+        for id in (0..self.nodes.len()).rev() {
+            if !needed[id] {
+                continue;
+            }
+            match &self.nodes[id].1 {
+                Node::Operation(n) => {
+                    needed[n.left_id.0] = true;
+                    needed[n.right_id.0] = true;
+                }
+                // Table entries are leaves, so only the index needs evaluating.
+                Node::Gather(g) => needed[g.index.0] = true,
+                Node::Dot(d) => d
+                    .weights
+                    .iter()
+                    .chain(d.inputs.iter())
+                    .for_each(|id| needed[id.0] = true),
+                _ => continue,
+            }
+            operations.push(id);
+        }
+        operations.reverse();
+        operations
+    }
+
+    /// Ids of the graph's Input nodes, in creation order.
+    pub fn input_ids(&self) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|(_, n)| matches!(n, Node::Input))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Evaluates many samples in parallel, each sample giving one value per Input node (in the
+    /// order of `input_ids`). Every thread works on its own copy of the node values, so the graph
+    /// itself is left untouched; random nodes keep the sample drawn by the last `evaluate`.
+    pub fn evaluate_batch(&self, inputs: &[Vec<f64>], outputs: &[NodeId]) -> Vec<Vec<f64>> {
+        let input_ids = self.input_ids();
+        let operations = self.operations_for(outputs);
+
+        inputs
+            .par_iter()
+            .map(|sample| {
+                if sample.len() != input_ids.len() {
+                    panic!(
+                        "Expected {} inputs, but got {}",
+                        input_ids.len(),
+                        sample.len()
+                    )
+                }
+
+                let mut values: Vec<f64> = self.data.iter().map(|d| d.value).collect();
+                input_ids
+                    .iter()
+                    .zip(sample.iter())
+                    .for_each(|(id, v)| values[id.0] = *v);
+
+                operations.iter().for_each(|id| {
+                    values[*id] = self.nodes[*id].1.compute(|id| values[id.0]);
+                });
+
+                outputs.iter().map(|id| values[id.0]).collect()
+            })
+            .collect()
+    }
+
+    /// Forward-mode pass: evaluates the graph while carrying the tangent of every node with
+    /// respect to `seed_input` (dual numbers), and returns d(output)/d(seed_input) for `outputs`.
+    ///
+    /// One sweep yields the derivative of every output, which is much cheaper than reverse mode
+    /// when there are few inputs and many outputs.
+    pub fn forward_grad(&mut self, seed_input: NodeId, outputs: &[NodeId]) -> Vec<f64> {
+        self.jvp(&[seed_input], &[1.], outputs)
+    }
+
+    /// Jacobian-vector product: pushes `tangents` on `inputs` forward through the graph and
+    /// returns the resulting tangents of `outputs`. The graph values are re-evaluated on the way.
+    pub fn jvp(&mut self, inputs: &[NodeId], tangents: &[f64], outputs: &[NodeId]) -> Vec<f64> {
+        if inputs.len() != tangents.len() {
+            panic!(
+                "Expected {} tangents, but got {}",
+                inputs.len(),
+                tangents.len()
+            )
+        }
+
+        let mut node_tangents = vec![0.; self.data.len()];
+        inputs
+            .iter()
+            .zip(tangents.iter())
+            .for_each(|(input, tangent)| node_tangents[input.0] = *tangent);
+
         self.nodes
             .clone()
             .iter()
             .enumerate()
-            .for_each(|(id, (_, node))| {
-                let id = NodeId(id);
-                if let Node::Operation(n) = node {
+            .for_each(|(id, (_, node))| match node {
+                Node::Operation(n) => {
                     let left_val = self.value_for_id(n.left_id);
                     let right_val = self.value_for_id(n.right_id);
-                    let value = match n.operation {
-                        Operation::Mul => left_val * right_val,
-                        Operation::Add => left_val + right_val,
-                        Operation::Sub => left_val - right_val,
-                        Operation::Div => right_val / left_val,
-                        Operation::Pow => right_val.pow(left_val),
-                        Operation::Relu => {
-                            if right_val < 0. {
-                                0.
-                            } else {
-                                right_val
-                            }
-                        }
-                    };
-                    self.update_data_value(id, value);
+                    let value = n.operation.apply(left_val, right_val);
+                    let (d_left, d_right) = n.operation.partials(left_val, right_val, value);
+
+                    node_tangents[id] =
+                        d_left * node_tangents[n.left_id.0] + d_right * node_tangents[n.right_id.0];
+                    self.update_data_value(NodeId(id), value);
+                }
+                Node::Gather(g) => {
+                    let selected = g.selected(self.value_for_id(g.index));
+                    node_tangents[id] = node_tangents[selected.0];
+                    self.update_data_value(NodeId(id), self.value_for_id(selected));
+                }
+                Node::Dot(d) => {
+                    let (value, tangent) = d.weights.iter().zip(d.inputs.iter()).fold(
+                        (0., 0.),
+                        |(value, tangent), (w, x)| {
+                            let (w_val, x_val) = (self.value_for_id(*w), self.value_for_id(*x));
+                            (
+                                value + w_val * x_val,
+                                tangent + x_val * node_tangents[w.0] + w_val * node_tangents[x.0],
+                            )
+                        },
+                    );
+                    node_tangents[id] = tangent;
+                    self.update_data_value(NodeId(id), value);
                 }
+                _ => {}
             });
 
-        outputs.iter().map(|id| self.value_for_id(*id)).collect()
+        outputs.iter().map(|id| node_tangents[id.0]).collect()
+    }
+
+    /// Vector-Jacobian product: pulls `cotangents` on `outputs` back through the graph and
+    /// returns the resulting gradients of `inputs`. Expects the graph to have been evaluated, and
+    /// resets any previously accumulated gradients.
+    pub fn vjp(&mut self, outputs: &[NodeId], cotangents: &[f64], inputs: &[NodeId]) -> Vec<f64> {
+        if outputs.len() != cotangents.len() {
+            panic!(
+                "Expected {} cotangents, but got {}",
+                outputs.len(),
+                cotangents.len()
+            )
+        }
+
+        self.zero_grads();
+        self.backwards(
+            outputs
+                .iter()
+                .cloned()
+                .zip(cotangents.iter().cloned())
+                .collect(),
+        );
+
+        inputs.iter().map(|id| self.grad_for_id(*id)).collect()
     }
 
     fn data_for_id_mut(&mut self, id: NodeId) -> &mut Data {
@@ -135,37 +536,21 @@ impl RunnableGraph {
         self.data.get(id.0).unwrap()
     }
 
-    fn grad_for_id(&self, id: NodeId) -> f64 {
+    pub fn grad_for_id(&self, id: NodeId) -> f64 {
         self.data_for_id(id).gradient
     }
 
-    fn value_for_id(&self, id: NodeId) -> f64 {
+    pub fn value_for_id(&self, id: NodeId) -> f64 {
         self.data_for_id(id).value
     }
 
-    fn update(
-        &mut self,
-        id: NodeId,
-        operation: Operation,
-        root_value: f64,
-        root_grad: f64,
-        other_value: f64,
-    ) {
-        {
-            let data = self.data_for_id_mut(id);
-            match operation {
-                Operation::Add => {
-                    data.gradient += root_grad;
-                }
-                Operation::Mul => {
-                    data.gradient += other_value * root_grad;
-                }
-                Operation::Relu => {
-                    data.gradient += if root_value > 0. { 1.0 } else { 0.0 } * root_grad
-                }
-                v => todo!("{:?}", v),
-            }
-        }
+    /// Accumulates a gradient computed outside the graph, e.g. by a batched tensor pass.
+    pub fn add_gradient(&mut self, id: NodeId, gradient: f64) {
+        self.data_for_id_mut(id).gradient += gradient;
+    }
+
+    fn update(&mut self, id: NodeId, partial: f64, root_grad: f64) {
+        self.data_for_id_mut(id).gradient += partial * root_grad;
     }
 
     pub fn zero_grads(&mut self) {
@@ -176,14 +561,7 @@ impl RunnableGraph {
 
     pub fn backwards(&mut self, out_grads: Vec<(NodeId, f64)>) {
         out_grads.iter().for_each(|(root, out_grad)| {
-            let root_value = self.value_for_id(*root);
-
-            let operation = match self.nodes.get(root.0).unwrap().1 {
-                Node::Operation(n) => n.operation,
-                n => panic!("This is not an Operation node: {:?} {:?}", root, n),
-            };
-
-            self.update(*root, operation, root_value, *out_grad, 0.);
+            self.update(*root, 1., *out_grad);
         });
 
         self.nodes
@@ -194,61 +572,449 @@ impl RunnableGraph {
             .for_each(|(id, (_, node))| {
                 let id = NodeId(id);
 
+                // Nodes that received no gradient, e.g. outside of the part of the graph that
+                // was evaluated, have nothing to pass on.
+                let root_grad = self.grad_for_id(id);
+                if root_grad == 0. {
+                    return;
+                }
+
                 let node = match node {
                     Node::Operation(n) => n,
+                    Node::Gather(g) => {
+                        let selected = g.selected(self.value_for_id(g.index));
+                        return self.update(selected, 1., root_grad);
+                    }
+                    Node::Dot(d) => {
+                        d.weights.iter().zip(d.inputs.iter()).for_each(|(w, x)| {
+                            let (w_val, x_val) = (self.value_for_id(*w), self.value_for_id(*x));
+                            self.update(*w, x_val, root_grad);
+                            self.update(*x, w_val, root_grad);
+                        });
+                        return;
+                    }
                     _ => return,
                 };
-
                 let root_value = self.value_for_id(id);
-                let root_grad = self.grad_for_id(id);
 
+                let left_value = self.value_for_id(node.left_id);
                 let right_value = self.value_for_id(node.right_id);
-                self.update(
-                    node.left_id,
-                    node.operation,
-                    root_value,
-                    root_grad,
-                    right_value,
-                );
+                let (d_left, d_right) =
+                    node.operation.partials(left_value, right_value, root_value);
 
-                let left_value = self.value_for_id(node.left_id);
-                self.update(
-                    node.right_id,
-                    node.operation,
-                    root_value,
-                    root_grad,
-                    left_value,
+                self.update(node.left_id, d_left, root_grad);
+                self.update(node.right_id, d_right, root_grad);
+            })
+    }
+
+    /// Gradient of every parameter, in the order `update_weights` hands them to the optimiser.
+    pub fn gradients(&self) -> Vec<f64> {
+        self.parameters
+            .iter()
+            .map(|id| self.grad_for_id(*id))
+            .collect()
+    }
+
+    /// Ids of the parameter nodes, in the order `update_weights` hands them to the optimiser.
+    pub fn parameter_ids(&self) -> &[NodeId] {
+        &self.parameters
+    }
+
+    /// Value of every parameter, in the order of `parameter_ids`.
+    pub fn parameter_values(&self) -> Vec<f64> {
+        self.parameters
+            .iter()
+            .map(|id| self.value_for_id(*id))
+            .collect()
+    }
+
+    /// Overwrites the value of every parameter, in the order of `parameter_ids`.
+    pub fn set_parameter_values(&mut self, values: &[f64]) {
+        if values.len() != self.parameters.len() {
+            panic!(
+                "Expected {} values, but got {}",
+                self.parameters.len(),
+                values.len()
+            )
+        }
+        self.parameters
+            .clone()
+            .iter()
+            .zip(values)
+            .for_each(|(id, value)| self.set_state(*id, *value));
+    }
+
+    /// Gradients accumulated on `inputs` by `backwards`, i.e. d(loss)/d(input).
+    pub fn input_gradients(&self, inputs: &[NodeId]) -> Vec<f64> {
+        inputs.iter().map(|id| self.grad_for_id(*id)).collect()
+    }
+
+    /// Runs the forward and backward pass for each sample of a batch and records every sample's
+    /// gradient vector individually. `loss_grad` maps a sample index and its output values to the
+    /// gradients to seed `outputs` with.
+    ///
+    /// The per-sample gradients are summed back into the graph, so `update_weights` can be called
+    /// afterwards to apply the batch step as usual.
+    pub fn per_sample_gradients(
+        &mut self,
+        samples: &[Vec<(NodeId, f64)>],
+        outputs: &[NodeId],
+        mut loss_grad: impl FnMut(usize, &[f64]) -> Vec<f64>,
+    ) -> Vec<Vec<f64>> {
+        let per_sample: Vec<Vec<f64>> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, inputs)| {
+                inputs
+                    .iter()
+                    .for_each(|(input, value)| self.set_input(*input, *value));
+                let values = self.evaluate(outputs);
+                let out_grads = loss_grad(i, &values);
+
+                self.zero_grads();
+                self.backwards(outputs.iter().cloned().zip(out_grads).collect());
+                self.gradients()
+            })
+            .collect();
+
+        self.zero_grads();
+        self.parameters
+            .clone()
+            .iter()
+            .enumerate()
+            .for_each(|(i, id)| {
+                self.add_gradient(*id, per_sample.iter().map(|g| g[i]).sum());
+            });
+
+        per_sample
+    }
+
+    /// Longest path from a leaf (input or immediate) to each node.
+    fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; self.nodes.len()];
+        self.nodes.iter().enumerate().for_each(|(id, (_, node))| {
+            depths[id] = match node {
+                Node::Operation(n) => 1 + depths[n.left_id.0].max(depths[n.right_id.0]),
+                Node::Gather(g) => 1 + depths[g.index.0],
+                Node::Dot(d) => {
+                    1 + d
+                        .weights
+                        .iter()
+                        .chain(d.inputs.iter())
+                        .map(|id| depths[id.0])
+                        .max()
+                        .unwrap_or(0)
+                }
+                _ => 0,
+            };
+        });
+        depths
+    }
+
+    /// Buckets the gradient magnitudes left by `backwards` by topological depth, flagging depths
+    /// whose gradients have vanished or exploded, e.g. because of dead relu paths.
+    pub fn gradient_flow_report(&self) -> Vec<DepthGradientStats> {
+        let depths = self.depths();
+        let max_depth = depths.iter().cloned().max().unwrap_or(0);
+
+        (0..=max_depth)
+            .filter_map(|depth| {
+                let grads: Vec<f64> = depths
+                    .iter()
+                    .zip(self.data.iter())
+                    .filter(|(d, _)| **d == depth)
+                    .map(|(_, data)| data.gradient.abs())
+                    .collect();
+                if grads.is_empty() {
+                    return None;
+                }
+
+                let mean_abs_gradient = grads.iter().mean();
+                let max_abs_gradient = grads.iter().cloned().fold(0., f64::max);
+                let flow = if max_abs_gradient < Self::VANISHING_GRADIENT {
+                    GradientFlow::Vanishing
+                } else if max_abs_gradient > Self::EXPLODING_GRADIENT {
+                    GradientFlow::Exploding
+                } else {
+                    GradientFlow::Healthy
+                };
+
+                Some(DepthGradientStats {
+                    depth,
+                    num_nodes: grads.len(),
+                    mean_abs_gradient,
+                    max_abs_gradient,
+                    flow,
+                })
+            })
+            .collect()
+    }
+
+    /// Hash of the graph topology (node kinds, operations and edges, but not values), stable across
+    /// program runs and platforms, so schedules derived from a graph can be cached and reused
+    /// for any structurally identical graph.
+    pub fn structural_hash(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut words: Vec<u64> = vec![self.nodes.len() as u64];
+        self.nodes.iter().for_each(|(_, node)| match node {
+            Node::Operation(n) => words.extend([
+                0,
+                n.operation as u64,
+                n.left_id.0 as u64,
+                n.right_id.0 as u64,
+            ]),
+            Node::Immediate(_) => words.push(1),
+            Node::Input => words.push(2),
+            Node::Normal => words.push(3),
+            Node::Parameter(_) => words.push(4),
+            Node::SparseParameter(_) => words.push(6),
+            Node::Mask(_) => words.push(5),
+            Node::Gather(g) => words.extend([
+                7,
+                g.index.0 as u64,
+                g.first.0 as u64,
+                g.rows as u64,
+                g.stride as u64,
+            ]),
+            Node::Dot(d) => {
+                words.extend([8, d.weights.len() as u64]);
+                words.extend(
+                    d.weights
+                        .iter()
+                        .chain(d.inputs.iter())
+                        .map(|id| id.0 as u64),
                 );
+            }
+        });
+
+        words
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .fold(FNV_OFFSET, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
             })
     }
 
+    /// Excludes parameters from `update_weights`, which leaves their values as they are.
+    pub fn freeze(&mut self, ids: &[NodeId]) {
+        self.frozen.extend(ids);
+    }
+
+    pub fn unfreeze(&mut self, ids: &[NodeId]) {
+        ids.iter().for_each(|id| {
+            self.frozen.remove(id);
+        });
+    }
+
+    pub fn is_frozen(&self, id: NodeId) -> bool {
+        self.frozen.contains(&id)
+    }
+
     pub fn update_weights(&mut self, optimiser: &mut impl Optimiser) {
-        optimiser.optimise(&mut self.data);
+        self.update_weights_grouped(optimiser, &[]);
+    }
+
+    /// Clamps the gradient of every parameter to `[-max, max]`, see `optimiser::clip_grad_value`.
+    pub fn clip_grad_value(&mut self, max: f64) {
+        self.clip_gradients(|data| optimiser::clip_grad_value(data, max));
+    }
+
+    /// Rescales the gradients of the parameters to a global L2 norm of at most `max_norm` and
+    /// returns the norm before clipping, see `optimiser::clip_grad_norm`.
+    pub fn clip_grad_norm(&mut self, max_norm: f64) -> f64 {
+        self.clip_gradients(|data| optimiser::clip_grad_norm(data, max_norm))
+    }
+
+    /// Applies `clip` to the parameters that aren't frozen, as those don't take any step.
+    fn clip_gradients<T>(&mut self, clip: impl FnOnce(&mut [Data]) -> T) -> T {
+        let ids: Vec<NodeId> = self
+            .parameters
+            .iter()
+            .filter(|id| !self.frozen.contains(id))
+            .cloned()
+            .collect();
+        let mut parameters: Vec<Data> = ids.iter().map(|id| self.data[id.0].clone()).collect();
+        let result = clip(&mut parameters);
+        ids.iter()
+            .zip(parameters)
+            .for_each(|(id, d)| self.data[id.0].gradient = d.gradient);
+        result
+    }
+
+    /// Same as `update_weights`, with the parameters partitioned into `groups`, e.g. one per
+    /// layer, for optimisers that treat groups separately. The parameters in none of the groups
+    /// form one more group.
+    pub fn update_weights_grouped(
+        &mut self,
+        optimiser: &mut impl Optimiser,
+        groups: &[Vec<NodeId>],
+    ) {
+        let positions: HashMap<NodeId, usize> = self
+            .parameters
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, i))
+            .collect();
+        let mut grouped = vec![false; self.parameters.len()];
+        let mut groups: Vec<Vec<usize>> = groups
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| {
+                        let position = *positions.get(id).unwrap_or_else(|| {
+                            panic!("This is not a Parameter node: {}", self.describe(*id))
+                        });
+                        grouped[position] = true;
+                        position
+                    })
+                    .collect()
+            })
+            .collect();
+        let rest: Vec<usize> = (0..grouped.len()).filter(|i| !grouped[*i]).collect();
+        if !rest.is_empty() {
+            groups.push(rest);
+        }
+
+        // Frozen parameters and sparse ones that received no gradient are still handed to the
+        // optimiser, with no gradient, so that stateful optimisers keep seeing the parameters in
+        // the same order, but are masked out so that neither they nor their state move.
+        let active: Vec<bool> = self
+            .parameters
+            .iter()
+            .map(|id| {
+                let untouched = self.data[id.0].gradient == 0.;
+                let sparse = matches!(self.nodes[id.0].1, Node::SparseParameter(_));
+                !(self.frozen.contains(id) || (untouched && sparse))
+            })
+            .collect();
+        let mut parameters: Vec<Data> = self
+            .parameters
+            .iter()
+            .zip(active.iter())
+            .map(|(id, active)| match active {
+                true => self.data[id.0].clone(),
+                false => Data::new(self.data[id.0].value),
+            })
+            .collect();
+        optimiser.optimise_masked(&mut parameters, &groups, &active);
+        self.parameters
+            .iter()
+            .zip(parameters)
+            .zip(active)
+            .filter(|(_, active)| *active)
+            .for_each(|((id, d), _)| self.data[id.0] = d);
+        self.revision += 1;
     }
 
     pub fn new(graphs: Vec<&GraphBuilder>) -> RunnableGraph {
         let mut nodes: Vec<(NodeId, Node)> = graphs
             .iter()
             .flat_map(|g| g.nodes.iter())
-            .map(|(id, node)| (*id, *node))
+            .map(|(id, node)| (*id, node.clone()))
             .collect();
-        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        nodes.sort_by_key(|a| a.0);
 
         nodes.dedup_by(|a, b| a.0 == b.0);
 
+        // Ids index straight into the node list, so fill in any ids that were handed out to
+        // builders that did not end up in the graph.
+        let num_nodes = nodes.last().map(|(id, _)| id.0 + 1).unwrap_or(0);
+        if nodes.len() != num_nodes {
+            let mut dense: Vec<(NodeId, Node)> = (0..num_nodes)
+                .map(|i| (NodeId(i), Node::Immediate(0.)))
+                .collect();
+            nodes.into_iter().for_each(|(id, n)| dense[id.0] = (id, n));
+            nodes = dense;
+        }
+
         let data = nodes
             .iter()
             .map(|(_, n)| match n {
-                Node::Immediate(imm) => Data::new(*imm),
+                Node::Immediate(v) | Node::Parameter(v) | Node::SparseParameter(v) => Data::new(*v),
+                Node::Mask(_) => Data::new(1.),
                 _ => Data::new(0.),
             })
             .collect();
 
-        RunnableGraph { nodes, data }
+        let parameters = nodes
+            .iter()
+            .filter(|(_, n)| matches!(n, Node::Parameter(_) | Node::SparseParameter(_)))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let labels = graphs
+            .iter()
+            .flat_map(|g| g.labels.iter())
+            .map(|(id, label)| (*id, label.clone()))
+            .collect();
+
+        RunnableGraph {
+            nodes,
+            data,
+            parameters,
+            frozen: HashSet::new(),
+            labels,
+            rng: StdRng::from_rng(thread_rng()).unwrap(),
+            training: true,
+            dropout: true,
+            revision: 0,
+        }
+    }
+
+    pub fn label(&self, id: NodeId) -> Option<&str> {
+        self.labels.get(&id).map(|l| l.as_str())
+    }
+
+    /// Human readable name for a node: its label if it has one, followed by its id.
+    pub fn describe(&self, id: NodeId) -> String {
+        match self.label(id) {
+            Some(label) => format!("{label} (#{})", id.0),
+            None => format!("#{}", id.0),
+        }
+    }
+
+    /// Renders the graph in Graphviz DOT format, using node labels where available.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n");
+        self.nodes.iter().for_each(|(id, node)| {
+            let kind = match node {
+                Node::Operation(n) => format!("{:?}", n.operation),
+                Node::Immediate(v) => format!("{v}"),
+                Node::Parameter(v) => format!("Parameter {v}"),
+                Node::SparseParameter(v) => format!("SparseParameter {v}"),
+                Node::Input => "Input".to_string(),
+                Node::Normal => "Normal".to_string(),
+                Node::Mask(keep) => format!("Mask {keep}"),
+                Node::Gather(g) => format!("Gather {}x{}", g.rows, g.stride),
+                Node::Dot(d) => format!("Dot {}", d.weights.len()),
+            };
+            dot.push_str(&format!(
+                "    n{} [label=\"{}\\n{kind}\"];\n",
+                id.0,
+                self.describe(*id)
+            ));
+            match node {
+                Node::Operation(n) => {
+                    dot.push_str(&format!("    n{} -> n{};\n", n.left_id.0, id.0));
+                    dot.push_str(&format!("    n{} -> n{};\n", n.right_id.0, id.0));
+                }
+                Node::Gather(g) => dot.push_str(&format!("    n{} -> n{};\n", g.index.0, id.0)),
+                Node::Dot(d) => {
+                    d.weights.iter().chain(d.inputs.iter()).for_each(|input| {
+                        dot.push_str(&format!("    n{} -> n{};\n", input.0, id.0))
+                    })
+                }
+                _ => {}
+            }
+        });
+        dot.push_str("}\n");
+        dot
     }
 
     pub fn num_parameters(&self) -> usize {
-        self.data.len()
+        self.parameters.len()
     }
 }
 
@@ -263,17 +1029,25 @@ impl<'a> GraphBuilder<'a> {
         let mut nodes = left.nodes.clone();
         nodes.extend(right.nodes);
 
+        let mut labels = left.labels.clone();
+        labels.extend(right.labels);
+
         let id = left.ids.borrow_mut().get_id();
         nodes.insert(id, Node::Operation(new_root));
 
         GraphBuilder {
             root: id,
             nodes,
+            labels,
             ids: left.ids,
         }
     }
 
-    fn with_immediate(op: Operation, left: f64, right: GraphBuilder<'a>) -> GraphBuilder<'a> {
+    pub(crate) fn with_immediate(
+        op: Operation,
+        left: f64,
+        right: GraphBuilder<'a>,
+    ) -> GraphBuilder<'a> {
         Self::combine(op, Self::new_of_immediate(right.ids.clone(), left), right)
     }
 
@@ -281,6 +1055,7 @@ impl<'a> GraphBuilder<'a> {
         GraphBuilder {
             root: NodeId(0),
             nodes: HashMap::new(),
+            labels: HashMap::new(),
             ids,
         }
     }
@@ -290,10 +1065,84 @@ impl<'a> GraphBuilder<'a> {
         GraphBuilder {
             root: id,
             nodes: HashMap::from([(id, Node::Immediate(val))]),
+            labels: HashMap::new(),
             ids,
         }
     }
 
+    /// Creates a standalone immediate, e.g. a weight, whose id can be kept to find it again in
+    /// the `RunnableGraph`.
+    pub fn create_immediate(&self, val: f64) -> (NodeId, GraphBuilder<'a>) {
+        let immediate = Self::new_of_immediate(self.ids.clone(), val);
+        (immediate.root, immediate)
+    }
+
+    /// Refers to the immediate `id` created by `create_immediate`, so that it can be shared by
+    /// several parts of a graph. `val` must be the value it was created with.
+    pub fn immediate(&self, id: NodeId, val: f64) -> GraphBuilder<'a> {
+        GraphBuilder {
+            root: id,
+            nodes: HashMap::from([(id, Node::Immediate(val))]),
+            labels: HashMap::new(),
+            ids: self.ids.clone(),
+        }
+    }
+
+    /// Creates a standalone trainable parameter, whose id can be kept to reuse it with
+    /// `parameter`.
+    pub fn create_parameter(&self, val: f64) -> (NodeId, GraphBuilder<'a>) {
+        let id = self.ids.borrow_mut().get_id();
+        (id, self.parameter(id, val))
+    }
+
+    /// Refers to the parameter `id` created by `create_parameter`, so that it can be shared by
+    /// several parts of a graph. `val` must be the value it was created with.
+    pub fn parameter(&self, id: NodeId, val: f64) -> GraphBuilder<'a> {
+        GraphBuilder {
+            root: id,
+            nodes: HashMap::from([(id, Node::Parameter(val))]),
+            labels: HashMap::new(),
+            ids: self.ids.clone(),
+        }
+    }
+
+    /// Like `create_parameter`, but the optimiser only updates it on steps where it received a
+    /// gradient.
+    pub fn create_sparse_parameter(&self, val: f64) -> (NodeId, GraphBuilder<'a>) {
+        let id = self.ids.borrow_mut().get_id();
+        (id, self.sparse_parameter(id, val))
+    }
+
+    /// Refers to the sparse parameter `id` created by `create_sparse_parameter`.
+    pub fn sparse_parameter(&self, id: NodeId, val: f64) -> GraphBuilder<'a> {
+        GraphBuilder {
+            root: id,
+            nodes: HashMap::from([(id, Node::SparseParameter(val))]),
+            labels: HashMap::new(),
+            ids: self.ids.clone(),
+        }
+    }
+
+    /// Whether the root is a sparse parameter, e.g. to leave it out of a weight penalty.
+    pub fn is_sparse_parameter(&self) -> bool {
+        matches!(self.nodes.get(&self.root), Some(Node::SparseParameter(_)))
+    }
+
+    /// Builder of a leaf node of this graph, such as a parameter, to reuse it in another
+    /// expression, or `None` if the graph has no such node.
+    pub fn leaf(&self, id: NodeId) -> Option<GraphBuilder<'a>> {
+        match self.nodes.get(&id) {
+            Some(node) if node.is_computed() => panic!("This is not a leaf node: #{}", id.0),
+            Some(node) => Some(GraphBuilder {
+                root: id,
+                nodes: HashMap::from([(id, node.clone())]),
+                labels: HashMap::new(),
+                ids: self.ids.clone(),
+            }),
+            None => None,
+        }
+    }
+
     pub fn create_input(&self) -> (NodeId, GraphBuilder<'a>) {
         let id = self.ids.borrow_mut().get_id();
 
@@ -305,14 +1154,177 @@ impl<'a> GraphBuilder<'a> {
             GraphBuilder {
                 root: id,
                 nodes,
+                labels: self.labels.clone(),
                 ids: self.ids.clone(),
             },
         )
     }
 
+    /// Reparameterised Gaussian sample `mu + sigma * eps`: the noise `eps` is drawn outside the
+    /// graph on every evaluation, so gradients flow to both `mu` and `sigma`.
+    pub fn gaussian(mu: GraphBuilder<'a>, sigma: GraphBuilder<'a>) -> GraphBuilder<'a> {
+        let id = mu.ids.borrow_mut().get_id();
+        let eps = GraphBuilder {
+            root: id,
+            nodes: HashMap::from([(id, Node::Normal)]),
+            labels: HashMap::new(),
+            ids: mu.ids.clone(),
+        };
+
+        mu + sigma * eps
+    }
+
+    /// Inverted dropout: zeroes the value with probability `p` while training and scales it by
+    /// `1 / (1 - p)` otherwise, so that its expectation is unchanged in eval mode.
+    pub fn dropout(self, p: f64) -> GraphBuilder<'a> {
+        if !(0. ..1.).contains(&p) {
+            panic!("Dropout probability must be in [0, 1), but got {p}")
+        }
+
+        let id = self.ids.borrow_mut().get_id();
+        let mask = GraphBuilder {
+            root: id,
+            nodes: HashMap::from([(id, Node::Mask(1. - p))]),
+            labels: HashMap::new(),
+            ids: self.ids.clone(),
+        };
+
+        mask * self
+    }
+
+    /// Attaches a debug label to the root node, shown by `RunnableGraph::describe` and DOT export.
+    pub fn named(mut self, label: &str) -> GraphBuilder<'a> {
+        self.labels.insert(self.root, label.to_string());
+        self
+    }
+
     pub fn relu(self) -> GraphBuilder<'a> {
         GraphBuilder::with_immediate(Operation::Relu, 0., self.clone())
     }
+
+    pub fn leaky_relu(self, slope: f64) -> GraphBuilder<'a> {
+        GraphBuilder::with_immediate(Operation::LeakyRelu, slope, self)
+    }
+
+    pub fn tanh(self) -> GraphBuilder<'a> {
+        GraphBuilder::with_immediate(Operation::Tanh, 0., self)
+    }
+
+    pub fn sigmoid(self) -> GraphBuilder<'a> {
+        GraphBuilder::with_immediate(Operation::Sigmoid, 0., self)
+    }
+
+    pub fn exp(self) -> GraphBuilder<'a> {
+        GraphBuilder::with_immediate(Operation::Exp, 0., self)
+    }
+
+    pub fn ln(self) -> GraphBuilder<'a> {
+        GraphBuilder::with_immediate(Operation::Ln, 0., self)
+    }
+
+    /// Softmax over `inputs`, shifted by their maximum so that large values don't overflow.
+    pub fn softmax(inputs: &[GraphBuilder<'a>]) -> Vec<GraphBuilder<'a>> {
+        let max = inputs
+            .iter()
+            .skip(1)
+            .fold(inputs[0].clone(), |m, x| m.max(x.clone()));
+        let exps: Vec<GraphBuilder> = inputs
+            .iter()
+            .map(|x| (x.clone() - max.clone()).exp())
+            .collect();
+        let sum = exps.iter().skip(1).fold(exps[0].clone(), |s, e| s + e);
+
+        exps.into_iter().map(|e| e / sum.clone()).collect()
+    }
+
+    /// Larger of the two values, with the gradient flowing only to the one that was picked.
+    pub fn max(self, other: GraphBuilder<'a>) -> GraphBuilder<'a> {
+        GraphBuilder::combine(Operation::Max, self, other)
+    }
+
+    /// Entry of `table` at the index given by the value, rounded to the nearest integer, with
+    /// the gradient flowing only to that entry. The entries must be leaves, e.g. parameters,
+    /// created at evenly spaced ids, such as a column of a row-major matrix.
+    pub fn gather(self, table: &[GraphBuilder<'a>]) -> GraphBuilder<'a> {
+        if table.is_empty() {
+            panic!("Expected a non-empty table")
+        }
+        let first = table[0].root;
+        let stride = table
+            .get(1)
+            .map(|t| t.root.0.saturating_sub(first.0))
+            .unwrap_or(1);
+        table.iter().enumerate().for_each(|(i, t)| {
+            if t.root.0 != first.0 + i * stride || stride == 0 {
+                panic!("Expected the table entries to be evenly spaced")
+            }
+            if t.nodes[&t.root].is_computed() {
+                panic!("This is not a leaf node: #{}", t.root.0)
+            }
+        });
+
+        let mut nodes = self.nodes.clone();
+        let mut labels = self.labels.clone();
+        table.iter().for_each(|t| {
+            nodes.insert(t.root, t.nodes[&t.root].clone());
+            labels.extend(t.labels.iter().map(|(id, l)| (*id, l.clone())));
+        });
+
+        let gather = GatherNode {
+            index: self.root,
+            first,
+            rows: table.len(),
+            stride,
+        };
+        let id = self.ids.borrow_mut().get_id();
+        nodes.insert(id, Node::Gather(gather));
+
+        GraphBuilder {
+            root: id,
+            nodes,
+            labels,
+            ids: self.ids,
+        }
+    }
+
+    /// Sum of the pairwise products of `weights` and `inputs` as a single node, e.g. the
+    /// pre-activation of a neuron, rather than one Mul and one Add node per pair.
+    pub fn dot(weights: &[GraphBuilder<'a>], inputs: &[GraphBuilder<'a>]) -> GraphBuilder<'a> {
+        if weights.len() != inputs.len() {
+            panic!(
+                "Expected {} inputs, but got {}",
+                weights.len(),
+                inputs.len()
+            )
+        }
+        if weights.is_empty() {
+            panic!("Expected at least one input")
+        }
+
+        let mut nodes = HashMap::new();
+        let mut labels = HashMap::new();
+        weights.iter().chain(inputs.iter()).for_each(|g| {
+            g.nodes.iter().for_each(|(id, node)| {
+                nodes.entry(*id).or_insert_with(|| node.clone());
+            });
+            labels.extend(g.labels.iter().map(|(id, l)| (*id, l.clone())));
+        });
+
+        let dot = DotNode {
+            weights: weights.iter().map(|w| w.root).collect(),
+            inputs: inputs.iter().map(|x| x.root).collect(),
+        };
+        let ids = weights[0].ids.clone();
+        let id = ids.borrow_mut().get_id();
+        nodes.insert(id, Node::Dot(dot));
+
+        GraphBuilder {
+            root: id,
+            nodes,
+            labels,
+            ids,
+        }
+    }
 }
 
 impl<'a> Add<GraphBuilder<'a>> for GraphBuilder<'a> {
@@ -443,6 +1455,14 @@ impl<'a> Div<f64> for &GraphBuilder<'a> {
     }
 }
 
+impl<'a> Div<GraphBuilder<'a>> for GraphBuilder<'a> {
+    type Output = GraphBuilder<'a>;
+
+    fn div(self, rhs: GraphBuilder<'a>) -> Self::Output {
+        GraphBuilder::combine(Operation::Div, rhs, self)
+    }
+}
+
 impl<'a> Div<&GraphBuilder<'a>> for f64 {
     type Output = GraphBuilder<'a>;
 
@@ -463,7 +1483,7 @@ impl<'a> Mul<f64> for GraphBuilder<'a> {
 mod tests {
     use std::vec;
 
-    use crate::engine::*;
+    use crate::{engine::*, optimiser::LearningRateOptimiser};
 
     #[test]
     fn test_graph_builder() {
@@ -609,5 +1629,453 @@ mod tests {
         assert_eq!(v, vec![6., 15.]);
 
         g.backwards(vec![(c.root, 1.), (f.root, 2.)]);
+
+        assert_eq!(
+            g.input_gradients(&[*a_id, *b_id, *d_id, *e_id]),
+            vec![4., 2., 4., 4.]
+        );
+    }
+
+    #[test]
+    fn test_forward_grad() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (a_id, a) = &graph.create_input();
+        let (b_id, b) = &graph.create_input();
+
+        let f = a * b + b.pow(3.);
+        let g = &(a.clone() - b.clone()) / 2.;
+
+        let outputs = vec![f.root, g.root];
+        let mut graph = RunnableGraph::new(vec![&f, &g]);
+
+        graph.set_input(*a_id, -4.);
+        graph.set_input(*b_id, 2.);
+
+        assert_eq!(graph.forward_grad(*a_id, &outputs), vec![2., 0.5]);
+        assert_eq!(graph.forward_grad(*b_id, &outputs), vec![8., -0.5]);
+
+        graph.backwards(vec![(f.root, 1.), (g.root, 1.)]);
+        assert_eq!(graph.grad_for_id(*a_id), 2.5);
+        assert_eq!(graph.grad_for_id(*b_id), 7.5);
+    }
+
+    #[test]
+    fn test_per_sample_gradients() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = &graph.create_input();
+        let (w_id, w) = &graph.create_parameter(3.);
+
+        let f = x * w;
+        let mut graph = RunnableGraph::new(vec![&f]);
+        assert_eq!(graph.parameter_ids(), &[*w_id]);
+
+        let samples = vec![vec![(*x_id, 1.)], vec![(*x_id, 2.)]];
+        let grads = graph.per_sample_gradients(&samples, &[f.root], |_, y| vec![2. * y[0]]);
+
+        assert_eq!(grads, vec![vec![6.], vec![24.]]);
+        assert_eq!(graph.grad_for_id(*w_id), 30.);
+    }
+
+    #[test]
+    fn test_update_weights_only_touches_parameters() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (w_id, w) = graph.create_parameter(1.);
+        let (c_id, c) = graph.create_immediate(2.);
+
+        let f = w * c;
+        let mut graph = RunnableGraph::new(vec![&f]);
+        assert_eq!(graph.num_parameters(), 1);
+
+        graph.evaluate(&[f.root]);
+        graph.backwards(vec![(f.root, 1.)]);
+        graph.update_weights(&mut LearningRateOptimiser::new(0.5));
+
+        assert_eq!(graph.value_for_id(w_id), 0.);
+        assert_eq!(graph.value_for_id(c_id), 2.);
+    }
+
+    #[test]
+    fn test_clip_gradients() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (w_id, w) = graph.create_parameter(1.);
+        let (v_id, v) = graph.create_parameter(1.);
+        let (u_id, u) = graph.create_parameter(1.);
+        let (c_id, c) = graph.create_immediate(2.);
+
+        let f = w * 3. + v * -4. + u * 12. + c * 10.;
+        let mut graph = RunnableGraph::new(vec![&f]);
+        graph.freeze(&[u_id]);
+        graph.evaluate(&[f.root]);
+        graph.backwards(vec![(f.root, 1.)]);
+
+        // Frozen parameters and immediates count towards neither the norm nor the clipping.
+        assert_eq!(graph.clip_grad_norm(1.), 5.);
+        assert!((graph.grad_for_id(w_id) - 0.6).abs() < 1e-12);
+        assert!((graph.grad_for_id(v_id) + 0.8).abs() < 1e-12);
+        assert_eq!(graph.grad_for_id(u_id), 12.);
+        assert_eq!(graph.grad_for_id(c_id), 10.);
+
+        graph.clip_grad_value(0.7);
+        assert!((graph.grad_for_id(w_id) - 0.6).abs() < 1e-12);
+        assert_eq!(graph.grad_for_id(v_id), -0.7);
+    }
+
+    #[test]
+    fn test_vjp_jvp() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (a_id, a) = &graph.create_input();
+        let (b_id, b) = &graph.create_input();
+
+        let f = a * b;
+        let g = b.pow(2.) + a;
+
+        let inputs = vec![*a_id, *b_id];
+        let outputs = vec![f.root, g.root];
+        let mut graph = RunnableGraph::new(vec![&f, &g]);
+
+        graph.set_input(*a_id, 3.);
+        graph.set_input(*b_id, 2.);
+
+        // Jacobian is [[b, a], [1, 2b]] = [[2, 3], [1, 4]]
+        assert_eq!(graph.jvp(&inputs, &[1., 2.], &outputs), vec![8., 9.]);
+        assert_eq!(graph.vjp(&outputs, &[1., 2.], &inputs), vec![4., 11.]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input();
+        let x = x.named("x");
+
+        let f = (2. * &x).named("double");
+        let g = RunnableGraph::new(vec![&f]);
+
+        assert_eq!(g.label(x_id), Some("x"));
+        assert_eq!(g.describe(f.root), format!("double (#{})", f.root.0));
+        assert!(g.to_dot().contains("double"));
+    }
+
+    #[test]
+    #[should_panic(expected = "This is not an Input node: double")]
+    fn test_set_input_panic_uses_label() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (_, x) = graph.create_input();
+
+        let f = (2. * &x).named("double");
+        let mut g = RunnableGraph::new(vec![&f]);
+        g.set_input(f.root, 1.);
+    }
+
+    #[test]
+    fn test_gradient_flow_report() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = &graph.create_input();
+
+        let dead = (2. * x).relu() * 3.;
+        let mut g = RunnableGraph::new(vec![&dead]);
+
+        g.set_input(*x_id, -1.);
+        g.evaluate(&[dead.root]);
+        g.backwards(vec![(dead.root, 1.)]);
+
+        let report = g.gradient_flow_report();
+        assert_eq!(report.len(), 4);
+        assert_eq!(report[0].flow, GradientFlow::Vanishing);
+        assert_eq!(report[3].flow, GradientFlow::Healthy);
+        assert_eq!(report[3].max_abs_gradient, 1.);
+    }
+
+    #[test]
+    fn test_structural_hash() {
+        fn build(use_pow: bool, imm: f64) -> u64 {
+            let ids = &mut IdGenerator::new();
+            let ids = Rc::new(RefCell::new(ids));
+
+            let graph = GraphBuilder::new(ids);
+            let (_, x) = &graph.create_input();
+            let f = if use_pow { x.pow(1.) } else { x + 1. };
+            let f = f * imm;
+            RunnableGraph::new(vec![&f]).structural_hash()
+        }
+
+        assert_eq!(build(false, 2.), build(false, 3.));
+        assert_ne!(build(false, 2.), build(true, 2.));
+    }
+
+    #[test]
+    fn test_gaussian() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (mu_id, mu) = graph.create_input();
+        let (sigma_id, sigma) = graph.create_input();
+
+        let z = GraphBuilder::gaussian(mu, sigma);
+        let mut g = RunnableGraph::new(vec![&z]);
+        g.set_input(mu_id, 1.);
+        g.set_input(sigma_id, 2.);
+
+        g.seed(0);
+        let first = g.evaluate(&[z.root])[0];
+        g.seed(0);
+        assert_eq!(g.evaluate(&[z.root])[0], first);
+
+        g.backwards(vec![(z.root, 1.)]);
+        let grads = g.input_gradients(&[mu_id, sigma_id]);
+        assert_eq!(grads[0], 1.);
+        assert!((grads[1] - (first - 1.) / 2.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_softmax() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let inputs: Vec<(NodeId, GraphBuilder)> = (0..3).map(|_| graph.create_input()).collect();
+        let builders: Vec<GraphBuilder> = inputs.iter().map(|(_, x)| x.clone()).collect();
+
+        let probs = GraphBuilder::softmax(&builders);
+        let outputs: Vec<NodeId> = probs.iter().map(|p| p.root).collect();
+        let mut g = RunnableGraph::new(probs.iter().collect());
+        [1000., 1001., 999.]
+            .iter()
+            .zip(inputs.iter())
+            .for_each(|(v, (id, _))| g.set_input(*id, *v));
+
+        let p = g.evaluate(&outputs);
+        let e = [1., 1_f64.exp(), (-1_f64).exp()];
+        let z: f64 = e.iter().sum();
+        (0..3).for_each(|i| assert!((p[i] - e[i] / z).abs() < 1e-12));
+
+        // d p_0 / d x_j = p_0 * (1[j == 0] - p_j)
+        g.backwards(vec![(outputs[0], 1.)]);
+        let ids: Vec<NodeId> = inputs.iter().map(|(id, _)| *id).collect();
+        let grads = g.input_gradients(&ids);
+        (0..3).for_each(|j| {
+            let expected = p[0] * (if j == 0 { 1. } else { 0. } - p[j]);
+            assert!((grads[j] - expected).abs() < 1e-12);
+        });
+    }
+
+    #[test]
+    fn test_dropout() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input();
+
+        let y = x.dropout(0.25);
+        let mut g = RunnableGraph::new(vec![&y]);
+        g.set_input(x_id, 3.);
+        g.seed(1);
+
+        let samples: Vec<f64> = (0..1000).map(|_| g.evaluate(&[y.root])[0]).collect();
+        assert!(samples.iter().all(|v| *v == 0. || *v == 4.));
+        let kept = samples.iter().filter(|v| **v == 4.).count();
+        assert!((700..800).contains(&kept));
+
+        g.set_training(false);
+        assert_eq!(g.evaluate(&[y.root]), vec![3.]);
+    }
+
+    #[test]
+    fn test_gather() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (i_id, i) = graph.create_input();
+        let table: Vec<GraphBuilder> = [1., 2., 3.]
+            .iter()
+            .map(|v| graph.create_parameter(*v).1)
+            .collect();
+
+        let y = i.gather(&table) * 2.;
+        let mut g = RunnableGraph::new(vec![&y]);
+        g.set_input(i_id, 2.);
+        assert_eq!(g.evaluate(&[y.root]), vec![6.]);
+        assert_eq!(g.forward_grad(table[2].root, &[y.root]), vec![2.]);
+
+        g.set_input(i_id, 0.9);
+        assert_eq!(g.evaluate(&[y.root]), vec![4.]);
+        g.backwards(vec![(y.root, 1.)]);
+        assert_eq!(g.gradients(), vec![0., 2., 0.]);
+        assert_eq!(g.grad_for_id(i_id), 0.);
+    }
+
+    #[test]
+    fn test_dot() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_ids, xs): (Vec<NodeId>, Vec<GraphBuilder>) =
+            (0..3).map(|_| graph.create_input()).unzip();
+        let ws: Vec<GraphBuilder> = [1., -2., 3.]
+            .iter()
+            .map(|v| graph.create_parameter(*v).1)
+            .collect();
+
+        let y = GraphBuilder::dot(&ws, &xs).tanh();
+        let chain =
+            (ws[0].clone() * &xs[0] + ws[1].clone() * &xs[1] + ws[2].clone() * &xs[2]).tanh();
+        let mut g = RunnableGraph::new(vec![&y, &chain]);
+        x_ids
+            .iter()
+            .zip([0.5, 0.25, -0.1])
+            .for_each(|(id, v)| g.set_input(*id, v));
+
+        let values = g.evaluate(&[y.root, chain.root]);
+        assert!((values[0] - values[1]).abs() < 1e-12);
+        let tangents = g.forward_grad(x_ids[1], &[y.root, chain.root]);
+        assert!((tangents[0] - tangents[1]).abs() < 1e-12);
+
+        let dot_grads = g.vjp(&[y.root], &[1.], &x_ids);
+        let dot_weight_grads = g.gradients();
+        let chain_grads = g.vjp(&[chain.root], &[1.], &x_ids);
+        let chain_weight_grads = g.gradients();
+        dot_grads
+            .iter()
+            .chain(dot_weight_grads.iter())
+            .zip(chain_grads.iter().chain(chain_weight_grads.iter()))
+            .for_each(|(d, c)| assert!((d - c).abs() < 1e-12));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected an index in 0..3, but got 3")]
+    fn test_gather_out_of_range() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (i_id, i) = graph.create_input();
+        let table: Vec<GraphBuilder> = (0..3).map(|_| graph.create_parameter(0.).1).collect();
+
+        let y = i.gather(&table);
+        let mut g = RunnableGraph::new(vec![&y]);
+        g.set_input(i_id, 3.);
+        g.evaluate(&[y.root]);
+    }
+
+    #[test]
+    fn test_evaluate_subgraph() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input();
+        let y = x.clone() * 2.;
+        let z = y.clone() + 1.;
+        let mut g = RunnableGraph::new(vec![&z]);
+
+        g.set_input(x_id, 3.);
+        assert_eq!(g.evaluate(&[y.root]), vec![6.]);
+        assert_eq!(g.value_for_id(z.root), 0.);
+        assert_eq!(g.evaluate(&[z.root]), vec![7.]);
+    }
+
+    #[test]
+    fn test_dropout_only() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input();
+        let (_, sigma) = graph.create_immediate(1.);
+
+        let y = GraphBuilder::gaussian(x.dropout(0.5), sigma);
+        let mut g = RunnableGraph::new(vec![&y]);
+        g.set_input(x_id, 3.);
+        g.seed(2);
+
+        g.set_training(false);
+        g.set_dropout(true);
+        assert!(!g.is_training());
+        let samples: Vec<f64> = (0..100).map(|_| g.evaluate(&[y.root])[0]).collect();
+        assert!(samples.iter().all(|v| *v == 0. || *v == 6.));
+        assert!(samples.contains(&0.) && samples.contains(&6.));
+
+        g.set_training(false);
+        assert_eq!(g.evaluate(&[y.root]), vec![3.]);
+    }
+
+    #[test]
+    fn test_evaluate_batch() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (a_id, a) = &graph.create_input();
+        let (b_id, b) = &graph.create_input();
+
+        let f = a * b + 1.;
+        let mut g = RunnableGraph::new(vec![&f]);
+        assert_eq!(g.input_ids(), vec![*a_id, *b_id]);
+
+        let inputs = vec![vec![1., 2.], vec![3., 4.], vec![-1., 5.]];
+        let batch = g.evaluate_batch(&inputs, &[f.root]);
+        assert_eq!(batch, vec![vec![3.], vec![13.], vec![-4.]]);
+
+        g.set_input(*a_id, 3.);
+        g.set_input(*b_id, 4.);
+        assert_eq!(g.evaluate(&[f.root]), batch[1]);
+    }
+
+    #[test]
+    fn test_activations() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input();
+
+        let builders = [
+            x.clone().leaky_relu(0.1),
+            x.clone().tanh(),
+            x.clone().sigmoid(),
+            (x.clone() * &x).ln(),
+        ];
+        let outputs: Vec<NodeId> = builders.iter().map(|b| b.root).collect();
+        let mut g = RunnableGraph::new(builders.iter().collect());
+
+        g.set_input(x_id, -2.);
+        let values = g.evaluate(&outputs);
+        assert_eq!(values[0], -0.2);
+        assert_eq!(values[1], (-2_f64).tanh());
+        assert_eq!(values[2], 1. / (1. + 2_f64.exp()));
+        assert_eq!(values[3], 4_f64.ln());
+
+        let tangents = g.forward_grad(x_id, &outputs);
+        assert_eq!(tangents[0], 0.1);
+        assert_eq!(tangents[1], 1. - values[1] * values[1]);
+        assert_eq!(tangents[2], values[2] * (1. - values[2]));
+        assert_eq!(tangents[3], -1.);
     }
 }
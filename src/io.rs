@@ -0,0 +1,473 @@
+use std::{collections::BTreeMap, io::Read};
+
+use flate2::{
+    read::{DeflateDecoder, GzDecoder},
+    Crc,
+};
+use serde_json::{json, Map, Value};
+
+use crate::tensor::Tensor;
+
+/// Serialises named tensors in the safetensors format as `F64` arrays.
+pub fn write_safetensors(tensors: &BTreeMap<String, Tensor>) -> Vec<u8> {
+    let mut header = Map::new();
+    let mut data = vec![];
+    tensors.iter().for_each(|(name, tensor)| {
+        let begin = data.len();
+        data.extend(tensor.data().iter().flat_map(|v| v.to_le_bytes()));
+        header.insert(
+            name.clone(),
+            json!({
+                "dtype": "F64",
+                "shape": tensor.shape(),
+                "data_offsets": [begin, data.len()],
+            }),
+        );
+    });
+
+    // The header is padded with spaces so that the data starts 8-byte aligned.
+    let mut header = Value::Object(header).to_string().into_bytes();
+    header.resize(header.len().next_multiple_of(8), b' ');
+
+    let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+    bytes.extend(header);
+    bytes.extend(data);
+    bytes
+}
+
+/// Reads the named tensors of a safetensors file, converting `F32` and `F64` arrays to `f64`.
+pub fn read_safetensors(bytes: &[u8]) -> BTreeMap<String, Tensor> {
+    if bytes.len() < 8 {
+        panic!("Expected at least 8 bytes, but got {}", bytes.len())
+    }
+    let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+    let header: Map<String, Value> = serde_json::from_slice(&bytes[8..8 + header_len])
+        .unwrap_or_else(|e| panic!("Invalid safetensors header: {e}"));
+    let data = &bytes[8 + header_len..];
+
+    header
+        .into_iter()
+        .filter(|(name, _)| name != "__metadata__")
+        .map(|(name, info)| {
+            let shape: Vec<usize> = serde_json::from_value(info["shape"].clone())
+                .unwrap_or_else(|e| panic!("Invalid shape for {name}: {e}"));
+            let offsets: [usize; 2] = serde_json::from_value(info["data_offsets"].clone())
+                .unwrap_or_else(|e| panic!("Invalid data offsets for {name}: {e}"));
+            let raw = &data[offsets[0]..offsets[1]];
+
+            let values = match info["dtype"].as_str() {
+                Some("F64") => raw
+                    .chunks_exact(8)
+                    .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+                Some("F32") => raw
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64)
+                    .collect(),
+                dtype => panic!("Unsupported dtype for {name}: {dtype:?}"),
+            };
+            (name, Tensor::new(shape, values))
+        })
+        .collect()
+}
+
+/// Serialises a tensor as a version 1.0 `.npy` array of `<f8`.
+pub fn write_npy(tensor: &Tensor) -> Vec<u8> {
+    let shape = match tensor.shape() {
+        [size] => format!("({size},)"),
+        shape => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': {shape}, }}");
+    // The header is padded so that the data starts 64-byte aligned, and ends in a newline.
+    let padded = (10 + header.len() + 1).next_multiple_of(64) - 10;
+    header.extend(std::iter::repeat_n(' ', padded - header.len() - 1));
+    header.push('\n');
+
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.into_bytes());
+    bytes.extend(tensor.data().iter().flat_map(|v| v.to_le_bytes()));
+    bytes
+}
+
+/// Reads a C-ordered `.npy` array of little-endian floats or integers, or of bytes.
+pub fn read_npy(bytes: &[u8]) -> Tensor {
+    if !bytes.starts_with(b"\x93NUMPY") {
+        panic!("Not an npy array")
+    }
+    let (header_len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        _ => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+    };
+    let header = String::from_utf8_lossy(&bytes[start..start + header_len]);
+    let data = &bytes[start + header_len..];
+
+    let value_of = |key: &str| -> &str {
+        let key = format!("'{key}':");
+        let begin = header
+            .find(&key)
+            .unwrap_or_else(|| panic!("Missing {key} in npy header: {header}"))
+            + key.len();
+        header[begin..].trim_start()
+    };
+    if value_of("fortran_order").starts_with("True") {
+        panic!("Fortran ordered npy arrays are not supported")
+    }
+    let descr = value_of("descr")[1..].split('\'').next().unwrap();
+    let shape: Vec<usize> = value_of("shape")[1..]
+        .split(')')
+        .next()
+        .unwrap()
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse().unwrap())
+        .collect();
+
+    let values: Vec<f64> = match descr {
+        "<f8" => data
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+            .collect(),
+        "<f4" => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64)
+            .collect(),
+        "<i8" => data
+            .chunks_exact(8)
+            .map(|b| i64::from_le_bytes(b.try_into().unwrap()) as f64)
+            .collect(),
+        "<i4" => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes(b.try_into().unwrap()) as f64)
+            .collect(),
+        "|u1" | "|b1" => data.iter().map(|b| *b as f64).collect(),
+        descr => panic!("Unsupported npy dtype: {descr}"),
+    };
+    Tensor::new(shape, values)
+}
+
+/// Serialises named arrays as an uncompressed `.npz` archive, like `numpy.savez`.
+pub fn write_npz(arrays: &[(String, Tensor)]) -> Vec<u8> {
+    let files: Vec<(String, Vec<u8>)> = arrays
+        .iter()
+        .map(|(name, tensor)| (format!("{name}.npy"), write_npy(tensor)))
+        .collect();
+    write_zip(&files)
+}
+
+/// Reads the arrays of an `.npz` archive, written by either `numpy.savez` or
+/// `numpy.savez_compressed`, in the order they were saved.
+pub fn read_npz(bytes: &[u8]) -> Vec<(String, Tensor)> {
+    read_zip(bytes)
+        .into_iter()
+        .map(|(name, file)| {
+            let name = name.strip_suffix(".npy").unwrap_or(&name).to_string();
+            (name, read_npy(&file))
+        })
+        .collect()
+}
+
+/// Reads an array in the IDX format of the original MNIST files, e.g.
+/// `train-images-idx3-ubyte`, which may be gzipped.
+pub fn read_idx(bytes: &[u8]) -> Tensor {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut decompressed = vec![];
+        GzDecoder::new(bytes)
+            .read_to_end(&mut decompressed)
+            .unwrap_or_else(|e| panic!("Failed to decompress IDX array: {e}"));
+        return read_idx(&decompressed);
+    }
+    if bytes.len() < 4 || bytes[0..2] != [0, 0] {
+        panic!("Not an IDX array")
+    }
+    let ndims = bytes[3] as usize;
+    let shape: Vec<usize> = (0..ndims)
+        .map(|d| u32::from_be_bytes(bytes[4 + 4 * d..8 + 4 * d].try_into().unwrap()) as usize)
+        .collect();
+    let data = &bytes[4 + 4 * ndims..];
+
+    let values: Vec<f64> = match bytes[2] {
+        0x08 => data.iter().map(|b| *b as f64).collect(),
+        0x09 => data.iter().map(|b| *b as i8 as f64).collect(),
+        0x0B => data
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes(b.try_into().unwrap()) as f64)
+            .collect(),
+        0x0C => data
+            .chunks_exact(4)
+            .map(|b| i32::from_be_bytes(b.try_into().unwrap()) as f64)
+            .collect(),
+        0x0D => data
+            .chunks_exact(4)
+            .map(|b| f32::from_be_bytes(b.try_into().unwrap()) as f64)
+            .collect(),
+        0x0E => data
+            .chunks_exact(8)
+            .map(|b| f64::from_be_bytes(b.try_into().unwrap()))
+            .collect(),
+        dtype => panic!("Unsupported IDX dtype: {dtype:#04x}"),
+    };
+    let expected: usize = shape.iter().product();
+    if values.len() != expected {
+        panic!("Expected {} values, but got {}", expected, values.len())
+    }
+    Tensor::new(shape, values)
+}
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+
+fn u16_at(bytes: &[u8], pos: usize) -> usize {
+    u16::from_le_bytes([bytes[pos], bytes[pos + 1]]) as usize
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> usize {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> usize {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap()) as usize
+}
+
+/// Stored (uncompressed) zip archive of the given files.
+fn write_zip(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut central = vec![];
+    files.iter().for_each(|(name, data)| {
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        // Fields shared by the local and central headers, from the compression method onwards.
+        let mut common = vec![];
+        common.extend(0u16.to_le_bytes()); // stored
+        common.extend([0u8; 4]); // modification time and date
+        common.extend(crc.sum().to_le_bytes());
+        common.extend((data.len() as u32).to_le_bytes());
+        common.extend((data.len() as u32).to_le_bytes());
+        common.extend((name.len() as u16).to_le_bytes());
+        common.extend(0u16.to_le_bytes()); // extra field length
+
+        central.extend(CENTRAL_HEADER.to_le_bytes());
+        central.extend([20, 0, 20, 0, 0, 0]); // versions and flags
+        central.extend(&common);
+        central.extend([0u8; 10]); // comment, disk and attributes
+        central.extend((bytes.len() as u32).to_le_bytes());
+        central.extend(name.as_bytes());
+
+        bytes.extend(LOCAL_HEADER.to_le_bytes());
+        bytes.extend([20, 0, 0, 0]); // version and flags
+        bytes.extend(common);
+        bytes.extend(name.as_bytes());
+        bytes.extend(data);
+    });
+
+    let offset = bytes.len();
+    bytes.extend(&central);
+    bytes.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    bytes.extend([0u8; 4]); // disks
+    bytes.extend((files.len() as u16).to_le_bytes());
+    bytes.extend((files.len() as u16).to_le_bytes());
+    bytes.extend((central.len() as u32).to_le_bytes());
+    bytes.extend((offset as u32).to_le_bytes());
+    bytes.extend(0u16.to_le_bytes()); // comment length
+    bytes
+}
+
+/// Names and contents of the files of a zip archive, which may be stored or deflated.
+fn read_zip(bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|pos| u32_at(bytes, *pos) == END_OF_CENTRAL_DIRECTORY as usize)
+        .expect("Not a zip archive");
+    let (mut num_files, mut offset) = (u16_at(bytes, end + 10), u32_at(bytes, end + 16));
+
+    // Archives written with ZIP64 extensions, such as by `numpy.savez`, keep the real values in
+    // a separate record pointed to by a locator right before the end of central directory.
+    if end >= 20 && u32_at(bytes, end - 20) == ZIP64_LOCATOR as usize {
+        let record = u64_at(bytes, end - 12);
+        if u32_at(bytes, record) != ZIP64_END_OF_CENTRAL_DIRECTORY as usize {
+            panic!("Invalid ZIP64 end of central directory record")
+        }
+        num_files = u64_at(bytes, record + 32);
+        offset = u64_at(bytes, record + 48);
+    }
+
+    (0..num_files)
+        .scan(offset, |pos, _| {
+            let header = *pos;
+            if u32_at(bytes, header) != CENTRAL_HEADER as usize {
+                panic!("Invalid zip central directory header")
+            }
+            let method = u16_at(bytes, header + 10);
+            let mut compressed_size = u32_at(bytes, header + 20);
+            let mut size = u32_at(bytes, header + 24);
+            let name_len = u16_at(bytes, header + 28);
+            let extra_len = u16_at(bytes, header + 30);
+            let comment_len = u16_at(bytes, header + 32);
+            let mut local = u32_at(bytes, header + 42);
+            let name = String::from_utf8_lossy(&bytes[header + 46..header + 46 + name_len]);
+
+            // Values that did not fit are replaced by 0xFFFFFFFF and stored, in this order, in
+            // the ZIP64 extra field.
+            let mut extra = header + 46 + name_len;
+            while extra < header + 46 + name_len + extra_len {
+                let (id, len) = (u16_at(bytes, extra), u16_at(bytes, extra + 2));
+                if id == 1 {
+                    let mut field = extra + 4;
+                    for value in [&mut size, &mut compressed_size, &mut local] {
+                        if *value == u32::MAX as usize {
+                            *value = u64_at(bytes, field);
+                            field += 8;
+                        }
+                    }
+                }
+                extra += 4 + len;
+            }
+            *pos = extra + comment_len;
+
+            let start = local + 30 + u16_at(bytes, local + 26) + u16_at(bytes, local + 28);
+            let data = &bytes[start..start + compressed_size];
+            let data = match method {
+                0 => data.to_vec(),
+                8 => {
+                    let mut inflated = Vec::with_capacity(size);
+                    DeflateDecoder::new(data)
+                        .read_to_end(&mut inflated)
+                        .unwrap_or_else(|e| panic!("Failed to inflate {name}: {e}"));
+                    inflated
+                }
+                method => panic!("Unsupported zip compression method {method} for {name}"),
+            };
+            Some((name.into_owned(), data))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safetensors_round_trip() {
+        let tensors = BTreeMap::from([
+            (
+                "0.weight".to_string(),
+                Tensor::new(vec![2, 3], vec![1., -2., 3., 0.5, 0.25, -0.125]),
+            ),
+            ("0.bias".to_string(), Tensor::new(vec![2], vec![0.1, 0.2])),
+        ]);
+
+        let bytes = write_safetensors(&tensors);
+        let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        assert_eq!(header_len % 8, 0);
+        assert_eq!(bytes.len(), 8 + header_len + 8 * 8);
+        assert_eq!(read_safetensors(&bytes), tensors);
+    }
+
+    #[test]
+    fn test_read_f32_safetensors() {
+        // As written by `safetensors.torch.save_file({"w": torch.tensor([1.5, -2.0])})`.
+        let header = br#"{"__metadata__":{"format":"pt"},"w":{"dtype":"F32","shape":[2],"data_offsets":[0,8]}}"#;
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header);
+        bytes.extend(1.5f32.to_le_bytes());
+        bytes.extend((-2f32).to_le_bytes());
+
+        let tensors = read_safetensors(&bytes);
+        assert_eq!(tensors.len(), 1);
+        assert_eq!(tensors["w"], Tensor::new(vec![2], vec![1.5, -2.]));
+    }
+
+    #[test]
+    fn test_npz_round_trip() {
+        let arrays = vec![
+            (
+                "fc1.weight".to_string(),
+                Tensor::new(vec![2, 3], vec![1., -2., 3., 0.5, 0.25, -0.125]),
+            ),
+            ("fc1.bias".to_string(), Tensor::new(vec![2], vec![0.1, 0.2])),
+        ];
+
+        let bytes = write_npz(&arrays);
+        assert_eq!(read_npz(&bytes), arrays);
+    }
+
+    #[test]
+    fn test_read_npy() {
+        // As written by `numpy.save(f, numpy.array([[1, 2, 3]], dtype=numpy.float32))`.
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (1, 3), }";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend([1f32, 2., 3.].iter().flat_map(|v| v.to_le_bytes()));
+        assert_eq!(read_npy(&bytes), Tensor::new(vec![1, 3], vec![1., 2., 3.]));
+
+        let tensor = Tensor::new(vec![3], vec![1., 2., 3.]);
+        let bytes = write_npy(&tensor);
+        assert_eq!((bytes.len() - 3 * 8) % 64, 0);
+        assert_eq!(read_npy(&bytes), tensor);
+    }
+
+    #[test]
+    fn test_read_compressed_npz() {
+        use flate2::{write::DeflateEncoder, Compression};
+        use std::io::Write;
+
+        let tensor = Tensor::new(vec![2, 2], vec![1., 0., 0., 1.]);
+        let npy = write_npy(&tensor);
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&npy).unwrap();
+        let deflated = encoder.finish().unwrap();
+
+        // Patch the method and compressed size of a stored archive to make it deflated.
+        let stored = write_zip(&[("eye.npy".to_string(), deflated.clone())]);
+        let mut bytes = stored.clone();
+        let central = stored.len() - 22 - (46 + 7);
+        for header in [0, central] {
+            let fields = header + if header == 0 { 8 } else { 10 };
+            bytes[fields..fields + 2].copy_from_slice(&8u16.to_le_bytes());
+            let size = fields + 14;
+            bytes[size..size + 4].copy_from_slice(&(npy.len() as u32).to_le_bytes());
+        }
+
+        assert_eq!(read_npz(&bytes), vec![("eye".to_string(), tensor)]);
+    }
+
+    #[test]
+    fn test_read_idx() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        // Two 2x3 images of bytes, as in `train-images-idx3-ubyte`.
+        let mut bytes = vec![0, 0, 0x08, 3];
+        [2u32, 2, 3]
+            .iter()
+            .for_each(|d| bytes.extend(d.to_be_bytes()));
+        bytes.extend(0..12u8);
+        let images = Tensor::new(vec![2, 2, 3], (0..12).map(|v| v as f64).collect());
+        assert_eq!(read_idx(&bytes), images);
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&bytes).unwrap();
+        assert_eq!(read_idx(&encoder.finish().unwrap()), images);
+
+        let mut bytes = vec![0, 0, 0x0D, 1];
+        bytes.extend(2u32.to_be_bytes());
+        bytes.extend([1.5f32, -2.].iter().flat_map(|v| v.to_be_bytes()));
+        assert_eq!(read_idx(&bytes), Tensor::new(vec![2], vec![1.5, -2.]));
+    }
+}
@@ -1,5 +1,11 @@
 pub mod data;
 pub mod engine;
+pub mod io;
+pub mod loss;
 pub mod nn;
+pub mod onnx;
 pub mod optimiser;
+pub mod quantise;
+pub mod scheduler;
+pub mod tensor;
 pub mod util;
@@ -0,0 +1,595 @@
+use crate::util::Util;
+
+/// Mean squared error.
+pub fn mse(outputs: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+    mean_of(outputs, targets, |y, t| ((y - t) * (y - t), 2. * (y - t)))
+}
+
+/// Mean absolute error, whose gradient is taken to be 0 where the output hits the target.
+pub fn mae(outputs: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+    mean_of(outputs, targets, |y, t| {
+        let error = y - t;
+        let grad = if error == 0. { 0. } else { error.signum() };
+        (error.abs(), grad)
+    })
+}
+
+/// Quadratic for errors up to `delta` and linear beyond, so less sensitive to outliers than
+/// `mse`.
+pub fn huber(outputs: &[f64], targets: &[f64], delta: f64) -> (f64, Vec<f64>) {
+    mean_of(outputs, targets, |y, t| {
+        let error = y - t;
+        match error.abs() <= delta {
+            true => (0.5 * error * error, error),
+            false => (delta * (error.abs() - 0.5 * delta), delta * error.signum()),
+        }
+    })
+}
+
+/// Negative log-likelihood of `counts` under Poisson distributions with rates
+/// `exp(log_rates)`, averaged and without the `ln(count!)` term, which doesn't depend on the
+/// outputs.
+pub fn poisson_nll_with_log_rates(log_rates: &[f64], counts: &[f64]) -> (f64, Vec<f64>) {
+    mean_of(log_rates, counts, |y, t| (y.exp() - t * y, y.exp() - t))
+}
+
+/// Negative log-likelihood of `targets` under Gaussians `N(means, exp(log_vars))`, averaged
+/// and without the constant `ln(2 pi) / 2`, along with its gradients with respect to `means`
+/// and `log_vars`. Predicting the variance lets the model learn how noisy each target is.
+pub fn gaussian_nll(means: &[f64], log_vars: &[f64], targets: &[f64]) -> (f64, Vec<f64>, Vec<f64>) {
+    if log_vars.len() != means.len() || targets.len() != means.len() {
+        panic!(
+            "Expected {} log-variances and targets, but got {} and {}",
+            means.len(),
+            log_vars.len(),
+            targets.len()
+        )
+    }
+
+    let n = means.len() as f64;
+    let terms: Vec<(f64, f64, f64)> = means
+        .iter()
+        .zip(log_vars)
+        .zip(targets)
+        .map(|((m, lv), t)| {
+            let precision = (-lv).exp();
+            let squared = (m - t) * (m - t);
+            (
+                0.5 * (lv + squared * precision),
+                (m - t) * precision / n,
+                0.5 * (1. - squared * precision) / n,
+            )
+        })
+        .collect();
+    (
+        terms.iter().map(|t| t.0).sum::<f64>() / n,
+        terms.iter().map(|t| t.1).collect(),
+        terms.iter().map(|t| t.2).collect(),
+    )
+}
+
+/// Logarithm of the softmax of `logits`, computed with log-sum-exp shifted by the maximum so that
+/// saturated classes give a large negative value rather than the log of an underflowed 0.
+fn log_softmax(logits: &[f64]) -> Vec<f64> {
+    let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = logits.iter().map(|z| (z - max).exp()).sum::<f64>().ln();
+    logits.iter().map(|z| z - max - log_sum).collect()
+}
+
+/// Cross-entropy of the softmax of `logits` against `target_class`, whose gradient is
+/// `softmax - onehot`.
+pub fn cross_entropy_with_logits(logits: &[f64], target_class: usize) -> (f64, Vec<f64>) {
+    if target_class >= logits.len() {
+        panic!(
+            "Expected a target class below {}, but got {}",
+            logits.len(),
+            target_class
+        )
+    }
+
+    let loss = -log_softmax(logits)[target_class];
+
+    let mut grads = Util::softmax(logits);
+    grads[target_class] -= 1.;
+    (loss, grads)
+}
+
+/// Cross-entropy against `target_class` scaled by the weight of the class, e.g. from
+/// `class_weights`, so that rare classes weigh as much as frequent ones on imbalanced data.
+pub fn weighted_cross_entropy_with_logits(
+    logits: &[f64],
+    target_class: usize,
+    weights: &[f64],
+) -> (f64, Vec<f64>) {
+    if weights.len() != logits.len() {
+        panic!(
+            "Expected {} class weights, but got {}",
+            logits.len(),
+            weights.len()
+        )
+    }
+    let (loss, grads) = cross_entropy_with_logits(logits, target_class);
+    let weight = weights[target_class];
+    (weight * loss, grads.iter().map(|g| weight * g).collect())
+}
+
+/// Weights inversely proportional to the frequencies of the classes among `labels`, scaled so
+/// that a balanced dataset gets weights of 1. Classes without samples get a weight of 0.
+pub fn class_weights(labels: impl IntoIterator<Item = usize>, num_classes: usize) -> Vec<f64> {
+    let mut counts = vec![0; num_classes];
+    labels.into_iter().for_each(|label| {
+        if label >= num_classes {
+            panic!("Expected a label below {}, but got {}", num_classes, label)
+        }
+        counts[label] += 1
+    });
+
+    let total: usize = counts.iter().sum();
+    counts
+        .iter()
+        .map(|count| match count {
+            0 => 0.,
+            _ => total as f64 / (num_classes * count) as f64,
+        })
+        .collect()
+}
+
+/// Cross-entropy of the softmax of `logits` against a distribution over the classes, e.g.
+/// soft labels, whose gradient is `softmax - targets`.
+pub fn soft_cross_entropy_with_logits(logits: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+    if targets.len() != logits.len() {
+        panic!(
+            "Expected {} targets, but got {}",
+            logits.len(),
+            targets.len()
+        )
+    }
+
+    let loss = targets
+        .iter()
+        .zip(log_softmax(logits))
+        .filter(|(t, _)| **t != 0.)
+        .map(|(t, ln_p)| -t * ln_p)
+        .sum();
+    let probs = Util::softmax(logits);
+    let grads = probs.iter().zip(targets).map(|(p, t)| p - t).collect();
+    (loss, grads)
+}
+
+/// Cross-entropy against `target_class` with label smoothing: the target distribution puts
+/// `1 - epsilon` on the class and spreads `epsilon` uniformly over all classes.
+pub fn cross_entropy_with_label_smoothing(
+    logits: &[f64],
+    target_class: usize,
+    epsilon: f64,
+) -> (f64, Vec<f64>) {
+    if target_class >= logits.len() {
+        panic!(
+            "Expected a target class below {}, but got {}",
+            logits.len(),
+            target_class
+        )
+    }
+    if !(0. ..=1.).contains(&epsilon) {
+        panic!("Label smoothing must be in [0, 1], but got {epsilon}")
+    }
+
+    let k = logits.len() as f64;
+    let mut targets = vec![epsilon / k; logits.len()];
+    targets[target_class] += 1. - epsilon;
+    soft_cross_entropy_with_logits(logits, &targets)
+}
+
+/// KL divergence `sum(t * ln(t / p))` of the softmax `p` of `logits` from a target
+/// distribution `t`, e.g. the softened predictions of a teacher network. It only differs from
+/// `soft_cross_entropy_with_logits` by the entropy of the targets, so shares its gradient
+/// `softmax - targets`.
+pub fn kl_divergence_with_logits(logits: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+    let (cross_entropy, grads) = soft_cross_entropy_with_logits(logits, targets);
+    let entropy: f64 = targets
+        .iter()
+        .filter(|t| **t != 0.)
+        .map(|t| -t * t.ln())
+        .sum();
+    (cross_entropy - entropy, grads)
+}
+
+/// KL divergence of the diagonal Gaussian `N(mu, exp(log_var))` from the standard normal, the
+/// regulariser of a variational autoencoder, along with its gradients with respect to `mu` and
+/// `log_var`.
+pub fn gaussian_kl_divergence(mu: &[f64], log_var: &[f64]) -> (f64, Vec<f64>, Vec<f64>) {
+    if log_var.len() != mu.len() {
+        panic!(
+            "Expected {} log-variances, but got {}",
+            mu.len(),
+            log_var.len()
+        )
+    }
+
+    let loss = mu
+        .iter()
+        .zip(log_var)
+        .map(|(m, lv)| 0.5 * (lv.exp() + m * m - 1. - lv))
+        .sum();
+    let log_var_grads = log_var.iter().map(|lv| 0.5 * (lv.exp() - 1.)).collect();
+    (loss, mu.to_vec(), log_var_grads)
+}
+
+/// Triplet loss `max(0, d(a, p) - d(a, n) + margin)` for Euclidean distances `d`, which pulls
+/// the embedding of the anchor `a` towards the positive `p` of the same class and away from the
+/// negative `n`, along with its gradients with respect to the three embeddings.
+pub fn triplet_loss(
+    anchor: &[f64],
+    positive: &[f64],
+    negative: &[f64],
+    margin: f64,
+) -> (f64, Vec<f64>, Vec<f64>, Vec<f64>) {
+    if positive.len() != anchor.len() || negative.len() != anchor.len() {
+        panic!(
+            "Expected embeddings of size {}, but got {} and {}",
+            anchor.len(),
+            positive.len(),
+            negative.len()
+        )
+    }
+
+    // Differences to the anchor along with their norms.
+    let difference = |other: &[f64]| -> (Vec<f64>, f64) {
+        let diffs: Vec<f64> = anchor.iter().zip(other).map(|(a, o)| a - o).collect();
+        let norm = diffs.iter().map(|d| d * d).sum::<f64>().sqrt();
+        (diffs, norm)
+    };
+    let (to_positive, d_positive) = difference(positive);
+    let (to_negative, d_negative) = difference(negative);
+
+    let loss = d_positive - d_negative + margin;
+    if loss <= 0. {
+        let zeros = vec![0.; anchor.len()];
+        return (0., zeros.clone(), zeros.clone(), zeros);
+    }
+
+    // The gradient of a distance is the unit difference, taken to be 0 at a distance of 0.
+    let unit = |diffs: &[f64], norm: f64| -> Vec<f64> {
+        match norm > 0. {
+            true => diffs.iter().map(|d| d / norm).collect(),
+            false => vec![0.; diffs.len()],
+        }
+    };
+    let u_positive = unit(&to_positive, d_positive);
+    let u_negative = unit(&to_negative, d_negative);
+    (
+        loss,
+        u_positive
+            .iter()
+            .zip(u_negative.iter())
+            .map(|(p, n)| p - n)
+            .collect(),
+        u_positive.iter().map(|p| -p).collect(),
+        u_negative,
+    )
+}
+
+/// Mean binary cross-entropy of the sigmoids of `logits` against targets in [0, 1], computed
+/// so as not to overflow for large logits.
+pub fn binary_cross_entropy_with_logits(logits: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+    mean_of(logits, targets, |z, t| {
+        // -(t ln p + (1 - t) ln (1 - p)) with p = sigmoid(z).
+        let loss = z.max(0.) - z * t + (-z.abs()).exp().ln_1p();
+        (loss, 1. / (1. + (-z).exp()) - t)
+    })
+}
+
+/// Focal loss `-alpha * (1 - p_t)^gamma * ln p_t` of the softmax of `logits` against
+/// `target_class`, where `p_t` is the probability of the target class. It down-weights the
+/// examples that are already well classified, focusing training on the hard ones, and reduces
+/// to `alpha` times the cross-entropy for `gamma = 0`.
+pub fn focal_loss_with_logits(
+    logits: &[f64],
+    target_class: usize,
+    gamma: f64,
+    alpha: f64,
+) -> (f64, Vec<f64>) {
+    if target_class >= logits.len() {
+        panic!(
+            "Expected a target class below {}, but got {}",
+            logits.len(),
+            target_class
+        )
+    }
+
+    let probs = Util::softmax(logits);
+    let ln_p = log_softmax(logits)[target_class];
+    let (p, q) = (probs[target_class], 1. - probs[target_class]);
+
+    // d loss / d z_j = alpha * (gamma * q^(gamma - 1) * p * ln p - q^gamma) * (onehot_j - p_j).
+    let scale = match q > 0. {
+        true => alpha * (gamma * q.powf(gamma - 1.) * p * ln_p - q.powf(gamma)),
+        false => 0.,
+    };
+    let grads = probs
+        .iter()
+        .zip(Util::one_hot(target_class, logits.len()))
+        .map(|(p_j, onehot_j)| scale * (onehot_j - p_j))
+        .collect();
+    (-alpha * q.powf(gamma) * ln_p, grads)
+}
+
+/// Mean focal loss of the sigmoids of `logits` against targets of 0 or 1, with the positive
+/// class weighted by `alpha` and the negative one by `1 - alpha`, see `focal_loss_with_logits`.
+pub fn binary_focal_loss_with_logits(
+    logits: &[f64],
+    targets: &[f64],
+    gamma: f64,
+    alpha: f64,
+) -> (f64, Vec<f64>) {
+    mean_of(logits, targets, |z, t| {
+        // With s = 1 for positives and -1 for negatives, p_t = sigmoid(s * z).
+        let (s, alpha_t) = if t == 1. {
+            (1., alpha)
+        } else {
+            (-1., 1. - alpha)
+        };
+        let ln_p = -((-s * z).max(0.) + (-z.abs()).exp().ln_1p());
+        let p = ln_p.exp();
+        let q = 1. - p;
+        let loss = -alpha_t * q.powf(gamma) * ln_p;
+        let grad = alpha_t * s * (gamma * p * q.powf(gamma) * ln_p - q.powf(gamma + 1.));
+        (loss, grad)
+    })
+}
+
+/// Mean hinge loss `max(0, 1 - t * y)` for targets of -1 or 1.
+pub fn hinge(outputs: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+    mean_of(outputs, targets, |y, t| match t * y < 1. {
+        true => (1. - t * y, -t),
+        false => (0., 0.),
+    })
+}
+
+/// Averages an elementwise loss, given as the loss and derivative for an output and its target,
+/// over the outputs.
+fn mean_of(
+    outputs: &[f64],
+    targets: &[f64],
+    loss: impl Fn(f64, f64) -> (f64, f64),
+) -> (f64, Vec<f64>) {
+    if targets.len() != outputs.len() {
+        panic!(
+            "Expected {} targets, but got {}",
+            outputs.len(),
+            targets.len()
+        )
+    }
+
+    let n = outputs.len() as f64;
+    let (losses, grads): (Vec<f64>, Vec<f64>) = outputs
+        .iter()
+        .zip(targets)
+        .map(|(y, t)| {
+            let (l, grad) = loss(*y, *t);
+            (l, grad / n)
+        })
+        .unzip();
+    (losses.iter().sum::<f64>() / n, grads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the gradient of `loss` against central differences.
+    fn check_gradients(loss: impl Fn(&[f64]) -> (f64, Vec<f64>), outputs: &[f64]) {
+        let (_, grads) = loss(outputs);
+        grads.iter().enumerate().for_each(|(i, grad)| {
+            let nudged = |step: f64| {
+                let mut outputs = outputs.to_vec();
+                outputs[i] += step;
+                loss(&outputs).0
+            };
+            let numerical = (nudged(1e-6) - nudged(-1e-6)) / 2e-6;
+            assert!((grad - numerical).abs() < 1e-6);
+        });
+    }
+
+    #[test]
+    fn test_mse() {
+        assert_eq!(mse(&[3., 1.], &[1., 1.]), (2., vec![2., 0.]));
+        check_gradients(|y| mse(y, &[0.5, -1., 2.]), &[0.1, 0.3, -0.4]);
+    }
+
+    #[test]
+    fn test_mae() {
+        assert_eq!(mae(&[3., 0.], &[1., 1.]), (1.5, vec![0.5, -0.5]));
+        check_gradients(|y| mae(y, &[0.5, -1., 2.]), &[0.1, 0.3, -0.4]);
+    }
+
+    #[test]
+    fn test_huber() {
+        assert_eq!(huber(&[1.5], &[1.], 1.), (0.125, vec![0.5]));
+        assert_eq!(huber(&[-2.], &[1.], 1.), (2.5, vec![-1.]));
+        check_gradients(|y| huber(y, &[0.5, -1., 2.], 1.), &[0.1, 0.3, -0.4]);
+    }
+
+    #[test]
+    fn test_poisson_nll() {
+        assert_eq!(poisson_nll_with_log_rates(&[0.], &[1.]), (1., vec![0.]));
+        check_gradients(
+            |y| poisson_nll_with_log_rates(y, &[0., 2., 5.]),
+            &[0.1, 0.3, -0.4],
+        );
+    }
+
+    #[test]
+    fn test_gaussian_nll() {
+        let (loss, mean_grads, log_var_grads) = gaussian_nll(&[1., 2.], &[0., 0.], &[1., 0.]);
+        assert_eq!(loss, 1.);
+        assert_eq!(
+            (mean_grads, log_var_grads),
+            (vec![0., 1.], vec![0.25, -0.75])
+        );
+
+        let (means, log_vars, targets) = ([0.1, 0.3, -0.4], [0.2, -0.5, 1.], [0.5, -1., 2.]);
+        let (_, mean_grads, log_var_grads) = gaussian_nll(&means, &log_vars, &targets);
+        check_gradients(
+            |y| (gaussian_nll(y, &log_vars, &targets).0, mean_grads.clone()),
+            &means,
+        );
+        check_gradients(
+            |y| (gaussian_nll(&means, y, &targets).0, log_var_grads.clone()),
+            &log_vars,
+        );
+    }
+
+    #[test]
+    fn test_cross_entropy() {
+        let (loss, grads) = cross_entropy_with_logits(&[0., 0.], 1);
+        assert!((loss - 2f64.ln()).abs() < 1e-12);
+        assert_eq!(grads, vec![0.5, -0.5]);
+        let (loss, grads) = cross_entropy_with_logits(&[0., 800.], 0);
+        assert_eq!(loss, 800.);
+        assert_eq!(grads, vec![-1., 1.]);
+        check_gradients(|y| cross_entropy_with_logits(y, 2), &[0.3, -1., 2.]);
+    }
+
+    #[test]
+    fn test_binary_cross_entropy() {
+        let (loss, _) = binary_cross_entropy_with_logits(&[800.], &[0.]);
+        assert_eq!(loss, 800.);
+        check_gradients(
+            |y| binary_cross_entropy_with_logits(y, &[1., 0., 0.3]),
+            &[0.7, -2., 0.1],
+        );
+    }
+
+    #[test]
+    fn test_label_smoothing() {
+        let logits = [0.3, -1., 2.];
+        let (loss, grads) = cross_entropy_with_logits(&logits, 2);
+        let (smoothed, smoothed_grads) = cross_entropy_with_label_smoothing(&logits, 2, 0.);
+        assert!((loss - smoothed).abs() < 1e-12);
+        grads
+            .iter()
+            .zip(smoothed_grads)
+            .for_each(|(g, s)| assert!((g - s).abs() < 1e-12));
+        check_gradients(|y| cross_entropy_with_label_smoothing(y, 0, 0.1), &logits);
+    }
+
+    #[test]
+    fn test_soft_cross_entropy() {
+        let (loss, _) = soft_cross_entropy_with_logits(&[0., 800.], &[0.5, 0.5]);
+        assert_eq!(loss, 400.);
+        let (smoothed, _) = cross_entropy_with_label_smoothing(&[0., 800.], 1, 0.1);
+        assert!((smoothed - 40.).abs() < 1e-9);
+        assert!(kl_divergence_with_logits(&[0., 800.], &[0.5, 0.5])
+            .0
+            .is_finite());
+        check_gradients(
+            |y| soft_cross_entropy_with_logits(y, &[0.2, 0.5, 0.3]),
+            &[0.3, -1., 2.],
+        );
+    }
+
+    #[test]
+    fn test_kl_divergence() {
+        let (logits, teacher) = ([0.3, -1., 2.], [0.2, 0.5, 0.3]);
+        assert!(
+            kl_divergence_with_logits(&teacher.map(f64::ln), &teacher)
+                .0
+                .abs()
+                < 1e-12
+        );
+        assert!(kl_divergence_with_logits(&logits, &teacher).0 > 0.);
+        check_gradients(|y| kl_divergence_with_logits(y, &teacher), &logits);
+    }
+
+    #[test]
+    fn test_gaussian_kl_divergence() {
+        assert_eq!(
+            gaussian_kl_divergence(&[0.], &[0.]),
+            (0., vec![0.], vec![0.])
+        );
+        let (mu, log_var) = ([0.5, -1.], [0.3, -0.2]);
+        let (_, mu_grads, log_var_grads) = gaussian_kl_divergence(&mu, &log_var);
+        check_gradients(
+            |y| {
+                let (loss, _, _) = gaussian_kl_divergence(y, &log_var);
+                (loss, mu_grads.clone())
+            },
+            &mu,
+        );
+        check_gradients(
+            |y| {
+                let (loss, _, _) = gaussian_kl_divergence(&mu, y);
+                (loss, log_var_grads.clone())
+            },
+            &log_var,
+        );
+    }
+
+    #[test]
+    fn test_triplet_loss() {
+        let (a, p, n) = ([0., 1.], [0.5, 1.], [2., 1.]);
+        assert_eq!(triplet_loss(&a, &p, &n, 1.).0, 0.);
+        let (triplet, a_grads, p_grads, n_grads) = triplet_loss(&a, &p, &n, 2.);
+        assert_eq!(triplet, 0.5);
+        assert_eq!(
+            (a_grads, p_grads, n_grads),
+            (vec![0., 0.], vec![1., -0.], vec![-1., 0.])
+        );
+        let (a, p, n) = ([0.3, -0.2], [0.1, 0.4], [-0.5, 0.2]);
+        let (_, a_grads, p_grads, n_grads) = triplet_loss(&a, &p, &n, 1.);
+        check_gradients(|y| (triplet_loss(y, &p, &n, 1.).0, a_grads.clone()), &a);
+        check_gradients(|y| (triplet_loss(&a, y, &n, 1.).0, p_grads.clone()), &p);
+        check_gradients(|y| (triplet_loss(&a, &p, y, 1.).0, n_grads.clone()), &n);
+    }
+
+    #[test]
+    fn test_class_weights() {
+        let weights = class_weights([0, 0, 0, 1, 2, 2], 4);
+        assert_eq!(weights, vec![0.5, 1.5, 0.75, 0.]);
+    }
+
+    #[test]
+    fn test_weighted_cross_entropy() {
+        let logits = [0.3, -1., 2.];
+        let (loss, _) = cross_entropy_with_logits(&logits, 2);
+        let (weighted, _) = weighted_cross_entropy_with_logits(&logits, 2, &[1., 1., 0.75]);
+        assert!((weighted - 0.75 * loss).abs() < 1e-12);
+        check_gradients(
+            |y| weighted_cross_entropy_with_logits(y, 1, &[1., 3., 0.5]),
+            &logits,
+        );
+    }
+
+    #[test]
+    fn test_focal_loss() {
+        let logits = [0.3, -1., 2.];
+        let (loss, grads) = cross_entropy_with_logits(&logits, 2);
+        let (focal, focal_grads) = focal_loss_with_logits(&logits, 2, 0., 0.5);
+        assert!((focal - 0.5 * loss).abs() < 1e-12);
+        focal_grads
+            .iter()
+            .zip(grads.iter())
+            .for_each(|(f, g)| assert!((f - 0.5 * g).abs() < 1e-12));
+        assert!(focal_loss_with_logits(&logits, 2, 2., 1.).0 < loss);
+        check_gradients(|y| focal_loss_with_logits(y, 0, 2., 0.25), &logits);
+        check_gradients(|y| focal_loss_with_logits(y, 2, 0.5, 1.), &logits);
+    }
+
+    #[test]
+    fn test_binary_focal_loss() {
+        let (bce, _) = binary_cross_entropy_with_logits(&[0.7, -2.], &[1., 0.]);
+        let (binary_focal, _) = binary_focal_loss_with_logits(&[0.7, -2.], &[1., 0.], 0., 0.5);
+        assert!((binary_focal - 0.5 * bce).abs() < 1e-12);
+        check_gradients(
+            |y| binary_focal_loss_with_logits(y, &[1., 0., 1.], 2., 0.25),
+            &[0.7, -2., 0.1],
+        );
+    }
+
+    #[test]
+    fn test_hinge() {
+        assert_eq!(hinge(&[0.5, 2.], &[1., 1.]), (0.25, vec![-0.5, 0.]));
+        check_gradients(|y| hinge(y, &[1., -1., 1.]), &[0.2, 0.4, -3.]);
+    }
+}
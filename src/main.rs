@@ -1,10 +1,10 @@
 use std::{fs::File, path::Path};
 
 use micrograd_rs::nn::MultiLayerPerceptron;
-use micrograd_rs::optimiser::{AdamOptimiser, LearningRateOptimiser};
-use rand::{seq::SliceRandom, thread_rng};
+use micrograd_rs::optimiser::AdamOptimiser;
+use micrograd_rs::scheduler::{CosineAnnealing, Scheduled, Warmup};
 
-use micrograd_rs::data::Mnist;
+use micrograd_rs::data::{DataLoader, Mnist, Standardise, Transformed};
 use micrograd_rs::util::{Mean, Util};
 
 fn main() {
@@ -18,55 +18,45 @@ fn main() {
 
     let mut mlp = MultiLayerPerceptron::new(vec![mnist.x_dim, mnist.y_dim], None);
 
-    // let optimiser = &mut LearningRateOptimiser::new(0.004);
-    let optimiser = &mut AdamOptimiser::new(mlp.num_parameters());
-
     let epochs = 100;
-    for i in 0..epochs {
-        let mut xy = mnist.as_xy();
-        xy.shuffle(&mut thread_rng());
 
-        let (acc, loss): (Vec<f64>, Vec<f64>) = xy
-            .iter()
-            .map(|(x, y)| {
-                let y_preds = mlp.forward(x);
-
-                let max = y_preds.iter().max_by(|l, r| l.total_cmp(r)).unwrap();
-                let sum_exp = y_preds.iter().map(|y| (y - max).exp()).sum::<f64>();
-                let softmax: Vec<_> = y_preds.iter().map(|y| (y - max).exp() / sum_exp).collect();
+    // let optimiser = LearningRateOptimiser::new(0.004);
+    let optimiser = AdamOptimiser::new(mlp.num_parameters());
+    let warmup = 5;
+    let schedule = Warmup {
+        steps: warmup,
+        then: CosineAnnealing {
+            steps: epochs - warmup,
+            min_lr: 1e-5,
+        },
+    };
+    let optimiser = &mut Scheduled::new(optimiser, schedule);
+    let standardised = Transformed::new(&mnist, Standardise::fit(&mnist));
+    let mut loader = DataLoader::new(&standardised, 32, None).with_drop_last(true);
 
-                // https://deepnotes.io/softmax-crossentropy
-                let grads: Vec<f64> = softmax
-                    .iter()
-                    .enumerate()
-                    .map(|(i, y_pred)| {
-                        let y = if (*y as usize) == i { 1. } else { 0. };
-                        y_pred - y
-                    })
-                    .collect();
+    for i in 0..epochs {
+        let (acc, loss): (Vec<f64>, Vec<f64>) = loader
+            .batches()
+            .map(|batch| {
+                let (xs, ys): (Vec<Vec<f64>>, Vec<usize>) =
+                    batch.into_iter().map(|(x, y)| (x, y as usize)).unzip();
+                let y_preds = mlp.forward_batch(&xs);
 
                 mlp.zero_grads();
-                mlp.backward(grads);
+                let loss = mlp.backward_cross_entropy_batch(&ys);
                 mlp.update_weights(optimiser);
 
-                let acc = if Util::argmax(&y_preds) == *y as usize {
-                    1.0
-                } else {
-                    0.0
-                };
-
-                let loss = -softmax
+                let acc = y_preds
                     .iter()
-                    .enumerate()
-                    .map(|(i, sm)| {
-                        let y = if (*y as usize) == i { 1. } else { 0. };
-                        y * sm.log10()
-                    })
-                    .sum::<f64>();
+                    .zip(ys.iter())
+                    .filter(|(y_pred, y)| Util::argmax(y_pred) == **y)
+                    .count() as f64
+                    / ys.len() as f64;
 
                 (acc, loss)
             })
             .unzip();
+        optimiser.step();
 
         if i % 10 == 0 {
             println!(
@@ -1,204 +1,6685 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    fs,
+    ops::{Deref, DerefMut},
+    path::Path,
+    rc::Rc,
+};
 
-use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use num::traits::Pow;
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
 
 use crate::{
-    engine::{GraphBuilder, IdGenerator, NodeId, RunnableGraph},
-    optimiser::Optimiser,
+    engine::{GraphBuilder, IdGenerator, NodeId, Operation, RunnableGraph},
+    io, loss,
+    optimiser::{ClosureOptimiser, GradientFreeOptimiser, Optimiser},
+    quantise::{QuantisedLayer, QuantisedLinear, QuantisedModel},
+    scheduler::LrRange,
+    tensor::{RunnableTensorGraph, Tensor, TensorGraphBuilder},
+    util::Util,
 };
 
-pub struct Neuron<'a> {
-    op: GraphBuilder<'a>,
+/// Non-linearity applied to the output of a layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Relu,
+    Tanh,
+    Sigmoid,
+    /// Relu letting through negative inputs scaled by the given slope.
+    LeakyRelu(f64),
+    None,
 }
 
-impl<'a> Neuron<'a> {
-    fn new(inputs: Vec<GraphBuilder<'a>>, non_linearity: bool, seed: Option<u64>) -> Neuron<'a> {
-        let mut rng = seed
-            .map(StdRng::seed_from_u64)
-            .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap());
+impl Activation {
+    /// Scalar operation computing the activation and its left operand, e.g. the slope of a
+    /// leaky relu, or `None` for the identity.
+    fn operation(&self) -> Option<(Operation, f64)> {
+        match self {
+            Activation::Relu => Some((Operation::Relu, 0.)),
+            Activation::Tanh => Some((Operation::Tanh, 0.)),
+            Activation::Sigmoid => Some((Operation::Sigmoid, 0.)),
+            Activation::LeakyRelu(slope) => Some((Operation::LeakyRelu, *slope)),
+            Activation::None => None,
+        }
+    }
 
-        let weights: Vec<GraphBuilder> =
-            inputs.iter().map(|i| rng.gen_range(-1.0..1.) * i).collect();
+    fn apply<'a>(&self, x: GraphBuilder<'a>) -> GraphBuilder<'a> {
+        match self.operation() {
+            Some((op, left)) => GraphBuilder::with_immediate(op, left, x),
+            None => x,
+        }
+    }
 
-        let mut first = weights[0].clone();
-        let tail = &weights[1..];
+    fn apply_tensor<'a>(&self, x: TensorGraphBuilder<'a>) -> TensorGraphBuilder<'a> {
+        match self {
+            Activation::Relu => x.relu(),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => x.sigmoid(),
+            Activation::LeakyRelu(slope) => x.leaky_relu(*slope),
+            Activation::None => x,
+        }
+    }
 
-        for g in tail {
-            first = first + g.clone();
+    /// Value of the activation at `x`, computed like the node built by `apply`.
+    pub(crate) fn apply_value(&self, x: f64) -> f64 {
+        match self.operation() {
+            Some((op, left)) => op.apply(left, x),
+            None => x,
         }
+    }
+}
 
-        let output_value = first + rng.gen_range(-1.0..1.);
-        let output_value = if non_linearity {
-            output_value.relu()
-        } else {
-            output_value
-        };
+/// Weight initialisation scheme, scaled by the fan-in/fan-out of the layer where relevant.
+#[derive(Debug, Clone, Copy)]
+pub enum Init {
+    /// U(-b, b) with b = sqrt(6 / (fan_in + fan_out)), suited to tanh/sigmoid layers.
+    XavierUniform,
+    /// N(0, 2 / fan_in), suited to relu layers.
+    KaimingNormal,
+    Uniform(f64, f64),
+    Normal(f64),
+    Zeros,
+}
 
-        Neuron { op: output_value }
+impl Init {
+    fn sample(&self, fan_in: usize, fan_out: usize, rng: &mut impl Rng) -> f64 {
+        match self {
+            Init::XavierUniform => {
+                let bound = (6. / (fan_in + fan_out) as f64).sqrt();
+                rng.gen_range(-bound..bound)
+            }
+            Init::KaimingNormal => (2. / fan_in as f64).sqrt() * Util::standard_normal(rng),
+            Init::Uniform(low, high) => rng.gen_range(*low..*high),
+            Init::Normal(std) => std * Util::standard_normal(rng),
+            Init::Zeros => 0.,
+        }
     }
 }
 
+/// Initial values of a layer's trainable parameters and, once the layer has been built, their
+/// node ids. Building the layer again reuses the same nodes, which is how weights are shared
+/// within a graph.
 #[derive(Debug)]
-pub struct MultiLayerPerceptron {
-    inputs: Vec<NodeId>,
-    outputs: Vec<NodeId>,
-    graph: RunnableGraph,
+pub struct Parameters {
+    values: Vec<f64>,
+    ids: RefCell<Vec<NodeId>>,
+    kind: ParameterKind,
 }
 
-impl MultiLayerPerceptron {
-    pub fn new(sizes: Vec<usize>, seed: Option<u64>) -> MultiLayerPerceptron {
-        let ids = &mut IdGenerator::new();
-        let ids = Rc::new(RefCell::new(ids));
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParameterKind {
+    Dense,
+    Sparse,
+    Buffer,
+}
 
-        let graph = GraphBuilder::new(ids);
+impl Parameters {
+    pub fn new(values: Vec<f64>) -> Parameters {
+        Parameters {
+            values,
+            ids: RefCell::new(vec![]),
+            kind: ParameterKind::Dense,
+        }
+    }
 
-        let num_inputs = sizes[0];
-        let builders: Vec<GraphBuilder> = (0..num_inputs)
-            .map(|_| {
-                let (_, g) = graph.create_input();
-                g
+    /// Parameters that the optimiser only updates on steps where they received a gradient, e.g.
+    /// embedding rows.
+    pub fn sparse(values: Vec<f64>) -> Parameters {
+        Parameters {
+            kind: ParameterKind::Sparse,
+            ..Self::new(values)
+        }
+    }
+
+    /// Non-trainable state such as running statistics, built as immediates which the optimiser
+    /// leaves alone but `RunnableGraph::set_immediate` can update.
+    pub fn buffer(values: Vec<f64>) -> Parameters {
+        Parameters {
+            kind: ParameterKind::Buffer,
+            ..Self::new(values)
+        }
+    }
+
+    /// Builders for the parameters, creating their nodes on first use.
+    pub fn build<'a>(&self, graph: &GraphBuilder<'a>) -> Vec<GraphBuilder<'a>> {
+        let mut ids = self.ids.borrow_mut();
+        if ids.is_empty() {
+            let (new_ids, builders) = self
+                .values
+                .iter()
+                .map(|v| match self.kind {
+                    ParameterKind::Dense => graph.create_parameter(*v),
+                    ParameterKind::Sparse => graph.create_sparse_parameter(*v),
+                    ParameterKind::Buffer => graph.create_immediate(*v),
+                })
+                .unzip();
+            *ids = new_ids;
+            return builders;
+        }
+
+        ids.iter()
+            .zip(self.values.iter())
+            .map(|(id, v)| match self.kind {
+                ParameterKind::Dense => graph.parameter(*id, *v),
+                ParameterKind::Sparse => graph.sparse_parameter(*id, *v),
+                ParameterKind::Buffer => graph.immediate(*id, *v),
             })
-            .collect();
+            .collect()
+    }
 
-        let outputs = sizes
-            .iter()
-            .enumerate()
-            .skip(1)
-            .fold(builders.clone(), |b, (i, s)| {
-                let non_linearity = i != sizes.len() - 1;
-                (0..*s)
-                    .map(|_| Neuron::new(b.clone(), non_linearity, seed).op)
-                    .collect()
-            });
+    /// Node ids of the parameters, empty until they have been built.
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.ids.borrow().clone()
+    }
 
-        MultiLayerPerceptron {
-            inputs: builders.iter().map(|i| i.root).collect(),
-            outputs: outputs.iter().map(|o| o.root).collect(),
-            graph: RunnableGraph::new(outputs.iter().collect()),
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Lets layers bind their parameters and state to tensors while building their batched tensor
+/// graph, and records which scalar nodes each tensor mirrors so that new values can be fed in and
+/// gradients carried back.
+#[derive(Debug)]
+pub struct BatchContext<'g> {
+    graph: &'g RunnableGraph,
+    bindings: Vec<Binding>,
+}
+
+impl<'g> BatchContext<'g> {
+    pub fn is_training(&self) -> bool {
+        self.graph.is_training()
+    }
+
+    /// Parameter tensor of `shape` mirroring the parameters `ids`, in row-major order.
+    pub fn parameter<'a>(
+        &mut self,
+        builder: &TensorGraphBuilder<'a>,
+        shape: Vec<usize>,
+        ids: Vec<NodeId>,
+    ) -> TensorGraphBuilder<'a> {
+        let tensor = builder.parameter(self.values(shape, &ids));
+        self.bind(tensor, ids, true)
+    }
+
+    /// Constant tensor of `shape` mirroring non-trainable state `ids`, e.g. running statistics.
+    pub fn buffer<'a>(
+        &mut self,
+        builder: &TensorGraphBuilder<'a>,
+        shape: Vec<usize>,
+        ids: Vec<NodeId>,
+    ) -> TensorGraphBuilder<'a> {
+        let tensor = builder.immediate(self.values(shape, &ids));
+        self.bind(tensor, ids, false)
+    }
+
+    fn values(&self, shape: Vec<usize>, ids: &[NodeId]) -> Tensor {
+        Tensor::new(
+            shape,
+            ids.iter().map(|id| self.graph.value_for_id(*id)).collect(),
+        )
+    }
+
+    fn bind<'a>(
+        &mut self,
+        tensor: TensorGraphBuilder<'a>,
+        ids: Vec<NodeId>,
+        trainable: bool,
+    ) -> TensorGraphBuilder<'a> {
+        self.bindings.push(Binding {
+            tensor: tensor.root,
+            ids,
+            trainable,
+        });
+        tensor
+    }
+}
+
+/// Named and shaped view of some of a layer's state, laid out like its PyTorch counterpart so
+/// that weights can be exchanged with it, e.g. `weight` of shape `[fan_out, fan_in]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateTensor {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub ids: Vec<NodeId>,
+}
+
+impl StateTensor {
+    fn new(name: &str, shape: Vec<usize>, ids: Vec<NodeId>) -> StateTensor {
+        StateTensor {
+            name: name.to_string(),
+            shape,
+            ids,
         }
     }
 
-    pub fn forward(&mut self, inputs: &Vec<f64>) -> Vec<f64> {
-        if inputs.len() != self.inputs.len() {
+    fn prefixed(prefix: &str, state: Vec<StateTensor>) -> Vec<StateTensor> {
+        state
+            .into_iter()
+            .map(|t| StateTensor {
+                name: format!("{prefix}.{}", t.name),
+                ..t
+            })
+            .collect()
+    }
+}
+
+/// Building block of a network, wired into the scalar graph one sample at a time and optionally
+/// into a tensor graph for whole mini-batches.
+pub trait Layer: Debug {
+    /// Name shown by `Sequential::summary`, the type name by default.
+    fn name(&self) -> String {
+        short_type_name(std::any::type_name::<Self>())
+    }
+
+    /// Wires the layer onto `inputs` and returns its outputs.
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>>;
+
+    /// Batched counterpart of `build`, mapping a `[batch, features]` tensor to another one.
+    fn build_batch<'a>(
+        &self,
+        _input: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        panic!(
+            "{} does not support batched evaluation",
+            std::any::type_name::<Self>()
+        )
+    }
+
+    /// Int8 counterpart of the layer for `Sequential::quantise`, given the graph the layer was
+    /// built into.
+    fn quantise(&self, _graph: &RunnableGraph) -> QuantisedLayer {
+        panic!(
+            "{} does not support quantisation",
+            std::any::type_name::<Self>()
+        )
+    }
+
+    /// Shape of the outputs given the shape of the inputs, which are flattened row-major when
+    /// handed to `build`. Most layers work elementwise and keep the shape unchanged.
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+
+    /// Ids of the layer's trainable parameters, empty until it has been built.
+    fn parameters(&self) -> Vec<NodeId> {
+        vec![]
+    }
+
+    /// Weights feeding each unit of the built layer, e.g. each neuron or output channel, which
+    /// `Sequential::set_max_norm` constrains. Empty for layers that don't support it.
+    fn incoming_weights(&self) -> Vec<Vec<NodeId>> {
+        vec![]
+    }
+
+    /// Parameters and buffers of the built layer as named tensors, by default all parameters
+    /// as a single flat `parameters` tensor.
+    fn state(&self) -> Vec<StateTensor> {
+        let ids = self.parameters();
+        match ids.is_empty() {
+            true => vec![],
+            false => vec![StateTensor::new("parameters", vec![ids.len()], ids)],
+        }
+    }
+
+    /// Called once `forward_batch` has evaluated the graph from `build_batch`, e.g. to fold batch
+    /// statistics into state kept in the scalar graph.
+    fn after_batch(&self, _batch: &RunnableTensorGraph, _graph: &mut RunnableGraph) {}
+}
+
+/// Strips the module paths from a type name, e.g. `Residual<Linear>`.
+fn short_type_name(name: &str) -> String {
+    let mut short = String::new();
+    let mut path = String::new();
+    for c in name.chars().chain(std::iter::once('\0')) {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            path.push(c);
+        } else {
+            short.push_str(path.rsplit("::").next().unwrap());
+            path.clear();
+            if c != '\0' {
+                short.push(c);
+            }
+        }
+    }
+    short
+}
+
+impl Layer for Activation {
+    fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        inputs.into_iter().map(|x| self.apply(x)).collect()
+    }
+
+    fn quantise(&self, _graph: &RunnableGraph) -> QuantisedLayer {
+        QuantisedLayer::Activation(*self)
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        self.apply_tensor(input)
+    }
+}
+
+/// Single timestep of a recurrent network, unrolled over a sequence by `Recurrent`.
+pub trait RecurrentCell: Debug {
+    fn input_size(&self) -> usize;
+
+    fn hidden_size(&self) -> usize;
+
+    /// Number of values in the state, just the hidden state by default.
+    fn state_size(&self) -> usize {
+        self.hidden_size()
+    }
+
+    /// State before the first timestep, starting with the `hidden_size` values of the output.
+    fn initial_state<'a>(&self, graph: &GraphBuilder<'a>) -> Vec<GraphBuilder<'a>>;
+
+    /// Consumes one timestep of `input` and returns the next state.
+    fn step<'a>(
+        &self,
+        input: Vec<GraphBuilder<'a>>,
+        state: Vec<GraphBuilder<'a>>,
+    ) -> Vec<GraphBuilder<'a>>;
+
+    /// Batched counterpart of `step`, mapping `[batch, input_size]` inputs and a `[batch, state]`
+    /// state laid out like `initial_state` to the next state.
+    fn step_batch<'a>(
+        &self,
+        _input: TensorGraphBuilder<'a>,
+        _state: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        panic!(
+            "{} does not support batched evaluation",
+            std::any::type_name::<Self>()
+        )
+    }
+
+    fn parameters(&self) -> Vec<NodeId>;
+}
+
+/// Unrolls a recurrent cell over a `[timesteps, input_size]` sequence, with the cell's parameters
+/// shared by every timestep. Outputs the hidden state of every timestep, or only of the last one.
+#[derive(Debug)]
+pub struct Recurrent<C: RecurrentCell> {
+    cell: C,
+    return_sequences: bool,
+}
+
+impl<C: RecurrentCell> Recurrent<C> {
+    pub fn new(cell: C, return_sequences: bool) -> Recurrent<C> {
+        Recurrent {
+            cell,
+            return_sequences,
+        }
+    }
+}
+
+impl<C: RecurrentCell> Layer for Recurrent<C> {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let input_size = self.cell.input_size();
+        if !inputs.len().is_multiple_of(input_size) {
             panic!(
-                "Expected {} inputs, but got {}",
-                self.inputs.len(),
+                "Expected a multiple of {} inputs, but got {}",
+                input_size,
                 inputs.len()
             )
         }
-        self.inputs
-            .iter()
-            .zip(inputs.iter())
-            .for_each(|(input, value)| self.graph.set_input(*input, *value));
 
-        self.graph.evaluate(&self.outputs)
+        let hidden_size = self.cell.hidden_size();
+        let mut state = self.cell.initial_state(&inputs[0]);
+        let mut outputs = vec![];
+        for x in inputs.chunks(input_size) {
+            state = self.cell.step(x.to_vec(), state);
+            if self.return_sequences {
+                outputs.extend(state[..hidden_size].iter().cloned());
+            }
+        }
+
+        if !self.return_sequences {
+            outputs.extend(state[..hidden_size].iter().cloned());
+        }
+        outputs
     }
 
-    pub fn backward(&mut self, out_grads: Vec<f64>) {
-        let pairs: Vec<(NodeId, f64)> = self
-            .outputs
-            .clone()
-            .into_iter()
-            .zip(out_grads.into_iter())
-            .collect();
-        self.graph.backwards(pairs);
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let (input_size, hidden_size) = (self.cell.input_size(), self.cell.hidden_size());
+        let (batch, n) = (input.shape()[0], input.shape()[1]);
+        if !n.is_multiple_of(input_size) {
+            panic!(
+                "Expected a multiple of {} inputs, but got {}",
+                input_size, n
+            )
+        }
+
+        let mut state = input.immediate(Tensor::zeros(vec![batch, self.cell.state_size()]));
+        let mut outputs = vec![];
+        for t in 0..n / input_size {
+            let x = input.slice(1, t * input_size, (t + 1) * input_size);
+            state = self.cell.step_batch(x, state, context);
+            if self.return_sequences {
+                outputs.push(state.slice(1, 0, hidden_size));
+            }
+        }
+
+        if !self.return_sequences {
+            outputs.push(state.slice(1, 0, hidden_size));
+        }
+        TensorGraphBuilder::concat(&outputs.iter().collect::<Vec<_>>(), 1)
     }
 
-    pub fn zero_grads(&mut self) {
-        self.graph.zero_grads();
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        match input_shape {
+            [timesteps, n] if *n == self.cell.input_size() => {
+                if self.return_sequences {
+                    vec![*timesteps, self.cell.hidden_size()]
+                } else {
+                    vec![self.cell.hidden_size()]
+                }
+            }
+            _ => panic!(
+                "Expected input shape [timesteps, {}], but got {:?}",
+                self.cell.input_size(),
+                input_shape
+            ),
+        }
     }
 
-    pub fn update_weights(&mut self, optimiser: &mut impl Optimiser) {
-        self.graph.update_weights(optimiser);
+    fn parameters(&self) -> Vec<NodeId> {
+        self.cell.parameters()
     }
+}
 
-    pub fn num_parameters(&self) -> usize {
-        self.graph.num_parameters()
+/// Long short-term memory cell, whose state holds the hidden state followed by the cell state.
+#[derive(Debug)]
+pub struct LstmCell {
+    input_size: usize,
+    hidden_size: usize,
+    input_gate: Linear,
+    forget_gate: Linear,
+    candidate: Linear,
+    output_gate: Linear,
+}
+
+impl LstmCell {
+    /// Gates act on the concatenated input and hidden state, with Xavier weights and zero biases.
+    pub fn new(input_size: usize, hidden_size: usize, rng: &mut impl Rng) -> LstmCell {
+        let mut gate = |activation| {
+            Linear::builder(input_size + hidden_size, hidden_size)
+                .activation(activation)
+                .init(Init::XavierUniform, Some(Init::Zeros))
+                .build(rng)
+        };
+
+        LstmCell {
+            input_size,
+            hidden_size,
+            input_gate: gate(Activation::Sigmoid),
+            forget_gate: gate(Activation::Sigmoid),
+            candidate: gate(Activation::Tanh),
+            output_gate: gate(Activation::Sigmoid),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
+impl RecurrentCell for LstmCell {
+    fn input_size(&self) -> usize {
+        self.input_size
+    }
 
-    use rand::{seq::SliceRandom, thread_rng};
+    fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
 
-    use crate::{
-        nn::*,
-        optimiser::LearningRateOptimiser,
-        util::{Mean, Util},
-    };
+    fn state_size(&self) -> usize {
+        2 * self.hidden_size
+    }
 
-    #[test]
-    fn test_mlp_xor() {
-        let xy = &vec![
-            (vec![1., 0.], vec![0., 1.]),
-            (vec![0., 1.], vec![0., 1.]),
-            (vec![1., 1.], vec![1., 0.]),
-            (vec![0., 0.], vec![1., 0.]),
-        ];
+    fn initial_state<'a>(&self, graph: &GraphBuilder<'a>) -> Vec<GraphBuilder<'a>> {
+        (0..2 * self.hidden_size)
+            .map(|_| graph.create_immediate(0.).1)
+            .collect()
+    }
 
-        let mut mlp =
-            MultiLayerPerceptron::new(Vec::from([xy[0].0.len(), 2, xy[0].1.len()]), Some(4));
+    fn step<'a>(
+        &self,
+        input: Vec<GraphBuilder<'a>>,
+        state: Vec<GraphBuilder<'a>>,
+    ) -> Vec<GraphBuilder<'a>> {
+        let (h, c) = state.split_at(self.hidden_size);
+        let xh: Vec<GraphBuilder> = input.into_iter().chain(h.iter().cloned()).collect();
 
-        let optimiser = &mut LearningRateOptimiser::new(0.1);
+        let i = self.input_gate.build(xh.clone());
+        let f = self.forget_gate.build(xh.clone());
+        let g = self.candidate.build(xh.clone());
+        let o = self.output_gate.build(xh);
 
-        let epochs = 1000;
-        for i in 0..epochs {
-            let mut xy = xy.clone();
-            xy.shuffle(&mut thread_rng());
+        let c: Vec<GraphBuilder> = (0..self.hidden_size)
+            .map(|k| &f[k] * &c[k] + &(&i[k] * &g[k]))
+            .collect();
+        let h: Vec<GraphBuilder> = (0..self.hidden_size)
+            .map(|k| &o[k] * &c[k].clone().tanh())
+            .collect();
 
-            let (acc, loss): (Vec<f64>, Vec<f64>) = xy
-                .iter()
-                .map(|(x, y)| {
-                    let y_preds = mlp.forward(x);
+        h.into_iter().chain(c).collect()
+    }
 
-                    let loss = y
-                        .iter()
-                        .zip(y_preds.iter())
-                        .map(|(y, y_pred)| (y_pred - y).powf(2.))
-                        .sum::<f64>();
+    fn step_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        state: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let (h, c) = (
+            state.slice(1, 0, self.hidden_size),
+            state.slice(1, self.hidden_size, 2 * self.hidden_size),
+        );
+        let xh = TensorGraphBuilder::concat(&[&input, &h], 1);
 
-                    let grads: Vec<f64> = y
-                        .iter()
-                        .zip(y_preds.iter())
-                        .map(|(y, y_pred)| (y_pred - y))
-                        .collect();
+        let i = self.input_gate.build_batch(xh.clone(), context);
+        let f = self.forget_gate.build_batch(xh.clone(), context);
+        let g = self.candidate.build_batch(xh.clone(), context);
+        let o = self.output_gate.build_batch(xh, context);
 
-                    mlp.zero_grads();
-                    mlp.backward(grads);
-                    mlp.update_weights(optimiser);
+        let c = &(&f * &c) + &(&i * &g);
+        let h = &o * &c.tanh();
+        TensorGraphBuilder::concat(&[&h, &c], 1)
+    }
 
-                    let acc = if Util::argmax(&y_preds) == Util::argmax(y) {
-                        1.0
-                    } else {
-                        0.0
-                    };
+    fn parameters(&self) -> Vec<NodeId> {
+        [
+            &self.input_gate,
+            &self.forget_gate,
+            &self.candidate,
+            &self.output_gate,
+        ]
+        .iter()
+        .flat_map(|gate| gate.parameters())
+        .collect()
+    }
+}
 
-                    (acc, loss)
-                })
-                .unzip();
+/// Gated recurrent unit, a lighter alternative to `LstmCell` whose state is just the hidden
+/// state.
+#[derive(Debug)]
+pub struct GruCell {
+    input_size: usize,
+    hidden_size: usize,
+    update_gate: Linear,
+    reset_gate: Linear,
+    candidate: Linear,
+}
 
-            if i % 100 == 0 {
-                println!(
-                    "Epoch {i} - Acc={:?}, Loss={:?}",
-                    acc.iter().mean(),
-                    loss.iter().mean()
-                );
-            }
+impl GruCell {
+    /// Gates act on the concatenated input and hidden state, with Xavier weights and zero biases.
+    pub fn new(input_size: usize, hidden_size: usize, rng: &mut impl Rng) -> GruCell {
+        let mut gate = |activation| {
+            Linear::builder(input_size + hidden_size, hidden_size)
+                .activation(activation)
+                .init(Init::XavierUniform, Some(Init::Zeros))
+                .build(rng)
+        };
+
+        GruCell {
+            input_size,
+            hidden_size,
+            update_gate: gate(Activation::Sigmoid),
+            reset_gate: gate(Activation::Sigmoid),
+            candidate: gate(Activation::Tanh),
         }
+    }
+}
 
-        let acc = xy
+impl RecurrentCell for GruCell {
+    fn input_size(&self) -> usize {
+        self.input_size
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    fn initial_state<'a>(&self, graph: &GraphBuilder<'a>) -> Vec<GraphBuilder<'a>> {
+        (0..self.hidden_size)
+            .map(|_| graph.create_immediate(0.).1)
+            .collect()
+    }
+
+    fn step<'a>(
+        &self,
+        input: Vec<GraphBuilder<'a>>,
+        state: Vec<GraphBuilder<'a>>,
+    ) -> Vec<GraphBuilder<'a>> {
+        let xh: Vec<GraphBuilder> = input.iter().chain(state.iter()).cloned().collect();
+        let z = self.update_gate.build(xh.clone());
+        let r = self.reset_gate.build(xh);
+
+        let reset: Vec<GraphBuilder> = input
+            .into_iter()
+            .chain(r.iter().zip(state.iter()).map(|(r, h)| r * h))
+            .collect();
+        let n = self.candidate.build(reset);
+
+        // h' = (1 - z) * n + z * h
+        (0..self.hidden_size)
+            .map(|k| n[k].clone() + &z[k] * &(state[k].clone() - n[k].clone()))
+            .collect()
+    }
+
+    fn step_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        state: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let xh = TensorGraphBuilder::concat(&[&input, &state], 1);
+        let z = self.update_gate.build_batch(xh.clone(), context);
+        let r = self.reset_gate.build_batch(xh, context);
+
+        let reset = TensorGraphBuilder::concat(&[&input, &(&r * &state)], 1);
+        let n = self.candidate.build_batch(reset, context);
+
+        // h' = (1 - z) * n + z * h
+        &n + &(&z * &(&state - &n))
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        [&self.update_gate, &self.reset_gate, &self.candidate]
             .iter()
-            .map(|(x, y)| {
-                let y_preds = mlp.forward(x);
-                if Util::argmax(&y_preds) == Util::argmax(y) {
-                    1.0
-                } else {
-                    0.0
-                }
-            })
-            .mean();
+            .flat_map(|gate| gate.parameters())
+            .collect()
+    }
+}
 
-        assert_eq!(acc, 1.0)
+/// Single-head scaled dot-product self-attention over a `[timesteps, dim]` sequence, followed by
+/// an output projection. The query, key, value and output projections are shared by every
+/// position.
+#[derive(Debug)]
+pub struct SelfAttention {
+    dim: usize,
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    output: Linear,
+}
+
+impl SelfAttention {
+    /// Projections have Xavier weights and zero biases.
+    pub fn new(dim: usize, rng: &mut impl Rng) -> SelfAttention {
+        let mut projection = || {
+            Linear::builder(dim, dim)
+                .activation(Activation::None)
+                .init(Init::XavierUniform, Some(Init::Zeros))
+                .build(rng)
+        };
+
+        SelfAttention {
+            dim,
+            query: projection(),
+            key: projection(),
+            value: projection(),
+            output: projection(),
+        }
+    }
+}
+
+impl Layer for SelfAttention {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        if !inputs.len().is_multiple_of(self.dim) {
+            panic!(
+                "Expected a multiple of {} inputs, but got {}",
+                self.dim,
+                inputs.len()
+            )
+        }
+
+        let positions: Vec<Vec<GraphBuilder>> =
+            inputs.chunks(self.dim).map(|x| x.to_vec()).collect();
+        let project = |layer: &Linear| -> Vec<Vec<GraphBuilder<'a>>> {
+            positions.iter().map(|x| layer.build(x.clone())).collect()
+        };
+        let (queries, keys, values) = (
+            project(&self.query),
+            project(&self.key),
+            project(&self.value),
+        );
+
+        let dot = |a: &[GraphBuilder<'a>], b: &[GraphBuilder<'a>]| {
+            a.iter()
+                .zip(b.iter())
+                .skip(1)
+                .fold(&a[0] * &b[0], |sum, (a, b)| sum + &(a * b))
+        };
+        let scale = 1. / (self.dim as f64).sqrt();
+
+        queries
+            .iter()
+            .flat_map(|q| {
+                let scores: Vec<GraphBuilder> = keys.iter().map(|k| dot(q, k) * scale).collect();
+                let weights = GraphBuilder::softmax(&scores);
+
+                let context = (0..self.dim)
+                    .map(|d| {
+                        let column: Vec<GraphBuilder> =
+                            values.iter().map(|v| v[d].clone()).collect();
+                        dot(&weights, &column)
+                    })
+                    .collect();
+                self.output.build(context)
+            })
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let (batch, n) = (input.shape()[0], input.shape()[1]);
+        if !n.is_multiple_of(self.dim) {
+            panic!("Expected a multiple of {} inputs, but got {}", self.dim, n)
+        }
+
+        // Every position of every sample goes through the projections as a row of its own.
+        let timesteps = n / self.dim;
+        let positions = input.reshape(vec![batch * timesteps, self.dim]);
+        let mut project = |layer: &Linear| {
+            layer
+                .build_batch(positions.clone(), context)
+                .reshape(vec![batch, timesteps, self.dim])
+        };
+        let (queries, keys, values) = (
+            project(&self.query),
+            project(&self.key),
+            project(&self.value),
+        );
+
+        let scale = input.immediate(Tensor::scalar(1. / (self.dim as f64).sqrt()));
+        let scores = &queries.matmul(&keys.transpose(1, 2)) * &scale;
+        let exps = (&scores - &scores.max(2, true)).exp();
+        let weights = &exps / &exps.sum(2, true);
+
+        let attended = weights
+            .matmul(&values)
+            .reshape(vec![batch * timesteps, self.dim]);
+        self.output
+            .build_batch(attended, context)
+            .reshape(vec![batch, n])
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        match input_shape {
+            [_, dim] if *dim == self.dim => input_shape.to_vec(),
+            _ => panic!(
+                "Expected input shape [timesteps, {}], but got {:?}",
+                self.dim, input_shape
+            ),
+        }
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        [&self.query, &self.key, &self.value, &self.output]
+            .iter()
+            .flat_map(|projection| projection.parameters())
+            .collect()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        [
+            ("query", &self.query),
+            ("key", &self.key),
+            ("value", &self.value),
+            ("output", &self.output),
+        ]
+        .iter()
+        .flat_map(|(name, projection)| StateTensor::prefixed(name, projection.state()))
+        .collect()
+    }
+}
+
+/// Skip connection adding the inputs of the wrapped layer to its outputs, which must have the
+/// same size.
+#[derive(Debug)]
+pub struct Residual<L: Layer>(pub L);
+
+impl<L: Layer> Layer for Residual<L> {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let outputs = self.0.build(inputs.clone());
+        if outputs.len() != inputs.len() {
+            panic!(
+                "Expected the residual branch to give {} outputs, but got {}",
+                inputs.len(),
+                outputs.len()
+            )
+        }
+
+        inputs
+            .into_iter()
+            .zip(outputs)
+            .map(|(x, y)| x + y)
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        &input + &self.0.build_batch(input.clone(), context)
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let output_shape = self.0.output_shape(input_shape);
+        if output_shape != input_shape {
+            panic!(
+                "Expected the residual branch to keep shape {:?}, but got {:?}",
+                input_shape, output_shape
+            )
+        }
+        output_shape
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        self.0.parameters()
+    }
+
+    fn incoming_weights(&self) -> Vec<Vec<NodeId>> {
+        self.0.incoming_weights()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        self.0.state()
+    }
+
+    fn after_batch(&self, batch: &RunnableTensorGraph, graph: &mut RunnableGraph) {
+        self.0.after_batch(batch, graph)
+    }
+}
+
+/// Turns its inputs into a probability distribution.
+#[derive(Debug, Clone, Copy)]
+pub struct Softmax;
+
+impl Layer for Softmax {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        GraphBuilder::softmax(&inputs)
+    }
+
+    fn quantise(&self, _graph: &RunnableGraph) -> QuantisedLayer {
+        QuantisedLayer::Softmax
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let exps = (&input - &input.max(1, true)).exp();
+        &exps / &exps.sum(1, true)
+    }
+}
+
+/// Zeroes each activation with probability `p` while training, scaling the others by
+/// `1 / (1 - p)`, and lets everything through in eval mode.
+#[derive(Debug, Clone, Copy)]
+pub struct Dropout(pub f64);
+
+impl Layer for Dropout {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        inputs.into_iter().map(|x| x.dropout(self.0)).collect()
+    }
+
+    fn quantise(&self, _graph: &RunnableGraph) -> QuantisedLayer {
+        QuantisedLayer::Identity
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        match context.is_training() {
+            true => input.dropout(self.0),
+            false => input,
+        }
+    }
+}
+
+/// Adds zero-mean Gaussian noise with standard deviation `self.0` to each activation while
+/// training, as a regulariser for small datasets, and lets everything through in eval mode.
+#[derive(Debug, Clone, Copy)]
+pub struct GaussianNoise(pub f64);
+
+impl Layer for GaussianNoise {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        if self.0 < 0. {
+            panic!(
+                "Noise standard deviation must be non-negative, but got {}",
+                self.0
+            )
+        }
+        inputs
+            .into_iter()
+            .map(|x| {
+                let (_, sigma) = x.create_immediate(self.0);
+                GraphBuilder::gaussian(x, sigma)
+            })
+            .collect()
+    }
+
+    fn quantise(&self, _graph: &RunnableGraph) -> QuantisedLayer {
+        QuantisedLayer::Identity
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        match context.is_training() {
+            true => TensorGraphBuilder::gaussian(&input, &input.immediate(Tensor::scalar(self.0))),
+            false => input,
+        }
+    }
+}
+
+/// 2D convolution over inputs laid out channel-major as `[channels, height, width]`, with every
+/// output position sharing the same kernel weights. Outputs use the same layout, with zero
+/// padding around the input.
+#[derive(Debug)]
+pub struct Conv2d {
+    input_shape: (usize, usize, usize),
+    out_channels: usize,
+    kernel_size: usize,
+    stride: usize,
+    padding: usize,
+    /// Row-major `[out_channels, in_channels, kernel_size, kernel_size]`.
+    weights: Parameters,
+    biases: Parameters,
+}
+
+impl Conv2d {
+    /// `input_shape` is `(channels, height, width)`. Weights are drawn from `Init::KaimingNormal`
+    /// and biases start at zero.
+    pub fn new(
+        input_shape: (usize, usize, usize),
+        out_channels: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+        rng: &mut impl Rng,
+    ) -> Conv2d {
+        let (in_channels, height, width) = input_shape;
+        if stride == 0 {
+            panic!("Expected a stride of at least 1")
+        }
+        if kernel_size > height + 2 * padding || kernel_size > width + 2 * padding {
+            panic!(
+                "Kernel size {} does not fit {}x{} inputs with padding {}",
+                kernel_size, height, width, padding
+            )
+        }
+
+        let fan_in = in_channels * kernel_size * kernel_size;
+        let fan_out = out_channels * kernel_size * kernel_size;
+        let weights = (0..out_channels * fan_in)
+            .map(|_| Init::KaimingNormal.sample(fan_in, fan_out, rng))
+            .collect();
+
+        Conv2d {
+            input_shape,
+            out_channels,
+            kernel_size,
+            stride,
+            padding,
+            weights: Parameters::new(weights),
+            biases: Parameters::new(vec![0.; out_channels]),
+        }
+    }
+
+    /// `(channels, height, width)` of the output.
+    fn output_dims(&self) -> (usize, usize, usize) {
+        let (_, height, width) = self.input_shape;
+        let size = |n: usize| (n + 2 * self.padding - self.kernel_size) / self.stride + 1;
+        (self.out_channels, size(height), size(width))
+    }
+
+    /// Index into the inputs of kernel tap `(c, ky, kx)` at output position `(oy, ox)`, or `None`
+    /// if the tap falls in the zero padding.
+    fn input_index(
+        &self,
+        (oy, ox): (usize, usize),
+        (c, ky, kx): (usize, usize, usize),
+    ) -> Option<usize> {
+        let (_, height, width) = self.input_shape;
+        let y = (oy * self.stride + ky).checked_sub(self.padding)?;
+        let x = (ox * self.stride + kx).checked_sub(self.padding)?;
+        (y < height && x < width).then(|| (c * height + y) * width + x)
+    }
+}
+
+impl Layer for Conv2d {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let (in_channels, height, width) = self.input_shape;
+        if inputs.len() != in_channels * height * width {
+            panic!(
+                "Expected {} inputs, but got {}",
+                in_channels * height * width,
+                inputs.len()
+            )
+        }
+
+        let weights = self.weights.build(&inputs[0]);
+        let biases = self.biases.build(&inputs[0]);
+
+        let k = self.kernel_size;
+        let (_, out_height, out_width) = self.output_dims();
+        let mut outputs = vec![];
+        for (o, bias) in biases.iter().enumerate() {
+            for oy in 0..out_height {
+                for ox in 0..out_width {
+                    let mut sum = bias.clone();
+                    for c in 0..in_channels {
+                        for ky in 0..k {
+                            for kx in 0..k {
+                                // Kernel taps falling in the zero padding contribute nothing.
+                                if let Some(i) = self.input_index((oy, ox), (c, ky, kx)) {
+                                    let w = &weights[((o * in_channels + c) * k + ky) * k + kx];
+                                    sum = sum + w * &inputs[i];
+                                }
+                            }
+                        }
+                    }
+                    outputs.push(sum);
+                }
+            }
+        }
+        outputs
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let (in_channels, k) = (self.input_shape.0, self.kernel_size);
+        let (out_channels, out_height, out_width) = self.output_dims();
+        let (batch, positions, taps) = (
+            input.shape()[0],
+            out_height * out_width,
+            in_channels * k * k,
+        );
+
+        // Unfolds the patch under the kernel at every output position into a row, so that the
+        // whole convolution is a single matmul with the kernels.
+        let indices = (0..positions)
+            .flat_map(|p| (0..taps).map(move |t| (p, t)))
+            .map(|(p, t)| {
+                self.input_index(
+                    (p / out_width, p % out_width),
+                    (t / (k * k), t / k % k, t % k),
+                )
+            })
+            .collect();
+        let patches = input
+            .gather(1, indices)
+            .reshape(vec![batch * positions, taps]);
+
+        let w = context.parameter(&input, vec![out_channels, taps], self.weights.ids());
+        let b = context.parameter(&input, vec![out_channels], self.biases.ids());
+        (&patches.matmul(&w.transpose(0, 1)) + &b)
+            .reshape(vec![batch, positions, out_channels])
+            .transpose(1, 2)
+            .reshape(vec![batch, out_channels * positions])
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        check_image_shape(self.input_shape, input_shape);
+        let (channels, height, width) = self.output_dims();
+        vec![channels, height, width]
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        let mut ids = self.weights.ids();
+        ids.extend(self.biases.ids());
+        ids
+    }
+
+    fn incoming_weights(&self) -> Vec<Vec<NodeId>> {
+        let (in_channels, k) = (self.input_shape.0, self.kernel_size);
+        self.weights
+            .ids()
+            .chunks(in_channels * k * k)
+            .map(|kernel| kernel.to_vec())
+            .collect()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        let (in_channels, k) = (self.input_shape.0, self.kernel_size);
+        vec![
+            StateTensor::new(
+                "weight",
+                vec![self.out_channels, in_channels, k, k],
+                self.weights.ids(),
+            ),
+            StateTensor::new("bias", vec![self.out_channels], self.biases.ids()),
+        ]
+    }
+}
+
+fn check_image_shape(expected: (usize, usize, usize), input_shape: &[usize]) {
+    let (channels, height, width) = expected;
+    if input_shape != [channels, height, width] {
+        panic!(
+            "Expected input shape {:?}, but got {:?}",
+            [channels, height, width],
+            input_shape
+        )
+    }
+}
+
+/// Maps integer ids in `0..vocab` to learnt vectors of `dim` values, one output row per input
+/// id. Only the rows that were looked up receive gradients, and the optimiser leaves the other
+/// rows untouched.
+#[derive(Debug)]
+pub struct Embedding {
+    vocab: usize,
+    dim: usize,
+    /// Row-major `[vocab, dim]`.
+    weights: Parameters,
+}
+
+impl Embedding {
+    /// Rows are drawn from a standard normal.
+    pub fn new(vocab: usize, dim: usize, rng: &mut impl Rng) -> Embedding {
+        let weights = (0..vocab * dim)
+            .map(|_| Util::standard_normal(rng))
+            .collect();
+
+        Embedding {
+            vocab,
+            dim,
+            weights: Parameters::sparse(weights),
+        }
+    }
+}
+
+impl Layer for Embedding {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let weights = self.weights.build(&inputs[0]);
+
+        // Each output picks its entry out of a column of the table, so that a lookup only
+        // reads, and passes gradients to, the row it selects.
+        let columns: Vec<Vec<GraphBuilder>> = (0..self.dim)
+            .map(|d| weights.iter().skip(d).step_by(self.dim).cloned().collect())
+            .collect();
+        inputs
+            .into_iter()
+            .flat_map(|id| {
+                columns
+                    .iter()
+                    .map(|column| id.clone().gather(column))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let mut shape = input_shape.to_vec();
+        shape.push(self.dim);
+        shape
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        self.weights.ids()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        vec![StateTensor::new(
+            "weight",
+            vec![self.vocab, self.dim],
+            self.weights.ids(),
+        )]
+    }
+}
+
+/// Collapses `[channels, height, width]` (or any other) shaped inputs into a flat vector, e.g.
+/// between convolutions and dense layers. Inputs are already stored flattened, so this only
+/// changes the shape.
+#[derive(Debug, Clone, Copy)]
+pub struct Flatten;
+
+impl Layer for Flatten {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        inputs
+    }
+
+    fn quantise(&self, _graph: &RunnableGraph) -> QuantisedLayer {
+        QuantisedLayer::Identity
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        input
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![input_shape.iter().product()]
+    }
+}
+
+/// Input indices covered by each pooling window over a `[channels, height, width]` input, in
+/// the channel-major order of the outputs.
+fn pool_windows(
+    input_shape: (usize, usize, usize),
+    kernel_size: usize,
+    stride: usize,
+) -> Vec<Vec<usize>> {
+    let (channels, height, width) = input_shape;
+    let (_, out_height, out_width) = pool_output_shape(input_shape, kernel_size, stride);
+
+    (0..channels)
+        .flat_map(|c| (0..out_height).flat_map(move |oy| (0..out_width).map(move |ox| (c, oy, ox))))
+        .map(|(c, oy, ox)| {
+            (0..kernel_size)
+                .flat_map(|ky| (0..kernel_size).map(move |kx| (ky, kx)))
+                .map(|(ky, kx)| (c * height + oy * stride + ky) * width + ox * stride + kx)
+                .collect()
+        })
+        .collect()
+}
+
+/// Batched rows gathered into `[batch, windows, kernel_size^2]`, one window per output, ready
+/// to be reduced over the last axis.
+fn pool_batch_windows<'a>(
+    input: &TensorGraphBuilder<'a>,
+    input_shape: (usize, usize, usize),
+    kernel_size: usize,
+    stride: usize,
+) -> TensorGraphBuilder<'a> {
+    let windows = pool_windows(input_shape, kernel_size, stride);
+    let (batch, num_windows) = (input.shape()[0], windows.len());
+    let indices = windows.into_iter().flatten().map(Some).collect();
+    input
+        .gather(1, indices)
+        .reshape(vec![batch, num_windows, kernel_size * kernel_size])
+}
+
+fn pool_output_shape(
+    input_shape: (usize, usize, usize),
+    kernel_size: usize,
+    stride: usize,
+) -> (usize, usize, usize) {
+    let (channels, height, width) = input_shape;
+    if kernel_size == 0 {
+        panic!("Expected a kernel size of at least 1")
+    }
+    if stride == 0 {
+        panic!("Expected a stride of at least 1")
+    }
+    if kernel_size > height || kernel_size > width {
+        panic!(
+            "Kernel size {} does not fit {}x{} inputs",
+            kernel_size, height, width
+        )
+    }
+    let size = |n: usize| (n - kernel_size) / stride + 1;
+    (channels, size(height), size(width))
+}
+
+/// Takes the maximum of each window of a `[channels, height, width]` input, routing the
+/// gradient to the element that was picked.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxPool2d {
+    input_shape: (usize, usize, usize),
+    kernel_size: usize,
+    stride: usize,
+}
+
+impl MaxPool2d {
+    pub fn new(input_shape: (usize, usize, usize), kernel_size: usize, stride: usize) -> MaxPool2d {
+        pool_output_shape(input_shape, kernel_size, stride);
+        MaxPool2d {
+            input_shape,
+            kernel_size,
+            stride,
+        }
+    }
+}
+
+impl Layer for MaxPool2d {
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        check_image_shape(self.input_shape, input_shape);
+        let (channels, height, width) =
+            pool_output_shape(self.input_shape, self.kernel_size, self.stride);
+        vec![channels, height, width]
+    }
+
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let (channels, height, width) = self.input_shape;
+        if inputs.len() != channels * height * width {
+            panic!(
+                "Expected {} inputs, but got {}",
+                channels * height * width,
+                inputs.len()
+            )
+        }
+
+        pool_windows(self.input_shape, self.kernel_size, self.stride)
+            .iter()
+            .map(|window| {
+                window
+                    .iter()
+                    .skip(1)
+                    .fold(inputs[window[0]].clone(), |m, i| m.max(inputs[*i].clone()))
+            })
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        pool_batch_windows(&input, self.input_shape, self.kernel_size, self.stride).max(2, false)
+    }
+}
+
+/// Averages each window of a `[channels, height, width]` input, splitting the gradient evenly
+/// over the window.
+#[derive(Debug, Clone, Copy)]
+pub struct AvgPool2d {
+    input_shape: (usize, usize, usize),
+    kernel_size: usize,
+    stride: usize,
+}
+
+impl AvgPool2d {
+    pub fn new(input_shape: (usize, usize, usize), kernel_size: usize, stride: usize) -> AvgPool2d {
+        pool_output_shape(input_shape, kernel_size, stride);
+        AvgPool2d {
+            input_shape,
+            kernel_size,
+            stride,
+        }
+    }
+}
+
+impl Layer for AvgPool2d {
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        check_image_shape(self.input_shape, input_shape);
+        let (channels, height, width) =
+            pool_output_shape(self.input_shape, self.kernel_size, self.stride);
+        vec![channels, height, width]
+    }
+
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let (channels, height, width) = self.input_shape;
+        if inputs.len() != channels * height * width {
+            panic!(
+                "Expected {} inputs, but got {}",
+                channels * height * width,
+                inputs.len()
+            )
+        }
+
+        let n = (self.kernel_size * self.kernel_size) as f64;
+        pool_windows(self.input_shape, self.kernel_size, self.stride)
+            .iter()
+            .map(|window| {
+                let sum = window
+                    .iter()
+                    .skip(1)
+                    .fold(inputs[window[0]].clone(), |s, i| s + &inputs[*i]);
+                &sum / n
+            })
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        _context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        pool_batch_windows(&input, self.input_shape, self.kernel_size, self.stride).mean(2, false)
+    }
+}
+
+/// Normalises each feature over the mini-batch, then scales and shifts it by the learnt `gamma`
+/// and `beta`. Running estimates of the mean and variance are tracked while training through
+/// `forward_batch` and used in eval mode as well as by the per-sample `forward`.
+#[derive(Debug)]
+pub struct BatchNorm {
+    features: usize,
+    gamma: Parameters,
+    beta: Parameters,
+    running_mean: Parameters,
+    running_var: Parameters,
+    /// Tensor ids of the batch mean and variance in the batched graph, if it was built in
+    /// training mode, and the batch size.
+    batch_stats: RefCell<Option<(NodeId, NodeId, usize)>>,
+}
+
+impl BatchNorm {
+    const MOMENTUM: f64 = 0.1;
+    const EPSILON: f64 = 1e-5;
+
+    pub fn new(features: usize) -> BatchNorm {
+        BatchNorm {
+            features,
+            gamma: Parameters::new(vec![1.; features]),
+            beta: Parameters::new(vec![0.; features]),
+            running_mean: Parameters::buffer(vec![0.; features]),
+            running_var: Parameters::buffer(vec![1.; features]),
+            batch_stats: RefCell::new(None),
+        }
+    }
+}
+
+impl Layer for BatchNorm {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        if inputs.len() != self.features {
+            panic!(
+                "Expected {} inputs, but got {}",
+                self.features,
+                inputs.len()
+            )
+        }
+
+        let gamma = self.gamma.build(&inputs[0]);
+        let beta = self.beta.build(&inputs[0]);
+        let mean = self.running_mean.build(&inputs[0]);
+        let var = self.running_var.build(&inputs[0]);
+
+        (0..self.features)
+            .map(|i| {
+                let inv_std = (var[i].clone() + Self::EPSILON).pow(-0.5);
+                let normed = (inputs[i].clone() - mean[i].clone()) * inv_std;
+                beta[i].clone() + gamma[i].clone() * normed
+            })
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let (mean, var) = if context.is_training() {
+            let mean = input.mean(0, true);
+            let centered = &input - &mean;
+            let var = (&centered * &centered).mean(0, true);
+            *self.batch_stats.borrow_mut() = Some((mean.root, var.root, input.shape()[0]));
+            (mean, var)
+        } else {
+            *self.batch_stats.borrow_mut() = None;
+            let shape = vec![self.features];
+            (
+                context.buffer(&input, shape.clone(), self.running_mean.ids()),
+                context.buffer(&input, shape, self.running_var.ids()),
+            )
+        };
+
+        let gamma = context.parameter(&input, vec![self.features], self.gamma.ids());
+        let beta = context.parameter(&input, vec![self.features], self.beta.ids());
+
+        let std = (&var + &input.immediate(Tensor::scalar(Self::EPSILON))).pow(0.5);
+        let normed = &(&input - &mean) / &std;
+        &(&normed * &gamma) + &beta
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        let mut ids = self.gamma.ids();
+        ids.extend(self.beta.ids());
+        ids
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        let shape = vec![self.features];
+        vec![
+            StateTensor::new("weight", shape.clone(), self.gamma.ids()),
+            StateTensor::new("bias", shape.clone(), self.beta.ids()),
+            StateTensor::new("running_mean", shape.clone(), self.running_mean.ids()),
+            StateTensor::new("running_var", shape, self.running_var.ids()),
+        ]
+    }
+
+    fn after_batch(&self, batch: &RunnableTensorGraph, graph: &mut RunnableGraph) {
+        let Some((mean, var, n)) = *self.batch_stats.borrow() else {
+            return;
+        };
+
+        // The running variance tracks the unbiased estimate of the population variance.
+        let correction = if n > 1 { n as f64 / (n - 1) as f64 } else { 1. };
+        [
+            (&self.running_mean, batch.value(mean), 1.),
+            (&self.running_var, batch.value(var), correction),
+        ]
+        .iter()
+        .for_each(|(running, stats, scale)| {
+            running
+                .ids()
+                .iter()
+                .zip(stats.data().iter())
+                .for_each(|(id, v)| {
+                    let old = graph.value_for_id(*id);
+                    let new = (1. - Self::MOMENTUM) * old + Self::MOMENTUM * scale * v;
+                    graph.set_immediate(*id, new);
+                })
+        });
+    }
+}
+
+/// Normalises each sample over its features, then scales and shifts it by the learnt `gamma`
+/// and `beta`. The statistics are computed inside the graph, so it behaves the same with or
+/// without batching.
+#[derive(Debug)]
+pub struct LayerNorm {
+    features: usize,
+    gamma: Parameters,
+    beta: Parameters,
+}
+
+impl LayerNorm {
+    const EPSILON: f64 = 1e-5;
+
+    pub fn new(features: usize) -> LayerNorm {
+        LayerNorm {
+            features,
+            gamma: Parameters::new(vec![1.; features]),
+            beta: Parameters::new(vec![0.; features]),
+        }
+    }
+}
+
+impl Layer for LayerNorm {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        if inputs.len() != self.features {
+            panic!(
+                "Expected {} inputs, but got {}",
+                self.features,
+                inputs.len()
+            )
+        }
+
+        let gamma = self.gamma.build(&inputs[0]);
+        let beta = self.beta.build(&inputs[0]);
+
+        let n = self.features as f64;
+        let mean = &inputs
+            .iter()
+            .skip(1)
+            .fold(inputs[0].clone(), |sum, x| sum + x)
+            / n;
+        let centered: Vec<GraphBuilder> = inputs.into_iter().map(|x| x - mean.clone()).collect();
+        let var = &centered
+            .iter()
+            .skip(1)
+            .fold(&centered[0] * &centered[0], |sum, c| sum + &(c * c))
+            / n;
+        let inv_std = (var + Self::EPSILON).pow(-0.5);
+
+        centered
+            .into_iter()
+            .zip(gamma.into_iter().zip(beta))
+            .map(|(c, (g, b))| b + g * (c * &inv_std))
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        let gamma = context.parameter(&input, vec![self.features], self.gamma.ids());
+        let beta = context.parameter(&input, vec![self.features], self.beta.ids());
+
+        let centered = &input - &input.mean(1, true);
+        let var = (&centered * &centered).mean(1, true);
+        let std = (&var + &input.immediate(Tensor::scalar(Self::EPSILON))).pow(0.5);
+        &(&(&centered / &std) * &gamma) + &beta
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        let mut ids = self.gamma.ids();
+        ids.extend(self.beta.ids());
+        ids
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        vec![
+            StateTensor::new("weight", vec![self.features], self.gamma.ids()),
+            StateTensor::new("bias", vec![self.features], self.beta.ids()),
+        ]
+    }
+}
+
+/// Fully connected layer followed by an activation.
+#[derive(Debug)]
+pub struct Linear {
+    fan_in: usize,
+    fan_out: usize,
+    /// Row-major `[fan_out, fan_in]`, one row per neuron, or `[fan_in, fan_out]` if transposed.
+    /// Layers holding the same weights build them onto the same nodes.
+    weights: Rc<Parameters>,
+    transposed: bool,
+    biases: Option<Rc<Parameters>>,
+    activation: Activation,
+}
+
+impl Linear {
+    pub fn new(
+        fan_in: usize,
+        fan_out: usize,
+        activation: Activation,
+        rng: &mut impl Rng,
+    ) -> Linear {
+        Self::builder(fan_in, fan_out)
+            .activation(activation)
+            .build(rng)
+    }
+
+    /// Starts describing a layer with the same defaults as `new` and no activation.
+    pub fn builder(fan_in: usize, fan_out: usize) -> LinearBuilder {
+        let init = Init::Uniform(-1., 1.);
+        LinearBuilder {
+            fan_in,
+            fan_out,
+            activation: Activation::None,
+            weight_init: init,
+            bias_init: Some(init),
+        }
+    }
+
+    /// Layer with the given row-major `[fan_out, fan_in]` weights and biases.
+    fn from_values(
+        fan_in: usize,
+        fan_out: usize,
+        weights: Vec<f64>,
+        biases: Option<Vec<f64>>,
+        activation: Activation,
+    ) -> Linear {
+        Linear {
+            fan_in,
+            fan_out,
+            weights: Rc::new(Parameters::new(weights)),
+            transposed: false,
+            biases: biases.map(|b| Rc::new(Parameters::new(b))),
+            activation,
+        }
+    }
+
+    /// Layer of the same shape reusing this layer's weights and biases, so that both uses
+    /// accumulate their gradients into the same parameters.
+    pub fn shared(&self, activation: Activation) -> Linear {
+        Linear {
+            fan_in: self.fan_in,
+            fan_out: self.fan_out,
+            weights: self.weights.clone(),
+            transposed: self.transposed,
+            biases: self.biases.clone(),
+            activation,
+        }
+    }
+
+    /// Layer mapping this layer's outputs back to its inputs with the transpose of its weights,
+    /// e.g. a decoder tied to its encoder. It gets its own zero biases if this layer has biases.
+    pub fn tied(&self, activation: Activation) -> Linear {
+        Linear {
+            fan_in: self.fan_out,
+            fan_out: self.fan_in,
+            weights: self.weights.clone(),
+            transposed: !self.transposed,
+            biases: self
+                .biases
+                .as_ref()
+                .map(|_| Rc::new(Parameters::new(vec![0.; self.fan_in]))),
+            activation,
+        }
+    }
+
+    /// Index into the stored weights of the weight connecting input `i` to neuron `o`.
+    fn weight_index(&self, o: usize, i: usize) -> usize {
+        match self.transposed {
+            true => i * self.fan_out + o,
+            false => o * self.fan_in + i,
+        }
+    }
+}
+
+impl Layer for Linear {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        if inputs.len() != self.fan_in {
+            panic!("Expected {} inputs, but got {}", self.fan_in, inputs.len())
+        }
+
+        let weights = self.weights.build(&inputs[0]);
+        let biases = self.biases.as_ref().map(|b| b.build(&inputs[0]));
+
+        (0..self.fan_out)
+            .map(|o| {
+                let row: Vec<GraphBuilder> = (0..self.fan_in)
+                    .map(|i| weights[self.weight_index(o, i)].clone())
+                    .collect();
+                let sum = GraphBuilder::dot(&row, &inputs);
+
+                let sum = match &biases {
+                    Some(b) => b[o].clone() + sum,
+                    None => sum,
+                };
+                self.activation.apply(sum)
+            })
+            .collect()
+    }
+
+    fn build_batch<'a>(
+        &self,
+        input: TensorGraphBuilder<'a>,
+        context: &mut BatchContext,
+    ) -> TensorGraphBuilder<'a> {
+        // Stored as [fan_in, fan_out] so that the batch rows multiply straight through.
+        let ids = self.weights.ids();
+        let transposed = (0..self.fan_in)
+            .flat_map(|i| (0..self.fan_out).map(move |o| (o, i)))
+            .map(|(o, i)| ids[self.weight_index(o, i)])
+            .collect();
+        let w = context.parameter(&input, vec![self.fan_in, self.fan_out], transposed);
+        let h = input.matmul(&w);
+
+        match &self.biases {
+            Some(biases) => {
+                let b = context.parameter(&input, vec![self.fan_out], biases.ids());
+                self.activation.apply_tensor(&h + &b)
+            }
+            None => self.activation.apply_tensor(h),
+        }
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if input_shape != [self.fan_in] {
+            panic!(
+                "Expected input shape {:?}, but got {:?}",
+                [self.fan_in],
+                input_shape
+            )
+        }
+        vec![self.fan_out]
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        let mut ids = self.weights.ids();
+        if let Some(biases) = &self.biases {
+            ids.extend(biases.ids());
+        }
+        ids
+    }
+
+    fn incoming_weights(&self) -> Vec<Vec<NodeId>> {
+        let ids = self.weights.ids();
+        (0..self.fan_out)
+            .map(|o| {
+                (0..self.fan_in)
+                    .map(|i| ids[self.weight_index(o, i)])
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        let ids = self.weights.ids();
+        let weights = (0..self.fan_out)
+            .flat_map(|o| (0..self.fan_in).map(move |i| (o, i)))
+            .map(|(o, i)| ids[self.weight_index(o, i)])
+            .collect();
+        let mut state = vec![StateTensor::new(
+            "weight",
+            vec![self.fan_out, self.fan_in],
+            weights,
+        )];
+        if let Some(biases) = &self.biases {
+            state.push(StateTensor::new("bias", vec![self.fan_out], biases.ids()));
+        }
+        state
+    }
+
+    fn quantise(&self, graph: &RunnableGraph) -> QuantisedLayer {
+        let values: Vec<Vec<f64>> = self
+            .state()
+            .iter()
+            .map(|t| t.ids.iter().map(|id| graph.value_for_id(*id)).collect())
+            .collect();
+        QuantisedLayer::Linear(QuantisedLinear::new(
+            self.fan_in,
+            self.fan_out,
+            &values[0],
+            values.get(1).map(|b| &b[..]),
+            self.activation,
+        ))
+    }
+}
+
+/// Step-by-step construction of a `Linear` layer, e.g.
+/// `Linear::builder(3, 2).activation(Activation::Tanh).init(Init::XavierUniform, None).build(rng)`.
+#[derive(Debug, Clone, Copy)]
+pub struct LinearBuilder {
+    fan_in: usize,
+    fan_out: usize,
+    activation: Activation,
+    weight_init: Init,
+    bias_init: Option<Init>,
+}
+
+impl LinearBuilder {
+    pub fn activation(mut self, activation: Activation) -> LinearBuilder {
+        self.activation = activation;
+        self
+    }
+
+    /// Weight and bias initialisation, without biases if `bias` is `None`.
+    pub fn init(mut self, weights: Init, bias: Option<Init>) -> LinearBuilder {
+        self.weight_init = weights;
+        self.bias_init = bias;
+        self
+    }
+
+    pub fn build(self, rng: &mut impl Rng) -> Linear {
+        let (fan_in, fan_out) = (self.fan_in, self.fan_out);
+        let mut weights = vec![];
+        let mut biases = vec![];
+        for _ in 0..fan_out {
+            weights.extend((0..fan_in).map(|_| self.weight_init.sample(fan_in, fan_out, rng)));
+            if let Some(init) = self.bias_init {
+                biases.push(init.sample(fan_in, fan_out, rng));
+            }
+        }
+
+        Linear::from_values(
+            fan_in,
+            fan_out,
+            weights,
+            self.bias_init.map(|_| biases),
+            self.activation,
+        )
+    }
+}
+
+/// Fully connected layer whose units each output the maximum of `pieces` affine functions of
+/// the inputs, a learnt piecewise linear activation. Gradients only flow to the winning piece.
+#[derive(Debug)]
+pub struct Maxout {
+    fan_in: usize,
+    fan_out: usize,
+    pieces: usize,
+    /// Row-major `[fan_out, pieces, fan_in]`.
+    weights: Parameters,
+    biases: Parameters,
+}
+
+impl Maxout {
+    pub fn new(fan_in: usize, fan_out: usize, pieces: usize, rng: &mut impl Rng) -> Maxout {
+        if pieces == 0 {
+            panic!("Expected at least one piece")
+        }
+        let init = Init::Uniform(-1., 1.);
+        let rows = fan_out * pieces;
+        Maxout {
+            fan_in,
+            fan_out,
+            pieces,
+            weights: Parameters::new(
+                (0..rows * fan_in)
+                    .map(|_| init.sample(fan_in, fan_out, rng))
+                    .collect(),
+            ),
+            biases: Parameters::new(
+                (0..rows)
+                    .map(|_| init.sample(fan_in, fan_out, rng))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Layer for Maxout {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        if inputs.len() != self.fan_in {
+            panic!("Expected {} inputs, but got {}", self.fan_in, inputs.len())
+        }
+
+        let weights = self.weights.build(&inputs[0]);
+        let biases = self.biases.build(&inputs[0]);
+
+        let pieces: Vec<GraphBuilder> = weights
+            .chunks(self.fan_in)
+            .zip(biases)
+            .map(|(row, b)| {
+                row.iter()
+                    .zip(inputs.iter())
+                    .fold(b, |sum, (w, x)| sum + w.clone() * x)
+            })
+            .collect();
+        pieces
+            .chunks(self.pieces)
+            .map(|unit| {
+                unit.iter()
+                    .skip(1)
+                    .fold(unit[0].clone(), |m, z| m.max(z.clone()))
+            })
+            .collect()
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        if input_shape != [self.fan_in] {
+            panic!(
+                "Expected input shape {:?}, but got {:?}",
+                [self.fan_in],
+                input_shape
+            )
+        }
+        vec![self.fan_out]
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        let mut ids = self.weights.ids();
+        ids.extend(self.biases.ids());
+        ids
+    }
+
+    fn incoming_weights(&self) -> Vec<Vec<NodeId>> {
+        self.weights
+            .ids()
+            .chunks(self.fan_in)
+            .map(|row| row.to_vec())
+            .collect()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        vec![
+            StateTensor::new(
+                "weight",
+                vec![self.fan_out, self.pieces, self.fan_in],
+                self.weights.ids(),
+            ),
+            StateTensor::new("bias", vec![self.fan_out, self.pieces], self.biases.ids()),
+        ]
+    }
+}
+
+/// Optional parts of the graph built by `Sequential::build` on top of the stack of layers.
+#[derive(Debug, Clone, Default)]
+struct BuildOptions {
+    l2: Option<f64>,
+    loss: Option<GraphLoss>,
+    branches: Vec<usize>,
+}
+
+/// Loss of the outputs against targets, built into the graph by `SequentialBuilder::loss`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphLoss {
+    /// Mean squared error.
+    Mse,
+    /// Cross-entropy of the softmax of the outputs, i.e. logits, against class probabilities
+    /// such as a one-hot encoding of the class.
+    CrossEntropy,
+    /// Mean binary cross-entropy of the sigmoids of the outputs against targets in [0, 1].
+    BinaryCrossEntropy,
+    /// Mean Poisson negative log-likelihood of counts, with the outputs as log-rates.
+    PoissonNll,
+    /// Mean Gaussian negative log-likelihood, with the first half of the outputs as means and
+    /// the second half as log-variances, so there is one target per pair of outputs.
+    GaussianNll,
+}
+
+impl GraphLoss {
+    /// Number of targets for `num_outputs` outputs.
+    fn num_targets(&self, num_outputs: usize) -> usize {
+        match self {
+            GraphLoss::GaussianNll => {
+                if !num_outputs.is_multiple_of(2) {
+                    panic!(
+                        "Expected an even number of outputs, but got {}",
+                        num_outputs
+                    )
+                }
+                num_outputs / 2
+            }
+            _ => num_outputs,
+        }
+    }
+
+    fn build<'a>(
+        &self,
+        outputs: &[GraphBuilder<'a>],
+        targets: &[GraphBuilder<'a>],
+    ) -> GraphBuilder<'a> {
+        let n = outputs.len() as f64;
+        let pairs = outputs.iter().zip(targets.iter());
+        match self {
+            GraphLoss::Mse => {
+                let squares = pairs
+                    .map(|(y, t)| {
+                        let error = y.clone() - t.clone();
+                        error.clone() * &error
+                    })
+                    .collect();
+                sum_tree(squares) * (1. / n)
+            }
+            GraphLoss::CrossEntropy => {
+                // -sum(t * log softmax(z)) = sum(t * (logsumexp(z) - z)), shifted by the maximum.
+                let max = outputs
+                    .iter()
+                    .skip(1)
+                    .fold(outputs[0].clone(), |m, z| m.max(z.clone()));
+                let exps = outputs
+                    .iter()
+                    .map(|z| (z.clone() - max.clone()).exp())
+                    .collect();
+                let logsumexp = sum_tree(exps).ln() + max;
+                sum_tree(
+                    pairs
+                        .map(|(z, t)| t.clone() * (logsumexp.clone() - z.clone()))
+                        .collect(),
+                )
+            }
+            GraphLoss::BinaryCrossEntropy => {
+                // max(z, 0) - z * t + ln(1 + exp(-|z|)), which doesn't overflow for large logits.
+                let terms = pairs
+                    .map(|(z, t)| {
+                        let abs = z.clone().max(-z);
+                        z.clone().relu() - z.clone() * t + (1. + (-&abs).exp()).ln()
+                    })
+                    .collect();
+                sum_tree(terms) * (1. / n)
+            }
+            GraphLoss::PoissonNll => {
+                let terms = pairs
+                    .map(|(z, t)| z.clone().exp() - z.clone() * t)
+                    .collect();
+                sum_tree(terms) * (1. / n)
+            }
+            GraphLoss::GaussianNll => {
+                let (means, log_vars) = outputs.split_at(targets.len());
+                let terms = means
+                    .iter()
+                    .zip(log_vars)
+                    .zip(targets)
+                    .map(|((m, lv), t)| {
+                        let error = m.clone() - t.clone();
+                        lv.clone() + error.clone() * &error * (-lv).exp()
+                    })
+                    .collect();
+                sum_tree(terms) * (0.5 / targets.len() as f64)
+            }
+        }
+    }
+}
+
+/// `lambda * sum(w^2)` node built by `SequentialBuilder::l2`, along with the parameters it sums
+/// over so that `backward_batch` can add its gradient straight onto them.
+#[derive(Debug)]
+struct Penalty {
+    value: NodeId,
+    lambda: f64,
+    parameters: Vec<NodeId>,
+}
+
+/// Targets and loss node built by `SequentialBuilder::loss`.
+#[derive(Debug)]
+struct Objective {
+    targets: Vec<NodeId>,
+    loss: NodeId,
+}
+
+/// Extra path through the layers from a given index on, built into the same graph as the main
+/// one so that it shares its parameters, e.g. to run the decoder of an autoencoder on its own.
+/// It is fed by immediates rather than inputs, which leaves the inputs of the model unchanged.
+#[derive(Debug)]
+struct Branch {
+    inputs: Vec<NodeId>,
+    outputs: Vec<NodeId>,
+}
+
+/// Counts of values falling in `counts.len()` equal-width bins spanning `[min, max]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub min: f64,
+    pub max: f64,
+    pub counts: Vec<usize>,
+}
+
+impl Histogram {
+    pub fn new(values: &[f64], bins: usize) -> Histogram {
+        if bins == 0 {
+            panic!("Expected at least one bin")
+        }
+        let (min, max) = match values.is_empty() {
+            true => (0., 0.),
+            false => values
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+                    (lo.min(*v), hi.max(*v))
+                }),
+        };
+        let width = (max - min) / bins as f64;
+
+        let mut counts = vec![0; bins];
+        values.iter().for_each(|v| {
+            let bin = match width > 0. {
+                true => (((v - min) / width) as usize).min(bins - 1),
+                false => 0,
+            };
+            counts[bin] += 1;
+        });
+        Histogram { min, max, counts }
+    }
+
+    /// Lower and upper edges of bin `i`.
+    pub fn bin_edges(&self, i: usize) -> (f64, f64) {
+        let width = (self.max - self.min) / self.counts.len() as f64;
+        (
+            self.min + i as f64 * width,
+            self.min + (i + 1) as f64 * width,
+        )
+    }
+}
+
+/// Distributions of the weights and gradients of one layer, see `Sequential::histograms`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerHistograms {
+    pub layer: usize,
+    pub name: String,
+    pub weights: Histogram,
+    pub gradients: Histogram,
+}
+
+/// Sum of the terms as a balanced tree of additions, which keeps building the graph for a large
+/// number of terms cheap.
+fn sum_tree(mut terms: Vec<GraphBuilder>) -> GraphBuilder {
+    while terms.len() > 1 {
+        let mut pairs = terms.into_iter();
+        let mut sums = vec![];
+        while let Some(a) = pairs.next() {
+            sums.push(match pairs.next() {
+                Some(b) => a + b,
+                None => a,
+            });
+        }
+        terms = sums;
+    }
+    terms.pop().expect("Expected at least one term to sum")
+}
+
+/// Tensor of the batched graph mirroring nodes of the scalar graph, in row-major order.
+#[derive(Debug)]
+struct Binding {
+    tensor: NodeId,
+    ids: Vec<NodeId>,
+    /// Whether the tensor is a parameter, whose gradients are carried back to `ids`.
+    trainable: bool,
+}
+
+/// Tensor graph built by the first `forward_batch` for a batch size and mode, and kept around
+/// for the following ones and for `backward_batch`.
+#[derive(Debug)]
+struct BatchGraph {
+    graph: RunnableTensorGraph,
+    input: NodeId,
+    output: NodeId,
+    /// Output of each layer, e.g. the logits under a softmax head.
+    layer_outputs: Vec<NodeId>,
+    bindings: Vec<Binding>,
+    batch_size: usize,
+    training: bool,
+    /// `RunnableGraph::revision` when the bound tensors were last refreshed.
+    revision: u64,
+}
+
+impl BatchGraph {
+    /// Feeds the current values of the bound scalar nodes into their tensors if they changed.
+    fn refresh(&mut self, graph: &RunnableGraph) {
+        if self.revision == graph.revision() {
+            return;
+        }
+        self.bindings.iter().for_each(|binding| {
+            let shape = self.graph.value(binding.tensor).shape().to_vec();
+            let values = binding
+                .ids
+                .iter()
+                .map(|id| graph.value_for_id(*id))
+                .collect();
+            self.graph
+                .set_state(binding.tensor, Tensor::new(shape, values));
+        });
+        self.revision = graph.revision();
+    }
+}
+
+/// Stack of layers compiled into a single graph, each layer feeding the next.
+#[derive(Debug)]
+pub struct Sequential {
+    layers: Vec<Box<dyn Layer>>,
+    inputs: Vec<NodeId>,
+    /// Outputs of each layer, the last of which are the outputs of the model.
+    layer_outputs: Vec<Vec<NodeId>>,
+    outputs: Vec<NodeId>,
+    /// Added to the objective on every backward pass.
+    penalty: Option<Penalty>,
+    objective: Option<Objective>,
+    /// Maximum norm of the incoming weights of each unit, per layer, see `set_max_norm`.
+    max_norms: Vec<Option<f64>>,
+    branches: Vec<Branch>,
+    /// Options the graph was built with, to build it again around other layers.
+    options: BuildOptions,
+    graph: RunnableGraph,
+    batch: Option<BatchGraph>,
+    /// Seeds the batched graph, and draws the new neurons of `MultiLayerPerceptron::widen`.
+    rng: StdRng,
+}
+
+impl Sequential {
+    /// Starts a stack over inputs of `input_shape`, with weights drawn from a generator seeded
+    /// with `seed`, or from entropy if `None`.
+    pub fn builder(input_shape: Vec<usize>, seed: Option<u64>) -> SequentialBuilder {
+        SequentialBuilder {
+            shape: input_shape.clone(),
+            input_shape,
+            layers: vec![],
+            options: BuildOptions::default(),
+            rng: seed
+                .map(StdRng::seed_from_u64)
+                .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap()),
+        }
+    }
+
+    pub fn new(num_inputs: usize, layers: Vec<Box<dyn Layer>>) -> Sequential {
+        Self::build(num_inputs, layers, BuildOptions::default())
+    }
+
+    fn build(num_inputs: usize, layers: Vec<Box<dyn Layer>>, options: BuildOptions) -> Sequential {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = GraphBuilder::new(ids);
+        let builders: Vec<GraphBuilder> = (0..num_inputs)
+            .map(|_| {
+                let (_, g) = graph.create_input();
+                g
+            })
+            .collect();
+
+        let mut layer_outputs: Vec<Vec<NodeId>> = vec![];
+        let outputs = layers.iter().fold(builders.clone(), |b, layer| {
+            let outputs = layer.build(b);
+            layer_outputs.push(outputs.iter().map(|o| o.root).collect());
+            outputs
+        });
+
+        let branch_outputs: Vec<(Vec<GraphBuilder>, Vec<GraphBuilder>)> = options
+            .branches
+            .iter()
+            .map(|start| {
+                let size = match start {
+                    0 => num_inputs,
+                    _ => layer_outputs[start - 1].len(),
+                };
+                let inputs: Vec<GraphBuilder> =
+                    (0..size).map(|_| graph.create_immediate(0.).1).collect();
+                let outputs = layers[*start..]
+                    .iter()
+                    .fold(inputs.clone(), |b, layer| layer.build(b));
+                (inputs, outputs)
+            })
+            .collect();
+
+        let objective = options.loss.map(|loss| {
+            let targets: Vec<GraphBuilder> = (0..loss.num_targets(outputs.len()))
+                .map(|_| graph.create_immediate(0.).1)
+                .collect();
+            let value = loss.build(&outputs, &targets);
+            (targets, value)
+        });
+
+        // Sparse parameters, such as embedding rows, are left out, as decaying them on every step
+        // would shrink the rows that are rarely looked up the most.
+        let penalty = options.l2.map(|lambda| {
+            let mut parameters: Vec<NodeId> = layers.iter().flat_map(|l| l.parameters()).collect();
+            parameters.sort_by_key(|id| id.0);
+            parameters.dedup();
+            let weights: Vec<GraphBuilder> = parameters
+                .iter()
+                .filter_map(|id| outputs.iter().find_map(|o| o.leaf(*id)))
+                .filter(|w| !w.is_sparse_parameter())
+                .collect();
+            let parameters: Vec<NodeId> = weights.iter().map(|w| w.root).collect();
+            let squares = weights.into_iter().map(|w| w.clone() * &w).collect();
+            (lambda * sum_tree(squares), lambda, parameters)
+        });
+
+        Sequential {
+            inputs: builders.iter().map(|i| i.root).collect(),
+            layer_outputs,
+            outputs: outputs.iter().map(|o| o.root).collect(),
+            penalty: penalty.as_ref().map(|(value, lambda, parameters)| Penalty {
+                value: value.root,
+                lambda: *lambda,
+                parameters: parameters.clone(),
+            }),
+            objective: objective.as_ref().map(|(targets, loss)| Objective {
+                targets: targets.iter().map(|t| t.root).collect(),
+                loss: loss.root,
+            }),
+            max_norms: vec![None; layers.len()],
+            branches: branch_outputs
+                .iter()
+                .map(|(inputs, outputs)| Branch {
+                    inputs: inputs.iter().map(|i| i.root).collect(),
+                    outputs: outputs.iter().map(|o| o.root).collect(),
+                })
+                .collect(),
+            layers,
+            graph: RunnableGraph::new(
+                outputs
+                    .iter()
+                    .chain(branch_outputs.iter().flat_map(|(_, o)| o.iter()))
+                    .chain(objective.iter().map(|(_, loss)| loss))
+                    .chain(penalty.iter().map(|(value, _, _)| value))
+                    .collect(),
+            ),
+            options,
+            batch: None,
+            rng: StdRng::from_rng(thread_rng()).unwrap(),
+        }
+    }
+
+    pub fn layers(&self) -> &[Box<dyn Layer>] {
+        &self.layers
+    }
+
+    /// State of every layer, with names prefixed by the index of the layer, e.g. `0.weight`.
+    pub fn state(&self) -> Vec<StateTensor> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, layer)| StateTensor::prefixed(&i.to_string(), layer.state()))
+            .collect()
+    }
+
+    /// Value and gradient of every trainable parameter, keyed by its state tensor and its index
+    /// in it, e.g. `0.weight[4]`.
+    pub fn named_parameters(&self) -> BTreeMap<String, (f64, f64)> {
+        self.named_parameter_ids()
+            .into_iter()
+            .map(|(name, id)| {
+                let data = (self.graph.value_for_id(id), self.graph.grad_for_id(id));
+                (name, data)
+            })
+            .collect()
+    }
+
+    /// Overwrites the value of one of the `named_parameters`.
+    pub fn set_named_parameter(&mut self, name: &str, value: f64) {
+        match self.named_parameter_ids().get(name) {
+            Some(id) => self.graph.set_state(*id, value),
+            None => panic!("Unknown parameter {name}"),
+        }
+    }
+
+    fn named_parameter_ids(&self) -> BTreeMap<String, NodeId> {
+        let parameters: HashSet<&NodeId> = self.graph.parameter_ids().iter().collect();
+        self.state()
+            .into_iter()
+            .flat_map(|t| {
+                t.ids
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, id)| (format!("{}[{i}]", t.name), id))
+            })
+            .filter(|(_, id)| parameters.contains(id))
+            .collect()
+    }
+
+    /// Writes the state of the model to a safetensors file, named as in `state`.
+    pub fn save_safetensors(&self, path: &Path) {
+        let tensors = self
+            .state()
+            .into_iter()
+            .map(|t| {
+                let values = t.ids.iter().map(|id| self.graph.value_for_id(*id));
+                (t.name, Tensor::new(t.shape, values.collect()))
+            })
+            .collect();
+        fs::write(path, io::write_safetensors(&tensors))
+            .unwrap_or_else(|e| panic!("Failed to write {}: {e}", path.display()))
+    }
+
+    /// Loads the state of the model from a safetensors file, e.g. exported from the equivalent
+    /// PyTorch model. Every tensor of `state` must be present with the same shape.
+    pub fn load_safetensors(&mut self, path: &Path) {
+        let bytes =
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        self.load_state(&io::read_safetensors(&bytes));
+    }
+
+    /// Loads the state of the model from an `.npz` archive, e.g. of a PyTorch state dict saved
+    /// with `numpy.savez(path, **{k: v.numpy() for k, v in model.state_dict().items()})`.
+    /// Arrays are matched by name if the archive uses the names of `state`, and otherwise in
+    /// order by shape, which maps e.g. `fc1.weight` onto `0.weight`.
+    pub fn load_npz(&mut self, path: &Path) {
+        let bytes =
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        let arrays = io::read_npz(&bytes);
+        let state = self.state();
+
+        let by_name: BTreeMap<String, Tensor> = arrays.iter().cloned().collect();
+        if state.iter().all(|t| by_name.contains_key(&t.name)) {
+            return self.load_state(&by_name);
+        }
+
+        let mut arrays = arrays.into_iter();
+        let by_shape = state
+            .into_iter()
+            .map(
+                |t| match arrays.by_ref().find(|(_, a)| a.shape() == t.shape) {
+                    Some((_, array)) => (t.name, array),
+                    None => panic!("No array of shape {:?} left for {}", t.shape, t.name),
+                },
+            )
+            .collect();
+        self.load_state(&by_shape);
+    }
+
+    fn load_state(&mut self, tensors: &BTreeMap<String, Tensor>) {
+        self.state().into_iter().for_each(|t| {
+            let tensor = tensors
+                .get(&t.name)
+                .unwrap_or_else(|| panic!("Missing tensor {}", t.name));
+            if tensor.shape() != t.shape {
+                panic!(
+                    "Expected shape {:?} for {}, but got {:?}",
+                    t.shape,
+                    t.name,
+                    tensor.shape()
+                )
+            }
+            t.ids
+                .iter()
+                .zip(tensor.data())
+                .for_each(|(id, v)| self.graph.set_state(*id, *v));
+        });
+    }
+
+    /// Table of the layers with their number of outputs and parameters, followed by the total
+    /// number of parameters in the graph, where shared parameters only count once.
+    pub fn summary(&self) -> String {
+        let rule = "=".repeat(48);
+        let mut summary = format!(
+            "{:<24}{:>12}{:>12}\n{rule}\n",
+            "Layer", "Outputs", "Parameters"
+        );
+        self.layers
+            .iter()
+            .zip(self.layer_outputs.iter())
+            .for_each(|(layer, outputs)| {
+                summary.push_str(&format!(
+                    "{:<24}{:>12}{:>12}\n",
+                    layer.name(),
+                    outputs.len(),
+                    layer.parameters().len()
+                ))
+            });
+        summary.push_str(&format!(
+            "{rule}\nTotal parameters: {}\n",
+            self.num_parameters()
+        ));
+        summary
+    }
+
+    /// Reseeds the generators behind stochastic layers such as dropout, for reproducible runs.
+    pub fn seed(&mut self, seed: u64) {
+        self.graph.seed(seed);
+        self.rng = StdRng::seed_from_u64(seed);
+        if let Some(batch) = &mut self.batch {
+            batch.graph.seed(self.rng.gen());
+        }
+    }
+
+    /// Switches stochastic layers between their training and inference behaviour.
+    pub fn set_training(&mut self, training: bool) {
+        self.graph.set_training(training);
+    }
+
+    /// Training mode, the default: dropout masks are sampled on every evaluation and batch
+    /// normalisation uses, and updates its running statistics from, the statistics of the batch.
+    pub fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    /// Inference mode: dropout is turned off and batch normalisation uses its running
+    /// statistics, which are left untouched.
+    pub fn eval(&mut self) {
+        self.set_training(false);
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.graph.is_training()
+    }
+
+    /// Post-training quantisation of the model to i8 weights with a scale per layer, for
+    /// low-memory inference with integer arithmetic. Supports dense models, i.e. made of
+    /// `Linear`, `Activation`, `Dropout`, `Flatten` and `Softmax` layers.
+    pub fn quantise(&self) -> QuantisedModel {
+        QuantisedModel::new(
+            self.layers
+                .iter()
+                .map(|layer| layer.quantise(&self.graph))
+                .collect(),
+        )
+    }
+
+    pub fn forward(&mut self, inputs: &[f64]) -> Vec<f64> {
+        self.forward_with(inputs, &[])
+    }
+
+    /// `forward` that also evaluates `extra` nodes, e.g. the outputs of branches, which are left
+    /// alone otherwise.
+    fn forward_with(&mut self, inputs: &[f64], extra: &[NodeId]) -> Vec<f64> {
+        self.set_inputs(inputs);
+        let roots: Vec<NodeId> = self
+            .outputs
+            .iter()
+            .chain(self.objective.iter().map(|o| &o.loss))
+            .chain(self.penalty.iter().map(|p| &p.value))
+            .chain(extra)
+            .cloned()
+            .collect();
+
+        let mut values = self.graph.evaluate(&roots);
+        values.truncate(self.outputs.len());
+        values
+    }
+
+    fn set_inputs(&mut self, inputs: &[f64]) {
+        if inputs.len() != self.inputs.len() {
+            panic!(
+                "Expected {} inputs, but got {}",
+                self.inputs.len(),
+                inputs.len()
+            )
+        }
+        self.inputs
+            .iter()
+            .zip(inputs.iter())
+            .for_each(|(input, value)| self.graph.set_input(*input, *value));
+    }
+
+    /// Sets the values fed into branch `index`, to be evaluated along with the main path.
+    fn set_branch_inputs(&mut self, index: usize, inputs: &[f64]) {
+        let branch = &self.branches[index];
+        if inputs.len() != branch.inputs.len() {
+            panic!(
+                "Expected {} inputs, but got {}",
+                branch.inputs.len(),
+                inputs.len()
+            )
+        }
+        branch
+            .inputs
+            .iter()
+            .zip(inputs.iter())
+            .for_each(|(id, value)| self.graph.set_immediate(*id, *value));
+    }
+
+    /// Evaluates branch `index` on `inputs`, leaving the inputs of the main path as they were.
+    fn forward_branch(&mut self, index: usize, inputs: &[f64]) -> Vec<f64> {
+        self.set_branch_inputs(index, inputs);
+        let outputs = self.branches[index].outputs.clone();
+        self.graph.evaluate(&outputs)
+    }
+
+    fn objective(&self) -> &Objective {
+        self.objective
+            .as_ref()
+            .expect("The model must be built with a loss, see SequentialBuilder::loss")
+    }
+
+    /// Sets the targets that the loss compares the outputs to on the next `forward`.
+    pub fn set_targets(&mut self, targets: &[f64]) {
+        let ids = self.objective().targets.clone();
+        if targets.len() != ids.len() {
+            panic!("Expected {} targets, but got {}", ids.len(), targets.len())
+        }
+        ids.iter()
+            .zip(targets.iter())
+            .for_each(|(id, value)| self.graph.set_immediate(*id, *value));
+    }
+
+    /// Evaluates the model on `inputs` and returns the loss against `targets`.
+    pub fn forward_loss(&mut self, inputs: &[f64], targets: &[f64]) -> f64 {
+        self.set_targets(targets);
+        self.forward(inputs);
+        self.loss()
+    }
+
+    /// Loss as of the last `forward`.
+    pub fn loss(&self) -> f64 {
+        self.graph.value_for_id(self.objective().loss)
+    }
+
+    /// Backpropagates from the loss node, seeded with 1, and returns the loss.
+    pub fn backward_loss(&mut self) -> f64 {
+        let loss = self.objective().loss;
+        self.backwards(vec![(loss, 1.)]);
+        self.graph.value_for_id(loss)
+    }
+
+    /// L2 norm of the gradients of each layer's parameters, 0 for layers without any, to spot
+    /// exploding or vanishing gradients after `backward`.
+    pub fn gradient_norms(&self) -> Vec<f64> {
+        self.layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .parameters()
+                    .iter()
+                    .map(|id| self.graph.grad_for_id(*id).powi(2))
+                    .sum::<f64>()
+                    .sqrt()
+            })
+            .collect()
+    }
+
+    /// Histograms of the parameter values and gradients of every layer that has parameters, to
+    /// be logged during training to follow how they drift.
+    pub fn histograms(&self, bins: usize) -> Vec<LayerHistograms> {
+        self.layers
+            .iter()
+            .enumerate()
+            .filter(|(_, layer)| !layer.parameters().is_empty())
+            .map(|(i, layer)| {
+                let parameters = layer.parameters();
+                let values: Vec<f64> = parameters
+                    .iter()
+                    .map(|id| self.graph.value_for_id(*id))
+                    .collect();
+                let grads: Vec<f64> = parameters
+                    .iter()
+                    .map(|id| self.graph.grad_for_id(*id))
+                    .collect();
+                LayerHistograms {
+                    layer: i,
+                    name: layer.name(),
+                    weights: Histogram::new(&values, bins),
+                    gradients: Histogram::new(&grads, bins),
+                }
+            })
+            .collect()
+    }
+
+    /// `histograms` as CSV, one row per bin: `layer,name,kind,bin_start,bin_end,count` where
+    /// `kind` is either `weights` or `gradients`.
+    pub fn histograms_csv(&self, bins: usize) -> String {
+        let mut csv = String::from("layer,name,kind,bin_start,bin_end,count\n");
+        self.histograms(bins).iter().for_each(|h| {
+            [("weights", &h.weights), ("gradients", &h.gradients)]
+                .iter()
+                .for_each(|(kind, histogram)| {
+                    histogram.counts.iter().enumerate().for_each(|(i, count)| {
+                        let (start, end) = histogram.bin_edges(i);
+                        csv.push_str(&format!(
+                            "{},{},{kind},{start},{end},{count}\n",
+                            h.layer, h.name
+                        ));
+                    })
+                })
+        });
+        csv
+    }
+
+    /// Stops `update_weights` from changing the parameters of layer `index`, e.g. to only
+    /// fine-tune the head of a pre-trained network.
+    pub fn freeze_layer(&mut self, index: usize) {
+        let parameters = self.layer_parameters(index);
+        self.graph.freeze(&parameters);
+    }
+
+    pub fn unfreeze_layer(&mut self, index: usize) {
+        let parameters = self.layer_parameters(index);
+        self.graph.unfreeze(&parameters);
+    }
+
+    fn layer_parameters(&self, index: usize) -> Vec<NodeId> {
+        match self.layers.get(index) {
+            Some(layer) => layer.parameters(),
+            None => panic!(
+                "Expected a layer index below {}, but got {}",
+                self.layers.len(),
+                index
+            ),
+        }
+    }
+
+    /// Runs the network like `forward`, but returns the outputs of every layer, with the final
+    /// layer's outputs last.
+    pub fn forward_with_activations(&mut self, inputs: &[f64]) -> Vec<Vec<f64>> {
+        self.forward(inputs);
+        self.layer_outputs
+            .iter()
+            .map(|ids| ids.iter().map(|id| self.graph.value_for_id(*id)).collect())
+            .collect()
+    }
+
+    /// Runs inference on many samples in parallel without touching the training state.
+    pub fn evaluate_batch(&self, inputs: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        self.graph.evaluate_batch(inputs, &self.outputs)
+    }
+
+    /// Runs a whole mini-batch through the network in a single tensor evaluation, with the batch
+    /// as the rows of the input matrix, and returns the outputs of each sample. The tensor graph
+    /// is built once per batch size and mode, and later calls only feed in the inputs, along with
+    /// the parameters if they changed since.
+    pub fn forward_batch(&mut self, inputs: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        if let Some(x) = inputs.iter().find(|x| x.len() != self.inputs.len()) {
+            panic!("Expected {} inputs, but got {}", self.inputs.len(), x.len())
+        }
+
+        let training = self.is_training();
+        if !matches!(&self.batch, Some(b) if b.batch_size == inputs.len() && b.training == training)
+        {
+            self.batch = Some(self.batch_graph(inputs.len()));
+        }
+        let batch = self.batch.as_mut().unwrap();
+        batch.refresh(&self.graph);
+
+        batch
+            .graph
+            .set_input(batch.input, Tensor::from_rows(inputs));
+        let values = batch.graph.evaluate(&[batch.output]).remove(0);
+        self.layers
+            .iter()
+            .for_each(|layer| layer.after_batch(&batch.graph, &mut self.graph));
+
+        values.rows()
+    }
+
+    /// Builds the layers into a tensor graph over batches of `batch_size` samples.
+    fn batch_graph(&mut self, batch_size: usize) -> BatchGraph {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (input, x) = graph.create_input(vec![batch_size, self.inputs.len()]);
+
+        let mut context = BatchContext {
+            graph: &self.graph,
+            bindings: vec![],
+        };
+        let mut layer_outputs = vec![];
+        let output = self.layers.iter().fold(x, |h, layer| {
+            let h = layer.build_batch(h, &mut context);
+            layer_outputs.push(h.root);
+            h
+        });
+
+        let mut runnable = RunnableTensorGraph::new(vec![&output]);
+        runnable.seed(self.rng.gen());
+        BatchGraph {
+            graph: runnable,
+            input,
+            output: output.root,
+            layer_outputs,
+            bindings: context.bindings,
+            batch_size,
+            training: self.is_training(),
+            revision: self.graph.revision(),
+        }
+    }
+
+    /// Backpropagates per-sample output gradients through the last `forward_batch`, accumulating
+    /// the batch's parameter gradients so that `update_weights` applies them.
+    pub fn backward_batch(&mut self, out_grads: Vec<Vec<f64>>) {
+        let output = self
+            .batch
+            .as_ref()
+            .expect("forward_batch must be called before backward_batch")
+            .output;
+        self.backward_batch_from(output, out_grads);
+    }
+
+    /// `backward_batch` with the gradients seeded into the tensor node `root` of the batch graph,
+    /// e.g. the logits under a softmax head.
+    fn backward_batch_from(&mut self, root: NodeId, out_grads: Vec<Vec<f64>>) {
+        let batch = self.batch.as_mut().unwrap();
+
+        batch.graph.zero_grads();
+        batch
+            .graph
+            .backwards(vec![(root, Tensor::from_rows(&out_grads))]);
+
+        batch
+            .bindings
+            .iter()
+            .filter(|binding| binding.trainable)
+            .for_each(|binding| {
+                let grads = batch.graph.gradient(binding.tensor).data();
+                binding
+                    .ids
+                    .iter()
+                    .zip(grads.iter())
+                    .for_each(|(id, g)| self.graph.add_gradient(*id, *g));
+            });
+        if let Some(penalty) = &self.penalty {
+            penalty.parameters.iter().for_each(|id| {
+                let w = self.graph.value_for_id(*id);
+                self.graph.add_gradient(*id, 2. * penalty.lambda * w);
+            });
+        }
+    }
+
+    pub fn backward(&mut self, out_grads: Vec<f64>) {
+        let pairs: Vec<(NodeId, f64)> = self.outputs.clone().into_iter().zip(out_grads).collect();
+        self.backwards(pairs);
+    }
+
+    /// Backpropagates gradients seeded into the graph along with the L2 penalty, if any, which
+    /// must happen in a single pass not to count the gradients of shared nodes twice.
+    fn backwards(&mut self, mut grads: Vec<(NodeId, f64)>) {
+        grads.extend(self.penalty.as_ref().map(|p| (p.value, 1.)));
+        if !grads.is_empty() {
+            self.graph.backwards(grads);
+        }
+    }
+
+    /// Value of the L2 penalty for the current parameters, or 0 without one.
+    pub fn l2_penalty(&self) -> f64 {
+        self.penalty.as_ref().map_or(0., |p| {
+            let squares: f64 = p
+                .parameters
+                .iter()
+                .map(|id| self.graph.value_for_id(*id).powi(2))
+                .sum();
+            p.lambda * squares
+        })
+    }
+
+    /// Runs a batch through the network and returns the gradient vector of each sample, leaving
+    /// their sum in the graph for the next `update_weights`. See `RunnableGraph::per_sample_gradients`.
+    pub fn per_sample_gradients(
+        &mut self,
+        inputs: &[Vec<f64>],
+        loss_grad: impl FnMut(usize, &[f64]) -> Vec<f64>,
+    ) -> Vec<Vec<f64>> {
+        let samples: Vec<Vec<(NodeId, f64)>> = inputs
+            .iter()
+            .map(|x| self.inputs.iter().cloned().zip(x.iter().cloned()).collect())
+            .collect();
+        self.graph
+            .per_sample_gradients(&samples, &self.outputs, loss_grad)
+    }
+
+    /// Gradient of the loss with respect to each network input after `backward`, e.g. for saliency
+    /// maps or adversarial examples.
+    pub fn input_gradients(&self) -> Vec<f64> {
+        self.graph.input_gradients(&self.inputs)
+    }
+
+    pub fn zero_grads(&mut self) {
+        self.graph.zero_grads();
+    }
+
+    /// Updates the parameters with the optimiser, grouped by layer, see `parameter_groups`.
+    pub fn update_weights(&mut self, optimiser: &mut impl Optimiser) {
+        let groups = self.parameter_groups();
+        self.graph.update_weights_grouped(optimiser, &groups);
+        self.apply_max_norms();
+    }
+
+    /// Clamps the gradient of every parameter to `[-max, max]`.
+    pub fn clip_grad_value(&mut self, max: f64) {
+        self.graph.clip_grad_value(max);
+    }
+
+    /// Rescales the gradients to a global L2 norm of at most `max_norm` and returns the norm
+    /// before clipping.
+    pub fn clip_grad_norm(&mut self, max_norm: f64) -> f64 {
+        self.graph.clip_grad_norm(max_norm)
+    }
+
+    /// Parameters of each layer that has any, with parameters shared between layers grouped with
+    /// the first one.
+    pub fn parameter_groups(&self) -> Vec<Vec<NodeId>> {
+        let mut seen = HashSet::new();
+        self.layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .parameters()
+                    .into_iter()
+                    .filter(|id| seen.insert(*id))
+                    .collect::<Vec<NodeId>>()
+            })
+            .filter(|group| !group.is_empty())
+            .collect()
+    }
+
+    /// Incoming weights of every unit of every layer, as indices into `parameters`, e.g. for
+    /// `CentralisedOptimiser`. Biases aren't part of any row.
+    pub fn incoming_weight_rows(&self) -> Vec<Vec<usize>> {
+        let positions: HashMap<NodeId, usize> = self
+            .graph
+            .parameter_ids()
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, i))
+            .collect();
+        let mut seen = HashSet::new();
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.incoming_weights())
+            .map(|row| {
+                row.iter()
+                    .filter_map(|id| positions.get(id).cloned())
+                    .collect()
+            })
+            .filter(|row: &Vec<usize>| !row.is_empty() && seen.insert(row.clone()))
+            .collect()
+    }
+
+    /// Constrains the incoming weight vector of every unit of layer `index` to a norm of at most
+    /// `max_norm`, rescaling the ones above it after each `update_weights`. `None` lifts it.
+    pub fn set_max_norm(&mut self, index: usize, max_norm: Option<f64>) {
+        self.layer_parameters(index);
+        if max_norm.is_some() && self.layers[index].incoming_weights().is_empty() {
+            panic!(
+                "{} does not support a max-norm constraint",
+                self.layers[index].name()
+            )
+        }
+        self.max_norms[index] = max_norm;
+        self.apply_max_norms();
+    }
+
+    fn apply_max_norms(&mut self) {
+        let constrained = self
+            .layers
+            .iter()
+            .zip(self.max_norms.iter())
+            .filter_map(|(layer, max_norm)| max_norm.map(|m| (layer.incoming_weights(), m)));
+        for (units, max_norm) in constrained {
+            for ids in units {
+                if ids.iter().any(|id| self.graph.is_frozen(*id)) {
+                    continue;
+                }
+                let values: Vec<f64> = ids.iter().map(|id| self.graph.value_for_id(*id)).collect();
+                let norm = values.iter().map(|v| v * v).sum::<f64>().sqrt();
+                if norm > max_norm {
+                    ids.iter()
+                        .zip(values)
+                        .for_each(|(id, v)| self.graph.set_state(*id, v * max_norm / norm));
+                }
+            }
+        }
+    }
+
+    pub fn num_parameters(&self) -> usize {
+        self.graph.num_parameters()
+    }
+
+    /// Takes a step with an optimiser that re-evaluates the objective, where `loss` runs the
+    /// forward and backward passes of the model, e.g. over the whole training set with
+    /// `forward_batch` and `backward_batch`, and returns the loss. Frozen parameters don't move.
+    /// Returns the loss before the step.
+    pub fn step_with(
+        &mut self,
+        optimiser: &mut impl ClosureOptimiser,
+        mut loss: impl FnMut(&mut Sequential) -> f64,
+    ) -> f64 {
+        let frozen: Vec<bool> = self
+            .graph
+            .parameter_ids()
+            .iter()
+            .map(|id| self.graph.is_frozen(*id))
+            .collect();
+        let initial_parameters = self.parameters();
+        let mut parameters = initial_parameters.clone();
+        let initial = optimiser.step(&mut parameters, &mut |values| {
+            self.set_parameters(values);
+            self.zero_grads();
+            let loss = loss(self);
+            let grads = self
+                .graph
+                .gradients()
+                .iter()
+                .zip(frozen.iter())
+                .map(|(g, frozen)| if *frozen { 0. } else { *g })
+                .collect();
+            (loss, grads)
+        });
+        // Optimisers wrapping another, like SAM, may still apply weight decay to them.
+        parameters
+            .iter_mut()
+            .zip(initial_parameters.iter().zip(frozen.iter()))
+            .filter(|(_, (_, frozen))| **frozen)
+            .for_each(|(p, (i, _))| *p = *i);
+        self.set_parameters(&parameters);
+        self.apply_max_norms();
+        initial
+    }
+
+    /// Learning-rate range test: takes up to `steps` steps with learning rates growing
+    /// exponentially from `start` to `end`, recording the loss after each, and stops early once
+    /// the loss diverges. `loss` runs the forward and backward passes of the model on the batch
+    /// of a step, given its index, and returns the loss. The parameters of the model and the state of
+    /// the optimiser are restored afterwards, so that training can start with the suggestion.
+    pub fn find_learning_rate(
+        &mut self,
+        optimiser: &mut impl Optimiser,
+        (start, end): (f64, f64),
+        steps: usize,
+        mut loss: impl FnMut(&mut Sequential, usize) -> f64,
+    ) -> LrRange {
+        const SMOOTHING: f64 = 0.98;
+        if steps < 2 {
+            panic!("Expected at least 2 steps, but got {}", steps)
+        }
+        let parameters = self.parameters();
+        let state = optimiser.save_state();
+        let learning_rate = optimiser.learning_rate();
+
+        let growth = (end / start).powf(1. / (steps - 1) as f64);
+        let (mut learning_rates, mut losses) = (vec![], vec![]);
+        let (mut average, mut best) = (0., f64::INFINITY);
+        for step in 0..steps {
+            let rate = start * growth.powi(step as i32);
+            optimiser.set_learning_rate(rate);
+            self.zero_grads();
+            let value = loss(self, step);
+            self.update_weights(optimiser);
+
+            average = SMOOTHING * average + (1. - SMOOTHING) * value;
+            let smoothed = average / (1. - SMOOTHING.powi(step as i32 + 1));
+            learning_rates.push(rate);
+            losses.push(smoothed);
+            best = best.min(smoothed);
+            if !smoothed.is_finite() || smoothed > 4. * best {
+                break;
+            }
+        }
+
+        // Loading the state last lets optimisers that keep their schedule in it, like `Scheduled`,
+        // pick up where they were rather than restart from the restored learning rate.
+        self.set_parameters(&parameters);
+        optimiser.set_learning_rate(learning_rate);
+        optimiser.load_state(&state);
+
+        // The first losses are left out, as the moving average is still mostly noise there.
+        let skip = (losses.len() / 10).min(losses.len().saturating_sub(2));
+        let steepest = (skip..losses.len().saturating_sub(1))
+            .min_by(|a, b| {
+                let slope = |i: usize| losses[i + 1] - losses[i];
+                slope(*a).total_cmp(&slope(*b))
+            })
+            .unwrap_or(0);
+        LrRange {
+            suggestion: learning_rates[steepest],
+            learning_rates,
+            losses,
+        }
+    }
+
+    /// Takes a step with an optimiser that only evaluates the loss, where `loss` runs the model,
+    /// e.g. over a batch, and returns the loss. Frozen parameters don't move. Returns the loss
+    /// before the step.
+    pub fn step_gradient_free(
+        &mut self,
+        optimiser: &mut impl GradientFreeOptimiser,
+        mut loss: impl FnMut(&mut Sequential) -> f64,
+    ) -> f64 {
+        let initial = self.parameters();
+        let frozen: Vec<bool> = self
+            .graph
+            .parameter_ids()
+            .iter()
+            .map(|id| self.graph.is_frozen(*id))
+            .collect();
+        let unfrozen = |values: &[f64]| -> Vec<f64> {
+            values
+                .iter()
+                .zip(initial.iter().zip(frozen.iter()))
+                .map(|(v, (i, frozen))| if *frozen { *i } else { *v })
+                .collect()
+        };
+
+        let mut parameters = initial.clone();
+        let loss = optimiser.step(&mut parameters, &mut |values| {
+            self.set_parameters(&unfrozen(values));
+            loss(self)
+        });
+        self.set_parameters(&unfrozen(&parameters));
+        self.apply_max_norms();
+        loss
+    }
+
+    /// Values of all the parameters as one flat vector, in the order `update_weights` hands
+    /// them to the optimiser.
+    pub fn parameters(&self) -> Vec<f64> {
+        self.graph.parameter_values()
+    }
+
+    /// Overwrites all the parameters from a flat vector ordered as in `parameters`.
+    pub fn set_parameters(&mut self, values: &[f64]) {
+        self.graph.set_parameter_values(values);
+    }
+}
+
+/// Composes a `Sequential` while keeping track of the shape flowing between layers, so that each
+/// layer can be sized from the output of the previous one.
+#[derive(Debug)]
+pub struct SequentialBuilder {
+    input_shape: Vec<usize>,
+    shape: Vec<usize>,
+    layers: Vec<Box<dyn Layer>>,
+    options: BuildOptions,
+    rng: StdRng,
+}
+
+impl SequentialBuilder {
+    /// Shape of the outputs of the layers added so far.
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// Appends a layer, checking that it accepts the current shape.
+    pub fn layer(mut self, layer: impl Layer + 'static) -> SequentialBuilder {
+        self.shape = layer.output_shape(&self.shape);
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn linear(mut self, size: usize, activation: Activation) -> SequentialBuilder {
+        let fan_in = match self.shape[..] {
+            [n] => n,
+            _ => panic!(
+                "Linear layers take flat inputs, but got shape {:?}; add a Flatten layer first",
+                self.shape
+            ),
+        };
+        let layer = Linear::builder(fan_in, size)
+            .activation(activation)
+            .init(Init::XavierUniform, Some(Init::Zeros))
+            .build(&mut self.rng);
+        self.layer(layer)
+    }
+
+    pub fn conv2d(
+        mut self,
+        out_channels: usize,
+        kernel_size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> SequentialBuilder {
+        let input_shape = self.image_shape();
+        let layer = Conv2d::new(
+            input_shape,
+            out_channels,
+            kernel_size,
+            stride,
+            padding,
+            &mut self.rng,
+        );
+        self.layer(layer)
+    }
+
+    pub fn max_pool2d(self, kernel_size: usize, stride: usize) -> SequentialBuilder {
+        let layer = MaxPool2d::new(self.image_shape(), kernel_size, stride);
+        self.layer(layer)
+    }
+
+    pub fn avg_pool2d(self, kernel_size: usize, stride: usize) -> SequentialBuilder {
+        let layer = AvgPool2d::new(self.image_shape(), kernel_size, stride);
+        self.layer(layer)
+    }
+
+    pub fn flatten(self) -> SequentialBuilder {
+        self.layer(Flatten)
+    }
+
+    /// Adds an L2 penalty `lambda * sum(w^2)` over all of the parameters but sparse ones to the
+    /// graph, so that every backward pass also pulls the weights towards zero.
+    pub fn l2(mut self, lambda: f64) -> SequentialBuilder {
+        self.options.l2 = Some(lambda);
+        self
+    }
+
+    /// Builds `loss` of the outputs against targets fed with `set_targets` into the graph, so
+    /// that training boils down to `forward_loss` and `backward_loss`.
+    pub fn loss(mut self, loss: GraphLoss) -> SequentialBuilder {
+        self.options.loss = Some(loss);
+        self
+    }
+
+    pub fn build(self) -> Sequential {
+        Sequential::build(self.input_shape.iter().product(), self.layers, self.options)
+    }
+
+    fn image_shape(&self) -> (usize, usize, usize) {
+        match self.shape[..] {
+            [channels, height, width] => (channels, height, width),
+            _ => panic!(
+                "Expected [channels, height, width] inputs, but got shape {:?}",
+                self.shape
+            ),
+        }
+    }
+}
+
+/// Layer recorded by `MultiLayerPerceptronBuilder`, created once the seed is known.
+#[derive(Debug, Clone, Copy)]
+enum LayerSpec {
+    Dense(usize, Activation),
+    Dropout(f64),
+}
+
+/// Step-by-step construction of a `MultiLayerPerceptron`, e.g.
+/// `MultiLayerPerceptron::builder().input(64).hidden(128, Activation::Relu).output(10, Activation::None).build()`.
+#[derive(Debug, Clone)]
+pub struct MultiLayerPerceptronBuilder {
+    input: Option<usize>,
+    layers: Vec<LayerSpec>,
+    weight_init: Init,
+    bias_init: Option<Init>,
+    head: OutputHead,
+    l2: Option<f64>,
+    loss: Option<GraphLoss>,
+    seed: Option<u64>,
+}
+
+impl MultiLayerPerceptronBuilder {
+    pub fn input(mut self, size: usize) -> MultiLayerPerceptronBuilder {
+        self.input = Some(size);
+        self
+    }
+
+    pub fn hidden(mut self, size: usize, activation: Activation) -> MultiLayerPerceptronBuilder {
+        self.layers.push(LayerSpec::Dense(size, activation));
+        self
+    }
+
+    pub fn dropout(mut self, p: f64) -> MultiLayerPerceptronBuilder {
+        self.layers.push(LayerSpec::Dropout(p));
+        self
+    }
+
+    /// The last dense layer, optionally followed by `softmax` or `sigmoid`.
+    pub fn output(self, size: usize, activation: Activation) -> MultiLayerPerceptronBuilder {
+        self.hidden(size, activation)
+    }
+
+    /// Ends the network in a `Softmax` layer, so that `forward` returns class probabilities.
+    pub fn softmax(mut self) -> MultiLayerPerceptronBuilder {
+        self.head = OutputHead::Softmax;
+        self
+    }
+
+    /// Ends the network in a sigmoid for binary classification, so that its single output is
+    /// the probability of the positive class. Train it with `backward_bce`.
+    pub fn sigmoid(mut self) -> MultiLayerPerceptronBuilder {
+        self.head = OutputHead::Sigmoid;
+        self
+    }
+
+    /// Weight and bias initialisation of every dense layer, without biases if `bias` is `None`.
+    pub fn init(mut self, weights: Init, bias: Option<Init>) -> MultiLayerPerceptronBuilder {
+        self.weight_init = weights;
+        self.bias_init = bias;
+        self
+    }
+
+    /// Adds `lambda * sum(w^2)` over the parameters to the training objective, see
+    /// `SequentialBuilder::l2`.
+    pub fn l2(mut self, lambda: f64) -> MultiLayerPerceptronBuilder {
+        self.l2 = Some(lambda);
+        self
+    }
+
+    /// Builds `loss` into the graph, see `SequentialBuilder::loss`.
+    pub fn loss(mut self, loss: GraphLoss) -> MultiLayerPerceptronBuilder {
+        self.loss = Some(loss);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> MultiLayerPerceptronBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> MultiLayerPerceptron {
+        let input = self
+            .input
+            .expect("The input size must be set before building the network");
+        if !self
+            .layers
+            .iter()
+            .any(|l| matches!(l, LayerSpec::Dense(..)))
+        {
+            panic!("Expected at least one dense layer")
+        }
+
+        let mut rng = self
+            .seed
+            .map(StdRng::seed_from_u64)
+            .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap());
+
+        let mut size = input;
+        let mut layers: Vec<Box<dyn Layer>> = self
+            .layers
+            .iter()
+            .map(|spec| match *spec {
+                LayerSpec::Dense(out, activation) => {
+                    let fan_in = size;
+                    size = out;
+                    Box::new(
+                        Linear::builder(fan_in, out)
+                            .activation(activation)
+                            .init(self.weight_init, self.bias_init)
+                            .build(&mut rng),
+                    ) as Box<dyn Layer>
+                }
+                LayerSpec::Dropout(p) => Box::new(Dropout(p)),
+            })
+            .collect();
+        self.head.push_layer(size, &mut layers);
+
+        let options = BuildOptions {
+            l2: self.l2,
+            loss: self.loss,
+            ..Default::default()
+        };
+        let mut model = Sequential::build(input, layers, options);
+        if let Some(seed) = self.seed {
+            model.seed(seed);
+        }
+
+        MultiLayerPerceptron {
+            model,
+            specs: self.layers,
+            head: self.head,
+        }
+    }
+}
+
+/// Objective for networks predicting real values, averaged over the outputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegressionLoss {
+    /// Mean squared error.
+    Mse,
+    /// Quadratic for errors up to the given threshold and linear beyond, so less sensitive to
+    /// outliers than `Mse`.
+    Huber(f64),
+}
+
+impl RegressionLoss {
+    /// Loss of the outputs and its gradient with respect to them, see the `loss` module.
+    fn apply(&self, outputs: &[f64], targets: &[f64]) -> (f64, Vec<f64>) {
+        match *self {
+            RegressionLoss::Mse => loss::mse(outputs, targets),
+            RegressionLoss::Huber(delta) => loss::huber(outputs, targets, delta),
+        }
+    }
+}
+
+/// Layer following the last `Linear` layer of a `MultiLayerPerceptron`, if any, to turn its
+/// logits into probabilities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputHead {
+    Logits,
+    Softmax,
+    Sigmoid,
+}
+
+impl OutputHead {
+    fn push_layer(&self, num_outputs: usize, layers: &mut Vec<Box<dyn Layer>>) {
+        match self {
+            OutputHead::Logits => {}
+            OutputHead::Softmax => layers.push(Box::new(Softmax)),
+            OutputHead::Sigmoid => {
+                if num_outputs != 1 {
+                    panic!("Expected 1 output for a sigmoid head, but got {num_outputs}")
+                }
+                layers.push(Box::new(Activation::Sigmoid))
+            }
+        }
+    }
+}
+
+/// `Sequential` stack of `Linear` layers, which it derefs to for training and inference. Batches
+/// go through `forward_batch` as one `MatMul` per layer on tensor copies of the parameters.
+#[derive(Debug)]
+pub struct MultiLayerPerceptron {
+    model: Sequential,
+    /// Layers before the head, from which `widen` and `deepen` rebuild the network.
+    specs: Vec<LayerSpec>,
+    head: OutputHead,
+}
+
+impl Deref for MultiLayerPerceptron {
+    type Target = Sequential;
+
+    fn deref(&self) -> &Sequential {
+        &self.model
+    }
+}
+
+impl DerefMut for MultiLayerPerceptron {
+    fn deref_mut(&mut self) -> &mut Sequential {
+        &mut self.model
+    }
+}
+
+impl MultiLayerPerceptron {
+    /// Starts describing a network layer by layer, with the same defaults as `new`.
+    pub fn builder() -> MultiLayerPerceptronBuilder {
+        let init = Init::Uniform(-1., 1.);
+        MultiLayerPerceptronBuilder {
+            input: None,
+            layers: vec![],
+            weight_init: init,
+            bias_init: Some(init),
+            head: OutputHead::Logits,
+            l2: None,
+            loss: None,
+            seed: None,
+        }
+    }
+
+    /// Builds the network with relu hidden layers and a linear output layer, with weights drawn
+    /// from a generator seeded with `seed`, or from entropy if `None`. See `builder` for other
+    /// layouts.
+    pub fn new(sizes: Vec<usize>, seed: Option<u64>) -> MultiLayerPerceptron {
+        let mut builder = Self::builder().input(sizes[0]);
+        for (i, size) in sizes.iter().enumerate().skip(1) {
+            builder = if i == sizes.len() - 1 {
+                builder.output(*size, Activation::None)
+            } else {
+                builder.hidden(*size, Activation::Relu)
+            };
+        }
+        match seed {
+            Some(seed) => builder.seed(seed).build(),
+            None => builder.build(),
+        }
+    }
+
+    /// Outputs of the last `Linear` layer, before any softmax or sigmoid.
+    fn logits(&self) -> &[NodeId] {
+        match self.head {
+            OutputHead::Logits => &self.model.outputs,
+            _ => &self.model.layer_outputs[self.model.layer_outputs.len() - 2],
+        }
+    }
+
+    /// Backpropagates the cross-entropy loss of the last `forward` against `target_class`, and
+    /// returns the loss. The gradient `softmax - onehot` is seeded straight into the logits, so
+    /// this works whether or not the network ends in a `Softmax` layer.
+    pub fn backward_cross_entropy(&mut self, target_class: usize) -> f64 {
+        let (grads, loss) = loss_gradients(&self.model.graph, self.logits(), |z| {
+            loss::cross_entropy_with_logits(z, target_class)
+        });
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// Batched `backward_cross_entropy`: backpropagates the cross-entropy of each sample of the
+    /// last `forward_batch` against its class through the tensor graph, summing the gradients
+    /// over the batch as `backward_batch` does, and returns the mean loss.
+    pub fn backward_cross_entropy_batch(&mut self, target_classes: &[usize]) -> f64 {
+        let batch = self
+            .model
+            .batch
+            .as_ref()
+            .expect("forward_batch must be called before backward_cross_entropy_batch");
+        let logits = match self.head {
+            OutputHead::Logits => batch.output,
+            _ => batch.layer_outputs[batch.layer_outputs.len() - 2],
+        };
+        let rows = batch.graph.value(logits).rows();
+        if rows.len() != target_classes.len() {
+            panic!(
+                "Expected {} target classes, but got {}",
+                rows.len(),
+                target_classes.len()
+            )
+        }
+
+        let (losses, grads): (Vec<f64>, Vec<Vec<f64>>) = rows
+            .iter()
+            .zip(target_classes)
+            .map(|(z, target_class)| loss::cross_entropy_with_logits(z, *target_class))
+            .unzip();
+        self.model.backward_batch_from(logits, grads);
+        losses.iter().sum::<f64>() / losses.len() as f64
+    }
+
+    /// `backward_cross_entropy` scaled by the weight of `target_class`, see
+    /// `loss::class_weights`.
+    pub fn backward_cross_entropy_weighted(&mut self, target_class: usize, weights: &[f64]) -> f64 {
+        let (grads, loss) = loss_gradients(&self.model.graph, self.logits(), |z| {
+            loss::weighted_cross_entropy_with_logits(z, target_class, weights)
+        });
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// `backward_cross_entropy` with label smoothing: the target puts `1 - epsilon` on
+    /// `target_class` and spreads `epsilon` uniformly over all classes, which keeps the network
+    /// from becoming over-confident.
+    pub fn backward_cross_entropy_smoothed(&mut self, target_class: usize, epsilon: f64) -> f64 {
+        let (grads, loss) = loss_gradients(&self.model.graph, self.logits(), |z| {
+            loss::cross_entropy_with_label_smoothing(z, target_class, epsilon)
+        });
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// Backpropagates the binary cross-entropy of the last `forward` of a single-output network
+    /// against `target`, the probability of the positive class (usually 0 or 1), and returns
+    /// the loss. As with `backward_cross_entropy`, the gradient `sigmoid - target` is seeded
+    /// straight into the logit, with or without a sigmoid head.
+    pub fn backward_bce(&mut self, target: f64) -> f64 {
+        let logit = match self.logits() {
+            [logit] => *logit,
+            logits => panic!("Expected 1 output, but got {}", logits.len()),
+        };
+        let z = self.model.graph.value_for_id(logit);
+        let (loss, grads) = loss::binary_cross_entropy_with_logits(&[z], &[target]);
+        self.model.backwards(vec![(logit, grads[0])]);
+        loss
+    }
+
+    /// Backpropagates `loss` between the outputs of the last `forward` and `targets`, and
+    /// returns the loss.
+    pub fn backward_regression(&mut self, targets: &[f64], loss: RegressionLoss) -> f64 {
+        let outputs = &self.model.outputs;
+        if targets.len() != outputs.len() {
+            panic!(
+                "Expected {} targets, but got {}",
+                outputs.len(),
+                targets.len()
+            )
+        }
+
+        let (grads, loss) = loss_gradients(&self.model.graph, outputs, |y| loss.apply(y, targets));
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// Single optimisation step fitting the outputs for `inputs` to `targets`, returning the
+    /// loss before the step.
+    pub fn train_step_regression(
+        &mut self,
+        inputs: &[f64],
+        targets: &[f64],
+        loss: RegressionLoss,
+        optimiser: &mut impl Optimiser,
+    ) -> f64 {
+        self.forward(inputs);
+        self.zero_grads();
+        let loss = self.backward_regression(targets, loss);
+        self.update_weights(optimiser);
+        loss
+    }
+
+    /// Class probabilities for `inputs`, applying a softmax to the outputs unless the network
+    /// already ends in one. Binary classifiers return the probabilities of the negative and
+    /// the positive class.
+    pub fn predict_proba(&mut self, inputs: &[f64]) -> Vec<f64> {
+        let outputs = self.forward(inputs);
+        match self.head {
+            OutputHead::Logits => Util::softmax(&outputs),
+            OutputHead::Softmax => outputs,
+            OutputHead::Sigmoid => vec![1. - outputs[0], outputs[0]],
+        }
+    }
+
+    /// Monte-Carlo dropout: mean and standard deviation of the class probabilities over
+    /// `n_samples` evaluations with dropout active and every other layer in eval mode, as an
+    /// estimate of how uncertain the prediction is. The previous mode is restored afterwards.
+    pub fn predict_with_uncertainty(
+        &mut self,
+        inputs: &[f64],
+        n_samples: usize,
+    ) -> (Vec<f64>, Vec<f64>) {
+        if n_samples == 0 {
+            panic!("Expected at least one sample")
+        }
+        let training = self.is_training();
+        self.eval();
+        self.graph.set_dropout(true);
+        let samples: Vec<Vec<f64>> = (0..n_samples).map(|_| self.predict_proba(inputs)).collect();
+        self.set_training(training);
+
+        let n = n_samples as f64;
+        let mean: Vec<f64> = (0..samples[0].len())
+            .map(|c| samples.iter().map(|p| p[c]).sum::<f64>() / n)
+            .collect();
+        let std = mean
+            .iter()
+            .enumerate()
+            .map(|(c, m)| (samples.iter().map(|p| (p[c] - m).powi(2)).sum::<f64>() / n).sqrt())
+            .collect();
+        (mean, std)
+    }
+
+    /// Net2Net widening: a copy of the network whose dense layer `layer` has `new_size`
+    /// neurons, computing the same function. The new neurons replicate randomly picked
+    /// existing ones, and the outgoing weights of every replica are split between its copies.
+    /// Replicas only drift apart once something, e.g. dropout, breaks their symmetry.
+    pub fn widen(&mut self, layer: usize, new_size: usize) -> MultiLayerPerceptron {
+        let Some(LayerSpec::Dense(size, activation)) = self.specs.get(layer).copied() else {
+            panic!("Layer {} is not a dense layer", layer)
+        };
+        let Some(next) =
+            (layer + 1..self.specs.len()).find(|i| matches!(self.specs[*i], LayerSpec::Dense(..)))
+        else {
+            panic!("Cannot widen the output layer")
+        };
+        if new_size < size {
+            panic!("Expected a size of at least {}, but got {}", size, new_size)
+        }
+
+        let sources: Vec<usize> = (0..new_size)
+            .map(|j| {
+                if j < size {
+                    j
+                } else {
+                    self.model.rng.gen_range(0..size)
+                }
+            })
+            .collect();
+        let mut copies = vec![0; size];
+        sources.iter().for_each(|j| copies[*j] += 1);
+
+        let mut values = self.dense_values();
+        let (weights, biases) = &values[layer];
+        let fan_in = weights.len() / size;
+        values[layer] = (
+            sources
+                .iter()
+                .flat_map(|j| weights[j * fan_in..(j + 1) * fan_in].to_vec())
+                .collect(),
+            biases
+                .as_ref()
+                .map(|b| sources.iter().map(|j| b[*j]).collect()),
+        );
+        let weights = &values[next].0;
+        values[next].0 = weights
+            .chunks(size)
+            .flat_map(|row| sources.iter().map(|j| row[*j] / copies[*j] as f64))
+            .collect();
+
+        let mut specs = self.specs.clone();
+        specs[layer] = LayerSpec::Dense(new_size, activation);
+        self.rebuilt(specs, values, None)
+    }
+
+    /// Net2Net deepening: a copy of the network with an extra dense layer inserted before layer
+    /// `position`, computing the same function. The new layer starts as the identity, with a
+    /// relu if the dense layer before it has one, which leaves its non-negative outputs alone.
+    pub fn deepen(&mut self, position: usize) -> MultiLayerPerceptron {
+        if position > self.specs.len() {
+            panic!(
+                "Expected a position up to {}, but got {}",
+                self.specs.len(),
+                position
+            )
+        }
+        let previous = self.specs[..position]
+            .iter()
+            .rev()
+            .find_map(|spec| match spec {
+                LayerSpec::Dense(size, activation) => Some((*size, *activation)),
+                LayerSpec::Dropout(_) => None,
+            });
+        let (size, activation) = match previous {
+            Some((size, Activation::Relu)) => (size, Activation::Relu),
+            Some((size, _)) => (size, Activation::None),
+            None => (self.model.inputs.len(), Activation::None),
+        };
+
+        let mut values = self.dense_values();
+        let has_bias = values[0].1.is_some();
+        let identity = (0..size * size)
+            .map(|i| if i / size == i % size { 1. } else { 0. })
+            .collect();
+        values.insert(position, (identity, has_bias.then(|| vec![0.; size])));
+
+        let mut specs = self.specs.clone();
+        specs.insert(position, LayerSpec::Dense(size, activation));
+        self.rebuilt(specs, values, Some(position))
+    }
+
+    /// Current weights and biases of every dense layer, indexed like `specs` with empty weights
+    /// for the other layers.
+    fn dense_values(&self) -> Vec<(Vec<f64>, Option<Vec<f64>>)> {
+        let value = |t: &StateTensor| -> Vec<f64> {
+            t.ids
+                .iter()
+                .map(|id| self.graph.value_for_id(*id))
+                .collect()
+        };
+        self.specs
+            .iter()
+            .zip(self.layers.iter())
+            .map(|(spec, layer)| match spec {
+                LayerSpec::Dense(..) => {
+                    let state = layer.state();
+                    (value(&state[0]), state.get(1).map(value))
+                }
+                LayerSpec::Dropout(_) => (vec![], None),
+            })
+            .collect()
+    }
+
+    /// Network with the given layers and dense layer values, and the same head and build
+    /// options, where `inserted` is the position of a layer added to the current ones.
+    fn rebuilt(
+        &self,
+        specs: Vec<LayerSpec>,
+        values: Vec<(Vec<f64>, Option<Vec<f64>>)>,
+        inserted: Option<usize>,
+    ) -> MultiLayerPerceptron {
+        let mut size = self.model.inputs.len();
+        let mut layers: Vec<Box<dyn Layer>> = specs
+            .iter()
+            .zip(values)
+            .map(|(spec, (weights, biases))| match *spec {
+                LayerSpec::Dense(out, activation) => {
+                    let fan_in = size;
+                    size = out;
+                    Box::new(Linear::from_values(
+                        fan_in, out, weights, biases, activation,
+                    )) as Box<dyn Layer>
+                }
+                LayerSpec::Dropout(p) => Box::new(Dropout(p)),
+            })
+            .collect();
+        self.head.push_layer(size, &mut layers);
+
+        let mut options = self.model.options.clone();
+        if let Some(position) = inserted {
+            options
+                .branches
+                .iter_mut()
+                .filter(|start| **start > position)
+                .for_each(|start| *start += 1);
+        }
+        let mut model = Sequential::build(self.model.inputs.len(), layers, options);
+        model.set_training(self.is_training());
+        MultiLayerPerceptron {
+            model,
+            specs,
+            head: self.head,
+        }
+    }
+}
+
+/// Evaluates `loss`, given as a function of the values of `outputs` returning the loss and its
+/// gradient (see the `loss` module), into gradients to be seeded into the outputs.
+fn loss_gradients(
+    graph: &RunnableGraph,
+    outputs: &[NodeId],
+    loss: impl FnOnce(&[f64]) -> (f64, Vec<f64>),
+) -> (Vec<(NodeId, f64)>, f64) {
+    let values: Vec<f64> = outputs.iter().map(|id| graph.value_for_id(*id)).collect();
+    let (loss, grads) = loss(&values);
+    (outputs.iter().cloned().zip(grads).collect(), loss)
+}
+
+/// Several stacks of layers applied to the same inputs, e.g. the output heads of a multi-task
+/// model, with their outputs concatenated.
+#[derive(Debug)]
+pub struct Heads(pub Vec<Vec<Box<dyn Layer>>>);
+
+impl Heads {
+    /// Number of outputs of each head.
+    pub fn sizes(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.0
+            .iter()
+            .map(|head| {
+                head.iter()
+                    .fold(input_shape.to_vec(), |shape, layer| {
+                        layer.output_shape(&shape)
+                    })
+                    .iter()
+                    .product()
+            })
+            .collect()
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &Box<dyn Layer>> {
+        self.0.iter().flatten()
+    }
+}
+
+impl Layer for Heads {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        self.0
+            .iter()
+            .flat_map(|head| head.iter().fold(inputs.clone(), |x, layer| layer.build(x)))
+            .collect()
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![self.sizes(input_shape).iter().sum()]
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        self.layers().flat_map(|layer| layer.parameters()).collect()
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        self.0
+            .iter()
+            .enumerate()
+            .flat_map(|(h, head)| {
+                head.iter().enumerate().flat_map(move |(i, layer)| {
+                    StateTensor::prefixed(&format!("{h}.{i}"), layer.state())
+                })
+            })
+            .collect()
+    }
+}
+
+/// Mixture of experts: stacks of layers applied to the same inputs, whose outputs are averaged
+/// with weights given by a softmax gate over the inputs. The gate is trained along with the
+/// experts, learning which of them to rely on for each input.
+#[derive(Debug)]
+pub struct MixtureOfExperts {
+    experts: Vec<Vec<Box<dyn Layer>>>,
+    gate: Linear,
+}
+
+impl MixtureOfExperts {
+    /// Every expert must map the `num_inputs` inputs to outputs of the same shape.
+    pub fn new(
+        num_inputs: usize,
+        experts: Vec<Vec<Box<dyn Layer>>>,
+        rng: &mut impl Rng,
+    ) -> MixtureOfExperts {
+        if experts.is_empty() {
+            panic!("Expected at least one expert")
+        }
+        let gate = Linear::new(num_inputs, experts.len(), Activation::None, rng);
+        MixtureOfExperts { experts, gate }
+    }
+
+    pub fn num_experts(&self) -> usize {
+        self.experts.len()
+    }
+}
+
+impl Layer for MixtureOfExperts {
+    fn build<'a>(&self, inputs: Vec<GraphBuilder<'a>>) -> Vec<GraphBuilder<'a>> {
+        let gates = GraphBuilder::softmax(&self.gate.build(inputs.clone()));
+        let outputs: Vec<Vec<GraphBuilder>> = self
+            .experts
+            .iter()
+            .map(|expert| {
+                expert
+                    .iter()
+                    .fold(inputs.clone(), |x, layer| layer.build(x))
+            })
+            .collect();
+
+        (0..outputs[0].len())
+            .map(|o| {
+                outputs
+                    .iter()
+                    .zip(gates.iter())
+                    .map(|(expert, gate)| gate.clone() * &expert[o])
+                    .reduce(|sum, y| sum + y)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let shapes: Vec<Vec<usize>> = self
+            .experts
+            .iter()
+            .map(|expert| {
+                expert.iter().fold(input_shape.to_vec(), |shape, layer| {
+                    layer.output_shape(&shape)
+                })
+            })
+            .collect();
+        if let Some(shape) = shapes.iter().find(|shape| **shape != shapes[0]) {
+            panic!(
+                "Expected expert output shape {:?}, but got {:?}",
+                shapes[0], shape
+            )
+        }
+        shapes[0].clone()
+    }
+
+    fn parameters(&self) -> Vec<NodeId> {
+        let mut ids = self.gate.parameters();
+        ids.extend(self.experts.iter().flatten().flat_map(|l| l.parameters()));
+        ids
+    }
+
+    fn state(&self) -> Vec<StateTensor> {
+        let mut state = StateTensor::prefixed("gate", self.gate.state());
+        self.experts.iter().enumerate().for_each(|(e, expert)| {
+            expert.iter().enumerate().for_each(|(i, layer)| {
+                state.extend(StateTensor::prefixed(
+                    &format!("experts.{e}.{i}"),
+                    layer.state(),
+                ))
+            })
+        });
+        state
+    }
+}
+
+/// Model with a shared trunk followed by several output heads, each of which gets its own
+/// outputs and output gradients, e.g. to predict a digit's class and its parity at once. All
+/// of `Sequential` is available on the whole model, whose outputs are those of the heads
+/// concatenated.
+#[derive(Debug)]
+pub struct MultiHeadModel {
+    model: Sequential,
+    /// Range of the model's outputs belonging to each head.
+    heads: Vec<std::ops::Range<usize>>,
+}
+
+impl MultiHeadModel {
+    pub fn new(
+        num_inputs: usize,
+        trunk: Vec<Box<dyn Layer>>,
+        heads: Vec<Vec<Box<dyn Layer>>>,
+    ) -> MultiHeadModel {
+        let trunk_shape = trunk
+            .iter()
+            .fold(vec![num_inputs], |shape, layer| layer.output_shape(&shape));
+        let heads_layer = Heads(heads);
+        let ranges = heads_layer
+            .sizes(&trunk_shape)
+            .iter()
+            .scan(0, |start, size| {
+                *start += size;
+                Some(*start - size..*start)
+            })
+            .collect();
+
+        let mut layers = trunk;
+        layers.push(Box::new(heads_layer));
+        MultiHeadModel {
+            model: Sequential::new(num_inputs, layers),
+            heads: ranges,
+        }
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.heads.len()
+    }
+
+    /// Outputs of each head.
+    pub fn forward_heads(&mut self, inputs: &[f64]) -> Vec<Vec<f64>> {
+        let outputs = self.model.forward(inputs);
+        self.heads
+            .iter()
+            .map(|r| outputs[r.clone()].to_vec())
+            .collect()
+    }
+
+    /// Backpropagates the gradients of the outputs of each head, which accumulate in the trunk.
+    pub fn backward_heads(&mut self, out_grads: Vec<Vec<f64>>) {
+        if out_grads.len() != self.heads.len() {
+            panic!(
+                "Expected {} heads, but got {}",
+                self.heads.len(),
+                out_grads.len()
+            )
+        }
+        self.model.backward(out_grads.concat());
+    }
+
+    /// Backpropagates the sum of the cross-entropies of the heads, whose outputs are taken as
+    /// logits, against their target classes, and returns it.
+    pub fn backward_cross_entropy(&mut self, target_classes: &[usize]) -> f64 {
+        if target_classes.len() != self.heads.len() {
+            panic!(
+                "Expected {} heads, but got {}",
+                self.heads.len(),
+                target_classes.len()
+            )
+        }
+        let (grads, losses): (Vec<Vec<(NodeId, f64)>>, Vec<f64>) = self
+            .heads
+            .iter()
+            .zip(target_classes)
+            .map(|(head, target)| {
+                let logits = &self.model.outputs[head.clone()];
+                loss_gradients(&self.model.graph, logits, |z| {
+                    loss::cross_entropy_with_logits(z, *target)
+                })
+            })
+            .unzip();
+        self.model.backwards(grads.concat());
+        losses.iter().sum()
+    }
+}
+
+impl Deref for MultiHeadModel {
+    type Target = Sequential;
+
+    fn deref(&self) -> &Sequential {
+        &self.model
+    }
+}
+
+impl DerefMut for MultiHeadModel {
+    fn deref_mut(&mut self) -> &mut Sequential {
+        &mut self.model
+    }
+}
+
+/// Independently initialised and trained classifiers whose predicted class probabilities are
+/// averaged.
+#[derive(Debug)]
+pub struct Ensemble {
+    models: Vec<MultiLayerPerceptron>,
+}
+
+impl Ensemble {
+    pub fn new(models: Vec<MultiLayerPerceptron>) -> Ensemble {
+        if models.is_empty() {
+            panic!("An ensemble needs at least one model")
+        }
+        Ensemble { models }
+    }
+
+    /// `size` models made by `model`, given the index of each model, e.g. to seed them.
+    pub fn from_fn(size: usize, model: impl FnMut(usize) -> MultiLayerPerceptron) -> Ensemble {
+        Self::new((0..size).map(model).collect())
+    }
+
+    pub fn models(&self) -> &[MultiLayerPerceptron] {
+        &self.models
+    }
+
+    pub fn models_mut(&mut self) -> &mut [MultiLayerPerceptron] {
+        &mut self.models
+    }
+
+    /// Mean of the class probabilities predicted by the models.
+    pub fn predict_proba(&mut self, inputs: &[f64]) -> Vec<f64> {
+        let probs: Vec<Vec<f64>> = self
+            .models
+            .iter_mut()
+            .map(|m| m.predict_proba(inputs))
+            .collect();
+        (0..probs[0].len())
+            .map(|i| probs.iter().map(|p| p[i]).sum::<f64>() / probs.len() as f64)
+            .collect()
+    }
+
+    /// Most likely class according to `predict_proba`.
+    pub fn predict(&mut self, inputs: &[f64]) -> usize {
+        self.predict_proba(inputs)
+            .iter()
+            .enumerate()
+            .fold(
+                (0, f64::MIN),
+                |best, (i, p)| if *p > best.1 { (i, *p) } else { best },
+            )
+            .0
+    }
+
+    /// Trains every model with cross-entropy for `epochs` passes over `samples` of inputs and
+    /// target class, each model with its own optimiser from `optimiser` and its own shuffling
+    /// of the samples. Returns the loss of each epoch, averaged over samples and models.
+    pub fn train<O: Optimiser>(
+        &mut self,
+        samples: &[(Vec<f64>, usize)],
+        epochs: usize,
+        mut optimiser: impl FnMut(&MultiLayerPerceptron) -> O,
+        rng: &mut impl Rng,
+    ) -> Vec<f64> {
+        let mut optimisers: Vec<O> = self.models.iter().map(&mut optimiser).collect();
+        let mut order: Vec<usize> = (0..samples.len()).collect();
+
+        (0..epochs)
+            .map(|_| {
+                let mut total = 0.;
+                self.models
+                    .iter_mut()
+                    .zip(optimisers.iter_mut())
+                    .for_each(|(model, optimiser)| {
+                        order.shuffle(rng);
+                        order.iter().for_each(|i| {
+                            let (x, y) = &samples[*i];
+                            model.forward(x);
+                            model.zero_grads();
+                            total += model.backward_cross_entropy(*y);
+                            model.update_weights(optimiser);
+                        });
+                    });
+                total / (samples.len() * self.models.len()) as f64
+            })
+            .collect()
+    }
+}
+
+/// Encoder and mirrored decoder MLPs trained to reconstruct their inputs, e.g. to pretrain on
+/// unlabelled data. With tied weights, each decoder layer uses the transposed weights of the
+/// matching encoder layer.
+#[derive(Debug)]
+pub struct Autoencoder {
+    model: Sequential,
+    /// Index of the last encoder layer, whose outputs are the codes.
+    code_layer: usize,
+}
+
+impl Autoencoder {
+    /// `sizes` describes the encoder, from the inputs down to the code, e.g. `[784, 128, 32]`.
+    /// Hidden layers use `activation` and the reconstruction uses `output`, e.g.
+    /// `Activation::Sigmoid` for pixels in [0, 1].
+    pub fn new(
+        sizes: &[usize],
+        activation: Activation,
+        output: Activation,
+        tied: bool,
+        seed: Option<u64>,
+    ) -> Autoencoder {
+        if sizes.len() < 2 {
+            panic!("Expected at least 2 sizes, but got {}", sizes.len())
+        }
+        let mut rng = seed
+            .map(StdRng::seed_from_u64)
+            .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap());
+
+        let encoder: Vec<Linear> = sizes
+            .windows(2)
+            .map(|w| Linear::new(w[0], w[1], activation, &mut rng))
+            .collect();
+        let decoder: Vec<Linear> = encoder
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, layer)| {
+                let activation = if i == 0 { output } else { activation };
+                match tied {
+                    true => layer.tied(activation),
+                    false => Linear::new(sizes[i + 1], sizes[i], activation, &mut rng),
+                }
+            })
+            .collect();
+
+        let code_layer = encoder.len() - 1;
+        let layers = encoder
+            .into_iter()
+            .chain(decoder)
+            .map(|l| Box::new(l) as Box<dyn Layer>)
+            .collect();
+        let options = BuildOptions {
+            branches: vec![code_layer + 1],
+            ..Default::default()
+        };
+        let mut model = Sequential::build(sizes[0], layers, options);
+        if let Some(seed) = seed {
+            model.seed(seed);
+        }
+
+        Autoencoder { model, code_layer }
+    }
+
+    /// Code of `inputs`, i.e. the outputs of the encoder, which is all that gets evaluated.
+    pub fn encode(&mut self, inputs: &[f64]) -> Vec<f64> {
+        self.model.set_inputs(inputs);
+        let code = self.model.layer_outputs[self.code_layer].clone();
+        self.model.graph.evaluate(&code)
+    }
+
+    /// Reconstruction of the inputs a code was computed from, evaluating the decoder alone.
+    pub fn decode(&mut self, code: &[f64]) -> Vec<f64> {
+        self.model.forward_branch(0, code)
+    }
+
+    /// Backpropagates the reconstruction `loss` of the last `forward` against its inputs,
+    /// returning the loss.
+    pub fn backward_reconstruction(&mut self, loss: RegressionLoss) -> f64 {
+        let inputs: Vec<f64> = self
+            .model
+            .inputs
+            .iter()
+            .map(|id| self.model.graph.value_for_id(*id))
+            .collect();
+        let (grads, loss) = loss_gradients(&self.model.graph, &self.model.outputs, |y| {
+            loss.apply(y, &inputs)
+        });
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// Single optimisation step reconstructing `inputs`, returning the loss before the step.
+    pub fn train_step(
+        &mut self,
+        inputs: &[f64],
+        loss: RegressionLoss,
+        optimiser: &mut impl Optimiser,
+    ) -> f64 {
+        self.model.forward(inputs);
+        self.model.zero_grads();
+        let loss = self.backward_reconstruction(loss);
+        self.model.update_weights(optimiser);
+        loss
+    }
+}
+
+impl Deref for Autoencoder {
+    type Target = Sequential;
+
+    fn deref(&self) -> &Sequential {
+        &self.model
+    }
+}
+
+impl DerefMut for Autoencoder {
+    fn deref_mut(&mut self) -> &mut Sequential {
+        &mut self.model
+    }
+}
+
+/// Twin network embedding two inputs with the same layers, built twice into one graph so that
+/// both towers share their parameters, for similarity learning with a contrastive loss. Triplet
+/// networks embed a third input in the same way, for the triplet loss.
+#[derive(Debug)]
+pub struct Siamese {
+    model: Sequential,
+}
+
+impl Siamese {
+    pub fn new(num_inputs: usize, layers: Vec<Box<dyn Layer>>) -> Siamese {
+        Self::with_towers(num_inputs, layers, 2)
+    }
+
+    /// Network with three towers, for anchors, positives and negatives.
+    pub fn new_triplet(num_inputs: usize, layers: Vec<Box<dyn Layer>>) -> Siamese {
+        Self::with_towers(num_inputs, layers, 3)
+    }
+
+    fn with_towers(num_inputs: usize, layers: Vec<Box<dyn Layer>>, towers: usize) -> Siamese {
+        let options = BuildOptions {
+            branches: vec![0; towers - 1],
+            ..Default::default()
+        };
+        Siamese {
+            model: Sequential::build(num_inputs, layers, options),
+        }
+    }
+
+    /// Outputs of tower `index`, the first of which is the main path of the model.
+    fn tower(&self, index: usize) -> &[NodeId] {
+        match index {
+            0 => &self.model.outputs,
+            _ => &self.model.branches[index - 1].outputs,
+        }
+    }
+
+    /// Outputs of every tower but the main path.
+    fn branch_outputs(&self) -> Vec<NodeId> {
+        (1..=self.model.branches.len())
+            .flat_map(|i| self.tower(i).to_vec())
+            .collect()
+    }
+
+    fn tower_values(&self, index: usize) -> Vec<f64> {
+        self.tower(index)
+            .iter()
+            .map(|id| self.model.graph.value_for_id(*id))
+            .collect()
+    }
+
+    /// Embeddings of both inputs.
+    pub fn forward_pair(&mut self, left: &[f64], right: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        self.model.set_branch_inputs(0, right);
+        let left = self.model.forward_with(left, &self.branch_outputs());
+        (left, self.tower_values(1))
+    }
+
+    /// Embeddings of an anchor, a positive and a negative, with a network from `new_triplet`.
+    pub fn forward_triplet(
+        &mut self,
+        anchor: &[f64],
+        positive: &[f64],
+        negative: &[f64],
+    ) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+        if self.model.branches.len() != 2 {
+            panic!("Expected a triplet network, see Siamese::new_triplet")
+        }
+        self.model.set_branch_inputs(0, positive);
+        self.model.set_branch_inputs(1, negative);
+        let anchor = self.model.forward_with(anchor, &self.branch_outputs());
+        (anchor, self.tower_values(1), self.tower_values(2))
+    }
+
+    /// Backpropagates the triplet loss of the last `forward_triplet` (see `loss::triplet_loss`)
+    /// and returns it.
+    pub fn backward_triplet(&mut self, margin: f64) -> f64 {
+        let (loss, anchor, positive, negative) = loss::triplet_loss(
+            &self.tower_values(0),
+            &self.tower_values(1),
+            &self.tower_values(2),
+            margin,
+        );
+        let grads = [anchor, positive, negative]
+            .iter()
+            .enumerate()
+            .flat_map(|(i, grads)| self.tower(i).iter().cloned().zip(grads.clone()))
+            .collect();
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// Single optimisation step on a triplet, returning the loss before the step.
+    pub fn train_step_triplet(
+        &mut self,
+        anchor: &[f64],
+        positive: &[f64],
+        negative: &[f64],
+        margin: f64,
+        optimiser: &mut impl Optimiser,
+    ) -> f64 {
+        self.forward_triplet(anchor, positive, negative);
+        self.model.zero_grads();
+        let loss = self.backward_triplet(margin);
+        self.model.update_weights(optimiser);
+        loss
+    }
+
+    /// Backpropagates the contrastive loss of the last `forward_pair`, which pulls the
+    /// embeddings of similar pairs together and pushes dissimilar ones at least `margin`
+    /// apart, returning the loss `d^2` or `max(0, margin - d)^2` for a distance `d`.
+    pub fn backward_contrastive(&mut self, similar: bool, margin: f64) -> f64 {
+        let (left, right) = (self.tower(0), self.tower(1));
+        let diffs: Vec<f64> = left
+            .iter()
+            .zip(right.iter())
+            .map(|(l, r)| self.model.graph.value_for_id(*l) - self.model.graph.value_for_id(*r))
+            .collect();
+        let distance = diffs.iter().map(|d| d * d).sum::<f64>().sqrt();
+
+        // Gradient of the loss with respect to the distance, divided by the distance, so that
+        // it scales the difference of the embeddings.
+        let (loss, scale) = match similar {
+            true => (distance.powi(2), 2.),
+            false if distance < margin && distance > 0. => (
+                (margin - distance).powi(2),
+                -2. * (margin - distance) / distance,
+            ),
+            false => ((margin - distance).max(0.).powi(2), 0.),
+        };
+
+        let grads = left
+            .iter()
+            .zip(right.iter())
+            .zip(diffs.iter())
+            .flat_map(|((l, r), d)| [(*l, scale * d), (*r, -scale * d)])
+            .collect();
+        self.model.backwards(grads);
+        loss
+    }
+
+    /// Single optimisation step on a pair, returning the loss before the step.
+    pub fn train_step_contrastive(
+        &mut self,
+        left: &[f64],
+        right: &[f64],
+        similar: bool,
+        margin: f64,
+        optimiser: &mut impl Optimiser,
+    ) -> f64 {
+        self.forward_pair(left, right);
+        self.model.zero_grads();
+        let loss = self.backward_contrastive(similar, margin);
+        self.model.update_weights(optimiser);
+        loss
+    }
+}
+
+impl Deref for Siamese {
+    type Target = Sequential;
+
+    fn deref(&self) -> &Sequential {
+        &self.model
+    }
+}
+
+impl DerefMut for Siamese {
+    fn deref_mut(&mut self) -> &mut Sequential {
+        &mut self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        data::{DataLoader, OneHot},
+        nn::*,
+        optimiser::{
+            AdamOptimiser, CentralisedOptimiser, EvolutionOptimiser, ExponentialAverage,
+            GroupedOptimiser, LambOptimiser, LbfgsOptimiser, LearningRateOptimiser, SamOptimiser,
+            WeightAverage,
+        },
+        scheduler::{ExponentialLr, Scheduled, Warmup},
+        util::{Mean, Util},
+    };
+
+    #[test]
+    fn test_mlp_xor() {
+        let xy: &Vec<(Vec<f64>, usize)> = &vec![
+            (vec![1., 0.], 1),
+            (vec![0., 1.], 1),
+            (vec![1., 1.], 0),
+            (vec![0., 0.], 0),
+        ];
+        let one_hot = OneHot::new(xy, 2);
+
+        let mut mlp = MultiLayerPerceptron::new(Vec::from([xy[0].0.len(), 2, 2]), Some(4));
+
+        let optimiser = &mut LearningRateOptimiser::new(0.1);
+        let mut loader = DataLoader::new(&one_hot, 1, Some(0));
+
+        let epochs = 1000;
+        for i in 0..epochs {
+            let (acc, loss): (Vec<f64>, Vec<f64>) = loader
+                .batches()
+                .flatten()
+                .map(|(x, y)| {
+                    let y_preds = mlp.forward(&x);
+
+                    let loss = y
+                        .iter()
+                        .zip(y_preds.iter())
+                        .map(|(y, y_pred)| (y_pred - y).powf(2.))
+                        .sum::<f64>();
+
+                    let grads: Vec<f64> = y
+                        .iter()
+                        .zip(y_preds.iter())
+                        .map(|(y, y_pred)| y_pred - y)
+                        .collect();
+
+                    mlp.zero_grads();
+                    mlp.backward(grads);
+                    mlp.update_weights(optimiser);
+
+                    let acc = if Util::argmax(&y_preds) == Util::argmax(&y) {
+                        1.0
+                    } else {
+                        0.0
+                    };
+
+                    (acc, loss)
+                })
+                .unzip();
+
+            if i % 100 == 0 {
+                println!(
+                    "Epoch {i} - Acc={:?}, Loss={:?}",
+                    acc.iter().mean(),
+                    loss.iter().mean()
+                );
+            }
+        }
+
+        let acc = xy
+            .iter()
+            .map(|(x, y)| {
+                let y_preds = mlp.forward(x);
+                if Util::argmax(&y_preds) == *y {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .mean();
+
+        assert_eq!(acc, 1.0)
+    }
+
+    #[test]
+    fn test_forward_backward_batch() {
+        let xs = vec![vec![1., -2., 0.5], vec![0.3, 0.2, -1.], vec![-1., 1., 1.]];
+        let mut mlp = MultiLayerPerceptron::new(vec![3, 4, 2], Some(1));
+
+        let batch = mlp.forward_batch(&xs);
+        let single: Vec<Vec<f64>> = xs.iter().map(|x| mlp.forward(x)).collect();
+        batch
+            .iter()
+            .flatten()
+            .zip(single.iter().flatten())
+            .for_each(|(b, s)| {
+                assert!((b - s).abs() < 1e-12);
+            });
+
+        mlp.per_sample_gradients(&xs, |_, y| y.to_vec());
+        let expected = mlp.graph.gradients();
+
+        mlp.zero_grads();
+        mlp.forward_batch(&xs);
+        mlp.backward_batch(batch.clone());
+
+        mlp.graph
+            .parameter_ids()
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(id, g)| assert!((mlp.graph.grad_for_id(*id) - g).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_seeded_init() {
+        let x = vec![0.5, -1., 2.];
+
+        let mut a = MultiLayerPerceptron::new(vec![3, 4, 2], Some(7));
+        let mut b = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .output(2, Activation::None)
+            .seed(7)
+            .build();
+        assert_eq!(a.forward(&x), b.forward(&x));
+
+        let first_layer: Vec<f64> = a.layers()[0]
+            .parameters()
+            .iter()
+            .map(|id| a.graph.value_for_id(*id))
+            .collect();
+        assert_ne!(first_layer[0..3], first_layer[3..6]);
+    }
+
+    #[test]
+    fn test_init_schemes() {
+        let mlp = MultiLayerPerceptron::builder()
+            .input(50)
+            .output(30, Activation::None)
+            .init(Init::XavierUniform, Some(Init::Zeros))
+            .seed(0)
+            .build();
+
+        let bound = (6. / 80_f64).sqrt();
+        let parameters = mlp.layers()[0].parameters();
+        let (weights, biases) = parameters.split_at(50 * 30);
+        weights.iter().for_each(|id| {
+            assert!(mlp.graph.value_for_id(*id).abs() <= bound);
+        });
+        biases
+            .iter()
+            .for_each(|id| assert_eq!(mlp.graph.value_for_id(*id), 0.));
+
+        let mlp = MultiLayerPerceptron::builder()
+            .input(200)
+            .output(50, Activation::None)
+            .init(Init::KaimingNormal, Some(Init::Zeros))
+            .seed(1)
+            .build();
+        let weights: Vec<f64> = mlp.layers()[0]
+            .parameters()
+            .iter()
+            .take(200 * 50)
+            .map(|id| mlp.graph.value_for_id(*id))
+            .collect();
+        let variance = weights.iter().map(|w| w * w).mean();
+        assert!((variance - 0.01).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_activations() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(3, Activation::Tanh)
+            .output(1, Activation::Sigmoid)
+            .seed(3)
+            .build();
+
+        let xs = vec![vec![-5., 5.], vec![2., 1.]];
+        xs.iter().for_each(|x| {
+            let y = mlp.forward(x)[0];
+            assert!(y > 0. && y < 1.);
+        });
+        let batch = mlp.forward_batch(&xs);
+        assert!((batch[1][0] - mlp.forward(&xs[1])[0]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_without_bias() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .output(2, Activation::None)
+            .init(Init::Uniform(-1., 1.), None)
+            .seed(2)
+            .build();
+        assert_eq!(mlp.num_parameters(), 3 * 4 + 4 * 2);
+
+        // Without biases, relu layers are positively homogeneous.
+        let x = vec![0.5, -1., 2.];
+        let scaled: Vec<f64> = x.iter().map(|v| v * 3.).collect();
+        let y = mlp.forward(&x);
+        let y_scaled = mlp.forward(&scaled);
+        y.iter()
+            .zip(y_scaled.iter())
+            .for_each(|(y, ys)| assert!((3. * y - ys).abs() < 1e-12));
+
+        let batch = mlp.forward_batch(&[x]);
+        y.iter()
+            .zip(batch[0].iter())
+            .for_each(|(y, b)| assert!((y - b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_sequential() {
+        let rng = &mut StdRng::seed_from_u64(5);
+        let mut model = Sequential::new(
+            2,
+            vec![
+                Box::new(Linear::new(2, 3, Activation::None, rng)),
+                Box::new(Activation::Tanh),
+                Box::new(
+                    Linear::builder(3, 1)
+                        .activation(Activation::Sigmoid)
+                        .init(Init::XavierUniform, None)
+                        .build(rng),
+                ),
+            ],
+        );
+        assert_eq!(model.num_parameters(), 2 * 3 + 3 + 3);
+        assert_eq!(model.layers()[1].parameters().len(), 0);
+
+        let xs = vec![vec![0.5, -1.], vec![2., 0.]];
+        let batch = model.forward_batch(&xs);
+        xs.iter().zip(batch.iter()).for_each(|(x, b)| {
+            let y = model.forward(x);
+            assert!(y[0] > 0. && y[0] < 1.);
+            assert!((y[0] - b[0]).abs() < 1e-12);
+        });
+    }
+
+    #[test]
+    fn test_dropout() {
+        let mut model = Sequential::new(100, vec![Box::new(Dropout(0.5))]);
+        let x = vec![1.; 100];
+
+        model.seed(3);
+        let y = model.forward(&x);
+        assert!(y.iter().all(|v| *v == 0. || *v == 2.));
+        assert!(y.contains(&0.) && y.contains(&2.));
+        model.seed(3);
+        assert_eq!(model.forward(&x), y);
+
+        let xs = vec![x.clone()];
+        let batch = model.forward_batch(&xs);
+        assert!(batch[0].iter().all(|v| *v == 0. || *v == 2.));
+        // The batched graph is kept, but draws a new mask on every evaluation.
+        assert_ne!(model.forward_batch(&xs), batch);
+
+        model.set_training(false);
+        assert_eq!(model.forward(&x), x);
+        assert_eq!(model.evaluate_batch(&xs)[0], x);
+        assert_eq!(model.forward_batch(&xs)[0], x);
+    }
+
+    #[test]
+    fn test_gaussian_noise() {
+        let mut model = Sequential::new(100, vec![Box::new(GaussianNoise(0.5))]);
+        let x = vec![1.; 100];
+
+        model.seed(4);
+        let y = model.forward(&x);
+        let noise: Vec<f64> = y.iter().map(|v| v - 1.).collect();
+        assert!(noise.iter().mean().abs() < 0.2);
+        let std = noise.iter().map(|n| n * n).mean().sqrt();
+        assert!((std - 0.5).abs() < 0.15);
+        model.seed(4);
+        assert_eq!(model.forward(&x), y);
+
+        model.zero_grads();
+        model.backward(vec![1.; 100]);
+        let grads = model.graph.input_gradients(&model.inputs);
+        assert!(grads.iter().all(|g| *g == 1.));
+
+        let xs = vec![x.clone()];
+        assert_ne!(model.forward_batch(&xs)[0], x);
+
+        model.eval();
+        assert_eq!(model.forward(&x), x);
+        assert_eq!(model.forward_batch(&xs)[0], x);
+    }
+
+    #[test]
+    fn test_batch_norm() {
+        let mut model = Sequential::new(2, vec![Box::new(BatchNorm::new(2))]);
+        assert_eq!(model.num_parameters(), 4);
+
+        let xs = vec![vec![1., 10.], vec![2., 20.], vec![3., 60.]];
+        let ys = model.forward_batch(&xs);
+        (0..2).for_each(|j| {
+            let column: Vec<f64> = ys.iter().map(|y| y[j]).collect();
+            assert!(column.iter().mean().abs() < 1e-9);
+            assert!((column.iter().map(|v| v * v).mean() - 1.).abs() < 1e-4);
+        });
+
+        model.backward_batch(vec![vec![1., 1.]; 3]);
+        let grads = model.graph.gradients();
+        assert!(grads[0].abs() < 1e-9 && grads[1].abs() < 1e-9);
+        assert_eq!(&grads[2..], &[3., 3.]);
+
+        // One step of the running statistics from mean 0 and variance 1.
+        model.set_training(false);
+        let expected_mean = 0.1 * 2.;
+        let expected_var = 0.9 + 0.1 * 1.;
+        let y = model.forward(&[2., 0.])[0];
+        assert!((y - (2. - expected_mean) / (expected_var + 1e-5_f64).sqrt()).abs() < 1e-9);
+        assert!((model.forward_batch(&[vec![2., 0.]])[0][0] - y).abs() < 1e-12);
+
+        // The kept training graph goes on updating them, and the eval graph picks them up.
+        model.set_training(true);
+        model.forward_batch(&xs);
+        model.set_training(false);
+        let expected_mean = 0.9 * expected_mean + 0.1 * 2.;
+        let expected_var = 0.9 * expected_var + 0.1 * 1.;
+        let y = model.forward_batch(&[vec![2., 0.]])[0][0];
+        assert!((y - (2. - expected_mean) / (expected_var + 1e-5_f64).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_batch_graph_reuse() {
+        let mut mlp = MultiLayerPerceptron::new(vec![3, 4, 2], Some(2));
+        let xs = vec![vec![0.5, -1., 2.], vec![1., 0., -0.5]];
+        let optimiser = &mut LearningRateOptimiser::new(0.1);
+
+        for _ in 0..3 {
+            let batch = mlp.forward_batch(&xs);
+            xs.iter().zip(batch.iter()).for_each(|(x, b)| {
+                let y = mlp.forward(x);
+                y.iter()
+                    .zip(b.iter())
+                    .for_each(|(y, b)| assert!((y - b).abs() < 1e-12));
+            });
+
+            mlp.zero_grads();
+            mlp.forward_batch(&xs);
+            mlp.backward_batch(vec![vec![1., -1.]; 2]);
+            let batched = mlp.graph.gradients();
+            mlp.per_sample_gradients(&xs, |_, _| vec![1., -1.]);
+            mlp.graph
+                .gradients()
+                .iter()
+                .zip(batched.iter())
+                .for_each(|(g, b)| assert!((g - b).abs() < 1e-12));
+            mlp.update_weights(optimiser);
+        }
+
+        let y = mlp.forward(&xs[1]);
+        assert_eq!(mlp.forward_batch(&xs[1..])[0], y);
+    }
+
+    #[test]
+    fn test_layer_norm() {
+        let mut model = Sequential::new(3, vec![Box::new(LayerNorm::new(3))]);
+        let xs = vec![vec![1., 2., 6.], vec![-4., 0., 1.]];
+
+        let batch = model.forward_batch(&xs);
+        xs.iter().zip(batch.iter()).for_each(|(x, b)| {
+            let y = model.forward(x);
+            assert!(y.iter().mean().abs() < 1e-9);
+            assert!((y.iter().map(|v| v * v).mean() - 1.).abs() < 1e-4);
+            y.iter()
+                .zip(b.iter())
+                .for_each(|(y, b)| assert!((y - b).abs() < 1e-12));
+        });
+
+        let out_grads = vec![vec![1., -2., 0.5], vec![0., 1., 3.]];
+        model.per_sample_gradients(&xs, |i, _| out_grads[i].clone());
+        let expected = model.graph.gradients();
+
+        model.zero_grads();
+        model.forward_batch(&xs);
+        model.backward_batch(out_grads);
+        model
+            .graph
+            .gradients()
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(g, e)| assert!((g - e).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_conv2d() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let conv = Conv2d::new((1, 3, 3), 2, 2, 1, 0, rng);
+        assert_eq!(conv.output_shape(&[1, 3, 3]), vec![2, 2, 2]);
+        assert_eq!(
+            Conv2d::new((1, 5, 5), 1, 3, 2, 1, rng).output_shape(&[1, 5, 5]),
+            vec![1, 3, 3]
+        );
+
+        let mut model = Sequential::new(9, vec![Box::new(conv)]);
+        assert_eq!(model.num_parameters(), 2 * 4 + 2);
+
+        let x: Vec<f64> = (0..9).map(|v| v as f64).collect();
+        let y = model.forward(&x);
+        let w: Vec<f64> = model.layers()[0]
+            .parameters()
+            .iter()
+            .map(|id| model.graph.value_for_id(*id))
+            .collect();
+        // Top-left and bottom-right windows of the first output channel.
+        assert!((y[0] - (w[0] * 0. + w[1] * 1. + w[2] * 3. + w[3] * 4.)).abs() < 1e-12);
+        assert!((y[3] - (w[0] * 4. + w[1] * 5. + w[2] * 7. + w[3] * 8.)).abs() < 1e-12);
+
+        // Shared weights accumulate the gradient of every window they were applied to.
+        model.backward(vec![1.; 8]);
+        let grads = model.graph.gradients();
+        assert_eq!(&grads[0..4], &[8., 12., 20., 24.]);
+        assert_eq!(&grads[8..10], &[4., 4.]);
+    }
+
+    #[test]
+    fn test_conv2d_batch() {
+        let rng = &mut StdRng::seed_from_u64(3);
+        let mut model = Sequential::new(
+            2 * 4 * 4,
+            vec![Box::new(Conv2d::new((2, 4, 4), 3, 3, 2, 1, rng))],
+        );
+        let xs: Vec<Vec<f64>> = (0..3)
+            .map(|i| {
+                (0..32)
+                    .map(|v| ((v * 7 + i * 5) % 11) as f64 - 5.)
+                    .collect()
+            })
+            .collect();
+        check_batch(&mut model, &xs);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a stride of at least 1")]
+    fn test_conv2d_zero_stride() {
+        Conv2d::new((1, 3, 3), 1, 2, 0, 0, &mut StdRng::seed_from_u64(0));
+    }
+
+    #[test]
+    fn test_pooling() {
+        let x = vec![
+            1., 5., 2., 0., //
+            3., 4., 8., 1., //
+            0., 2., 6., 6., //
+            7., 1., 3., 2.,
+        ];
+
+        let mut max = Sequential::new(16, vec![Box::new(MaxPool2d::new((1, 4, 4), 2, 2))]);
+        assert_eq!(max.forward(&x), vec![5., 8., 7., 6.]);
+        max.backward(vec![1., 2., 3., 4.]);
+        let grads = max.input_gradients();
+        assert_eq!(grads.iter().sum::<f64>(), 10.);
+        assert_eq!((grads[1], grads[6], grads[12], grads[10]), (1., 2., 3., 4.));
+
+        let avg = AvgPool2d::new((1, 4, 4), 3, 1);
+        assert_eq!(avg.output_shape(&[1, 4, 4]), vec![1, 2, 2]);
+        let mut avg = Sequential::new(16, vec![Box::new(avg)]);
+        assert_eq!(avg.forward(&x)[0], 31. / 9.);
+        avg.backward(vec![9., 0., 0., 0.]);
+        let grads = avg.input_gradients();
+        assert_eq!(grads.iter().filter(|g| **g == 1.).count(), 9);
+        assert_eq!(grads.iter().sum::<f64>(), 9.);
+    }
+
+    #[test]
+    fn test_pooling_batch() {
+        let rng = &mut StdRng::seed_from_u64(4);
+        let xs: Vec<Vec<f64>> = (0..3)
+            .map(|_| (0..2 * 5 * 5).map(|_| rng.gen_range(-1. ..1.)).collect())
+            .collect();
+
+        let mut max = Sequential::builder(vec![1, 5, 5], Some(2))
+            .conv2d(2, 2, 1, 0)
+            .max_pool2d(2, 2)
+            .flatten()
+            .linear(3, Activation::Tanh)
+            .build();
+        let images: Vec<Vec<f64>> = xs.iter().map(|x| x[..25].to_vec()).collect();
+        check_batch(&mut max, &images);
+
+        let layers: Vec<Box<dyn Layer>> = vec![
+            Box::new(AvgPool2d::new((2, 5, 5), 3, 2)),
+            Box::new(Flatten),
+            Box::new(Linear::new(8, 2, Activation::None, rng)),
+        ];
+        let mut avg = Sequential::new(50, layers);
+        check_batch(&mut avg, &xs);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a stride of at least 1")]
+    fn test_max_pool2d_zero_stride() {
+        MaxPool2d::new((1, 4, 4), 2, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a kernel size of at least 1")]
+    fn test_avg_pool2d_zero_kernel() {
+        AvgPool2d::new((1, 4, 4), 0, 1);
+    }
+
+    #[test]
+    fn test_sequential_builder() {
+        let builder = Sequential::builder(vec![1, 6, 6], Some(0))
+            .conv2d(2, 3, 1, 1)
+            .max_pool2d(2, 2);
+        assert_eq!(builder.shape(), &[2, 3, 3]);
+
+        let builder = builder.flatten().linear(4, Activation::Relu);
+        assert_eq!(builder.shape(), &[4]);
+
+        let mut model = builder.layer(Dropout(0.1)).build();
+        assert_eq!(model.num_parameters(), 2 * 9 + 2 + 18 * 4 + 4);
+        assert_eq!(model.forward(&[0.5; 36]).len(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "add a Flatten layer first")]
+    fn test_sequential_builder_needs_flatten() {
+        Sequential::builder(vec![1, 4, 4], Some(0)).linear(2, Activation::None);
+    }
+
+    #[test]
+    fn test_embedding() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let embedding = Embedding::new(5, 2, rng);
+        assert_eq!(embedding.output_shape(&[3]), vec![3, 2]);
+
+        let mut model = Sequential::new(3, vec![Box::new(embedding)]);
+        let values = |model: &Sequential| -> Vec<f64> {
+            model
+                .graph
+                .parameter_ids()
+                .iter()
+                .map(|id| model.graph.value_for_id(*id))
+                .collect()
+        };
+        let rows = values(&model);
+
+        let y = model.forward(&[4., 1., 4.]);
+        assert_eq!(
+            y,
+            vec![rows[8], rows[9], rows[2], rows[3], rows[8], rows[9]]
+        );
+
+        // Rows that are not looked up keep their values and their Adam moments, rather than
+        // being moved by the momentum of earlier steps.
+        let optimiser = &mut AdamOptimiser::new(model.num_parameters());
+        model.backward(vec![1.; 6]);
+        model.update_weights(optimiser);
+        let first = values(&model);
+        let first_moments = optimiser.save_state()["m"].data().to_vec();
+
+        model.zero_grads();
+        model.forward(&[0., 0., 0.]);
+        model.backward(vec![1.; 6]);
+        model.update_weights(optimiser);
+        let second = values(&model);
+        let second_moments = optimiser.save_state()["m"].data().to_vec();
+        [2, 3, 8, 9]
+            .iter()
+            .for_each(|i| assert_eq!(second_moments[*i], first_moments[*i]));
+
+        (0..10).for_each(|i| match i / 2 {
+            0 => assert!(first[i] == rows[i] && second[i] < first[i]),
+            1 | 4 => assert!(first[i] < rows[i] && second[i] == first[i]),
+            _ => assert!(first[i] == rows[i] && second[i] == rows[i]),
+        });
+    }
+
+    #[test]
+    fn test_residual() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let inner = Linear::new(2, 2, Activation::None, rng);
+        let mut model = Sequential::new(2, vec![Box::new(Residual(inner))]);
+        assert_eq!(model.num_parameters(), 6);
+
+        let p: Vec<f64> = model.layers()[0]
+            .parameters()
+            .iter()
+            .map(|id| model.graph.value_for_id(*id))
+            .collect();
+        let x = vec![0.3, -2.];
+        let expected = [
+            x[0] + p[0] * x[0] + p[1] * x[1] + p[4],
+            x[1] + p[2] * x[0] + p[3] * x[1] + p[5],
+        ];
+
+        let y = model.forward(&x);
+        let batch = model.forward_batch(&[x]);
+        (0..2).for_each(|i| {
+            assert!((y[i] - expected[i]).abs() < 1e-12);
+            assert!((batch[0][i] - expected[i]).abs() < 1e-12);
+        });
+
+        model.backward(vec![1., 0.]);
+        assert_eq!(model.input_gradients(), vec![1. + p[0], p[1]]);
+    }
+
+    #[test]
+    fn test_weight_averaging() {
+        let mut mlp = MultiLayerPerceptron::new(vec![2, 3, 1], Some(8));
+        let x = [0.4, -0.7];
+        let optimiser = &mut LearningRateOptimiser::new(0.1);
+        let mut swa = WeightAverage::new();
+        let snapshots: Vec<Vec<f64>> = (0..3)
+            .map(|_| {
+                let (_, grads) = loss::mse(&mlp.forward(&x), &[1.]);
+                mlp.zero_grads();
+                mlp.backward(grads);
+                mlp.update_weights(optimiser);
+                swa.update(&mlp.parameters());
+                mlp.parameters()
+            })
+            .collect();
+        assert_eq!(snapshots[2].len(), mlp.num_parameters());
+
+        let last = mlp.forward(&x);
+        mlp.set_parameters(swa.average());
+        assert_ne!(mlp.forward(&x), last);
+        mlp.parameters().iter().enumerate().for_each(|(i, p)| {
+            let mean = snapshots.iter().map(|s| s[i]).sum::<f64>() / 3.;
+            assert!((p - mean).abs() < 1e-12);
+        });
+    }
+
+    #[test]
+    fn test_exponential_average() {
+        let mut mlp = MultiLayerPerceptron::new(vec![2, 3, 1], Some(9));
+        let x = [0.4, -0.7];
+        let optimiser = &mut LearningRateOptimiser::new(0.1);
+        let mut ema = ExponentialAverage::new(0.5);
+        let initial = mlp.parameters();
+        ema.update(&mlp.parameters());
+        let (_, grads) = loss::mse(&mlp.forward(&x), &[1.]);
+        mlp.zero_grads();
+        mlp.backward(grads);
+        mlp.update_weights(optimiser);
+        ema.update(&mlp.parameters());
+
+        let raw = mlp.parameters();
+        let average = ema.apply_ema(mlp.parameters());
+        mlp.set_parameters(average);
+        mlp.parameters()
+            .iter()
+            .zip(initial.iter().zip(raw.iter()))
+            .for_each(|(p, (i, r))| assert!((p - 0.5 * (i + r)).abs() < 1e-12));
+        mlp.set_parameters(&ema.restore());
+        assert_eq!(mlp.parameters(), raw);
+    }
+
+    #[test]
+    fn test_find_learning_rate() {
+        let rng = &mut StdRng::seed_from_u64(2);
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(Linear::new(2, 1, Activation::None, rng))];
+        let mut model = Sequential::new(2, layers);
+        let samples = [([0.5, -1.], 2.), ([1., 0.3], -1.), ([-0.4, 0.8], 0.5)];
+        let parameters = model.parameters();
+
+        let optimiser = &mut AdamOptimiser::new(model.num_parameters());
+        let range = model.find_learning_rate(optimiser, (1e-4, 10.), 200, |model, i| {
+            let (x, y) = samples[i % samples.len()];
+            let (loss, grads) = loss::mse(&model.forward(&x), &[y]);
+            model.backward(grads);
+            loss
+        });
+        assert_eq!(model.parameters(), parameters);
+        assert_eq!(optimiser.learning_rate(), 0.001);
+        let state = optimiser.save_state();
+        assert!(["m", "v", "t"]
+            .iter()
+            .all(|name| state[*name].data().iter().all(|v| *v == 0.)));
+
+        assert_eq!(range.learning_rates.len(), range.losses.len());
+        assert_eq!(range.learning_rates[0], 1e-4);
+        let ratios: Vec<f64> = range
+            .learning_rates
+            .windows(2)
+            .map(|w| w[1] / w[0])
+            .collect();
+        assert!(ratios.iter().all(|r| (r - ratios[0]).abs() < 1e-9));
+        assert!(range.suggestion > 1e-4 && range.suggestion < 10.);
+    }
+
+    #[test]
+    fn test_find_learning_rate_scheduled() {
+        let rng = &mut StdRng::seed_from_u64(2);
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(Linear::new(1, 1, Activation::None, rng))];
+        let mut model = Sequential::new(1, layers);
+
+        let warmup = Warmup {
+            steps: 4,
+            then: ExponentialLr { gamma: 1. },
+        };
+        let mut optimiser = Scheduled::new(LearningRateOptimiser::new(1.), warmup);
+        optimiser.step();
+        model.find_learning_rate(&mut optimiser, (1e-3, 1.), 10, |model, _| {
+            let (loss, grads) = loss::mse(&model.forward(&[1.]), &[0.]);
+            model.backward(grads);
+            loss
+        });
+        assert_eq!(optimiser.learning_rate(), 0.5);
+        assert_eq!(optimiser.step(), 0.75);
+    }
+
+    #[test]
+    fn test_lbfgs() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(4, Activation::Tanh)
+            .output(1, Activation::None)
+            .seed(5)
+            .build();
+        let xs = vec![vec![0., 0.], vec![0., 1.], vec![1., 0.], vec![1., 1.]];
+        let ys = [0., 1., 1., 0.];
+        let full_batch = |model: &mut Sequential| {
+            let (losses, grads): (Vec<f64>, Vec<Vec<f64>>) = model
+                .forward_batch(&xs)
+                .iter()
+                .zip(ys)
+                .map(|(outputs, y)| loss::mse(outputs, &[y]))
+                .unzip();
+            model.backward_batch(grads);
+            losses.iter().sum::<f64>()
+        };
+
+        let optimiser = &mut LbfgsOptimiser::new(10);
+        let initial = mlp.step_with(optimiser, full_batch);
+        (0..10).for_each(|_| {
+            mlp.step_with(optimiser, full_batch);
+        });
+        assert!(mlp.step_with(optimiser, full_batch) < initial / 100.);
+    }
+
+    #[test]
+    fn test_sam() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(4, Activation::Tanh)
+            .output(1, Activation::None)
+            .seed(5)
+            .build();
+        let frozen = mlp.layers()[0].parameters()[0];
+        mlp.graph.freeze(&[frozen]);
+        let frozen_value = mlp.graph.value_for_id(frozen);
+        let xs = vec![vec![0., 0.], vec![0., 1.], vec![1., 0.], vec![1., 1.]];
+        let ys = [0., 1., 1., 0.];
+        let full_batch = |model: &mut Sequential| {
+            let (losses, grads): (Vec<f64>, Vec<Vec<f64>>) = model
+                .forward_batch(&xs)
+                .iter()
+                .zip(ys)
+                .map(|(outputs, y)| loss::mse(outputs, &[y]))
+                .unzip();
+            model.backward_batch(grads);
+            losses.iter().sum::<f64>()
+        };
+
+        let base = AdamOptimiser::builder()
+            .lr(0.05)
+            .weight_decay(1e-3)
+            .build(mlp.num_parameters());
+        let optimiser = &mut SamOptimiser::new(base, 0.05);
+        let initial = mlp.step_with(optimiser, full_batch);
+        (0..300).for_each(|_| {
+            mlp.step_with(optimiser, full_batch);
+        });
+        assert!(mlp.step_with(optimiser, full_batch) < initial / 10.);
+        assert_eq!(mlp.graph.value_for_id(frozen), frozen_value);
+    }
+
+    #[test]
+    fn test_evolution() {
+        let rng = &mut StdRng::seed_from_u64(6);
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(Linear::new(2, 1, Activation::None, rng))];
+        let mut model = Sequential::new(2, layers);
+        model.set_parameters(&[0., -1., 0.]);
+        let frozen = model.layers()[0].parameters()[2];
+        model.graph.freeze(&[frozen]);
+        let frozen_value = model.graph.value_for_id(frozen);
+
+        // The error rate of a linear classifier, whose gradient is 0 almost everywhere.
+        let samples = [
+            ([1., 2.], 1.),
+            ([2., -1.], 0.),
+            ([-1., -3.], 0.),
+            ([-2., 1.5], 1.),
+        ];
+        let error_rate = |model: &mut Sequential| {
+            samples
+                .iter()
+                .filter(|(x, y)| (model.forward(x)[0] > 0.) != (*y == 1.))
+                .count() as f64
+                / samples.len() as f64
+        };
+
+        let optimiser = &mut EvolutionOptimiser::new(10, 0.5, 1., Some(1));
+        let initial = model.step_gradient_free(optimiser, error_rate);
+        assert!(initial > 0.);
+        (0..30).for_each(|_| {
+            model.step_gradient_free(optimiser, error_rate);
+        });
+        assert_eq!(error_rate(&mut model), 0.);
+        assert_eq!(model.graph.value_for_id(frozen), frozen_value);
+    }
+
+    #[test]
+    fn test_parameter_groups() {
+        let rng = &mut StdRng::seed_from_u64(3);
+        let mut model = Sequential::new(
+            3,
+            vec![
+                Box::new(Linear::new(3, 4, Activation::None, rng)),
+                Box::new(Activation::Tanh),
+                Box::new(Linear::new(4, 2, Activation::None, rng)),
+            ],
+        );
+        let groups = model.parameter_groups();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0], model.layers()[0].parameters());
+        assert_eq!(groups[1], model.layers()[2].parameters());
+
+        let x = [0.5, -0.3, 0.9];
+        let targets = [1., -1.];
+        let step = |model: &mut Sequential, optimiser: &mut LambOptimiser| {
+            let (loss, grads) = loss::mse(&model.forward(&x), &targets);
+            model.zero_grads();
+            model.backward(grads);
+            model.update_weights(optimiser);
+            loss
+        };
+        let optimiser = &mut LambOptimiser::new(model.num_parameters(), 0.05, 0.);
+        let before = step(&mut model, optimiser);
+        (0..50).for_each(|_| {
+            step(&mut model, optimiser);
+        });
+        assert!(step(&mut model, optimiser) < before / 10.);
+
+        // Freezing the first layer in all but name, with a learning rate of 0.
+        let first = model.layers()[0].parameters();
+        let values = |model: &Sequential| -> Vec<f64> {
+            first
+                .iter()
+                .map(|id| model.graph.value_for_id(*id))
+                .collect()
+        };
+        let before = values(&model);
+        let optimisers = groups
+            .iter()
+            .zip([0., 0.1])
+            .map(|(group, lr)| AdamOptimiser::builder().lr(lr).build(group.len()))
+            .collect();
+        let optimiser = &mut GroupedOptimiser::new(optimisers);
+        let mut loss = |model: &mut Sequential| {
+            let (loss, grads) = loss::mse(&model.forward(&x), &[-1., 1.]);
+            model.zero_grads();
+            model.backward(grads);
+            model.update_weights(optimiser);
+            loss
+        };
+        let start = loss(&mut model);
+        (0..20).for_each(|_| {
+            loss(&mut model);
+        });
+        assert!(loss(&mut model) < start);
+        assert_eq!(values(&model), before);
+
+        // With centralised gradients, plain SGD keeps the sum of the incoming weights of each
+        // unit, while the biases move freely.
+        let rows = model.incoming_weight_rows();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[0], vec![0, 1, 2]);
+        let sums = |model: &Sequential| -> Vec<f64> {
+            let parameters = model.parameters();
+            rows.iter()
+                .map(|row| row.iter().map(|i| parameters[*i]).sum())
+                .collect()
+        };
+        let before = (sums(&model), model.parameters());
+        let optimiser =
+            &mut CentralisedOptimiser::new(LearningRateOptimiser::new(0.1), rows.clone());
+        model.zero_grads();
+        let (_, grads) = loss::mse(&model.forward(&x), &targets);
+        model.backward(grads);
+        model.update_weights(optimiser);
+        sums(&model)
+            .iter()
+            .zip(before.0)
+            .for_each(|(after, before)| assert!((after - before).abs() < 1e-12));
+        let bias = model.layers()[2].parameters()[8];
+        let position = model
+            .graph
+            .parameter_ids()
+            .iter()
+            .position(|id| *id == bias);
+        assert_ne!(
+            model.parameters()[position.unwrap()],
+            before.1[position.unwrap()]
+        );
+    }
+
+    /// Checks that `forward_batch` and `backward_batch` agree with running the samples one by one
+    /// through the scalar graph.
+    fn check_batch(model: &mut Sequential, xs: &[Vec<f64>]) {
+        let batch = model.forward_batch(xs);
+        batch.iter().zip(xs).for_each(|(b, x)| {
+            b.iter()
+                .zip(model.forward(x))
+                .for_each(|(b, s)| assert!((b - s).abs() < 1e-12))
+        });
+
+        model.zero_grads();
+        model.per_sample_gradients(xs, |_, y| y.to_vec());
+        let expected = model.graph.gradients();
+
+        model.zero_grads();
+        model.forward_batch(xs);
+        model.backward_batch(batch);
+        model
+            .graph
+            .gradients()
+            .iter()
+            .zip(expected)
+            .for_each(|(g, e)| assert!((g - e).abs() < 1e-9));
+    }
+
+    /// Compares the gradient of the summed outputs with central finite differences, nudging
+    /// each parameter through `update_weights`.
+    fn check_parameter_gradients(model: &mut Sequential, x: &[f64]) {
+        const EPSILON: f64 = 1e-6;
+        let nudge = |model: &mut Sequential, id: NodeId, step: f64| {
+            model.zero_grads();
+            model.graph.add_gradient(id, -step);
+            model.update_weights(&mut LearningRateOptimiser::new(1.));
+            model.forward(x).iter().sum::<f64>()
+        };
+
+        let num_outputs = model.forward(x).len();
+        model.zero_grads();
+        model.backward(vec![1.; num_outputs]);
+        let grads = model.graph.gradients();
+
+        model
+            .graph
+            .parameter_ids()
+            .to_vec()
+            .iter()
+            .zip(grads)
+            .for_each(|(id, grad)| {
+                let up = nudge(model, *id, EPSILON);
+                let down = nudge(model, *id, -2. * EPSILON);
+                nudge(model, *id, EPSILON);
+                assert!((grad - (up - down) / (2. * EPSILON)).abs() < 1e-6);
+            });
+    }
+
+    #[test]
+    fn test_lstm() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let lstm = Recurrent::new(LstmCell::new(2, 3, rng), true);
+        assert_eq!(lstm.output_shape(&[4, 2]), vec![4, 3]);
+
+        // Parameters are shared across timesteps, however long the sequence.
+        let mut model = Sequential::new(8, vec![Box::new(lstm)]);
+        assert_eq!(model.num_parameters(), 4 * (5 * 3 + 3));
+
+        let x = vec![0.5, -1., 0.2, 0.3, -0.7, 1., 0., 2.];
+        let y = model.forward(&x);
+        assert_eq!(y.len(), 12);
+        assert!(y.iter().all(|h| h.abs() < 1.));
+        check_parameter_gradients(&mut model, &x);
+
+        let last = Recurrent::new(LstmCell::new(2, 3, rng), false);
+        let mut model = Sequential::new(8, vec![Box::new(last)]);
+        assert_eq!(model.forward(&x).len(), 3);
+    }
+
+    #[test]
+    fn test_gru() {
+        let rng = &mut StdRng::seed_from_u64(1);
+        let mut model = Sequential::new(
+            6,
+            vec![Box::new(Recurrent::new(GruCell::new(2, 4, rng), false))],
+        );
+        assert_eq!(model.num_parameters(), 3 * (6 * 4 + 4));
+
+        let x = vec![1., 0.5, -0.3, 0., 0.8, -1.];
+        assert_eq!(model.forward(&x).len(), 4);
+        check_parameter_gradients(&mut model, &x);
+    }
+
+    #[test]
+    fn test_recurrent_batch() {
+        let rng = &mut StdRng::seed_from_u64(4);
+        let xs = vec![
+            vec![1., 0.5, -0.3, 0., 0.8, -1.],
+            vec![-0.2, 0.7, 0.4, -1., 0.1, 0.6],
+        ];
+
+        let lstm = Recurrent::new(LstmCell::new(2, 3, rng), true);
+        check_batch(&mut Sequential::new(6, vec![Box::new(lstm)]), &xs);
+        let gru = Recurrent::new(GruCell::new(2, 4, rng), false);
+        check_batch(&mut Sequential::new(6, vec![Box::new(gru)]), &xs);
+    }
+
+    #[test]
+    fn test_self_attention() {
+        let rng = &mut StdRng::seed_from_u64(2);
+        let mut model = Sequential::new(6, vec![Box::new(SelfAttention::new(2, rng))]);
+        assert_eq!(model.num_parameters(), 4 * (2 * 2 + 2));
+
+        // Without positional information, permuting the sequence permutes the outputs.
+        let x = vec![0.5, -1., 2., 0.3, -0.4, 0.9];
+        let permuted = vec![-0.4, 0.9, 0.5, -1., 2., 0.3];
+        let y = model.forward(&x);
+        let y_permuted = model.forward(&permuted);
+        [2, 0, 1].iter().enumerate().for_each(|(i, j)| {
+            (0..2).for_each(|d| assert!((y_permuted[2 * i + d] - y[2 * j + d]).abs() < 1e-12));
+        });
+
+        check_parameter_gradients(&mut model, &x);
+        check_batch(&mut model, &[x, permuted]);
+    }
+
+    #[test]
+    fn test_cross_entropy_batch() {
+        let xs = vec![vec![1., -2., 0.5], vec![0.3, 0.2, -1.], vec![-1., 1., 1.]];
+        let classes = [2, 0, 1];
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .output(3, Activation::None)
+            .softmax()
+            .seed(3)
+            .build();
+
+        let mut expected = vec![0.; mlp.num_parameters()];
+        let losses: Vec<f64> = xs
+            .iter()
+            .zip(classes)
+            .map(|(x, class)| {
+                mlp.zero_grads();
+                mlp.forward(x);
+                let loss = mlp.backward_cross_entropy(class);
+                expected
+                    .iter_mut()
+                    .zip(mlp.graph.gradients())
+                    .for_each(|(e, g)| *e += g);
+                loss
+            })
+            .collect();
+
+        mlp.zero_grads();
+        mlp.forward_batch(&xs);
+        let loss = mlp.backward_cross_entropy_batch(&classes);
+        assert!((loss - losses.iter().sum::<f64>() / 3.).abs() < 1e-12);
+        mlp.graph
+            .gradients()
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(g, e)| assert!((g - e).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_linear_node_count() {
+        let model = Sequential::new(
+            4,
+            vec![Box::new(Linear::new(
+                4,
+                3,
+                Activation::Relu,
+                &mut thread_rng(),
+            ))],
+        );
+        let num_nodes: usize = model
+            .graph
+            .gradient_flow_report()
+            .iter()
+            .map(|d| d.num_nodes)
+            .sum();
+
+        // Inputs, weights and biases, then a dot product, a bias sum and a relu with its
+        // immediate operand per neuron.
+        assert_eq!(num_nodes, 4 + 12 + 3 + 3 * 4);
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy() {
+        let mut logits = MultiLayerPerceptron::new(vec![3, 4, 3], Some(6));
+        let mut probs = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .output(3, Activation::None)
+            .softmax()
+            .seed(6)
+            .build();
+        assert_eq!(logits.num_parameters(), probs.num_parameters());
+
+        let x = vec![0.2, -1., 0.7];
+        let batch = probs.forward_batch(std::slice::from_ref(&x));
+        let p = probs.forward(&x);
+        assert!((p.iter().sum::<f64>() - 1.).abs() < 1e-12);
+        assert_eq!(p, Util::softmax(&logits.forward(&x)));
+        (0..3).for_each(|i| assert!((batch[0][i] - p[i]).abs() < 1e-12));
+
+        let loss = probs.backward_cross_entropy(2);
+        assert_eq!(loss, -p[2].ln());
+        assert_eq!(logits.backward_cross_entropy(2), loss);
+        assert_eq!(probs.graph.gradients(), logits.graph.gradients());
+    }
+
+    #[test]
+    fn test_builder() {
+        let x = vec![0.1, 0.9, -0.4];
+        let mut built = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .output(2, Activation::None)
+            .seed(1)
+            .build();
+        let mut mlp = MultiLayerPerceptron::new(vec![3, 4, 2], Some(1));
+        assert_eq!(built.forward(&x), mlp.forward(&x));
+
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(8, Activation::Tanh)
+            .dropout(0.2)
+            .output(2, Activation::None)
+            .softmax()
+            .init(Init::XavierUniform, None)
+            .seed(42)
+            .build();
+        assert_eq!(mlp.num_parameters(), 3 * 8 + 8 * 2);
+        assert_eq!(mlp.layers().len(), 4);
+
+        mlp.set_training(false);
+        let y = mlp.forward(&x);
+        assert_eq!(mlp.forward(&x), y);
+        assert!((y.iter().sum::<f64>() - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mlp = MultiLayerPerceptron::builder()
+            .input(4)
+            .hidden(8, Activation::LeakyRelu(0.1))
+            .output(3, Activation::None)
+            .softmax()
+            .build();
+
+        let summary = mlp.summary();
+        let lines: Vec<Vec<&str>> = summary
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(lines[0], vec!["Layer", "Outputs", "Parameters"]);
+        assert_eq!(lines[2], vec!["Linear", "8", "40"]);
+        assert_eq!(lines[3], vec!["Linear", "3", "27"]);
+        assert_eq!(lines[4], vec!["Softmax", "3", "0"]);
+        assert_eq!(lines[6], vec!["Total", "parameters:", "67"]);
+
+        assert_eq!(Residual(Dropout(0.5)).name(), "Residual<Dropout>");
+        assert_eq!(Activation::LeakyRelu(0.1).name(), "LeakyRelu(0.1)");
+    }
+
+    #[test]
+    fn test_forward_with_activations() {
+        let mut mlp = MultiLayerPerceptron::new(vec![2, 5, 3], Some(3));
+
+        let x = [0.3, -0.7];
+        let activations = mlp.forward_with_activations(&x);
+        assert_eq!(activations.len(), 2);
+        assert_eq!(activations[0].len(), 5);
+        assert!(activations[0].iter().all(|a| *a >= 0.));
+        assert_eq!(activations[1], mlp.forward(&x));
+    }
+
+    #[test]
+    fn test_freeze_layer() {
+        let mut mlp = MultiLayerPerceptron::new(vec![2, 3, 1], Some(5));
+        let frozen = mlp.layers()[0].parameters();
+        let trained = mlp.layers()[1].parameters();
+        let values = |mlp: &MultiLayerPerceptron, ids: &[NodeId]| -> Vec<f64> {
+            ids.iter().map(|id| mlp.graph.value_for_id(*id)).collect()
+        };
+        let (before_frozen, before_trained) = (values(&mlp, &frozen), values(&mlp, &trained));
+
+        let mut optimiser = LearningRateOptimiser::new(0.1);
+        mlp.freeze_layer(0);
+        mlp.forward(&[0.5, -1.]);
+        mlp.zero_grads();
+        mlp.backward(vec![1.]);
+        mlp.update_weights(&mut optimiser);
+        assert_eq!(values(&mlp, &frozen), before_frozen);
+        assert_ne!(values(&mlp, &trained), before_trained);
+
+        // Weight decay doesn't reach the state of frozen parameters either.
+        let mut adam = AdamOptimiser::builder()
+            .weight_decay(0.1)
+            .build(mlp.num_parameters());
+        mlp.update_weights(&mut adam);
+        assert_eq!(values(&mlp, &frozen), before_frozen);
+        let moments = adam.save_state()["m"].data().to_vec();
+        mlp.graph
+            .parameter_ids()
+            .iter()
+            .zip(moments)
+            .filter(|(id, _)| frozen.contains(id))
+            .for_each(|(_, m)| assert_eq!(m, 0.));
+
+        mlp.unfreeze_layer(0);
+        mlp.forward(&[0.5, -1.]);
+        mlp.zero_grads();
+        mlp.backward(vec![1.]);
+        mlp.update_weights(&mut optimiser);
+        assert_ne!(values(&mlp, &frozen), before_frozen);
+    }
+
+    #[test]
+    fn test_weight_tying() {
+        let rng = &mut StdRng::seed_from_u64(7);
+        let encoder = Linear::new(3, 2, Activation::Tanh, rng);
+        let decoder = encoder.tied(Activation::None);
+        let mut model = Sequential::new(3, vec![Box::new(encoder), Box::new(decoder)]);
+
+        let encoder_ids = model.layers()[0].parameters();
+        let decoder_ids = model.layers()[1].parameters();
+        assert_eq!(encoder_ids[..6], decoder_ids[..6]);
+        assert_eq!(model.num_parameters(), 6 + 2 + 3);
+
+        let x = [0.2, -0.4, 0.9];
+        check_parameter_gradients(&mut model, &x);
+        let batch = model.forward_batch(&[x.to_vec()]);
+        model
+            .forward(&x)
+            .iter()
+            .zip(batch[0].iter())
+            .for_each(|(y, b)| assert!((y - b).abs() < 1e-12));
+
+        let layer = Linear::new(2, 2, Activation::Tanh, rng);
+        let shared = layer.shared(Activation::Tanh);
+        let mut model = Sequential::new(2, vec![Box::new(layer), Box::new(shared)]);
+        assert_eq!(model.num_parameters(), 6);
+        check_parameter_gradients(&mut model, &[0.5, -0.1]);
+    }
+
+    #[test]
+    fn test_safetensors() {
+        let rng = &mut StdRng::seed_from_u64(3);
+        let layers = |rng: &mut StdRng| -> Vec<Box<dyn Layer>> {
+            vec![
+                Box::new(Linear::new(3, 4, Activation::Relu, rng)),
+                Box::new(BatchNorm::new(4)),
+                Box::new(Linear::new(4, 2, Activation::None, rng)),
+            ]
+        };
+        let mut model = Sequential::new(3, layers(rng));
+        let names: Vec<String> = model.state().into_iter().map(|t| t.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "0.weight",
+                "0.bias",
+                "1.weight",
+                "1.bias",
+                "1.running_mean",
+                "1.running_var",
+                "2.weight",
+                "2.bias"
+            ]
+        );
+        assert_eq!(model.state()[0].shape, vec![4, 3]);
+
+        let xs = vec![vec![0.1, 0.2, 0.3], vec![-1., 0.5, 2.]];
+        model.forward_batch(&xs);
+        model.set_training(false);
+
+        let name = format!("micrograd_rs_test_{}.safetensors", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        model.save_safetensors(&path);
+        let mut loaded = Sequential::new(3, layers(rng));
+        loaded.set_training(false);
+        loaded.load_safetensors(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.forward(&xs[1]), model.forward(&xs[1]));
+    }
+
+    #[test]
+    fn test_load_npz() {
+        let rng = &mut StdRng::seed_from_u64(4);
+        let mut model = Sequential::new(
+            2,
+            vec![
+                Box::new(Linear::new(2, 3, Activation::Relu, rng)),
+                Box::new(Dropout(0.5)),
+                Box::new(Linear::new(3, 1, Activation::None, rng)),
+            ],
+        );
+        model.set_training(false);
+
+        // Named like the attributes of a PyTorch module rather than by layer index.
+        let arrays = vec![
+            (
+                "fc1.weight".to_string(),
+                Tensor::new(vec![3, 2], vec![1., 2., -1., 0.5, 0., 1.]),
+            ),
+            (
+                "fc1.bias".to_string(),
+                Tensor::new(vec![3], vec![0., 1., -2.]),
+            ),
+            (
+                "fc2.weight".to_string(),
+                Tensor::new(vec![1, 3], vec![1., -1., 2.]),
+            ),
+            ("fc2.bias".to_string(), Tensor::new(vec![1], vec![0.5])),
+        ];
+        let name = format!("micrograd_rs_test_load_{}.npz", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, io::write_npz(&arrays)).unwrap();
+        model.load_npz(&path);
+        fs::remove_file(&path).unwrap();
+
+        // h = relu([1 + 2, -1 + 0.5 + 1, 0 + 1 - 2]) = [3, 0.5, 0]
+        assert_eq!(model.forward(&[1., 1.]), vec![3. - 0.5 + 0.5]);
+    }
+
+    #[test]
+    fn test_train_eval() {
+        let rng = &mut StdRng::seed_from_u64(9);
+        let mut model = Sequential::new(
+            4,
+            vec![
+                Box::new(Linear::new(4, 8, Activation::Relu, rng)),
+                Box::new(Dropout(0.5)),
+                Box::new(BatchNorm::new(8)),
+            ],
+        );
+        let running: Vec<NodeId> = model.state()[4..]
+            .iter()
+            .flat_map(|t| t.ids.clone())
+            .collect();
+        let values = |model: &Sequential| -> Vec<f64> {
+            running
+                .iter()
+                .map(|id| model.graph.value_for_id(*id))
+                .collect()
+        };
+        let xs = vec![vec![0.1, 0.2, 0.3, 0.4], vec![-1., 0.5, 2., 0.]];
+
+        assert!(model.is_training());
+        let before = values(&model);
+        model.forward_batch(&xs);
+        assert_ne!(values(&model), before);
+
+        model.eval();
+        assert!(!model.is_training());
+        let before = values(&model);
+        let batch = model.forward_batch(&xs);
+        assert_eq!(values(&model), before);
+        assert_eq!(model.forward(&xs[0]), model.forward(&xs[0]));
+        batch[1]
+            .iter()
+            .zip(model.forward(&xs[1]))
+            .for_each(|(b, s)| assert!((b - s).abs() < 1e-12));
+
+        model.train();
+        let outputs: Vec<Vec<f64>> = (0..10).map(|_| model.forward(&xs[0])).collect();
+        assert!(outputs.iter().any(|o| *o != outputs[0]));
+    }
+
+    #[test]
+    fn test_named_parameters() {
+        let rng = &mut StdRng::seed_from_u64(2);
+        let mut model = Sequential::new(
+            2,
+            vec![
+                Box::new(Linear::new(2, 2, Activation::None, rng)),
+                Box::new(BatchNorm::new(2)),
+            ],
+        );
+
+        let names: Vec<String> = model.named_parameters().into_keys().collect();
+        let expected = [
+            "0.bias[0]",
+            "0.bias[1]",
+            "0.weight[0]",
+            "0.weight[1]",
+            "0.weight[2]",
+            "0.weight[3]",
+            "1.bias[0]",
+            "1.bias[1]",
+            "1.weight[0]",
+            "1.weight[1]",
+        ];
+        assert_eq!(names, expected);
+
+        model.set_named_parameter("0.weight[2]", 3.);
+        model.set_named_parameter("0.bias[1]", 0.5);
+        model.forward(&[1., 0.]);
+        model.zero_grads();
+        model.backward(vec![0., 1.]);
+
+        let parameters = model.named_parameters();
+        assert_eq!(parameters["0.weight[2]"].0, 3.);
+        // Gradient of the second output with respect to the weight from the first input.
+        let (gamma, _) = parameters["1.weight[1]"];
+        let scale = gamma / (1. + BatchNorm::EPSILON).sqrt();
+        assert!((parameters["0.weight[2]"].1 - scale).abs() < 1e-12);
+        assert!((parameters["0.bias[1]"].1 - scale).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_quantise() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(4)
+            .hidden(16, Activation::Relu)
+            .dropout(0.2)
+            .output(3, Activation::None)
+            .softmax()
+            .seed(11)
+            .build();
+        mlp.eval();
+
+        let quantised = mlp.quantise();
+        assert_eq!(quantised.layers().len(), 4);
+        assert_eq!(quantised.weight_bytes(), 4 * 16 + 16 * 3);
+
+        let rng = &mut StdRng::seed_from_u64(1);
+        (0..20).for_each(|_| {
+            let x: Vec<f64> = (0..4).map(|_| rng.gen_range(-1. ..1.)).collect();
+            let y = mlp.forward(&x);
+            quantised
+                .forward(&x)
+                .iter()
+                .zip(y.iter())
+                .for_each(|(q, y)| assert!((q - y).abs() < 0.05));
+        });
+    }
+
+    #[test]
+    fn test_ensemble() {
+        // Three blobs around the corners of a triangle.
+        let rng = &mut StdRng::seed_from_u64(0);
+        let centres = [(0., 1.), (-1., -1.), (1., -1.)];
+        let samples: Vec<(Vec<f64>, usize)> = (0..60)
+            .map(|i| {
+                let (cx, cy) = centres[i % 3];
+                let noise = |rng: &mut StdRng| 0.3 * Util::standard_normal(rng);
+                (vec![cx + noise(rng), cy + noise(rng)], i % 3)
+            })
+            .collect();
+
+        let mut ensemble = Ensemble::from_fn(3, |i| {
+            MultiLayerPerceptron::builder()
+                .input(2)
+                .hidden(8, Activation::Tanh)
+                .output(3, Activation::None)
+                .init(Init::XavierUniform, Some(Init::Zeros))
+                .seed(i as u64)
+                .build()
+        });
+        let losses = ensemble.train(
+            &samples,
+            10,
+            |m| AdamOptimiser::new(m.num_parameters()),
+            rng,
+        );
+        assert_eq!(losses.len(), 10);
+        assert!(losses[9] < losses[0]);
+
+        let probs = ensemble.predict_proba(&samples[0].0);
+        assert!((probs.iter().sum::<f64>() - 1.).abs() < 1e-12);
+        let expected: Vec<f64> = (0..3)
+            .map(|i| {
+                let probs: Vec<Vec<f64>> = ensemble
+                    .models_mut()
+                    .iter_mut()
+                    .map(|m| m.predict_proba(&samples[0].0))
+                    .collect();
+                probs.iter().map(|p| p[i]).sum::<f64>() / 3.
+            })
+            .collect();
+        assert_eq!(probs, expected);
+
+        let correct = samples
+            .iter()
+            .filter(|(x, y)| ensemble.predict(x) == *y)
+            .count();
+        assert!(correct >= 55);
+    }
+
+    #[test]
+    fn test_multi_head() {
+        let rng = &mut StdRng::seed_from_u64(8);
+        let mut model = MultiHeadModel::new(
+            3,
+            vec![Box::new(Linear::new(3, 6, Activation::Tanh, rng))],
+            vec![
+                vec![Box::new(Linear::new(6, 4, Activation::None, rng))],
+                vec![
+                    Box::new(Linear::new(6, 3, Activation::Relu, rng)),
+                    Box::new(Linear::new(3, 2, Activation::None, rng)),
+                ],
+            ],
+        );
+        assert_eq!(model.num_heads(), 2);
+        assert_eq!(model.num_parameters(), 24 + 28 + 21 + 8);
+        assert_eq!(model.state()[2].name, "1.0.0.weight");
+
+        let x = [0.5, -0.2, 0.9];
+        let heads = model.forward_heads(&x);
+        assert_eq!(heads.len(), 2);
+        assert_eq!((heads[0].len(), heads[1].len()), (4, 2));
+        assert_eq!(heads.concat(), model.forward(&x));
+
+        model.zero_grads();
+        let loss = model.backward_cross_entropy(&[3, 0]);
+        let grads = model.graph.gradients();
+        let (p0, p1) = (Util::softmax(&heads[0]), Util::softmax(&heads[1]));
+        assert_eq!(loss, -p0[3].ln() - p1[0].ln());
+
+        model.zero_grads();
+        let onehot = |p: &[f64], target: usize| -> Vec<f64> {
+            p.iter()
+                .zip(Util::one_hot(target, p.len()))
+                .map(|(p, t)| p - t)
+                .collect()
+        };
+        model.backward_heads(vec![onehot(&p0, 3), onehot(&p1, 0)]);
+        model
+            .graph
+            .gradients()
+            .iter()
+            .zip(grads)
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_binary_classification() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(4, Activation::Relu)
+            .output(1, Activation::None)
+            .sigmoid()
+            .seed(3)
+            .build();
+        let mut builder = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(4, Activation::Relu)
+            .output(1, Activation::None)
+            .sigmoid()
+            .seed(3)
+            .build();
+        let x = [0.3, -0.8];
+        assert_eq!(mlp.forward(&x), builder.forward(&x));
+
+        let p = mlp.forward(&x)[0];
+        assert_eq!(mlp.predict_proba(&x), vec![1. - p, p]);
+        mlp.zero_grads();
+        let loss = mlp.backward_bce(1.);
+        assert!((loss + p.ln()).abs() < 1e-12);
+
+        // Same as seeding d(bce)/dp into the sigmoid output.
+        let grads = mlp.graph.gradients();
+        mlp.zero_grads();
+        mlp.backward(vec![(p - 1.) / (p * (1. - p))]);
+        mlp.graph
+            .gradients()
+            .iter()
+            .zip(grads)
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-9));
+
+        mlp.zero_grads();
+        let loss = mlp.backward_bce(0.);
+        assert!((loss + (1. - p).ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_gradient_norms() {
+        let mut mlp = MultiLayerPerceptron::new(vec![2, 3, 2], Some(1));
+        mlp.forward(&[0.5, -0.2]);
+        assert!(mlp.gradient_norms().iter().all(|n| *n == 0.));
+
+        mlp.backward(vec![1., -1.]);
+        let norms = mlp.gradient_norms();
+        assert_eq!(norms.len(), mlp.layers.len());
+        let grads = mlp.graph.gradients();
+        let total: f64 = norms.iter().map(|n| n * n).sum();
+        assert!((total - grads.iter().map(|g| g * g).sum::<f64>()).abs() < 1e-12);
+        assert!(norms.iter().all(|n| *n > 0.));
+    }
+
+    #[test]
+    fn test_histograms() {
+        let histogram = Histogram::new(&[0., 0.5, 1., 2., 4.], 4);
+        assert_eq!(histogram.counts, vec![2, 1, 1, 1]);
+        assert_eq!(histogram.bin_edges(1), (1., 2.));
+        assert_eq!(Histogram::new(&[3., 3.], 2).counts, vec![2, 0]);
+
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(3, Activation::Relu)
+            .output(2, Activation::None)
+            .softmax()
+            .seed(2)
+            .build();
+        mlp.forward(&[0.1, 0.9]);
+        mlp.backward(vec![1., 0.]);
+        let histograms = mlp.histograms(5);
+        assert_eq!(
+            histograms.iter().map(|h| h.layer).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        histograms.iter().for_each(|h| {
+            let count = mlp.layers[h.layer].parameters().len();
+            assert_eq!(h.weights.counts.iter().sum::<usize>(), count);
+            assert_eq!(h.gradients.counts.iter().sum::<usize>(), count);
+        });
+
+        let csv = mlp.histograms_csv(5);
+        assert_eq!(csv.lines().count(), 1 + 2 * 2 * 5);
+        assert!(csv.lines().nth(1).unwrap().starts_with("0,Linear,weights,"));
+    }
+
+    #[test]
+    fn test_max_norm() {
+        let mut mlp = MultiLayerPerceptron::new(vec![3, 4, 2], Some(3));
+        let norms = |mlp: &MultiLayerPerceptron, layer: usize| -> Vec<f64> {
+            mlp.layers[layer]
+                .incoming_weights()
+                .iter()
+                .map(|ids| {
+                    ids.iter()
+                        .map(|id| mlp.graph.value_for_id(*id).powi(2))
+                        .sum::<f64>()
+                        .sqrt()
+                })
+                .collect()
+        };
+        let before = norms(&mlp, 1);
+        mlp.set_max_norm(0, Some(0.5));
+        assert!(norms(&mlp, 0).iter().all(|n| *n <= 0.5 + 1e-12));
+
+        let mut optimiser = LearningRateOptimiser::new(10.);
+        mlp.forward(&[1., -1., 0.5]);
+        mlp.backward(vec![1., 1.]);
+        mlp.update_weights(&mut optimiser);
+        assert!(norms(&mlp, 0).iter().all(|n| *n <= 0.5 + 1e-12));
+        assert!(norms(&mlp, 1).iter().zip(before).any(|(n, b)| *n != b));
+    }
+
+    #[test]
+    fn test_autoencoder() {
+        let mut autoencoder = Autoencoder::new(
+            &[4, 3, 2],
+            Activation::Tanh,
+            Activation::None,
+            false,
+            Some(1),
+        );
+        let x = [0.2, -0.5, 0.9, 0.1];
+        let code = autoencoder.encode(&x);
+        assert_eq!(code.len(), 2);
+        let reconstruction = autoencoder.forward(&x);
+        assert_eq!(autoencoder.decode(&code), reconstruction);
+        check_parameter_gradients(&mut autoencoder, &x);
+
+        // Encoding and decoding only evaluate their own half of the network.
+        let reconstruction = autoencoder.forward(&x);
+        let branch = autoencoder.branches[0].outputs.clone();
+        let decoded: Vec<f64> = branch
+            .iter()
+            .map(|id| autoencoder.graph.value_for_id(*id))
+            .collect();
+        let other = autoencoder.encode(&[0.7, 0.3, -0.2, -0.8]);
+        assert_ne!(other, code);
+        autoencoder
+            .outputs
+            .iter()
+            .zip(&reconstruction)
+            .for_each(|(id, v)| {
+                assert_eq!(autoencoder.graph.value_for_id(*id), *v);
+            });
+        autoencoder.forward(&x);
+        branch.iter().zip(&decoded).for_each(|(id, v)| {
+            assert_eq!(autoencoder.graph.value_for_id(*id), *v);
+        });
+        autoencoder.decode(&other);
+        autoencoder
+            .outputs
+            .iter()
+            .zip(&reconstruction)
+            .for_each(|(id, v)| {
+                assert_eq!(autoencoder.graph.value_for_id(*id), *v);
+            });
+
+        let mut tied = Autoencoder::new(
+            &[4, 3, 2],
+            Activation::Tanh,
+            Activation::None,
+            true,
+            Some(1),
+        );
+        assert_eq!(tied.num_parameters(), 4 * 3 + 3 * 2 + 3 + 2 + 3 + 4);
+        assert_eq!(
+            tied.layers()[0].parameters()[..12],
+            tied.layers()[3].parameters()[..12]
+        );
+
+        let xs = [[0., 1., 0., 1.], [1., 0., 1., 0.], [1., 1., 0., 0.]];
+        let optimiser = &mut AdamOptimiser::new(tied.num_parameters());
+        let losses: Vec<f64> = (0..300)
+            .map(|_| {
+                xs.iter()
+                    .map(|x| tied.train_step(x, RegressionLoss::Mse, optimiser))
+                    .mean()
+            })
+            .collect();
+        assert!(losses[299] < losses[0] / 5.);
+    }
+
+    #[test]
+    fn test_siamese() {
+        let rng = &mut StdRng::seed_from_u64(6);
+        let layers: Vec<Box<dyn Layer>> = vec![
+            Box::new(Linear::new(3, 4, Activation::Tanh, rng)),
+            Box::new(Linear::new(4, 2, Activation::None, rng)),
+        ];
+        let mut siamese = Siamese::new(3, layers);
+        assert_eq!(siamese.num_parameters(), 3 * 4 + 4 + 4 * 2 + 2);
+
+        let (a, b) = ([0.5, -0.2, 0.1], [-0.3, 0.8, 0.4]);
+        let (left, right) = siamese.forward_pair(&a, &b);
+        assert_eq!(siamese.forward(&b), right);
+        assert_eq!(siamese.forward_pair(&a, &b).0, left);
+
+        // The gradients of both towers accumulate into the shared parameters.
+        let loss = |siamese: &mut Siamese, similar: bool| {
+            let (left, right) = siamese.forward_pair(&a, &b);
+            let d = left
+                .iter()
+                .zip(right)
+                .map(|(l, r)| (l - r).powi(2))
+                .sum::<f64>()
+                .sqrt();
+            match similar {
+                true => d * d,
+                false => (5. - d).max(0.).powi(2),
+            }
+        };
+        [true, false].iter().for_each(|similar| {
+            siamese.forward_pair(&a, &b);
+            siamese.zero_grads();
+            let expected = loss(&mut siamese, *similar);
+            assert_eq!(siamese.backward_contrastive(*similar, 5.), expected);
+            let grads = siamese.graph.gradients();
+            siamese
+                .graph
+                .parameter_ids()
+                .to_vec()
+                .iter()
+                .zip(grads)
+                .for_each(|(id, grad)| {
+                    let value = siamese.graph.value_for_id(*id);
+                    siamese.graph.set_state(*id, value + 1e-6);
+                    let up = loss(&mut siamese, *similar);
+                    siamese.graph.set_state(*id, value - 1e-6);
+                    let down = loss(&mut siamese, *similar);
+                    siamese.graph.set_state(*id, value);
+                    assert!((grad - (up - down) / 2e-6).abs() < 1e-5);
+                });
+        });
+    }
+
+    #[test]
+    fn test_mc_dropout() {
+        let builder = || {
+            MultiLayerPerceptron::builder()
+                .input(3)
+                .hidden(16, Activation::Relu)
+                .output(3, Activation::None)
+                .seed(8)
+        };
+        let x = [0.4, -0.1, 0.7];
+
+        let mut mlp = builder().build();
+        let (mean, std) = mlp.predict_with_uncertainty(&x, 10);
+        mean.iter()
+            .zip(mlp.predict_proba(&x))
+            .for_each(|(m, p)| assert!((m - p).abs() < 1e-12));
+        assert!(std.iter().all(|s| *s < 1e-12));
+
+        let mut mlp = builder().dropout(0.5).build();
+        mlp.eval();
+        let (mean, std) = mlp.predict_with_uncertainty(&x, 50);
+        assert!(!mlp.is_training());
+        assert!((mean.iter().sum::<f64>() - 1.).abs() < 1e-12);
+        assert!(std.iter().all(|s| *s > 0.));
+        assert_eq!(mlp.predict_proba(&x), mlp.predict_proba(&x));
+
+        mlp.train();
+        mlp.predict_with_uncertainty(&x, 2);
+        assert!(mlp.is_training());
+    }
+
+    #[test]
+    fn test_net2net() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .dropout(0.5)
+            .hidden(3, Activation::Tanh)
+            .output(2, Activation::None)
+            .softmax()
+            .seed(12)
+            .build();
+        mlp.eval();
+        let xs = [[0.3, -0.6, 0.9], [1., 0.2, -0.4]];
+        let close = |a: Vec<f64>, b: Vec<f64>| {
+            a.iter()
+                .zip(b)
+                .for_each(|(a, b)| assert!((a - b).abs() < 1e-12))
+        };
+
+        let mut wider = mlp.widen(0, 7).widen(2, 5);
+        assert_eq!(wider.layer_outputs[0].len(), 7);
+        assert_eq!(wider.layer_outputs[2].len(), 5);
+        assert_eq!(
+            wider.num_parameters(),
+            (3 * 7 + 7) + (7 * 5 + 5) + (5 * 2 + 2)
+        );
+        xs.iter()
+            .for_each(|x| close(wider.forward(x), mlp.forward(x)));
+
+        let mut deeper = mlp.deepen(2).deepen(0).deepen(5);
+        assert_eq!(deeper.layers().len(), mlp.layers().len() + 3);
+        xs.iter()
+            .for_each(|x| close(deeper.forward(x), mlp.forward(x)));
+        check_parameter_gradients(&mut deeper, &xs[0]);
+    }
+
+    #[test]
+    fn test_net2net_build_options() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(3)
+            .hidden(4, Activation::Relu)
+            .output(2, Activation::None)
+            .l2(0.1)
+            .loss(GraphLoss::Mse)
+            .seed(12)
+            .build();
+        let (x, y) = ([0.3, -0.6, 0.9], [1., -1.]);
+        let loss = mlp.forward_loss(&x, &y);
+        let penalty = mlp.l2_penalty();
+        assert!(penalty > 0.);
+
+        let mut wider = mlp.widen(0, 6);
+        assert!((wider.forward_loss(&x, &y) - loss).abs() < 1e-12);
+        assert!(wider.l2_penalty() > 0.);
+
+        let mut deeper = mlp.deepen(1);
+        assert!((deeper.forward_loss(&x, &y) - loss).abs() < 1e-12);
+        assert!((deeper.l2_penalty() - penalty - 0.1 * 4.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mixture_of_experts() {
+        let rng = &mut StdRng::seed_from_u64(13);
+        let experts: Vec<Vec<Box<dyn Layer>>> = (0..3)
+            .map(|_| {
+                vec![
+                    Box::new(Linear::new(3, 4, Activation::Tanh, rng)) as Box<dyn Layer>,
+                    Box::new(Linear::new(4, 2, Activation::None, rng)),
+                ]
+            })
+            .collect();
+        let moe = MixtureOfExperts::new(3, experts, rng);
+        assert_eq!(moe.num_experts(), 3);
+        assert_eq!(moe.output_shape(&[3]), vec![2]);
+        let mut model = Sequential::new(3, vec![Box::new(moe)]);
+        assert_eq!(
+            model.num_parameters(),
+            3 * 3 + 3 + 3 * (3 * 4 + 4 + 4 * 2 + 2)
+        );
+        assert!(model.state()[0].name.starts_with("0.gate."));
+
+        let x = [0.5, -0.3, 0.8];
+        check_parameter_gradients(&mut model, &x);
+        model.forward(&x);
+        model.zero_grads();
+        model.backward(vec![1., -1.]);
+        let gate = &model.layers()[0].parameters()[..12];
+        assert!(gate.iter().all(|id| model.graph.grad_for_id(*id) != 0.));
+
+        // With identical experts, the gate weights sum to one and leave their outputs unchanged.
+        let expert = Linear::new(3, 2, Activation::None, rng);
+        let experts = vec![
+            vec![Box::new(expert.shared(Activation::None)) as Box<dyn Layer>],
+            vec![Box::new(expert.shared(Activation::None))],
+        ];
+        let mut moe = Sequential::new(3, vec![Box::new(MixtureOfExperts::new(3, experts, rng))]);
+        let mut single = Sequential::new(3, vec![Box::new(expert)]);
+        moe.forward(&x)
+            .iter()
+            .zip(single.forward(&x))
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_maxout() {
+        let rng = &mut StdRng::seed_from_u64(14);
+        let mut model = Sequential::new(3, vec![Box::new(Maxout::new(3, 2, 4, rng))]);
+        assert_eq!(model.num_parameters(), 2 * 4 * 3 + 2 * 4);
+        assert_eq!(model.state()[0].shape, vec![2, 4, 3]);
+
+        let x = [0.7, -0.2, 0.4];
+        check_parameter_gradients(&mut model, &x);
+
+        // Each unit outputs its largest piece, which alone receives a gradient.
+        let y = model.forward(&x);
+        model.zero_grads();
+        model.backward(vec![1., 1.]);
+        let state = model.state();
+        let value = |id: &NodeId| model.graph.value_for_id(*id);
+        (0..2).for_each(|o| {
+            let pieces: Vec<f64> = (0..4)
+                .map(|k| {
+                    let row = &state[0].ids[(o * 4 + k) * 3..(o * 4 + k + 1) * 3];
+                    let dot: f64 = row.iter().zip(x).map(|(w, x)| value(w) * x).sum();
+                    dot + value(&state[1].ids[o * 4 + k])
+                })
+                .collect();
+            let winner = Util::argmax(&pieces);
+            assert!((y[o] - pieces[winner]).abs() < 1e-12);
+            (0..4).for_each(|k| {
+                let grad = model.graph.grad_for_id(state[1].ids[o * 4 + k]);
+                assert_eq!(grad, if k == winner { 1. } else { 0. });
+            });
+        });
+    }
+
+    #[test]
+    fn test_graph_loss() {
+        let x = [0.4, -0.8, 0.1];
+        let cases = [
+            (GraphLoss::Mse, vec![0.5, -1.]),
+            (GraphLoss::CrossEntropy, vec![0., 1.]),
+            (GraphLoss::BinaryCrossEntropy, vec![1., 0.3]),
+            (GraphLoss::PoissonNll, vec![3., 0.]),
+            (GraphLoss::GaussianNll, vec![0.7]),
+        ];
+        cases.iter().for_each(|(graph_loss, targets)| {
+            let builder = || {
+                MultiLayerPerceptron::builder()
+                    .input(3)
+                    .hidden(4, Activation::Tanh)
+                    .output(2, Activation::None)
+                    .seed(15)
+            };
+            let mut model = builder().loss(*graph_loss).build();
+            let mut reference = builder().build();
+
+            let outputs = reference.forward(&x);
+            let (expected, grads) = match graph_loss {
+                GraphLoss::Mse => loss::mse(&outputs, targets),
+                GraphLoss::CrossEntropy => loss::cross_entropy_with_logits(&outputs, 1),
+                GraphLoss::BinaryCrossEntropy => {
+                    loss::binary_cross_entropy_with_logits(&outputs, targets)
+                }
+                GraphLoss::PoissonNll => loss::poisson_nll_with_log_rates(&outputs, targets),
+                GraphLoss::GaussianNll => {
+                    let (loss, mut grads, log_var_grads) =
+                        loss::gaussian_nll(&outputs[..1], &outputs[1..], targets);
+                    grads.extend(log_var_grads);
+                    (loss, grads)
+                }
+            };
+            reference.zero_grads();
+            reference.backward(grads);
+
+            assert!((model.forward_loss(&x, targets) - expected).abs() < 1e-12);
+            model.zero_grads();
+            assert!((model.backward_loss() - expected).abs() < 1e-12);
+            model
+                .graph
+                .gradients()
+                .iter()
+                .zip(reference.graph.gradients())
+                .for_each(|(g, r)| assert!((g - r).abs() < 1e-12));
+        });
+    }
+
+    #[test]
+    fn test_label_smoothing() {
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(2)
+            .hidden(3, Activation::Relu)
+            .output(4, Activation::None)
+            .softmax()
+            .seed(16)
+            .build();
+        let x = [0.6, -0.3];
+        mlp.forward(&x);
+        mlp.zero_grads();
+        let loss = mlp.backward_cross_entropy_smoothed(2, 0.);
+        let grads = mlp.graph.gradients();
+        mlp.zero_grads();
+        assert_eq!(mlp.backward_cross_entropy(2), loss);
+        assert_eq!(mlp.graph.gradients(), grads);
+
+        mlp.zero_grads();
+        let smoothed = mlp.backward_cross_entropy_smoothed(2, 0.2);
+        assert_ne!(smoothed, loss);
+        assert_ne!(mlp.graph.gradients(), grads);
+    }
+
+    #[test]
+    fn test_triplet() {
+        let rng = &mut StdRng::seed_from_u64(17);
+        let layers: Vec<Box<dyn Layer>> = vec![
+            Box::new(Linear::new(3, 4, Activation::Tanh, rng)),
+            Box::new(Linear::new(4, 2, Activation::None, rng)),
+        ];
+        let mut triplet = Siamese::new_triplet(3, layers);
+        assert_eq!(triplet.num_parameters(), 3 * 4 + 4 + 4 * 2 + 2);
+
+        let (a, p, n) = ([0.5, -0.2, 0.1], [0.4, -0.1, 0.2], [-0.3, 0.8, 0.4]);
+        let (anchor, positive, negative) = triplet.forward_triplet(&a, &p, &n);
+        assert_eq!(triplet.forward(&n), negative);
+        assert_eq!(triplet.forward_triplet(&a, &p, &n).1, positive);
+
+        let loss = |triplet: &mut Siamese| {
+            let (anchor, positive, negative) = triplet.forward_triplet(&a, &p, &n);
+            loss::triplet_loss(&anchor, &positive, &negative, 3.).0
+        };
+        assert!(loss(&mut triplet) > 0.);
+        triplet.zero_grads();
+        assert_eq!(triplet.backward_triplet(3.), loss(&mut triplet));
+        let grads = triplet.graph.gradients();
+        triplet
+            .graph
+            .parameter_ids()
+            .to_vec()
+            .iter()
+            .zip(grads)
+            .for_each(|(id, grad)| {
+                let value = triplet.graph.value_for_id(*id);
+                triplet.graph.set_state(*id, value + 1e-6);
+                let up = loss(&mut triplet);
+                triplet.graph.set_state(*id, value - 1e-6);
+                let down = loss(&mut triplet);
+                triplet.graph.set_state(*id, value);
+                assert!((grad - (up - down) / 2e-6).abs() < 1e-5);
+            });
+
+        let optimiser = &mut LearningRateOptimiser::new(0.05);
+        let before = loss(&mut triplet);
+        (0..20).for_each(|_| {
+            triplet.train_step_triplet(&a, &p, &n, 3., optimiser);
+        });
+        assert!(loss(&mut triplet) < before);
+        assert_ne!(anchor, triplet.forward(&a));
+    }
+
+    #[test]
+    fn test_l2_penalty() {
+        let builder = || {
+            MultiLayerPerceptron::builder()
+                .input(2)
+                .hidden(3, Activation::Tanh)
+                .output(2, Activation::None)
+                .seed(5)
+        };
+        let mut plain = builder().build();
+        let mut regularised = builder().l2(0.1).build();
+
+        let x = [0.3, -0.7];
+        assert_eq!(plain.forward(&x), regularised.forward(&x));
+        plain.backward(vec![1., -0.5]);
+        regularised.backward(vec![1., -0.5]);
+
+        let plain = plain.named_parameters();
+        let regularised_parameters = regularised.named_parameters();
+        let squares: f64 = plain.values().map(|(w, _)| w * w).sum();
+        assert_eq!(plain.len(), 17);
+        assert!((regularised.l2_penalty() - 0.1 * squares).abs() < 1e-12);
+        plain.iter().for_each(|(name, (w, g))| {
+            let (_, g_l2) = regularised_parameters[name];
+            assert!((g_l2 - (g + 0.2 * w)).abs() < 1e-12);
+        });
+
+        // The batched backward pass adds the penalty gradient once per batch.
+        let xs = vec![x.to_vec(), vec![1., 0.5]];
+        regularised.zero_grads();
+        regularised.forward_batch(&xs);
+        regularised.backward_batch(vec![vec![1., -0.5]; 2]);
+        let batched = regularised.graph.gradients();
+        regularised.zero_grads();
+        regularised.per_sample_gradients(&xs, |_, _| vec![1., -0.5]);
+        regularised.backward(vec![0., 0.]);
+        regularised
+            .graph
+            .gradients()
+            .iter()
+            .zip(batched.iter())
+            .for_each(|(g, b)| assert!((g - b).abs() < 1e-12));
+
+        // Sparse parameters are left out of the penalty.
+        let rng = &mut StdRng::seed_from_u64(0);
+        let mut model = Sequential::builder(vec![2], Some(0))
+            .layer(Embedding::new(5, 2, rng))
+            .flatten()
+            .linear(1, Activation::None)
+            .l2(0.1)
+            .build();
+        model.forward(&[1., 3.]);
+        let dense = model.layers()[2].parameters();
+        let squares: f64 = dense
+            .iter()
+            .map(|id| model.graph.value_for_id(*id).powi(2))
+            .sum();
+        assert!((model.l2_penalty() - 0.1 * squares).abs() < 1e-12);
+        model.backward(vec![0.]);
+        model.layers()[0]
+            .parameters()
+            .iter()
+            .for_each(|id| assert_eq!(model.graph.grad_for_id(*id), 0.));
+    }
+
+    #[test]
+    fn test_regression_losses() {
+        assert_eq!(RegressionLoss::Mse.apply(&[3.], &[1.]), (4., vec![4.]));
+        assert_eq!(
+            RegressionLoss::Huber(1.).apply(&[1.5], &[1.]),
+            (0.125, vec![0.5])
+        );
+        assert_eq!(
+            RegressionLoss::Huber(1.).apply(&[-2.], &[1.]),
+            (2.5, vec![-1.])
+        );
+
+        let mut mlp = MultiLayerPerceptron::new(vec![2, 3, 2], Some(4));
+        let x = [0.5, 0.1];
+        let outputs = mlp.forward(&x);
+        mlp.zero_grads();
+        let loss = mlp.backward_regression(&[0., 1.], RegressionLoss::Mse);
+        let expected = (outputs[0].powi(2) + (outputs[1] - 1.).powi(2)) / 2.;
+        assert!((loss - expected).abs() < 1e-12);
+
+        let grads = mlp.graph.gradients();
+        mlp.zero_grads();
+        mlp.backward(vec![outputs[0], outputs[1] - 1.]);
+        assert_eq!(mlp.graph.gradients(), grads);
+    }
+
+    #[test]
+    fn test_sine_regression() {
+        let samples: Vec<(f64, f64)> = (0..32)
+            .map(|i| {
+                let x = -std::f64::consts::PI + i as f64 * std::f64::consts::PI / 16.;
+                (x, x.sin())
+            })
+            .collect();
+
+        let mut mlp = MultiLayerPerceptron::builder()
+            .input(1)
+            .hidden(16, Activation::Tanh)
+            .output(1, Activation::None)
+            .init(Init::XavierUniform, Some(Init::Zeros))
+            .seed(5)
+            .build();
+        let mut optimiser = AdamOptimiser::new(mlp.num_parameters());
+        let rng = &mut StdRng::seed_from_u64(5);
+        let mut order: Vec<usize> = (0..samples.len()).collect();
+
+        let mut loss = 0.;
+        for _ in 0..300 {
+            order.shuffle(rng);
+            loss = order
+                .iter()
+                .map(|i| {
+                    let (x, y) = samples[*i];
+                    mlp.train_step_regression(&[x], &[y], RegressionLoss::Mse, &mut optimiser)
+                })
+                .mean();
+        }
+        assert!(loss < 0.01);
     }
 }
@@ -0,0 +1,512 @@
+use std::{cell::RefCell, collections::HashMap, fs, path::Path, rc::Rc};
+
+use crate::engine::{GraphBuilder, IdGenerator, NodeId, RunnableGraph};
+
+/// Graph imported from an ONNX model, evaluated one sample at a time, i.e. with the batch
+/// dimension of the model's inputs dropped. Initializers become trainable parameters.
+#[derive(Debug)]
+pub struct OnnxModel {
+    pub graph: RunnableGraph,
+    pub inputs: Vec<NodeId>,
+    pub outputs: Vec<NodeId>,
+}
+
+impl OnnxModel {
+    pub fn load(path: &Path) -> OnnxModel {
+        let bytes =
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        Self::from_bytes(&bytes)
+    }
+
+    /// Builds the graph of a serialised `ModelProto`, which may only use the Gemm, Relu and
+    /// Softmax operators.
+    pub fn from_bytes(bytes: &[u8]) -> OnnxModel {
+        let graph = fields(bytes)
+            .into_iter()
+            .find(|(number, _)| *number == 7)
+            .map(|(_, f)| f.bytes())
+            .expect("The model does not contain a graph");
+
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+        let builder = GraphBuilder::new(ids);
+
+        // Values by name, with their shapes.
+        let mut values: HashMap<String, (Vec<usize>, Vec<GraphBuilder>)> = HashMap::new();
+        let mut inputs = vec![];
+        let mut output_names = vec![];
+        let mut nodes = vec![];
+
+        fields(graph)
+            .into_iter()
+            .for_each(|(number, field)| match number {
+                1 => nodes.push(field.bytes()),
+                5 => {
+                    let (name, shape, data) = parse_tensor(field.bytes());
+                    let params = data
+                        .into_iter()
+                        .map(|v| builder.create_parameter(v).1)
+                        .collect();
+                    values.insert(name, (shape, params));
+                }
+                11 => inputs.push(parse_value_info(field.bytes())),
+                12 => output_names.push(parse_value_info(field.bytes()).0),
+                _ => {}
+            });
+
+        // Older exporters also list the initializers as graph inputs.
+        let inputs: Vec<(String, Vec<usize>)> = inputs
+            .into_iter()
+            .filter(|(name, _)| !values.contains_key(name))
+            .collect();
+        let mut input_ids = vec![];
+        inputs.into_iter().for_each(|(name, shape)| {
+            let size = match shape.last() {
+                Some(size) => *size,
+                None => panic!("Input {name} has no shape"),
+            };
+            let (ids, builders): (Vec<NodeId>, Vec<GraphBuilder>) =
+                (0..size).map(|_| builder.create_input()).unzip();
+            input_ids.extend(ids);
+            values.insert(name, (vec![size], builders));
+        });
+
+        nodes.into_iter().for_each(|node| {
+            let node = parse_node(node);
+            let input = |i: usize| -> &(Vec<usize>, Vec<GraphBuilder>) {
+                let name = &node.inputs[i];
+                values
+                    .get(name)
+                    .unwrap_or_else(|| panic!("Unknown value {name} used by {}", node.op_type))
+            };
+
+            let output = match node.op_type.as_str() {
+                "Gemm" => gemm(
+                    &node,
+                    input(0),
+                    input(1),
+                    node.inputs.get(2).map(|_| input(2)),
+                ),
+                "Relu" => {
+                    let (shape, x) = input(0);
+                    (shape.clone(), x.iter().map(|x| x.clone().relu()).collect())
+                }
+                "Softmax" => {
+                    let (shape, x) = input(0);
+                    (shape.clone(), GraphBuilder::softmax(x))
+                }
+                op => panic!("Unsupported ONNX operator: {op}"),
+            };
+            values.insert(node.outputs[0].clone(), output);
+        });
+
+        let outputs: Vec<GraphBuilder> = output_names
+            .iter()
+            .flat_map(|name| match values.get(name) {
+                Some((_, builders)) => builders.clone(),
+                None => panic!("Unknown graph output {name}"),
+            })
+            .collect();
+
+        OnnxModel {
+            graph: RunnableGraph::new(outputs.iter().collect()),
+            inputs: input_ids,
+            outputs: outputs.iter().map(|o| o.root).collect(),
+        }
+    }
+
+    pub fn forward(&mut self, inputs: &[f64]) -> Vec<f64> {
+        if inputs.len() != self.inputs.len() {
+            panic!(
+                "Expected {} inputs, but got {}",
+                self.inputs.len(),
+                inputs.len()
+            )
+        }
+        self.inputs
+            .iter()
+            .zip(inputs.iter())
+            .for_each(|(input, value)| self.graph.set_input(*input, *value));
+
+        self.graph.evaluate(&self.outputs)
+    }
+}
+
+/// `alpha * a @ b + beta * c`, for a single row `a`.
+fn gemm<'a>(
+    node: &OnnxNode,
+    a: &(Vec<usize>, Vec<GraphBuilder<'a>>),
+    b: &(Vec<usize>, Vec<GraphBuilder<'a>>),
+    c: Option<&(Vec<usize>, Vec<GraphBuilder<'a>>)>,
+) -> (Vec<usize>, Vec<GraphBuilder<'a>>) {
+    let alpha = node.float_attribute("alpha", 1.);
+    let beta = node.float_attribute("beta", 1.);
+    if node.int_attribute("transA", 0) != 0 && a.1.len() != 1 {
+        panic!("Gemm with transA is only supported for a single input row")
+    }
+    let (k, n) = match b.0[..] {
+        [rows, columns] if node.int_attribute("transB", 0) != 0 => (columns, rows),
+        [rows, columns] => (rows, columns),
+        _ => panic!("Expected a matrix for Gemm, but got shape {:?}", b.0),
+    };
+    if a.1.len() != k {
+        panic!("Expected {} inputs, but got {}", k, a.1.len())
+    }
+    let weight = |i: usize, j: usize| match node.int_attribute("transB", 0) {
+        0 => b.1[i * n + j].clone(),
+        _ => b.1[j * k + i].clone(),
+    };
+
+    let outputs = (0..n)
+        .map(|j| {
+            let mut sum = weight(0, j) * &a.1[0];
+            for i in 1..k {
+                sum = sum + weight(i, j) * &a.1[i];
+            }
+            if alpha != 1. {
+                sum = sum * alpha;
+            }
+            match c {
+                // The bias is either one value per output or broadcast from a single one.
+                Some((_, c)) => beta * c[if c.len() == 1 { 0 } else { j }].clone() + sum,
+                None => sum,
+            }
+        })
+        .collect();
+    (vec![1, n], outputs)
+}
+
+#[derive(Debug)]
+struct OnnxNode {
+    op_type: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    attributes: HashMap<String, Attribute>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Attribute {
+    Float(f64),
+    Int(i64),
+}
+
+impl OnnxNode {
+    fn float_attribute(&self, name: &str, default: f64) -> f64 {
+        match self.attributes.get(name) {
+            Some(Attribute::Float(f)) => *f,
+            _ => default,
+        }
+    }
+
+    fn int_attribute(&self, name: &str, default: i64) -> i64 {
+        match self.attributes.get(name) {
+            Some(Attribute::Int(i)) => *i,
+            _ => default,
+        }
+    }
+}
+
+/// Value of a protobuf field, as read off the wire.
+#[derive(Debug, Clone, Copy)]
+enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Field<'a> {
+    fn bytes(self) -> &'a [u8] {
+        match self {
+            Field::Bytes(b) => b,
+            f => panic!("Expected a length-delimited field, but got {:?}", f),
+        }
+    }
+
+    fn string(self) -> String {
+        String::from_utf8_lossy(self.bytes()).into_owned()
+    }
+
+    fn varint(self) -> u64 {
+        match self {
+            Field::Varint(v) => v,
+            f => panic!("Expected a varint field, but got {:?}", f),
+        }
+    }
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+/// Splits a protobuf message into its field numbers and values.
+fn fields(bytes: &[u8]) -> Vec<(u64, Field<'_>)> {
+    let mut pos = 0;
+    let mut fields = vec![];
+    while pos < bytes.len() {
+        let key = read_varint(bytes, &mut pos);
+        let field = match key & 7 {
+            0 => Field::Varint(read_varint(bytes, &mut pos)),
+            1 => {
+                pos += 8;
+                Field::Fixed64(u64::from_le_bytes(bytes[pos - 8..pos].try_into().unwrap()))
+            }
+            2 => {
+                let len = read_varint(bytes, &mut pos) as usize;
+                pos += len;
+                Field::Bytes(&bytes[pos - len..pos])
+            }
+            5 => {
+                pos += 4;
+                Field::Fixed32(u32::from_le_bytes(bytes[pos - 4..pos].try_into().unwrap()))
+            }
+            wire_type => panic!("Unsupported protobuf wire type {wire_type}"),
+        };
+        fields.push((key >> 3, field));
+    }
+    fields
+}
+
+/// Values of a repeated varint field, which may or may not be packed.
+fn varints(field: Field) -> Vec<u64> {
+    match field {
+        Field::Bytes(packed) => {
+            let mut pos = 0;
+            let mut values = vec![];
+            while pos < packed.len() {
+                values.push(read_varint(packed, &mut pos));
+            }
+            values
+        }
+        f => vec![f.varint()],
+    }
+}
+
+fn parse_node(bytes: &[u8]) -> OnnxNode {
+    let mut node = OnnxNode {
+        op_type: String::new(),
+        inputs: vec![],
+        outputs: vec![],
+        attributes: HashMap::new(),
+    };
+    fields(bytes)
+        .into_iter()
+        .for_each(|(number, field)| match number {
+            1 => node.inputs.push(field.string()),
+            2 => node.outputs.push(field.string()),
+            4 => node.op_type = field.string(),
+            5 => {
+                let mut name = String::new();
+                let mut value = None;
+                fields(field.bytes())
+                    .into_iter()
+                    .for_each(|(n, f)| match (n, f) {
+                        (1, f) => name = f.string(),
+                        (2, Field::Fixed32(bits)) => {
+                            value = Some(Attribute::Float(f32::from_bits(bits) as f64))
+                        }
+                        (3, Field::Varint(i)) => value = Some(Attribute::Int(i as i64)),
+                        _ => {}
+                    });
+                if let Some(value) = value {
+                    node.attributes.insert(name, value);
+                }
+            }
+            _ => {}
+        });
+    node
+}
+
+/// Name, shape and values of a `TensorProto` holding floats or doubles.
+fn parse_tensor(bytes: &[u8]) -> (String, Vec<usize>, Vec<f64>) {
+    const FLOAT: u64 = 1;
+    const DOUBLE: u64 = 11;
+
+    let mut name = String::new();
+    let mut dims = vec![];
+    let mut data_type = FLOAT;
+    let mut floats = vec![];
+    let mut raw: &[u8] = &[];
+    fields(bytes)
+        .into_iter()
+        .for_each(|(number, field)| match number {
+            1 => dims.extend(varints(field)),
+            2 => data_type = field.varint(),
+            4 => match field {
+                Field::Bytes(packed) => floats.extend(
+                    packed
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64),
+                ),
+                Field::Fixed32(bits) => floats.push(f32::from_bits(bits) as f64),
+                f => panic!("Unexpected float_data field: {:?}", f),
+            },
+            8 => name = field.string(),
+            9 => raw = field.bytes(),
+            10 => match field {
+                Field::Bytes(packed) => floats.extend(
+                    packed
+                        .chunks_exact(8)
+                        .map(|b| f64::from_le_bytes(b.try_into().unwrap())),
+                ),
+                Field::Fixed64(bits) => floats.push(f64::from_bits(bits)),
+                f => panic!("Unexpected double_data field: {:?}", f),
+            },
+            _ => {}
+        });
+
+    if !raw.is_empty() {
+        floats = match data_type {
+            FLOAT => raw
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64)
+                .collect(),
+            DOUBLE => raw
+                .chunks_exact(8)
+                .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+            t => panic!("Unsupported data type {t} for tensor {name}"),
+        };
+    }
+    let dims = dims.into_iter().map(|d| d as usize).collect();
+    (name, dims, floats)
+}
+
+/// Name and shape of a `ValueInfoProto`, with unknown dimensions such as the batch size as 0.
+fn parse_value_info(bytes: &[u8]) -> (String, Vec<usize>) {
+    let mut name = String::new();
+    let mut shape = vec![];
+    fields(bytes)
+        .into_iter()
+        .for_each(|(number, field)| match number {
+            1 => name = field.string(),
+            // TypeProto.tensor_type.shape.dim.dim_value
+            2 => fields(field.bytes())
+                .into_iter()
+                .filter(|(n, _)| *n == 1)
+                .flat_map(|(_, tensor)| fields(tensor.bytes()))
+                .filter(|(n, _)| *n == 2)
+                .flat_map(|(_, s)| fields(s.bytes()))
+                .filter(|(n, _)| *n == 1)
+                .for_each(|(_, dim)| {
+                    let value = fields(dim.bytes())
+                        .into_iter()
+                        .find(|(n, _)| *n == 1)
+                        .map(|(_, v)| v.varint() as usize);
+                    shape.push(value.unwrap_or(0));
+                }),
+            _ => {}
+        });
+    (name, shape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut v: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while v >= 0x80 {
+            bytes.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        bytes.push(v as u8);
+        bytes
+    }
+
+    fn message(number: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut field = varint(number << 3 | 2);
+        field.extend(varint(bytes.len() as u64));
+        field.extend(bytes);
+        field
+    }
+
+    fn int(number: u64, v: u64) -> Vec<u8> {
+        let mut field = varint(number << 3);
+        field.extend(varint(v));
+        field
+    }
+
+    fn node(op: &str, inputs: &[&str], output: &str, attributes: &[Vec<u8>]) -> Vec<u8> {
+        let mut node: Vec<u8> = inputs
+            .iter()
+            .flat_map(|i| message(1, i.as_bytes()))
+            .collect();
+        node.extend(message(2, output.as_bytes()));
+        node.extend(message(4, op.as_bytes()));
+        attributes.iter().for_each(|a| node.extend(message(5, a)));
+        message(1, &node)
+    }
+
+    fn initializer(name: &str, dims: &[u64], values: &[f64], raw: bool) -> Vec<u8> {
+        let mut tensor: Vec<u8> = dims.iter().flat_map(|d| int(1, *d)).collect();
+        tensor.extend(int(2, 1));
+        let data: Vec<u8> = values
+            .iter()
+            .flat_map(|v| (*v as f32).to_le_bytes())
+            .collect();
+        tensor.extend(message(if raw { 9 } else { 4 }, &data));
+        tensor.extend(message(8, name.as_bytes()));
+        message(5, &tensor)
+    }
+
+    fn value_info(number: u64, name: &str, dims: &[u64]) -> Vec<u8> {
+        let dims: Vec<u8> = dims.iter().flat_map(|d| message(1, &int(1, *d))).collect();
+        let tensor_type = [int(1, 1), message(2, &dims)].concat();
+        let info = [
+            message(1, name.as_bytes()),
+            message(2, &message(1, &tensor_type)),
+        ];
+        message(number, &info.concat())
+    }
+
+    #[test]
+    fn test_gemm_relu_softmax() {
+        let w1 = [0.5, -1., 0.25, 0.75, -0.5, 1.];
+        let b1 = [0.125, 0., -0.25];
+        let w2 = [1., -1., 0.5, 0.25, -0.75, 2.];
+        let b2 = [0., 0.5];
+
+        let trans_b = [message(1, b"transB"), int(3, 1), int(20, 2)].concat();
+        let graph = [
+            node("Gemm", &["x", "w1", "b1"], "h", &[trans_b]),
+            node("Relu", &["h"], "r", &[]),
+            node("Gemm", &["r", "w2", "b2"], "logits", &[]),
+            node("Softmax", &["logits"], "y", &[]),
+            initializer("w1", &[3, 2], &w1, true),
+            initializer("b1", &[3], &b1, false),
+            initializer("w2", &[3, 2], &w2, false),
+            initializer("b2", &[2], &b2, true),
+            value_info(11, "x", &[1, 2]),
+            value_info(12, "y", &[1, 2]),
+        ]
+        .concat();
+        let model = [int(1, 8), message(7, &graph)].concat();
+
+        let mut onnx = OnnxModel::from_bytes(&model);
+        assert_eq!(onnx.graph.num_parameters(), 6 + 3 + 6 + 2);
+
+        let x: [f64; 2] = [0.8, -0.4];
+        let h: Vec<f64> = (0..3)
+            .map(|j| (w1[2 * j] * x[0] + w1[2 * j + 1] * x[1] + b1[j]).max(0.))
+            .collect();
+        let logits: Vec<f64> = (0..2)
+            .map(|j| (0..3).map(|i| h[i] * w2[2 * i + j]).sum::<f64>() + b2[j])
+            .collect();
+        let expected = crate::util::Util::softmax(&logits);
+
+        onnx.forward(&x)
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(y, e)| assert!((y - e).abs() < 1e-12));
+    }
+}
@@ -1,12 +1,123 @@
-use crate::engine::Data;
+use std::collections::{BTreeMap, VecDeque};
+
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+
+use crate::{engine::Data, tensor::Tensor, util::Util};
 
 pub trait Optimiser {
     fn optimise(&mut self, data: &mut [Data]);
+
+    fn learning_rate(&self) -> f64;
+
+    /// Changes the learning rate of the next steps, e.g. following a schedule.
+    fn set_learning_rate(&mut self, learning_rate: f64);
+
+    /// Momentum of the optimiser, e.g. the first-moment decay of Adam, if it has any.
+    fn momentum(&self) -> Option<f64> {
+        None
+    }
+
+    /// Changes the momentum of the next steps. Optimisers without momentum ignore it.
+    fn set_momentum(&mut self, _momentum: f64) {}
+
+    /// Internal state of the optimiser by name, e.g. the moments of Adam along with the learning
+    /// rate and momentum, to checkpoint training, e.g. with `io::write_safetensors`.
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        BTreeMap::new()
+    }
+
+    /// Restores the state saved by `save_state`, so that training resumes exactly where it
+    /// stopped.
+    fn load_state(&mut self, _state: &BTreeMap<String, Tensor>) {}
+
+    /// Optimises `data` partitioned into `groups` of indices, e.g. the parameters of each layer.
+    /// Optimisers that don't treat groups separately ignore them.
+    fn optimise_groups(&mut self, data: &mut [Data], _groups: &[Vec<usize>]) {
+        self.optimise(data)
+    }
+
+    /// Same as `optimise_groups`, but only steps the parameters whose `active` flag is set and
+    /// leaves the others, along with their state, e.g. moments, untouched. Parameters that took
+    /// no part in a step, such as embedding rows that were not looked up, are kept out of it this
+    /// way. The default optimises the active parameters on their own, which only suits
+    /// optimisers without per-parameter state.
+    fn optimise_masked(&mut self, data: &mut [Data], groups: &[Vec<usize>], active: &[bool]) {
+        let indices: Vec<usize> = (0..data.len()).filter(|i| active[*i]).collect();
+        let mut positions = vec![None; data.len()];
+        indices
+            .iter()
+            .enumerate()
+            .for_each(|(position, i)| positions[*i] = Some(position));
+        let groups: Vec<Vec<usize>> = groups
+            .iter()
+            .map(|group| group.iter().filter_map(|i| positions[*i]).collect())
+            .collect();
+
+        let mut subset: Vec<Data> = indices.iter().map(|i| data[*i].clone()).collect();
+        self.optimise_groups(&mut subset, &groups);
+        indices.iter().zip(subset).for_each(|(i, d)| data[*i] = d);
+    }
+}
+
+/// Every parameter active, for optimisers whose `optimise` goes through `optimise_masked`.
+fn all_active(data: &[Data]) -> Vec<bool> {
+    vec![true; data.len()]
+}
+
+/// All the parameters as a single group.
+fn one_group(data: &[Data]) -> Vec<Vec<usize>> {
+    vec![(0..data.len()).collect()]
+}
+
+/// Gradient of the parameter with that of the L2 penalty `weight_decay / 2 * w^2` added, which
+/// pulls the parameter towards 0.
+fn decayed(data: &Data, weight_decay: f64) -> f64 {
+    data.gradient + weight_decay * data.value
+}
+
+/// Named vectors and scalars as a state for `Optimiser::save_state`.
+fn state_of(vectors: &[(&str, &[f64])], scalars: &[(&str, f64)]) -> BTreeMap<String, Tensor> {
+    let vectors = vectors.iter().map(|(name, values)| {
+        (
+            name.to_string(),
+            Tensor::new(vec![values.len()], values.to_vec()),
+        )
+    });
+    let scalars = scalars
+        .iter()
+        .map(|(name, value)| (name.to_string(), Tensor::scalar(*value)));
+    vectors.chain(scalars).collect()
+}
+
+/// Copies the values of `state[name]` into `values`, which must have the same number of them.
+fn restore(state: &BTreeMap<String, Tensor>, name: &str, values: &mut [f64]) {
+    let tensor = state
+        .get(name)
+        .unwrap_or_else(|| panic!("Missing tensor {name}"));
+    if tensor.data().len() != values.len() {
+        panic!(
+            "Expected {} values for {}, but got {}",
+            values.len(),
+            name,
+            tensor.data().len()
+        )
+    }
+    values.copy_from_slice(tensor.data());
+}
+
+fn restore_scalar(state: &BTreeMap<String, Tensor>, name: &str) -> f64 {
+    let mut value = [0.];
+    restore(state, name, &mut value);
+    value[0]
 }
 
 pub struct AdamOptimiser {
+    config: AdamOptimiserBuilder,
     m: Vec<f64>,
     v: Vec<f64>,
+    /// Maximum of the past second moments, which AMSGrad divides by instead of the current one
+    /// so that the effective step sizes never grow.
+    v_max: Option<Vec<f64>>,
     t: f64,
 }
 
@@ -17,51 +128,1845 @@ impl AdamOptimiser {
     const EPSILON: f64 = 1e-8;
 
     pub fn new(num_params: usize) -> Self {
+        Self::builder().build(num_params)
+    }
+
+    /// Starts configuring the hyperparameters, which default to the ones of `new`.
+    pub fn builder() -> AdamOptimiserBuilder {
+        AdamOptimiserBuilder {
+            learning_rate: Self::ALPHA,
+            beta_1: Self::BETA_1,
+            beta_2: Self::BETA_2,
+            epsilon: Self::EPSILON,
+            weight_decay: 0.,
+            amsgrad: false,
+        }
+    }
+}
+
+impl Optimiser for AdamOptimiser {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_masked(data, &one_group(data), &all_active(data));
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], _groups: &[Vec<usize>], active: &[bool]) {
+        self.t += 1.;
+
+        let AdamOptimiserBuilder {
+            learning_rate,
+            beta_1,
+            beta_2,
+            epsilon,
+            weight_decay,
+            ..
+        } = self.config;
+        let mut v_max = self.v_max.as_mut();
+        self.m
+            .iter_mut()
+            .zip(self.v.iter_mut())
+            .zip(data.iter_mut())
+            .enumerate()
+            .filter(|(i, _)| active[*i])
+            .for_each(|(i, ((m, v), d))| {
+                let grad: f64 = decayed(d, weight_decay);
+
+                *m = beta_1 * *m + (1. - beta_1) * grad;
+                *v = beta_2 * *v + (1. - beta_2) * grad.powf(2.);
+
+                let beta1 = beta_1.powf(self.t);
+                let beta2 = beta_2.powf(self.t);
+                let alpha = learning_rate * (1. - beta2).sqrt() / (1. - beta1);
+
+                let v = match v_max {
+                    Some(ref mut v_max) => {
+                        v_max[i] = v_max[i].max(*v);
+                        v_max[i]
+                    }
+                    None => *v,
+                };
+                d.value -= alpha * *m / (v.sqrt() + epsilon)
+            });
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.config.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.config.learning_rate = learning_rate;
+    }
+
+    fn momentum(&self) -> Option<f64> {
+        Some(self.config.beta_1)
+    }
+
+    fn set_momentum(&mut self, momentum: f64) {
+        self.config.beta_1 = momentum;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let mut vectors = vec![("m", &self.m[..]), ("v", &self.v[..])];
+        if let Some(v_max) = &self.v_max {
+            vectors.push(("v_max", v_max));
+        }
+        let scalars = [
+            ("t", self.t),
+            ("lr", self.config.learning_rate),
+            ("beta_1", self.config.beta_1),
+        ];
+        state_of(&vectors, &scalars)
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        restore(state, "m", &mut self.m);
+        restore(state, "v", &mut self.v);
+        if let Some(v_max) = &mut self.v_max {
+            restore(state, "v_max", v_max);
+        }
+        self.t = restore_scalar(state, "t");
+        self.config.learning_rate = restore_scalar(state, "lr");
+        self.config.beta_1 = restore_scalar(state, "beta_1");
+    }
+}
+
+/// Hyperparameters of an `AdamOptimiser`, e.g.
+/// `AdamOptimiser::builder().lr(3e-4).betas(0.9, 0.99).eps(1e-8).build(num_params)`.
+#[derive(Debug, Clone, Copy)]
+pub struct AdamOptimiserBuilder {
+    learning_rate: f64,
+    beta_1: f64,
+    beta_2: f64,
+    epsilon: f64,
+    weight_decay: f64,
+    amsgrad: bool,
+}
+
+impl AdamOptimiserBuilder {
+    pub fn lr(mut self, learning_rate: f64) -> AdamOptimiserBuilder {
+        self.learning_rate = learning_rate;
+        self
+    }
+
+    /// Decay rates of the running averages of the gradients and of their squares.
+    pub fn betas(mut self, beta_1: f64, beta_2: f64) -> AdamOptimiserBuilder {
+        self.beta_1 = beta_1;
+        self.beta_2 = beta_2;
+        self
+    }
+
+    pub fn eps(mut self, epsilon: f64) -> AdamOptimiserBuilder {
+        self.epsilon = epsilon;
+        self
+    }
+
+    /// Strength of the L2 penalty added to the gradients.
+    pub fn weight_decay(mut self, weight_decay: f64) -> AdamOptimiserBuilder {
+        self.weight_decay = weight_decay;
+        self
+    }
+
+    pub fn amsgrad(mut self, amsgrad: bool) -> AdamOptimiserBuilder {
+        self.amsgrad = amsgrad;
+        self
+    }
+
+    pub fn build(self, num_params: usize) -> AdamOptimiser {
         AdamOptimiser {
+            config: self,
             m: vec![0.; num_params],
             v: vec![0.; num_params],
+            v_max: self.amsgrad.then(|| vec![0.; num_params]),
             t: 0.,
         }
     }
 }
 
-impl Optimiser for AdamOptimiser {
+/// Adam with Nesterov momentum, i.e. whose step looks ahead by using the first moment as it
+/// will be after the next update.
+pub struct NadamOptimiser {
+    learning_rate: f64,
+    weight_decay: f64,
+    m: Vec<f64>,
+    v: Vec<f64>,
+    t: f64,
+}
+
+impl NadamOptimiser {
+    const ALPHA: f64 = 0.002;
+    const BETA_1: f64 = 0.9;
+    const BETA_2: f64 = 0.999;
+    const EPSILON: f64 = 1e-8;
+
+    pub fn new(num_params: usize) -> NadamOptimiser {
+        NadamOptimiser {
+            learning_rate: Self::ALPHA,
+            weight_decay: 0.,
+            m: vec![0.; num_params],
+            v: vec![0.; num_params],
+            t: 0.,
+        }
+    }
+
+    /// Adds an L2 penalty of strength `weight_decay` to the gradients.
+    pub fn with_weight_decay(mut self, weight_decay: f64) -> NadamOptimiser {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimiser for NadamOptimiser {
     fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_masked(data, &one_group(data), &all_active(data));
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], _groups: &[Vec<usize>], active: &[bool]) {
         self.t += 1.;
 
+        let beta1 = Self::BETA_1.powf(self.t);
+        let beta2 = Self::BETA_2.powf(self.t);
         self.m
             .iter_mut()
             .zip(self.v.iter_mut())
             .zip(data.iter_mut())
-            .for_each(|((m, v), d)| {
-                let grad: f64 = d.gradient;
+            .zip(active)
+            .filter(|(_, active)| **active)
+            .for_each(|(((m, v), d), _)| {
+                let grad: f64 = decayed(d, self.weight_decay);
 
                 *m = Self::BETA_1 * *m + (1. - Self::BETA_1) * grad;
                 *v = Self::BETA_2 * *v + (1. - Self::BETA_2) * grad.powf(2.);
 
-                let beta1 = Self::BETA_1.powf(self.t);
-                let beta2 = Self::BETA_2.powf(self.t);
-                let alpha = Self::ALPHA * (1. - beta2).sqrt() / (1. - beta1);
+                let m_hat = Self::BETA_1 * *m / (1. - beta1 * Self::BETA_1)
+                    + (1. - Self::BETA_1) * grad / (1. - beta1);
+                let v_hat = *v / (1. - beta2);
 
-                d.value -= alpha * *m / (v.sqrt() + Self::EPSILON)
+                d.value -= self.learning_rate * m_hat / (v_hat.sqrt() + Self::EPSILON)
             });
     }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let scalars = [("t", self.t), ("lr", self.learning_rate)];
+        state_of(&[("m", &self.m), ("v", &self.v)], &scalars)
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        restore(state, "m", &mut self.m);
+        restore(state, "v", &mut self.v);
+        self.t = restore_scalar(state, "t");
+        self.learning_rate = restore_scalar(state, "lr");
+    }
+}
+
+/// Adam with decoupled weight decay whose step is rescaled, for each group of parameters, by
+/// the trust ratio of the norm of the group to the norm of its step, so that every layer learns
+/// at a pace relative to the size of its weights regardless of the scale of its gradients.
+pub struct LambOptimiser {
+    learning_rate: f64,
+    weight_decay: f64,
+    m: Vec<f64>,
+    v: Vec<f64>,
+    t: f64,
+}
+
+impl LambOptimiser {
+    const BETA_1: f64 = 0.9;
+    const BETA_2: f64 = 0.999;
+    const EPSILON: f64 = 1e-6;
+
+    pub fn new(num_params: usize, learning_rate: f64, weight_decay: f64) -> LambOptimiser {
+        LambOptimiser {
+            learning_rate,
+            weight_decay,
+            m: vec![0.; num_params],
+            v: vec![0.; num_params],
+            t: 0.,
+        }
+    }
+}
+
+impl Optimiser for LambOptimiser {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_groups(data, &one_group(data));
+    }
+
+    fn optimise_groups(&mut self, data: &mut [Data], groups: &[Vec<usize>]) {
+        self.optimise_masked(data, groups, &all_active(data));
+    }
+
+    /// Trust ratios are computed over the active parameters of each group.
+    fn optimise_masked(&mut self, data: &mut [Data], groups: &[Vec<usize>], active: &[bool]) {
+        self.t += 1.;
+
+        let beta1 = Self::BETA_1.powf(self.t);
+        let beta2 = Self::BETA_2.powf(self.t);
+        let steps: Vec<f64> = self
+            .m
+            .iter_mut()
+            .zip(self.v.iter_mut())
+            .zip(data.iter())
+            .zip(active)
+            .map(|(((m, v), d), active)| {
+                if !active {
+                    return 0.;
+                }
+                let grad: f64 = d.gradient;
+
+                *m = Self::BETA_1 * *m + (1. - Self::BETA_1) * grad;
+                *v = Self::BETA_2 * *v + (1. - Self::BETA_2) * grad.powf(2.);
+
+                let m_hat = *m / (1. - beta1);
+                let v_hat = *v / (1. - beta2);
+                m_hat / (v_hat.sqrt() + Self::EPSILON) + self.weight_decay * d.value
+            })
+            .collect();
+
+        groups.iter().for_each(|group| {
+            let group: Vec<usize> = group.iter().cloned().filter(|i| active[*i]).collect();
+            let norm = |values: &dyn Fn(usize) -> f64| {
+                group
+                    .iter()
+                    .map(|i| values(*i).powf(2.))
+                    .sum::<f64>()
+                    .sqrt()
+            };
+            let weight_norm = norm(&|i| data[i].value);
+            let step_norm = norm(&|i| steps[i]);
+            let trust_ratio = match weight_norm > 0. && step_norm > 0. {
+                true => weight_norm / step_norm,
+                false => 1.,
+            };
+            group
+                .iter()
+                .for_each(|i| data[*i].value -= self.learning_rate * trust_ratio * steps[*i]);
+        });
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let scalars = [("t", self.t), ("lr", self.learning_rate)];
+        state_of(&[("m", &self.m), ("v", &self.v)], &scalars)
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        restore(state, "m", &mut self.m);
+        restore(state, "v", &mut self.v);
+        self.t = restore_scalar(state, "t");
+        self.learning_rate = restore_scalar(state, "lr");
+    }
 }
 
 pub struct LearningRateOptimiser {
     learning_rate: f64,
+    weight_decay: f64,
 }
 
 impl LearningRateOptimiser {
     pub fn new(learning_rate: f64) -> LearningRateOptimiser {
-        LearningRateOptimiser { learning_rate }
+        LearningRateOptimiser {
+            learning_rate,
+            weight_decay: 0.,
+        }
+    }
+
+    /// Adds an L2 penalty of strength `weight_decay` to the gradients.
+    pub fn with_weight_decay(mut self, weight_decay: f64) -> LearningRateOptimiser {
+        self.weight_decay = weight_decay;
+        self
     }
 }
 
 impl Optimiser for LearningRateOptimiser {
     fn optimise(&mut self, data: &mut [Data]) {
         data.iter_mut().for_each(|v| {
-            v.value -= self.learning_rate * v.gradient;
+            v.value -= self.learning_rate * decayed(v, self.weight_decay);
         });
     }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        state_of(&[], &[("lr", self.learning_rate)])
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.learning_rate = restore_scalar(state, "lr");
+    }
+}
+
+/// Divides the gradients by the root of a running average of their squares, optionally with
+/// momentum on the resulting steps.
+pub struct RmsPropOptimiser {
+    learning_rate: f64,
+    momentum: f64,
+    weight_decay: f64,
+    mean_square: Vec<f64>,
+    velocity: Vec<f64>,
+}
+
+impl RmsPropOptimiser {
+    const RHO: f64 = 0.9;
+    const EPSILON: f64 = 1e-8;
+
+    pub fn new(num_params: usize, learning_rate: f64) -> RmsPropOptimiser {
+        RmsPropOptimiser {
+            learning_rate,
+            momentum: 0.,
+            weight_decay: 0.,
+            mean_square: vec![0.; num_params],
+            velocity: vec![0.; num_params],
+        }
+    }
+
+    /// Adds momentum to the steps, 0 by default.
+    pub fn with_momentum(mut self, momentum: f64) -> RmsPropOptimiser {
+        self.momentum = momentum;
+        self
+    }
+
+    /// Adds an L2 penalty of strength `weight_decay` to the gradients.
+    pub fn with_weight_decay(mut self, weight_decay: f64) -> RmsPropOptimiser {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimiser for RmsPropOptimiser {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_masked(data, &one_group(data), &all_active(data));
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], _groups: &[Vec<usize>], active: &[bool]) {
+        self.mean_square
+            .iter_mut()
+            .zip(self.velocity.iter_mut())
+            .zip(data.iter_mut())
+            .zip(active)
+            .filter(|(_, active)| **active)
+            .for_each(|(((s, b), d), _)| {
+                let grad = decayed(d, self.weight_decay);
+
+                *s = Self::RHO * *s + (1. - Self::RHO) * grad.powf(2.);
+                *b = self.momentum * *b + grad / (s.sqrt() + Self::EPSILON);
+
+                d.value -= self.learning_rate * *b
+            });
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn momentum(&self) -> Option<f64> {
+        Some(self.momentum)
+    }
+
+    fn set_momentum(&mut self, momentum: f64) {
+        self.momentum = momentum;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let vectors = [
+            ("mean_square", &self.mean_square[..]),
+            ("velocity", &self.velocity[..]),
+        ];
+        let scalars = [("lr", self.learning_rate), ("momentum", self.momentum)];
+        state_of(&vectors, &scalars)
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        restore(state, "mean_square", &mut self.mean_square);
+        restore(state, "velocity", &mut self.velocity);
+        self.learning_rate = restore_scalar(state, "lr");
+        self.momentum = restore_scalar(state, "momentum");
+    }
+}
+
+/// Divides the gradients by the root of the sum of all their past squares, so that rarely
+/// updated parameters, such as the rows of an embedding, keep taking large steps.
+pub struct AdaGradOptimiser {
+    learning_rate: f64,
+    weight_decay: f64,
+    sum_square: Vec<f64>,
+}
+
+impl AdaGradOptimiser {
+    const EPSILON: f64 = 1e-10;
+
+    pub fn new(num_params: usize, learning_rate: f64) -> AdaGradOptimiser {
+        AdaGradOptimiser {
+            learning_rate,
+            weight_decay: 0.,
+            sum_square: vec![0.; num_params],
+        }
+    }
+
+    /// Adds an L2 penalty of strength `weight_decay` to the gradients.
+    pub fn with_weight_decay(mut self, weight_decay: f64) -> AdaGradOptimiser {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimiser for AdaGradOptimiser {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_masked(data, &one_group(data), &all_active(data));
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], _groups: &[Vec<usize>], active: &[bool]) {
+        self.sum_square
+            .iter_mut()
+            .zip(data.iter_mut())
+            .zip(active)
+            .filter(|(_, active)| **active)
+            .for_each(|((s, d), _)| {
+                let grad = decayed(d, self.weight_decay);
+                *s += grad.powf(2.);
+                d.value -= self.learning_rate * grad / (s.sqrt() + Self::EPSILON)
+            });
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        state_of(
+            &[("sum_square", &self.sum_square)],
+            &[("lr", self.learning_rate)],
+        )
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        restore(state, "sum_square", &mut self.sum_square);
+        self.learning_rate = restore_scalar(state, "lr");
+    }
+}
+
+/// Scales the gradients by the ratio of the roots of running averages of the squared updates
+/// and of the squared gradients, so that the steps have the units of the parameters and need
+/// no learning rate.
+pub struct AdaDeltaOptimiser {
+    /// Scale of the updates, 1 unless set by a schedule.
+    learning_rate: f64,
+    weight_decay: f64,
+    mean_square_grad: Vec<f64>,
+    mean_square_update: Vec<f64>,
+}
+
+impl AdaDeltaOptimiser {
+    const RHO: f64 = 0.95;
+    const EPSILON: f64 = 1e-6;
+
+    pub fn new(num_params: usize) -> AdaDeltaOptimiser {
+        AdaDeltaOptimiser {
+            learning_rate: 1.,
+            weight_decay: 0.,
+            mean_square_grad: vec![0.; num_params],
+            mean_square_update: vec![0.; num_params],
+        }
+    }
+
+    /// Adds an L2 penalty of strength `weight_decay` to the gradients.
+    pub fn with_weight_decay(mut self, weight_decay: f64) -> AdaDeltaOptimiser {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimiser for AdaDeltaOptimiser {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_masked(data, &one_group(data), &all_active(data));
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], _groups: &[Vec<usize>], active: &[bool]) {
+        self.mean_square_grad
+            .iter_mut()
+            .zip(self.mean_square_update.iter_mut())
+            .zip(data.iter_mut())
+            .zip(active)
+            .filter(|(_, active)| **active)
+            .for_each(|(((g, u), d), _)| {
+                let grad = decayed(d, self.weight_decay);
+
+                *g = Self::RHO * *g + (1. - Self::RHO) * grad.powf(2.);
+                let update = -(*u + Self::EPSILON).sqrt() / (*g + Self::EPSILON).sqrt() * grad;
+                *u = Self::RHO * *u + (1. - Self::RHO) * update.powf(2.);
+
+                d.value += self.learning_rate * update
+            });
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let vectors = [
+            ("mean_square_grad", &self.mean_square_grad[..]),
+            ("mean_square_update", &self.mean_square_update[..]),
+        ];
+        state_of(&vectors, &[("lr", self.learning_rate)])
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        restore(state, "mean_square_grad", &mut self.mean_square_grad);
+        restore(state, "mean_square_update", &mut self.mean_square_update);
+        self.learning_rate = restore_scalar(state, "lr");
+    }
+}
+
+/// Clamps every gradient to `[-max, max]`.
+pub fn clip_grad_value(data: &mut [Data], max: f64) {
+    data.iter_mut()
+        .for_each(|d| d.gradient = d.gradient.clamp(-max, max));
+}
+
+/// Rescales the gradients so that their global L2 norm is at most `max_norm`, preserving their
+/// direction, and returns the norm before clipping.
+pub fn clip_grad_norm(data: &mut [Data], max_norm: f64) -> f64 {
+    let norm = data.iter().map(|d| d.gradient.powf(2.)).sum::<f64>().sqrt();
+    if norm > max_norm {
+        data.iter_mut().for_each(|d| d.gradient *= max_norm / norm);
+    }
+    norm
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradientClip {
+    /// See `clip_grad_value`.
+    Value(f64),
+    /// See `clip_grad_norm`.
+    Norm(f64),
+}
+
+/// Clips the gradients before every step of the wrapped optimiser.
+pub struct ClippedOptimiser<O: Optimiser> {
+    optimiser: O,
+    clip: GradientClip,
+}
+
+impl<O: Optimiser> ClippedOptimiser<O> {
+    pub fn new(optimiser: O, clip: GradientClip) -> ClippedOptimiser<O> {
+        ClippedOptimiser { optimiser, clip }
+    }
+
+    fn clip(&self, data: &mut [Data]) {
+        match self.clip {
+            GradientClip::Value(max) => clip_grad_value(data, max),
+            GradientClip::Norm(max_norm) => {
+                clip_grad_norm(data, max_norm);
+            }
+        }
+    }
+}
+
+impl<O: Optimiser> Optimiser for ClippedOptimiser<O> {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.clip(data);
+        self.optimiser.optimise(data);
+    }
+
+    fn optimise_groups(&mut self, data: &mut [Data], groups: &[Vec<usize>]) {
+        self.clip(data);
+        self.optimiser.optimise_groups(data, groups);
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], groups: &[Vec<usize>], active: &[bool]) {
+        self.clip(data);
+        self.optimiser.optimise_masked(data, groups, active);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.optimiser.learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.optimiser.set_learning_rate(learning_rate);
+    }
+
+    fn momentum(&self) -> Option<f64> {
+        self.optimiser.momentum()
+    }
+
+    fn set_momentum(&mut self, momentum: f64) {
+        self.optimiser.set_momentum(momentum);
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        self.optimiser.save_state()
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.optimiser.load_state(state);
+    }
+}
+
+/// Gradient centralisation: subtracts from the gradients of each row of parameters, e.g. the
+/// incoming weights of each unit given by `Sequential::incoming_weight_rows`, their mean before
+/// every step of the wrapped optimiser. Parameters in no row, such as biases, are left alone.
+pub struct CentralisedOptimiser<O: Optimiser> {
+    optimiser: O,
+    rows: Vec<Vec<usize>>,
+}
+
+impl<O: Optimiser> CentralisedOptimiser<O> {
+    /// Centralises the rows of indices into the parameters handed to the optimiser.
+    pub fn new(optimiser: O, rows: Vec<Vec<usize>>) -> CentralisedOptimiser<O> {
+        CentralisedOptimiser { optimiser, rows }
+    }
+
+    fn centralise(&self, data: &mut [Data]) {
+        self.rows
+            .iter()
+            .filter(|row| row.len() > 1)
+            .for_each(|row| {
+                if let Some(i) = row.iter().find(|i| **i >= data.len()) {
+                    panic!("Expected indices below {}, but got {}", data.len(), i)
+                }
+                let mean = row.iter().map(|i| data[*i].gradient).sum::<f64>() / row.len() as f64;
+                row.iter().for_each(|i| data[*i].gradient -= mean);
+            });
+    }
+}
+
+impl<O: Optimiser> Optimiser for CentralisedOptimiser<O> {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.centralise(data);
+        self.optimiser.optimise(data);
+    }
+
+    fn optimise_groups(&mut self, data: &mut [Data], groups: &[Vec<usize>]) {
+        self.centralise(data);
+        self.optimiser.optimise_groups(data, groups);
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], groups: &[Vec<usize>], active: &[bool]) {
+        self.centralise(data);
+        self.optimiser.optimise_masked(data, groups, active);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.optimiser.learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.optimiser.set_learning_rate(learning_rate);
+    }
+
+    fn momentum(&self) -> Option<f64> {
+        self.optimiser.momentum()
+    }
+
+    fn set_momentum(&mut self, momentum: f64) {
+        self.optimiser.set_momentum(momentum);
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        self.optimiser.save_state()
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.optimiser.load_state(state);
+    }
+}
+
+/// Optimises each group of parameters, e.g. each layer of a `Sequential`, with its own
+/// optimiser, so that groups can have different learning rates and weight decays, e.g. to
+/// fine-tune the lower layers of a pretrained model more gently than the upper ones.
+pub struct GroupedOptimiser<O: Optimiser> {
+    optimisers: Vec<O>,
+    /// Learning rates of the groups when the optimiser was created, which `set_learning_rate`
+    /// scales, so that groups starting at 0 stay there.
+    base_rates: Vec<f64>,
+    learning_rate: f64,
+}
+
+impl<O: Optimiser> GroupedOptimiser<O> {
+    /// One optimiser per group, in the order of the groups, each for as many parameters as its
+    /// group has.
+    pub fn new(optimisers: Vec<O>) -> GroupedOptimiser<O> {
+        if optimisers.is_empty() {
+            panic!("Expected at least one optimiser")
+        }
+        let base_rates: Vec<f64> = optimisers.iter().map(|o| o.learning_rate()).collect();
+        GroupedOptimiser {
+            learning_rate: base_rates.iter().cloned().fold(0., f64::max),
+            optimisers,
+            base_rates,
+        }
+    }
+
+    pub fn groups(&self) -> &[O] {
+        &self.optimisers
+    }
+
+    pub fn groups_mut(&mut self) -> &mut [O] {
+        &mut self.optimisers
+    }
+}
+
+impl<O: Optimiser> Optimiser for GroupedOptimiser<O> {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimise_groups(data, &one_group(data));
+    }
+
+    fn optimise_groups(&mut self, data: &mut [Data], groups: &[Vec<usize>]) {
+        self.optimise_masked(data, groups, &all_active(data));
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], groups: &[Vec<usize>], active: &[bool]) {
+        if groups.len() != self.optimisers.len() {
+            panic!(
+                "Expected {} parameter groups, but got {}",
+                self.optimisers.len(),
+                groups.len()
+            )
+        }
+        self.optimisers
+            .iter_mut()
+            .zip(groups)
+            .for_each(|(optimiser, group)| {
+                let mut group_data: Vec<Data> = group.iter().map(|i| data[*i].clone()).collect();
+                let group_active: Vec<bool> = group.iter().map(|i| active[*i]).collect();
+                let whole = one_group(&group_data);
+                optimiser.optimise_masked(&mut group_data, &whole, &group_active);
+                group.iter().zip(group_data).for_each(|(i, d)| data[*i] = d);
+            });
+    }
+
+    /// Learning rate of the group with the highest base rate.
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    /// Scales the base rates of all the groups by the ratio of `learning_rate` to the highest of
+    /// them, or sets all the groups to `learning_rate` if they all started at 0.
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        let peak = self.base_rates.iter().cloned().fold(0., f64::max);
+        self.optimisers
+            .iter_mut()
+            .zip(self.base_rates.iter())
+            .for_each(|(optimiser, base)| match peak > 0. {
+                true => optimiser.set_learning_rate(base * learning_rate / peak),
+                false => optimiser.set_learning_rate(learning_rate),
+            });
+        self.learning_rate = learning_rate;
+    }
+
+    fn momentum(&self) -> Option<f64> {
+        self.optimisers[0].momentum()
+    }
+
+    fn set_momentum(&mut self, momentum: f64) {
+        self.optimisers
+            .iter_mut()
+            .for_each(|optimiser| optimiser.set_momentum(momentum));
+    }
+
+    /// States of the groups, with names prefixed by the index of the group, e.g. `0.m`, along
+    /// with the overall learning rate.
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let mut state: BTreeMap<String, Tensor> = self
+            .optimisers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, optimiser)| {
+                optimiser
+                    .save_state()
+                    .into_iter()
+                    .map(move |(name, tensor)| (format!("{i}.{name}"), tensor))
+            })
+            .collect();
+        state.insert("lr".to_string(), Tensor::scalar(self.learning_rate));
+        state
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.learning_rate = restore_scalar(state, "lr");
+        self.optimisers
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, optimiser)| {
+                let prefix = format!("{i}.");
+                let group_state = state
+                    .iter()
+                    .filter_map(|(name, tensor)| {
+                        name.strip_prefix(&prefix)
+                            .map(|name| (name.to_string(), tensor.clone()))
+                    })
+                    .collect();
+                optimiser.load_state(&group_state);
+            });
+    }
+}
+
+/// Stochastic weight averaging: running mean of snapshots of the parameters, e.g. taken after
+/// every epoch over the tail of training, which lies in a flatter region of the loss than the
+/// last weights and so tends to generalise better. Swap it into the model at the end with
+/// `Sequential::set_parameters`. Running statistics, e.g. of batch norms, aren't averaged.
+#[derive(Debug, Clone, Default)]
+pub struct WeightAverage {
+    average: Vec<f64>,
+    count: usize,
+}
+
+impl WeightAverage {
+    pub fn new() -> WeightAverage {
+        WeightAverage::default()
+    }
+
+    /// Adds a snapshot of the flat parameters, e.g. from `Sequential::parameters`.
+    pub fn update(&mut self, parameters: &[f64]) {
+        if self.count == 0 {
+            self.average = parameters.to_vec();
+        } else if parameters.len() != self.average.len() {
+            panic!(
+                "Expected {} parameters, but got {}",
+                self.average.len(),
+                parameters.len()
+            )
+        } else {
+            let n = (self.count + 1) as f64;
+            self.average
+                .iter_mut()
+                .zip(parameters)
+                .for_each(|(a, p)| *a += (p - *a) / n);
+        }
+        self.count += 1;
+    }
+
+    /// Mean of the snapshots so far.
+    pub fn average(&self) -> &[f64] {
+        &self.average
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// Exponential moving average of the parameters of a model, updated after every optimiser
+/// step, whose smoothed weights often evaluate better than the raw ones. `apply_ema` gives them
+/// to swap into the model, e.g. for validation, and `restore` gives back the raw ones to carry
+/// on training.
+#[derive(Debug, Clone)]
+pub struct ExponentialAverage {
+    decay: f64,
+    average: Vec<f64>,
+    raw: Option<Vec<f64>>,
+}
+
+impl ExponentialAverage {
+    /// Keeps `decay` of the average at each update, e.g. 0.999.
+    pub fn new(decay: f64) -> ExponentialAverage {
+        ExponentialAverage {
+            decay,
+            average: vec![],
+            raw: None,
+        }
+    }
+
+    /// Moves the average towards the flat parameters, e.g. from `Sequential::parameters`,
+    /// starting from them.
+    pub fn update(&mut self, parameters: &[f64]) {
+        if self.raw.is_some() {
+            panic!("The EMA weights are applied, see ExponentialAverage::restore")
+        }
+        if self.average.is_empty() {
+            self.average = parameters.to_vec();
+        } else if parameters.len() != self.average.len() {
+            panic!(
+                "Expected {} parameters, but got {}",
+                self.average.len(),
+                parameters.len()
+            )
+        } else {
+            self.average
+                .iter_mut()
+                .zip(parameters)
+                .for_each(|(a, p)| *a = self.decay * *a + (1. - self.decay) * p);
+        }
+    }
+
+    pub fn average(&self) -> &[f64] {
+        &self.average
+    }
+
+    /// Keeps the raw parameters of the model aside and returns the averaged ones to swap in.
+    pub fn apply_ema(&mut self, raw: Vec<f64>) -> &[f64] {
+        if self.raw.is_some() {
+            panic!("The EMA weights are already applied")
+        }
+        if raw.len() != self.average.len() {
+            panic!(
+                "Expected {} parameters, but got {}",
+                self.average.len(),
+                raw.len()
+            )
+        }
+        self.raw = Some(raw);
+        &self.average
+    }
+
+    /// Returns the raw parameters set aside by `apply_ema`, to put back into the model.
+    pub fn restore(&mut self) -> Vec<f64> {
+        match self.raw.take() {
+            Some(raw) => raw,
+            None => panic!("The EMA weights aren't applied, see ExponentialAverage::apply_ema"),
+        }
+    }
+}
+
+/// Function returning the loss and its gradient at a point.
+pub type LossAndGradient<'a> = dyn FnMut(&[f64]) -> (f64, Vec<f64>) + 'a;
+
+/// Optimiser that evaluates the objective as many times as it needs to take a step, e.g. for a
+/// line search, rather than being handed a single gradient.
+pub trait ClosureOptimiser {
+    /// Takes one step on the flat `parameters`, where `objective` returns the loss and its
+    /// gradient at a point. Returns the loss before the step.
+    fn step(&mut self, parameters: &mut [f64], objective: &mut LossAndGradient) -> f64;
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Armijo condition of the line searches: the loss has to decrease by at least this fraction of
+/// the decrease predicted by the gradient.
+const SUFFICIENT_DECREASE: f64 = 1e-4;
+const MAX_HALVINGS: usize = 30;
+
+/// Backtracking line search along `direction` from `parameters`, where the loss is `loss` and
+/// its slope along `direction` is `slope`: halves the step until the loss decreases enough.
+/// Returns the accepted point with its loss and gradient, or `None` if no step is good enough.
+fn backtrack(
+    parameters: &[f64],
+    direction: &[f64],
+    loss: f64,
+    slope: f64,
+    objective: &mut LossAndGradient,
+) -> Option<(Vec<f64>, f64, Vec<f64>)> {
+    let mut t = 1.;
+    (0..MAX_HALVINGS).find_map(|_| {
+        let candidate: Vec<f64> = parameters
+            .iter()
+            .zip(direction)
+            .map(|(p, d)| p + t * d)
+            .collect();
+        let (new_loss, new_grad) = objective(&candidate);
+        match new_loss <= loss + SUFFICIENT_DECREASE * t * slope {
+            true => Some((candidate, new_loss, new_grad)),
+            false => {
+                t /= 2.;
+                None
+            }
+        }
+    })
+}
+
+/// Takes the steps proposed by the wrapped optimiser as search directions, and backtracks along
+/// them until the loss decreases enough, so that a too large learning rate can't make the loss
+/// blow up. Steps that aren't descent directions, e.g. because of momentum, are taken as they
+/// are.
+pub struct LineSearchOptimiser<O: Optimiser> {
+    base: O,
+}
+
+impl<O: Optimiser> LineSearchOptimiser<O> {
+    pub fn new(base: O) -> LineSearchOptimiser<O> {
+        LineSearchOptimiser { base }
+    }
+
+    pub fn base(&self) -> &O {
+        &self.base
+    }
+
+    pub fn base_mut(&mut self) -> &mut O {
+        &mut self.base
+    }
+}
+
+impl<O: Optimiser> ClosureOptimiser for LineSearchOptimiser<O> {
+    fn step(&mut self, parameters: &mut [f64], objective: &mut LossAndGradient) -> f64 {
+        let (loss, grad) = objective(parameters);
+        let mut data: Vec<Data> = parameters
+            .iter()
+            .zip(&grad)
+            .map(|(value, gradient)| Data {
+                value: *value,
+                gradient: *gradient,
+            })
+            .collect();
+        self.base.optimise(&mut data);
+        let direction: Vec<f64> = data
+            .iter()
+            .zip(parameters.iter())
+            .map(|(d, p)| d.value - p)
+            .collect();
+        let slope = dot(&grad, &direction);
+        if slope >= 0. {
+            parameters.copy_from_slice(&data.iter().map(|d| d.value).collect::<Vec<f64>>());
+        } else if let Some((candidate, _, _)) =
+            backtrack(parameters, &direction, loss, slope, objective)
+        {
+            parameters.copy_from_slice(&candidate);
+        }
+        loss
+    }
+}
+
+/// Limited-memory BFGS, which approximates Newton steps from the last `history` changes of the
+/// parameters and gradients, with a backtracking line search along each direction. It needs
+/// the loss over the whole dataset rather than over batches, which suits small deterministic
+/// problems.
+pub struct LbfgsOptimiser {
+    history: usize,
+    max_iterations: usize,
+    tolerance: f64,
+    /// Changes of the parameters and of the gradients over the last iterations.
+    steps: VecDeque<(Vec<f64>, Vec<f64>)>,
+}
+
+impl LbfgsOptimiser {
+    pub fn new(history: usize) -> LbfgsOptimiser {
+        LbfgsOptimiser {
+            history,
+            max_iterations: 20,
+            tolerance: 1e-9,
+            steps: VecDeque::new(),
+        }
+    }
+
+    /// Iterations per `step`, 20 by default.
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> LbfgsOptimiser {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Stops iterating once the largest gradient or the change of the loss falls below
+    /// `tolerance`, 1e-9 by default.
+    pub fn with_tolerance(mut self, tolerance: f64) -> LbfgsOptimiser {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Approximates `-H^-1 * grad` with the two-loop recursion over the history.
+    fn direction(&self, grad: &[f64]) -> Vec<f64> {
+        let mut q = grad.to_vec();
+        let alphas: Vec<f64> = self
+            .steps
+            .iter()
+            .rev()
+            .map(|(s, y)| {
+                let alpha = dot(s, &q) / dot(y, s);
+                q.iter_mut().zip(y).for_each(|(q, y)| *q -= alpha * y);
+                alpha
+            })
+            .collect();
+
+        let gamma = match self.steps.back() {
+            Some((s, y)) => dot(s, y) / dot(y, y),
+            None => 1. / grad.iter().map(|g| g.abs()).sum::<f64>().max(1.),
+        };
+        q.iter_mut().for_each(|q| *q *= gamma);
+
+        self.steps
+            .iter()
+            .zip(alphas.iter().rev())
+            .for_each(|((s, y), alpha)| {
+                let beta = dot(y, &q) / dot(y, s);
+                q.iter_mut()
+                    .zip(s)
+                    .for_each(|(q, s)| *q += (alpha - beta) * s);
+            });
+        q.iter().map(|q| -q).collect()
+    }
+}
+
+impl ClosureOptimiser for LbfgsOptimiser {
+    fn step(&mut self, parameters: &mut [f64], objective: &mut LossAndGradient) -> f64 {
+        let (initial, mut grad) = objective(parameters);
+        let mut loss = initial;
+        for _ in 0..self.max_iterations {
+            if grad.iter().all(|g| g.abs() <= self.tolerance) {
+                break;
+            }
+            let mut direction = self.direction(&grad);
+            let mut slope = dot(&grad, &direction);
+            if slope >= 0. {
+                // Not a descent direction, so the history is of no use anymore.
+                self.steps.clear();
+                direction = self.direction(&grad);
+                slope = dot(&grad, &direction);
+            }
+
+            let accepted = backtrack(parameters, &direction, loss, slope, objective);
+            let Some((candidate, new_loss, new_grad)) = accepted else {
+                self.steps.clear();
+                break;
+            };
+
+            let s: Vec<f64> = candidate
+                .iter()
+                .zip(parameters.iter())
+                .map(|(c, p)| c - p)
+                .collect();
+            let y: Vec<f64> = new_grad.iter().zip(&grad).map(|(n, g)| n - g).collect();
+            if dot(&s, &y) > 1e-10 {
+                if self.steps.len() == self.history {
+                    self.steps.pop_front();
+                }
+                self.steps.push_back((s, y));
+            }
+
+            parameters.copy_from_slice(&candidate);
+            grad = new_grad;
+            let change = loss - new_loss;
+            loss = new_loss;
+            if change.abs() <= self.tolerance {
+                break;
+            }
+        }
+        initial
+    }
+}
+
+/// Sharpness-aware minimisation: first ascends to the worst-case weights within a ball of
+/// radius `rho` along the gradient, then updates the original weights with the `base` optimiser
+/// using the gradient found there, which favours flat minima. Since the gradient has to be
+/// recomputed at the perturbed weights, it takes its steps through `ClosureOptimiser`.
+pub struct SamOptimiser<O: Optimiser> {
+    base: O,
+    rho: f64,
+}
+
+impl<O: Optimiser> SamOptimiser<O> {
+    pub fn new(base: O, rho: f64) -> SamOptimiser<O> {
+        SamOptimiser { base, rho }
+    }
+
+    pub fn base(&self) -> &O {
+        &self.base
+    }
+
+    pub fn base_mut(&mut self) -> &mut O {
+        &mut self.base
+    }
+}
+
+impl<O: Optimiser> ClosureOptimiser for SamOptimiser<O> {
+    fn step(&mut self, parameters: &mut [f64], objective: &mut LossAndGradient) -> f64 {
+        let (loss, grad) = objective(parameters);
+        let norm = dot(&grad, &grad).sqrt();
+        let scale = match norm > 0. {
+            true => self.rho / norm,
+            false => 0.,
+        };
+        let perturbed: Vec<f64> = parameters
+            .iter()
+            .zip(&grad)
+            .map(|(p, g)| p + scale * g)
+            .collect();
+        let (_, sharp_grad) = objective(&perturbed);
+
+        let mut data: Vec<Data> = parameters
+            .iter()
+            .zip(sharp_grad)
+            .map(|(value, gradient)| Data {
+                value: *value,
+                gradient,
+            })
+            .collect();
+        self.base.optimise(&mut data);
+        parameters
+            .iter_mut()
+            .zip(data)
+            .for_each(|(p, d)| *p = d.value);
+        loss
+    }
+}
+
+/// Optimiser that only evaluates the loss, for objectives without usable gradients, e.g.
+/// with step activations or a non-differentiable metric such as the error rate.
+pub trait GradientFreeOptimiser {
+    /// Takes one step on the flat `parameters`, where `loss` evaluates a point. Returns the
+    /// loss before the step.
+    fn step(&mut self, parameters: &mut [f64], loss: &mut dyn FnMut(&[f64]) -> f64) -> f64;
+}
+
+/// Evolution strategy: estimates the gradient from the losses of a population of Gaussian
+/// perturbations of the parameters, with scale `sigma`, and descends along it. Perturbations
+/// come in mirrored pairs, which cancels out most of the noise of the estimate.
+pub struct EvolutionOptimiser {
+    population: usize,
+    sigma: f64,
+    learning_rate: f64,
+    rng: StdRng,
+}
+
+impl EvolutionOptimiser {
+    /// `population` is rounded up to an even number, with perturbations drawn from a generator
+    /// seeded with `seed`, or from entropy if `None`.
+    pub fn new(
+        population: usize,
+        sigma: f64,
+        learning_rate: f64,
+        seed: Option<u64>,
+    ) -> EvolutionOptimiser {
+        EvolutionOptimiser {
+            population: population.div_ceil(2) * 2,
+            sigma,
+            learning_rate,
+            rng: seed
+                .map(StdRng::seed_from_u64)
+                .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap()),
+        }
+    }
+}
+
+impl GradientFreeOptimiser for EvolutionOptimiser {
+    fn step(&mut self, parameters: &mut [f64], loss: &mut dyn FnMut(&[f64]) -> f64) -> f64 {
+        let initial = loss(parameters);
+        let pairs = self.population / 2;
+        let mut gradient = vec![0.; parameters.len()];
+        for _ in 0..pairs {
+            let noise: Vec<f64> = parameters
+                .iter()
+                .map(|_| Util::standard_normal(&mut self.rng))
+                .collect();
+            let mut perturbed = |sign: f64| {
+                let point: Vec<f64> = parameters
+                    .iter()
+                    .zip(&noise)
+                    .map(|(p, n)| p + sign * self.sigma * n)
+                    .collect();
+                loss(&point)
+            };
+            let difference = perturbed(1.) - perturbed(-1.);
+            gradient
+                .iter_mut()
+                .zip(&noise)
+                .for_each(|(g, n)| *g += difference * n / (2. * pairs as f64 * self.sigma));
+        }
+        parameters
+            .iter_mut()
+            .zip(gradient)
+            .for_each(|(p, g)| *p -= self.learning_rate * g);
+        initial
+    }
+}
+
+/// Simulated annealing: proposes a Gaussian move of the parameters with scale `step_size` at
+/// every step, always accepting it if it lowers the loss and otherwise with probability
+/// `exp(-increase / temperature)`, so that it can escape local minima while the temperature is
+/// high. The temperature cools geometrically, by `cooling` per step.
+pub struct AnnealingOptimiser {
+    temperature: f64,
+    cooling: f64,
+    step_size: f64,
+    rng: StdRng,
+}
+
+impl AnnealingOptimiser {
+    /// Moves are drawn from a generator seeded with `seed`, or from entropy if `None`.
+    pub fn new(
+        temperature: f64,
+        cooling: f64,
+        step_size: f64,
+        seed: Option<u64>,
+    ) -> AnnealingOptimiser {
+        AnnealingOptimiser {
+            temperature,
+            cooling,
+            step_size,
+            rng: seed
+                .map(StdRng::seed_from_u64)
+                .unwrap_or_else(|| StdRng::from_rng(thread_rng()).unwrap()),
+        }
+    }
+
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+}
+
+impl GradientFreeOptimiser for AnnealingOptimiser {
+    fn step(&mut self, parameters: &mut [f64], loss: &mut dyn FnMut(&[f64]) -> f64) -> f64 {
+        let current = loss(parameters);
+        let proposal: Vec<f64> = parameters
+            .iter()
+            .map(|p| p + self.step_size * Util::standard_normal(&mut self.rng))
+            .collect();
+        let increase = loss(&proposal) - current;
+        if increase <= 0. || self.rng.gen::<f64>() < (-increase / self.temperature).exp() {
+            parameters.copy_from_slice(&proposal);
+        }
+        self.temperature *= self.cooling;
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{engine::Data, io, optimiser::*};
+
+    /// Runs `steps` steps of the optimiser on `sum((x - 3)^2)` from 0 and returns the final
+    /// distance to the minimum.
+    fn minimise(optimiser: &mut impl Optimiser, steps: usize) -> f64 {
+        let mut data = vec![Data::new(0.); 2];
+        (0..steps).for_each(|_| {
+            data.iter_mut()
+                .for_each(|d| d.gradient = 2. * (d.value - 3.));
+            optimiser.optimise(&mut data);
+        });
+        data.iter().map(|d| (d.value - 3.).abs()).fold(0., f64::max)
+    }
+
+    #[test]
+    fn test_rmsprop() {
+        let mut data = vec![Data::new(1.)];
+        data[0].gradient = 4.;
+        RmsPropOptimiser::new(1, 0.1).optimise(&mut data);
+        // The first step is lr / sqrt(1 - rho) regardless of the gradient's scale.
+        assert!((data[0].value - (1. - 0.1 / 0.1f64.sqrt())).abs() < 1e-6);
+
+        assert!(minimise(&mut RmsPropOptimiser::new(2, 0.01), 1000) < 0.05);
+        assert!(minimise(&mut RmsPropOptimiser::new(2, 0.01).with_momentum(0.9), 500) < 0.05);
+    }
+
+    #[test]
+    fn test_adagrad() {
+        let mut optimiser = AdaGradOptimiser::new(2, 0.5);
+        let mut data = vec![Data::new(1.), Data::new(1.)];
+        data[0].gradient = 2.;
+        optimiser.optimise(&mut data);
+        assert!((data[0].value - 0.5).abs() < 1e-9);
+        // Parameters without gradient don't move, nor accumulate anything.
+        assert_eq!(data[1].value, 1.);
+        data[0].gradient = 0.;
+        data[1].gradient = 2.;
+        optimiser.optimise(&mut data);
+        assert!((data[0].value - 0.5).abs() < 1e-9);
+        assert!((data[1].value - 0.5).abs() < 1e-9);
+
+        assert!(minimise(&mut AdaGradOptimiser::new(2, 1.), 500) < 0.05);
+    }
+
+    #[test]
+    fn test_adadelta() {
+        let mut data = vec![Data::new(1.)];
+        data[0].gradient = 100.;
+        AdaDeltaOptimiser::new(1).optimise(&mut data);
+        // The first step is about sqrt(eps / (1 - rho)), whatever the gradient.
+        assert!((1. - data[0].value - (1e-6f64 / 0.05).sqrt()).abs() < 1e-6);
+
+        assert!(minimise(&mut AdaDeltaOptimiser::new(2), 2000) < 0.05);
+    }
+
+    #[test]
+    fn test_amsgrad() {
+        let mut adam = AdamOptimiser::new(1);
+        let mut amsgrad = AdamOptimiser::builder().amsgrad(true).build(1);
+        let steps = |optimiser: &mut AdamOptimiser| {
+            let mut data = vec![Data::new(0.)];
+            [10., 0.1]
+                .iter()
+                .map(|grad| {
+                    let before = data[0].value;
+                    data[0].gradient = *grad;
+                    optimiser.optimise(&mut data);
+                    before - data[0].value
+                })
+                .collect::<Vec<f64>>()
+        };
+        let (adam, amsgrad) = (steps(&mut adam), steps(&mut amsgrad));
+        // The first step is the same, but the second one, after the gradient shrinks, isn't
+        // scaled by a smaller second moment.
+        assert_eq!(adam[0], amsgrad[0]);
+        assert!(amsgrad[1] < adam[1]);
+
+        assert!(minimise(&mut AdamOptimiser::builder().amsgrad(true).build(2), 10000) < 0.05);
+    }
+
+    #[test]
+    fn test_nadam() {
+        let mut data = vec![Data::new(0.)];
+        data[0].gradient = -5.;
+        NadamOptimiser::new(1).optimise(&mut data);
+        // The look-ahead first moment is corrected with beta_1^2, the gradient with beta_1.
+        let expected = 0.002 * (0.9 * 0.1 / (1. - 0.81) + 1.);
+        assert!((data[0].value - expected).abs() < 1e-9);
+
+        assert!(minimise(&mut NadamOptimiser::new(2), 3000) < 0.05);
+    }
+
+    #[test]
+    fn test_lamb() {
+        // Each group moves by lr times its own norm, however large its gradients.
+        let mut data: Vec<Data> = [3., 4., 0.5].iter().map(|v| Data::new(*v)).collect();
+        data.iter_mut()
+            .zip([100., 1., 0.01])
+            .for_each(|(d, g)| d.gradient = g);
+        LambOptimiser::new(3, 0.1, 0.).optimise_groups(&mut data, &[vec![0, 1], vec![2]]);
+        let moved = |i: usize, from: f64| from - data[i].value;
+        let first = (moved(0, 3.).powf(2.) + moved(1, 4.).powf(2.)).sqrt();
+        assert!((first - 0.1 * 5.).abs() < 1e-6);
+        assert!((moved(2, 0.5) - 0.1 * 0.5).abs() < 1e-6);
+
+        assert!(minimise(&mut LambOptimiser::new(2, 0.01, 0.), 2000) < 0.05);
+    }
+
+    #[test]
+    fn test_adam_builder() {
+        let step = |optimiser: &mut AdamOptimiser| {
+            let mut data = vec![Data::new(0.)];
+            data[0].gradient = 2.;
+            optimiser.optimise(&mut data);
+            -data[0].value
+        };
+        // The first step of Adam is the learning rate, whatever the betas.
+        assert!((step(&mut AdamOptimiser::new(1)) - 0.001).abs() < 1e-9);
+        let mut optimiser = AdamOptimiser::builder()
+            .lr(3e-4)
+            .betas(0.5, 0.99)
+            .eps(1e-8)
+            .build(1);
+        assert!((step(&mut optimiser) - 3e-4).abs() < 1e-9);
+
+        let optimiser = &mut AdamOptimiser::builder().lr(0.1).betas(0.8, 0.99).build(2);
+        assert!(minimise(optimiser, 300) < 0.05);
+    }
+
+    #[test]
+    fn test_weight_decay() {
+        // Without any gradient from the loss, weight decay alone shrinks the parameters.
+        let shrinks = |optimiser: &mut dyn Optimiser| {
+            let mut data = vec![Data::new(2.), Data::new(-2.)];
+            (0..10).for_each(|_| optimiser.optimise(&mut data));
+            data[0].value < 2. && data[0].value > 0. && data[1].value == -data[0].value
+        };
+        assert!(shrinks(
+            &mut LearningRateOptimiser::new(0.1).with_weight_decay(0.1)
+        ));
+        assert!(shrinks(
+            &mut AdamOptimiser::builder().weight_decay(0.1).build(2)
+        ));
+        assert!(shrinks(&mut NadamOptimiser::new(2).with_weight_decay(0.1)));
+        assert!(shrinks(
+            &mut RmsPropOptimiser::new(2, 0.01).with_weight_decay(0.1)
+        ));
+        assert!(shrinks(
+            &mut AdaGradOptimiser::new(2, 0.1).with_weight_decay(0.1)
+        ));
+        assert!(shrinks(
+            &mut AdaDeltaOptimiser::new(2).with_weight_decay(0.1)
+        ));
+        assert!(shrinks(&mut LambOptimiser::new(2, 0.01, 0.1)));
+
+        let mut data = vec![Data::new(2.)];
+        LearningRateOptimiser::new(1.).optimise(&mut data);
+        assert_eq!(data[0].value, 2.);
+    }
+
+    #[test]
+    fn test_gradient_clipping() {
+        let grads = |data: &[Data]| data.iter().map(|d| d.gradient).collect::<Vec<f64>>();
+        let mut data = vec![Data::new(0.); 3];
+        data.iter_mut()
+            .zip([3., -4., 0.5])
+            .for_each(|(d, g)| d.gradient = g);
+
+        let mut clipped = data.clone();
+        clip_grad_value(&mut clipped, 1.);
+        assert_eq!(grads(&clipped), vec![1., -1., 0.5]);
+        let mut clipped = data.clone();
+        assert_eq!(clip_grad_norm(&mut clipped, 10.), 25.25f64.sqrt());
+        assert_eq!(grads(&clipped), grads(&data));
+        clip_grad_norm(&mut clipped, 1.);
+        let norm = grads(&clipped).iter().map(|g| g * g).sum::<f64>().sqrt();
+        assert!((norm - 1.).abs() < 1e-12);
+        assert!((clipped[0].gradient / clipped[1].gradient - 3. / -4.).abs() < 1e-12);
+
+        let optimiser = LearningRateOptimiser::new(1.);
+        let mut clipped = ClippedOptimiser::new(optimiser, GradientClip::Value(0.5));
+        clipped.optimise(&mut data);
+        assert_eq!(
+            data.iter().map(|d| d.value).collect::<Vec<f64>>(),
+            vec![-0.5, 0.5, -0.5]
+        );
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let optimisers: Vec<Box<dyn Fn() -> Box<dyn Optimiser>>> = vec![
+            Box::new(|| Box::new(AdamOptimiser::new(2))),
+            Box::new(|| Box::new(AdamOptimiser::builder().amsgrad(true).build(2))),
+            Box::new(|| Box::new(NadamOptimiser::new(2))),
+            Box::new(|| Box::new(LambOptimiser::new(2, 0.01, 0.1))),
+            Box::new(|| Box::new(RmsPropOptimiser::new(2, 0.01).with_momentum(0.9))),
+            Box::new(|| Box::new(AdaGradOptimiser::new(2, 0.1))),
+            Box::new(|| Box::new(AdaDeltaOptimiser::new(2))),
+            Box::new(|| {
+                let optimiser = AdamOptimiser::new(2);
+                Box::new(ClippedOptimiser::new(optimiser, GradientClip::Norm(1.)))
+            }),
+        ];
+        optimisers.iter().for_each(|optimiser| {
+            let mut data = vec![Data::new(0.); 2];
+            let step = |optimiser: &mut Box<dyn Optimiser>, data: &mut [Data]| {
+                data.iter_mut()
+                    .for_each(|d| d.gradient = 2. * (d.value - 3.));
+                optimiser.optimise(data);
+            };
+
+            let mut trained = optimiser();
+            (0..5).for_each(|_| step(&mut trained, &mut data));
+            let state = io::read_safetensors(&io::write_safetensors(&trained.save_state()));
+            let mut resumed = optimiser();
+            resumed.load_state(&state);
+
+            let mut resumed_data = data.clone();
+            (0..5).for_each(|_| {
+                step(&mut trained, &mut data);
+                step(&mut resumed, &mut resumed_data);
+            });
+            let values = |data: &[Data]| data.iter().map(|d| d.value).collect::<Vec<f64>>();
+            assert_eq!(values(&data), values(&resumed_data));
+        });
+    }
+
+    #[test]
+    fn test_grouped_optimiser() {
+        let mut data: Vec<Data> = [1., 1., 1.].iter().map(|v| Data::new(*v)).collect();
+        data.iter_mut().for_each(|d| d.gradient = 1.);
+        let mut optimiser = GroupedOptimiser::new(vec![
+            LearningRateOptimiser::new(0.1),
+            LearningRateOptimiser::new(0.5).with_weight_decay(1.),
+        ]);
+        optimiser.optimise_groups(&mut data, &[vec![0, 2], vec![1]]);
+        let values: Vec<f64> = data.iter().map(|d| d.value).collect();
+        assert_eq!(values, vec![0.9, 0., 0.9]);
+
+        assert_eq!(optimiser.learning_rate(), 0.5);
+        optimiser.set_learning_rate(0.25);
+        assert_eq!(optimiser.groups()[0].learning_rate(), 0.05);
+        assert_eq!(optimiser.learning_rate(), 0.25);
+    }
+
+    #[test]
+    fn test_optimise_masked() {
+        let active = [true, false, true];
+        let mut data: Vec<Data> = [1., 1., 1.].iter().map(|v| Data::new(*v)).collect();
+        data.iter_mut().for_each(|d| d.gradient = 1.);
+        let mut optimiser = LearningRateOptimiser::new(0.1).with_weight_decay(1.);
+        optimiser.optimise_masked(&mut data, &[vec![0, 1, 2]], &active);
+        let values: Vec<f64> = data.iter().map(|d| d.value).collect();
+        assert_eq!(values, vec![0.8, 1., 0.8]);
+
+        let mut optimiser = GroupedOptimiser::new(vec![
+            AdamOptimiser::new(2),
+            AdamOptimiser::builder().weight_decay(1.).build(1),
+        ]);
+        optimiser.optimise_masked(&mut data, &[vec![0, 1], vec![2]], &active);
+        assert_eq!(data[1].value, 1.);
+        let state = optimiser.save_state();
+        assert_eq!(state["0.m"].data()[1], 0.);
+        assert_eq!(state["0.v"].data()[1], 0.);
+        assert!(state["0.m"].data()[0] > 0. && state["1.m"].data()[0] > 0.);
+    }
+
+    #[test]
+    fn test_grouped_optimiser_zero_rate() {
+        let mut optimiser = GroupedOptimiser::new(vec![
+            LearningRateOptimiser::new(0.),
+            LearningRateOptimiser::new(0.5),
+        ]);
+        optimiser.set_learning_rate(0.1);
+        optimiser.set_learning_rate(0.2);
+        let rates: Vec<f64> = optimiser
+            .groups()
+            .iter()
+            .map(|o| o.learning_rate())
+            .collect();
+        assert_eq!(rates, vec![0., 0.2]);
+    }
+
+    #[test]
+    fn test_weight_average() {
+        let mut swa = WeightAverage::new();
+        swa.update(&[1., -2.]);
+        swa.update(&[2., 0.]);
+        swa.update(&[6., 5.]);
+        assert_eq!(swa.count(), 3);
+        assert_eq!(swa.average(), &[3., 1.]);
+    }
+
+    #[test]
+    fn test_lbfgs() {
+        let rosenbrock = &mut |x: &[f64]| {
+            let (a, b) = (1. - x[0], x[1] - x[0] * x[0]);
+            let loss = a * a + 100. * b * b;
+            (loss, vec![-2. * a - 400. * b * x[0], 200. * b])
+        };
+        let mut optimiser = LbfgsOptimiser::new(10).with_max_iterations(100);
+        let mut x = [-1.2, 1.];
+        let initial = optimiser.step(&mut x, rosenbrock);
+        assert!((initial - 24.2).abs() < 1e-12);
+        (0..5).for_each(|_| {
+            optimiser.step(&mut x, rosenbrock);
+        });
+        assert!((x[0] - 1.).abs() < 1e-4 && (x[1] - 1.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_evolution() {
+        let quadratic = &mut |x: &[f64]| x.iter().map(|x| (x - 3.).powf(2.)).sum::<f64>();
+        let mut optimiser = EvolutionOptimiser::new(20, 0.1, 0.05, Some(0));
+        let mut x = [0., 0.];
+        let initial = optimiser.step(&mut x, quadratic);
+        assert_eq!(initial, 18.);
+        (0..200).for_each(|_| {
+            optimiser.step(&mut x, quadratic);
+        });
+        assert!(quadratic(&x) < 1e-2);
+    }
+
+    #[test]
+    fn test_annealing() {
+        // Two basins, with the global minimum at x = 2 behind a barrier from the start at -1.
+        let double_well = &mut |x: &[f64]| {
+            (x[0] * x[0] - 1.).powf(2.) * 0.5 - 0.5 * x[0] + (x[0] - 2.).powf(2.) * 0.1
+        };
+        let mut optimiser = AnnealingOptimiser::new(1., 0.99, 0.3, Some(4));
+        let mut x = [-1.];
+        (0..1000).for_each(|_| {
+            optimiser.step(&mut x, double_well);
+        });
+        assert!(optimiser.temperature() < 1e-4);
+        assert!(x[0] > 0.5);
+
+        // Once cold, only improvements are accepted.
+        let mut cold = AnnealingOptimiser::new(1e-12, 1., 0.1, Some(5));
+        let quadratic = &mut |x: &[f64]| x[0] * x[0];
+        let mut x = [1.];
+        let losses: Vec<f64> = (0..50).map(|_| cold.step(&mut x, quadratic)).collect();
+        assert!(losses.windows(2).all(|w| w[1] <= w[0]));
+    }
+
+    #[test]
+    fn test_sam() {
+        let mut quartic = |x: &[f64]| (x[0].powf(4.), vec![4. * x[0].powf(3.)]);
+
+        // Without the ascent SAM is its base optimiser.
+        let mut sam = SamOptimiser::new(LearningRateOptimiser::new(0.01), 0.);
+        let mut x = [1.];
+        assert_eq!(sam.step(&mut x, &mut quartic), 1.);
+        assert!((x[0] - 0.96).abs() < 1e-12);
+
+        // Otherwise the gradient is taken at x + rho, where the loss is steeper.
+        let mut sam = SamOptimiser::new(LearningRateOptimiser::new(0.01), 0.5);
+        let mut x = [1.];
+        assert_eq!(sam.step(&mut x, &mut quartic), 1.);
+        assert!((x[0] - (1. - 0.01 * 4. * 1.5f64.powf(3.))).abs() < 1e-12);
+        assert_eq!(sam.base().learning_rate(), 0.01);
+
+        let mut x = [1.];
+        (0..500).for_each(|_| {
+            sam.step(&mut x, &mut quartic);
+        });
+        assert!(x[0].abs() < 0.5);
+    }
+
+    #[test]
+    fn test_centralised() {
+        let gradients = [1., 2., 3., 10., -1.];
+        let step = |optimiser: &mut CentralisedOptimiser<LearningRateOptimiser>,
+                    groups: &[Vec<usize>]| {
+            let mut data: Vec<Data> = gradients
+                .iter()
+                .map(|g| Data {
+                    value: 0.,
+                    gradient: *g,
+                })
+                .collect();
+            match groups.is_empty() {
+                true => optimiser.optimise(&mut data),
+                false => optimiser.optimise_groups(&mut data, groups),
+            }
+            data.iter().map(|d| d.value).collect::<Vec<f64>>()
+        };
+
+        // The parameters in no row, e.g. biases, keep their gradients.
+        let rows = vec![vec![0, 1, 2], vec![3, 4]];
+        let mut optimiser = CentralisedOptimiser::new(LearningRateOptimiser::new(1.), rows);
+        assert_eq!(step(&mut optimiser, &[]), vec![1., 0., -1., -5.5, 5.5]);
+        let mut optimiser =
+            CentralisedOptimiser::new(LearningRateOptimiser::new(1.), vec![vec![0, 1, 2]]);
+        assert_eq!(
+            step(&mut optimiser, &[vec![0, 1, 2, 3, 4]]),
+            vec![1., 0., -1., -10., 1.]
+        );
+        optimiser.set_learning_rate(0.5);
+        assert_eq!(optimiser.learning_rate(), 0.5);
+    }
+
+    #[test]
+    fn test_line_search() {
+        let mut quadratic = |x: &[f64]| {
+            let loss = x.iter().map(|x| (x - 3.).powf(2.)).sum::<f64>();
+            (loss, x.iter().map(|x| 2. * (x - 3.)).collect())
+        };
+
+        // A learning rate of 2 overshoots further and further, unless the step is cut back.
+        let mut x = [0., 0.];
+        (0..20).for_each(|_| {
+            let (_, grad) = quadratic(&x);
+            x.iter_mut().zip(grad).for_each(|(x, g)| *x -= 2. * g);
+        });
+        assert!(quadratic(&x).0 > 1e6);
+
+        let mut optimiser = LineSearchOptimiser::new(LearningRateOptimiser::new(2.));
+        let mut x = [0., 0.];
+        let losses: Vec<f64> = (0..20)
+            .map(|_| optimiser.step(&mut x, &mut quadratic))
+            .collect();
+        assert_eq!(losses[0], 18.);
+        assert!(losses.windows(2).all(|w| w[1] <= w[0]));
+        assert!(quadratic(&x).0 < 1e-6);
+        assert_eq!(optimiser.base().learning_rate(), 2.);
+    }
 }
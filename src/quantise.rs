@@ -0,0 +1,162 @@
+use crate::{nn::Activation, util::Util};
+
+/// Post-training int8 counterpart of a layer, see `Sequential::quantise`.
+#[derive(Debug, Clone)]
+pub enum QuantisedLayer {
+    Linear(QuantisedLinear),
+    Activation(Activation),
+    Softmax,
+    /// Layers such as dropout or flatten which do nothing at inference time.
+    Identity,
+}
+
+/// Linear layer with i8 weights sharing a single scale. Inputs are quantised to i8 with a
+/// scale of their own on the fly, so that the dot products and biases are accumulated in i32
+/// and only the accumulators are converted back to floats.
+#[derive(Debug, Clone)]
+pub struct QuantisedLinear {
+    fan_in: usize,
+    /// Row-major `[fan_out, fan_in]`.
+    weights: Vec<i8>,
+    scale: f64,
+    biases: Vec<f64>,
+    activation: Activation,
+}
+
+impl QuantisedLinear {
+    /// Quantises row-major `[fan_out, fan_in]` weights symmetrically, mapping the largest
+    /// magnitude to 127.
+    pub fn new(
+        fan_in: usize,
+        fan_out: usize,
+        weights: &[f64],
+        biases: Option<&[f64]>,
+        activation: Activation,
+    ) -> QuantisedLinear {
+        if weights.len() != fan_in * fan_out {
+            panic!(
+                "Expected {} weights, but got {}",
+                fan_in * fan_out,
+                weights.len()
+            )
+        }
+        let (weights, scale) = quantise(weights);
+        QuantisedLinear {
+            fan_in,
+            weights,
+            scale,
+            biases: biases.map(|b| b.to_vec()).unwrap_or(vec![0.; fan_out]),
+            activation,
+        }
+    }
+
+    fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        if inputs.len() != self.fan_in {
+            panic!("Expected {} inputs, but got {}", self.fan_in, inputs.len())
+        }
+        let (inputs, input_scale) = quantise(inputs);
+        let scale = self.scale * input_scale;
+
+        self.weights
+            .chunks(self.fan_in)
+            .zip(self.biases.iter())
+            .map(|(row, b)| {
+                // Without any signal, the bias cannot be put on the accumulator's scale.
+                if scale == 0. {
+                    return self.activation.apply_value(*b);
+                }
+                let acc = row
+                    .iter()
+                    .zip(inputs.iter())
+                    .fold((b / scale).round() as i32, |acc, (w, x)| {
+                        acc + *w as i32 * *x as i32
+                    });
+                self.activation.apply_value(acc as f64 * scale)
+            })
+            .collect()
+    }
+}
+
+/// Symmetric i8 quantisation of the values, along with the scale to multiply them back by.
+fn quantise(values: &[f64]) -> (Vec<i8>, f64) {
+    let max = values.iter().fold(0., |m: f64, v| m.max(v.abs()));
+    if max == 0. {
+        return (vec![0; values.len()], 0.);
+    }
+    let scale = max / 127.;
+    let quantised = values
+        .iter()
+        .map(|v| (v / scale).round().clamp(-127., 127.) as i8)
+        .collect();
+    (quantised, scale)
+}
+
+/// Inference-only model made of quantised layers.
+#[derive(Debug, Clone)]
+pub struct QuantisedModel {
+    layers: Vec<QuantisedLayer>,
+}
+
+impl QuantisedModel {
+    pub fn new(layers: Vec<QuantisedLayer>) -> QuantisedModel {
+        QuantisedModel { layers }
+    }
+
+    pub fn layers(&self) -> &[QuantisedLayer] {
+        &self.layers
+    }
+
+    pub fn forward(&self, inputs: &[f64]) -> Vec<f64> {
+        self.layers
+            .iter()
+            .fold(inputs.to_vec(), |x, layer| match layer {
+                QuantisedLayer::Linear(linear) => linear.forward(&x),
+                QuantisedLayer::Activation(activation) => {
+                    x.into_iter().map(|v| activation.apply_value(v)).collect()
+                }
+                QuantisedLayer::Softmax => Util::softmax(&x),
+                QuantisedLayer::Identity => x,
+            })
+    }
+
+    /// Memory taken up by the weights, i.e. one byte per weight, against eight for the f64
+    /// weights of the original model.
+    pub fn weight_bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| match layer {
+                QuantisedLayer::Linear(linear) => linear.weights.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantise() {
+        let (q, scale) = quantise(&[0.5, -1., 0.25, 0.]);
+        assert_eq!(q, vec![64, -127, 32, 0]);
+        assert_eq!(scale, 1. / 127.);
+        assert_eq!(quantise(&[0., 0.]), (vec![0, 0], 0.));
+    }
+
+    #[test]
+    fn test_quantised_linear() {
+        let weights = [0.5, -1., 0.25, 1., 0., -0.5];
+        let biases = [0.1, -0.2];
+        let linear = QuantisedLinear::new(3, 2, &weights, Some(&biases), Activation::None);
+
+        let x = [1., 0.5, -0.25];
+        let expected = [0.5 - 0.5 - 0.0625 + 0.1, 1. + 0.125 - 0.2];
+        linear
+            .forward(&x)
+            .iter()
+            .zip(expected)
+            .for_each(|(y, e)| assert!((y - e).abs() < 0.01));
+        assert_eq!(linear.forward(&[0.; 3]), biases);
+    }
+}
@@ -0,0 +1,527 @@
+use std::{collections::BTreeMap, f64::consts::PI};
+
+use crate::{engine::Data, optimiser::Optimiser, tensor::Tensor};
+
+pub trait LrScheduler {
+    /// Learning rate after `step` calls to `Scheduled::step`, starting from `base`.
+    fn learning_rate(&mut self, step: usize, base: f64) -> f64;
+
+    /// Momentum after `step` calls to `Scheduled::step`, for schedules that also cycle the
+    /// momentum of the optimiser.
+    fn momentum(&mut self, _step: usize) -> Option<f64> {
+        None
+    }
+
+    /// State the schedule keeps on top of the step, e.g. the best metric so far of
+    /// `ReduceLrOnPlateau`, saved along with the optimiser by `Scheduled::save_state`.
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        BTreeMap::new()
+    }
+
+    /// Restores the state saved by `save_state`.
+    fn load_state(&mut self, _state: &BTreeMap<String, Tensor>) {}
+}
+
+/// Goes from `from` to `to` along half a cosine as `progress` goes from 0 to 1.
+fn anneal(from: f64, to: f64, progress: f64) -> f64 {
+    to + 0.5 * (from - to) * (1. + (PI * progress).cos())
+}
+
+/// Multiplies the learning rate by `gamma` every `step_size` steps.
+#[derive(Debug, Clone, Copy)]
+pub struct StepLr {
+    pub step_size: usize,
+    pub gamma: f64,
+}
+
+impl LrScheduler for StepLr {
+    fn learning_rate(&mut self, step: usize, base: f64) -> f64 {
+        base * self.gamma.powi((step / self.step_size) as i32)
+    }
+}
+
+/// Multiplies the learning rate by `gamma` every step.
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialLr {
+    pub gamma: f64,
+}
+
+impl LrScheduler for ExponentialLr {
+    fn learning_rate(&mut self, step: usize, base: f64) -> f64 {
+        base * self.gamma.powi(step as i32)
+    }
+}
+
+/// Anneals the learning rate from its initial value down to `min_lr` along half a cosine over
+/// `steps` steps, after which it stays at `min_lr`.
+#[derive(Debug, Clone, Copy)]
+pub struct CosineAnnealing {
+    pub steps: usize,
+    pub min_lr: f64,
+}
+
+impl LrScheduler for CosineAnnealing {
+    fn learning_rate(&mut self, step: usize, base: f64) -> f64 {
+        let progress = step.min(self.steps) as f64 / self.steps as f64;
+        anneal(base, self.min_lr, progress)
+    }
+}
+
+/// Ramps the learning rate up linearly from close to 0 over the first `steps` steps, then hands
+/// off to `then`, which starts over from step 0 at the full learning rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Warmup<S: LrScheduler> {
+    pub steps: usize,
+    pub then: S,
+}
+
+impl<S: LrScheduler> LrScheduler for Warmup<S> {
+    fn learning_rate(&mut self, step: usize, base: f64) -> f64 {
+        match step < self.steps {
+            true => base * (step + 1) as f64 / self.steps as f64,
+            false => self.then.learning_rate(step - self.steps, base),
+        }
+    }
+
+    /// Leaves the momentum alone during the warmup, then follows `then`.
+    fn momentum(&mut self, step: usize) -> Option<f64> {
+        match step < self.steps {
+            true => None,
+            false => self.then.momentum(step - self.steps),
+        }
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        self.then.save_state()
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.then.load_state(state);
+    }
+}
+
+/// One-cycle policy over `steps` steps: the learning rate anneals up from `base / div_factor`
+/// to `base` over the first `warmup` fraction of the steps, then down to
+/// `base / (div_factor * final_div_factor)`, while the momentum, if cycled, does the opposite.
+/// The short spell of large learning rates makes for much shorter training.
+#[derive(Debug, Clone, Copy)]
+pub struct OneCycle {
+    steps: usize,
+    warmup: f64,
+    div_factor: f64,
+    final_div_factor: f64,
+    momentum: Option<(f64, f64)>,
+}
+
+impl OneCycle {
+    pub fn new(steps: usize) -> OneCycle {
+        OneCycle {
+            steps,
+            warmup: 0.3,
+            div_factor: 25.,
+            final_div_factor: 1e4,
+            momentum: None,
+        }
+    }
+
+    /// Fraction of the steps spent increasing the learning rate, 0.3 by default.
+    pub fn with_warmup(mut self, warmup: f64) -> OneCycle {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Ratios of the maximum learning rate to the initial one, 25 by default, and of the
+    /// initial learning rate to the final one, 1e4 by default.
+    pub fn with_div_factors(mut self, div_factor: f64, final_div_factor: f64) -> OneCycle {
+        self.div_factor = div_factor;
+        self.final_div_factor = final_div_factor;
+        self
+    }
+
+    /// Cycles the momentum from `max` down to `min` while the learning rate increases, and back.
+    pub fn with_momentum(mut self, min: f64, max: f64) -> OneCycle {
+        self.momentum = Some((min, max));
+        self
+    }
+
+    /// Goes from `start` to `peak` during warmup, then to `end`.
+    fn cycle(&self, step: usize, start: f64, peak: f64, end: f64) -> f64 {
+        let peak_step = self.warmup * self.steps as f64;
+        let step = step.min(self.steps) as f64;
+        match step < peak_step {
+            true => anneal(start, peak, step / peak_step),
+            false => anneal(
+                peak,
+                end,
+                (step - peak_step) / (self.steps as f64 - peak_step),
+            ),
+        }
+    }
+}
+
+impl LrScheduler for OneCycle {
+    fn learning_rate(&mut self, step: usize, base: f64) -> f64 {
+        let start = base / self.div_factor;
+        self.cycle(step, start, base, start / self.final_div_factor)
+    }
+
+    fn momentum(&mut self, step: usize) -> Option<f64> {
+        self.momentum
+            .map(|(min, max)| self.cycle(step, max, min, max))
+    }
+}
+
+/// Multiplies the learning rate by `factor` whenever the metric reported to `observe`, e.g. the
+/// validation loss, hasn't improved on its best value for `patience` reports in a row, down to
+/// `min_lr`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReduceLrOnPlateau {
+    factor: f64,
+    patience: usize,
+    min_lr: f64,
+    scale: f64,
+    best: f64,
+    bad_reports: usize,
+}
+
+impl ReduceLrOnPlateau {
+    pub fn new(factor: f64, patience: usize, min_lr: f64) -> ReduceLrOnPlateau {
+        ReduceLrOnPlateau {
+            factor,
+            patience,
+            min_lr,
+            scale: 1.,
+            best: f64::INFINITY,
+            bad_reports: 0,
+        }
+    }
+
+    /// Records the latest value of the metric, which is better when lower.
+    pub fn observe(&mut self, metric: f64) {
+        if metric < self.best {
+            self.best = metric;
+            self.bad_reports = 0;
+        } else {
+            self.bad_reports += 1;
+            if self.bad_reports > self.patience {
+                self.scale *= self.factor;
+                self.bad_reports = 0;
+            }
+        }
+    }
+}
+
+impl LrScheduler for ReduceLrOnPlateau {
+    fn learning_rate(&mut self, _step: usize, base: f64) -> f64 {
+        (base * self.scale).max(self.min_lr)
+    }
+
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        [
+            ("scale", self.scale),
+            ("best", self.best),
+            ("bad_reports", self.bad_reports as f64),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), Tensor::scalar(value)))
+        .collect()
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.scale = scalar(state, "scale");
+        self.best = scalar(state, "best");
+        self.bad_reports = scalar(state, "bad_reports") as usize;
+    }
+}
+
+/// Value of the scalar `state[name]`.
+fn scalar(state: &BTreeMap<String, Tensor>, name: &str) -> f64 {
+    state
+        .get(name)
+        .unwrap_or_else(|| panic!("Missing tensor {name}"))
+        .data()[0]
+}
+
+/// Optimiser whose learning rate follows a schedule, advanced by `step`, e.g. after every epoch
+/// or every batch.
+pub struct Scheduled<O: Optimiser, S: LrScheduler> {
+    optimiser: O,
+    scheduler: S,
+    base: f64,
+    step: usize,
+}
+
+impl<O: Optimiser, S: LrScheduler> Scheduled<O, S> {
+    /// Starts the schedule from the current learning rate of `optimiser`.
+    pub fn new(mut optimiser: O, mut scheduler: S) -> Scheduled<O, S> {
+        let base = optimiser.learning_rate();
+        optimiser.set_learning_rate(scheduler.learning_rate(0, base));
+        if let Some(momentum) = scheduler.momentum(0) {
+            optimiser.set_momentum(momentum);
+        }
+        Scheduled {
+            optimiser,
+            scheduler,
+            base,
+            step: 0,
+        }
+    }
+
+    /// Moves on to the next step of the schedule and returns the new learning rate.
+    pub fn step(&mut self) -> f64 {
+        self.step += 1;
+        let learning_rate = self.scheduler.learning_rate(self.step, self.base);
+        self.optimiser.set_learning_rate(learning_rate);
+        if let Some(momentum) = self.scheduler.momentum(self.step) {
+            self.optimiser.set_momentum(momentum);
+        }
+        learning_rate
+    }
+
+    pub fn scheduler(&self) -> &S {
+        &self.scheduler
+    }
+
+    pub fn scheduler_mut(&mut self) -> &mut S {
+        &mut self.scheduler
+    }
+}
+
+impl<O: Optimiser> Scheduled<O, ReduceLrOnPlateau> {
+    /// Reports the metric of the last epoch and moves on to the next one, returning the new
+    /// learning rate.
+    pub fn observe(&mut self, metric: f64) -> f64 {
+        self.scheduler.observe(metric);
+        self.step()
+    }
+}
+
+impl<O: Optimiser, S: LrScheduler> Optimiser for Scheduled<O, S> {
+    fn optimise(&mut self, data: &mut [Data]) {
+        self.optimiser.optimise(data);
+    }
+
+    fn optimise_groups(&mut self, data: &mut [Data], groups: &[Vec<usize>]) {
+        self.optimiser.optimise_groups(data, groups);
+    }
+
+    fn optimise_masked(&mut self, data: &mut [Data], groups: &[Vec<usize>], active: &[bool]) {
+        self.optimiser.optimise_masked(data, groups, active);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.optimiser.learning_rate()
+    }
+
+    fn momentum(&self) -> Option<f64> {
+        self.optimiser.momentum()
+    }
+
+    /// Overridden by the schedule on the next step if it cycles the momentum.
+    fn set_momentum(&mut self, momentum: f64) {
+        self.optimiser.set_momentum(momentum);
+    }
+
+    /// State of the optimiser along with the position in the schedule and the state of the
+    /// schedule, with names prefixed by `schedule.`.
+    fn save_state(&self) -> BTreeMap<String, Tensor> {
+        let mut state = self.optimiser.save_state();
+        let schedule = self
+            .scheduler
+            .save_state()
+            .into_iter()
+            .chain([
+                ("base".to_string(), Tensor::scalar(self.base)),
+                ("step".to_string(), Tensor::scalar(self.step as f64)),
+            ])
+            .map(|(name, tensor)| (format!("schedule.{name}"), tensor));
+        state.extend(schedule);
+        state
+    }
+
+    fn load_state(&mut self, state: &BTreeMap<String, Tensor>) {
+        self.optimiser.load_state(state);
+        let schedule = state
+            .iter()
+            .filter_map(|(name, tensor)| {
+                name.strip_prefix("schedule.")
+                    .map(|name| (name.to_string(), tensor.clone()))
+            })
+            .collect();
+        self.scheduler.load_state(&schedule);
+        self.base = scalar(state, "schedule.base");
+        self.step = scalar(state, "schedule.step") as usize;
+        let learning_rate = self.scheduler.learning_rate(self.step, self.base);
+        self.optimiser.set_learning_rate(learning_rate);
+        if let Some(momentum) = self.scheduler.momentum(self.step) {
+            self.optimiser.set_momentum(momentum);
+        }
+    }
+
+    /// Restarts the schedule from `learning_rate`.
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.base = learning_rate;
+        self.step = 0;
+        let learning_rate = self.scheduler.learning_rate(0, learning_rate);
+        self.optimiser.set_learning_rate(learning_rate);
+    }
+}
+
+/// Loss against learning rate, as recorded by `Sequential::find_learning_rate`.
+#[derive(Debug, Clone)]
+pub struct LrRange {
+    pub learning_rates: Vec<f64>,
+    /// Losses smoothed with an exponential moving average, as single batches are noisy.
+    pub losses: Vec<f64>,
+    /// Learning rate where the loss decreases the fastest.
+    pub suggestion: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        optimiser::{AdamOptimiser, LearningRateOptimiser},
+        scheduler::*,
+    };
+
+    /// Learning rates of the first `steps` steps of the schedule, starting from 1.
+    fn rates(scheduler: impl LrScheduler, steps: usize) -> Vec<f64> {
+        let mut scheduled = Scheduled::new(LearningRateOptimiser::new(1.), scheduler);
+        let first = scheduled.learning_rate();
+        std::iter::once(first)
+            .chain((1..steps).map(|_| scheduled.step()))
+            .collect()
+    }
+
+    #[test]
+    fn test_schedules() {
+        let step = StepLr {
+            step_size: 2,
+            gamma: 0.5,
+        };
+        assert_eq!(rates(step, 5), vec![1., 1., 0.5, 0.5, 0.25]);
+        assert_eq!(rates(ExponentialLr { gamma: 0.5 }, 3), vec![1., 0.5, 0.25]);
+
+        let cosine = CosineAnnealing {
+            steps: 4,
+            min_lr: 0.1,
+        };
+        let rates = rates(cosine, 6);
+        assert_eq!(rates[0], 1.);
+        assert!((rates[2] - 0.55).abs() < 1e-12);
+        assert!(rates.windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(rates[4..], [0.1, 0.1]);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let warmup = Warmup {
+            steps: 4,
+            then: StepLr {
+                step_size: 2,
+                gamma: 0.5,
+            },
+        };
+        assert_eq!(
+            rates(warmup, 8),
+            vec![0.25, 0.5, 0.75, 1., 1., 1., 0.5, 0.5]
+        );
+    }
+
+    #[test]
+    fn test_one_cycle() {
+        let one_cycle = OneCycle::new(10)
+            .with_div_factors(10., 100.)
+            .with_momentum(0.85, 0.95);
+        let rates = rates(one_cycle, 12);
+        assert!((rates[0] - 0.1).abs() < 1e-12);
+        assert_eq!(rates[3], 1.);
+        assert!(rates[..4].windows(2).all(|w| w[1] > w[0]));
+        assert!(rates[3..].windows(2).all(|w| w[1] <= w[0]));
+        assert!((rates[10] - 1e-3).abs() < 1e-12);
+
+        let optimiser = AdamOptimiser::new(1);
+        let mut scheduled = Scheduled::new(optimiser, one_cycle);
+        assert_eq!(scheduled.momentum(), Some(0.95));
+        (0..3).for_each(|_| {
+            scheduled.step();
+        });
+        assert_eq!(scheduled.momentum(), Some(0.85));
+        (0..7).for_each(|_| {
+            scheduled.step();
+        });
+        assert!((scheduled.momentum().unwrap() - 0.95).abs() < 1e-12);
+
+        let warmup = Warmup {
+            steps: 2,
+            then: one_cycle,
+        };
+        let mut scheduled = Scheduled::new(AdamOptimiser::new(1), warmup);
+        (0..2).for_each(|_| {
+            scheduled.step();
+        });
+        assert_eq!(scheduled.momentum(), Some(0.95));
+        (0..3).for_each(|_| {
+            scheduled.step();
+        });
+        assert_eq!(scheduled.momentum(), Some(0.85));
+    }
+
+    #[test]
+    fn test_reduce_on_plateau() {
+        let plateau = ReduceLrOnPlateau::new(0.5, 1, 0.2);
+        let mut scheduled = Scheduled::new(LearningRateOptimiser::new(1.), plateau);
+        let rates: Vec<f64> = [3., 2., 2.5, 2., 1.9, 2., 2., 2., 2., 2., 2.]
+            .iter()
+            .map(|loss| scheduled.observe(*loss))
+            .collect();
+        assert_eq!(
+            rates,
+            vec![1., 1., 1., 0.5, 0.5, 0.5, 0.25, 0.25, 0.2, 0.2, 0.2]
+        );
+    }
+
+    #[test]
+    fn test_plateau_state() {
+        let plateau = ReduceLrOnPlateau::new(0.5, 1, 0.);
+        let mut scheduled = Scheduled::new(AdamOptimiser::new(1), plateau);
+        [3., 3., 3., 3.5].iter().for_each(|loss| {
+            scheduled.observe(*loss);
+        });
+        scheduled.set_momentum(0.8);
+
+        let mut resumed = Scheduled::new(AdamOptimiser::new(1), plateau);
+        resumed.load_state(&scheduled.save_state());
+        assert_eq!(resumed.learning_rate(), scheduled.learning_rate());
+        assert_eq!(resumed.momentum(), Some(0.8));
+        assert_eq!(resumed.observe(3.), scheduled.observe(3.));
+        assert_eq!(resumed.observe(4.), scheduled.observe(4.));
+        assert_eq!(resumed.learning_rate(), 0.00025);
+    }
+
+    #[test]
+    fn test_scheduled_steps() {
+        let mut data = vec![Data::new(0.)];
+        data[0].gradient = 1.;
+        let mut scheduled = Scheduled::new(
+            LearningRateOptimiser::new(0.1),
+            ExponentialLr { gamma: 0.1 },
+        );
+        scheduled.optimise(&mut data);
+        scheduled.step();
+        scheduled.optimise(&mut data);
+        assert!((data[0].value + 0.11).abs() < 1e-12);
+
+        let mut resumed = Scheduled::new(
+            LearningRateOptimiser::new(0.1),
+            ExponentialLr { gamma: 0.1 },
+        );
+        resumed.load_state(&scheduled.save_state());
+        assert_eq!(resumed.learning_rate(), scheduled.learning_rate());
+        assert_eq!(resumed.step(), scheduled.step());
+
+        scheduled.set_learning_rate(1.);
+        assert_eq!(scheduled.learning_rate(), 1.);
+        assert!((scheduled.step() - 0.1).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,1410 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    ops::{Add, Div, Mul, Sub},
+    rc::Rc,
+};
+
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+
+use crate::{
+    engine::{Data, IdGenerator, NodeId},
+    optimiser::Optimiser,
+    util::Util,
+};
+
+/// Dense n-dimensional array. Its storage is shared between clones and between the views made by
+/// `reshape` and `transpose`, which only change the shape and the strides.
+#[derive(Debug, Clone)]
+pub struct Tensor {
+    shape: Vec<usize>,
+    /// Step in `storage` between consecutive elements along each axis, row-major unless the
+    /// tensor is a transposed view.
+    strides: Vec<usize>,
+    storage: Rc<Vec<f64>>,
+    /// Row-major copy of a transposed view, made the first time its `data` is read.
+    contiguous: OnceCell<Rc<Vec<f64>>>,
+}
+
+impl PartialEq for Tensor {
+    fn eq(&self, other: &Tensor) -> bool {
+        self.shape == other.shape && self.data() == other.data()
+    }
+}
+
+impl Tensor {
+    pub fn new(shape: Vec<usize>, data: Vec<f64>) -> Tensor {
+        let size: usize = shape.iter().product();
+        if size != data.len() {
+            panic!(
+                "Shape {:?} expects {} values, but got {}",
+                shape,
+                size,
+                data.len()
+            )
+        }
+        Tensor::from_storage(shape, Rc::new(data))
+    }
+
+    fn from_storage(shape: Vec<usize>, storage: Rc<Vec<f64>>) -> Tensor {
+        Tensor {
+            strides: strides(&shape),
+            shape,
+            storage,
+            contiguous: OnceCell::new(),
+        }
+    }
+
+    pub fn scalar(value: f64) -> Tensor {
+        Tensor::new(vec![], vec![value])
+    }
+
+    pub fn zeros(shape: Vec<usize>) -> Tensor {
+        let size = shape.iter().product();
+        Tensor::new(shape, vec![0.; size])
+    }
+
+    /// Stacks equally sized rows into a `[rows, columns]` matrix.
+    pub fn from_rows(rows: &[Vec<f64>]) -> Tensor {
+        let columns = rows.first().map(|r| r.len()).unwrap_or(0);
+        if let Some(row) = rows.iter().find(|r| r.len() != columns) {
+            panic!("Expected rows of {} values, but got {}", columns, row.len())
+        }
+        Tensor::new(vec![rows.len(), columns], rows.concat())
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// Values in row-major order.
+    pub fn data(&self) -> &[f64] {
+        self.row_major()
+    }
+
+    /// Storage of the values in row-major order, `storage` itself unless `self` is a transposed
+    /// view.
+    fn row_major(&self) -> &Rc<Vec<f64>> {
+        if self.is_contiguous() {
+            return &self.storage;
+        }
+        self.contiguous.get_or_init(|| {
+            let size = self.shape.iter().product();
+            Rc::new((0..size).map(|i| self.storage[self.offset(i)]).collect())
+        })
+    }
+
+    /// Values in row-major order, copied out of shared storage first if needed.
+    fn data_mut(&mut self) -> &mut [f64] {
+        if !self.is_contiguous() {
+            *self = Tensor::from_storage(self.shape.clone(), Rc::new(self.data().to_vec()));
+        }
+        Rc::make_mut(&mut self.storage).as_mut_slice()
+    }
+
+    fn is_contiguous(&self) -> bool {
+        self.strides == strides(&self.shape)
+    }
+
+    /// Position in `storage` of the element at row-major position `flat`.
+    fn offset(&self, flat: usize) -> usize {
+        self.shape
+            .iter()
+            .zip(self.strides.iter())
+            .rev()
+            .fold((0, flat), |(offset, rem), (n, s)| {
+                (offset + rem % n * s, rem / n)
+            })
+            .0
+    }
+
+    /// Splits a matrix back into its rows.
+    pub fn rows(&self) -> Vec<Vec<f64>> {
+        let (_, columns) = self.matrix_dims();
+        self.data().chunks(columns).map(|r| r.to_vec()).collect()
+    }
+
+    fn matrix_dims(&self) -> (usize, usize) {
+        match self.shape[..] {
+            [rows, columns] => (rows, columns),
+            _ => panic!("Expected a matrix, but got shape {:?}", self.shape),
+        }
+    }
+
+    /// Same values in `shape`, sharing the storage unless `self` is a transposed view.
+    fn reshape(&self, shape: Vec<usize>) -> Tensor {
+        Tensor::from_storage(shape, self.row_major().clone())
+    }
+
+    fn map(&self, f: impl Fn(f64) -> f64) -> Tensor {
+        Tensor::new(
+            self.shape.clone(),
+            self.data().iter().map(|v| f(*v)).collect(),
+        )
+    }
+
+    /// Applies `f` element-wise, broadcasting both operands to their common shape.
+    fn zip_map(&self, other: &Tensor, f: impl Fn(f64, f64) -> f64) -> Tensor {
+        let shape = broadcast_shape(&self.shape, &other.shape);
+        let left = self.broadcast_to(&shape);
+        let right = other.broadcast_to(&shape);
+        let data = left
+            .data()
+            .iter()
+            .zip(right.data().iter())
+            .map(|(l, r)| f(*l, *r))
+            .collect();
+        Tensor::new(shape, data)
+    }
+
+    /// For every element of a tensor of shape `target`, the index of the element of `self` it
+    /// was broadcast from.
+    fn broadcast_indices(&self, target: &[usize]) -> Vec<usize> {
+        let offset = target.len() - self.shape.len();
+        let source_strides = strides(&self.shape);
+        let strides: Vec<usize> = (0..target.len())
+            .map(|i| {
+                if i < offset || self.shape[i - offset] == 1 {
+                    0
+                } else {
+                    source_strides[i - offset]
+                }
+            })
+            .collect();
+        let target_strides = self::strides(target);
+
+        (0..target.iter().product())
+            .map(|flat: usize| {
+                target_strides
+                    .iter()
+                    .zip(strides.iter())
+                    .fold((0, flat), |(index, rem), (ts, s)| {
+                        (index + (rem / ts) * s, rem % ts)
+                    })
+                    .0
+            })
+            .collect()
+    }
+
+    fn broadcast_to(&self, shape: &[usize]) -> Tensor {
+        if self.shape == shape {
+            return self.clone();
+        }
+        let data = self.data();
+        let broadcast = self
+            .broadcast_indices(shape)
+            .iter()
+            .map(|i| data[*i])
+            .collect();
+        Tensor::new(shape.to_vec(), broadcast)
+    }
+
+    /// Sums a gradient of a broadcast result back down to the operand's `shape`.
+    fn reduce_to(&self, shape: &[usize]) -> Tensor {
+        if self.shape == shape {
+            return self.clone();
+        }
+        let mut reduced = Tensor::zeros(shape.to_vec());
+        let indices = reduced.broadcast_indices(&self.shape);
+        let data = reduced.data_mut();
+        indices
+            .iter()
+            .zip(self.data().iter())
+            .for_each(|(i, v)| data[*i] += v);
+        reduced
+    }
+
+    /// Product of the matrices in the last two dimensions, for every index of the leading ones.
+    /// Operands are read through their strides, so transposed views aren't copied first.
+    fn matmul(&self, other: &Tensor) -> Tensor {
+        let rank = self.shape.len();
+        let (m, k) = (self.shape[rank - 2], self.shape[rank - 1]);
+        let n = other.shape[rank - 1];
+        let batch: usize = self.shape[..rank - 2].iter().product();
+
+        // Offset of the matrix at each index of the leading dimensions.
+        let starts = |t: &Tensor| -> Vec<usize> {
+            let leading = Tensor {
+                shape: t.shape[..rank - 2].to_vec(),
+                strides: t.strides[..rank - 2].to_vec(),
+                storage: t.storage.clone(),
+                contiguous: OnceCell::new(),
+            };
+            (0..batch).map(|b| leading.offset(b)).collect()
+        };
+        let (left_starts, right_starts) = (starts(self), starts(other));
+        let (li, lp) = (self.strides[rank - 2], self.strides[rank - 1]);
+        let (rp, rj) = (other.strides[rank - 2], other.strides[rank - 1]);
+
+        let mut data = vec![0.; batch * m * n];
+        for b in 0..batch {
+            let (left, right) = (
+                &self.storage[left_starts[b]..],
+                &other.storage[right_starts[b]..],
+            );
+            let out = &mut data[b * m * n..(b + 1) * m * n];
+            for i in 0..m {
+                let out_row = &mut out[i * n..(i + 1) * n];
+                for p in 0..k {
+                    let l = left[i * li + p * lp];
+                    if l == 0. {
+                        continue;
+                    }
+                    match rj {
+                        1 => out_row
+                            .iter_mut()
+                            .zip(right[p * rp..p * rp + n].iter())
+                            .for_each(|(d, r)| *d += l * r),
+                        _ => out_row
+                            .iter_mut()
+                            .enumerate()
+                            .for_each(|(j, d)| *d += l * right[p * rp + j * rj]),
+                    }
+                }
+            }
+        }
+
+        let mut shape = self.shape.clone();
+        shape[rank - 1] = n;
+        Tensor::new(shape, data)
+    }
+
+    /// Splits the shape around `axis` into (elements before, axis length, elements after).
+    fn axis_dims(&self, axis: usize) -> (usize, usize, usize) {
+        if axis >= self.shape.len() {
+            panic!("Axis {} out of range for shape {:?}", axis, self.shape)
+        }
+        (
+            self.shape[..axis].iter().product(),
+            self.shape[axis],
+            self.shape[axis + 1..].iter().product(),
+        )
+    }
+
+    /// Folds the elements along `axis`, keeping a dimension of size 1 in its place.
+    fn fold_axis(&self, axis: usize, init: f64, f: impl Fn(f64, f64) -> f64) -> Tensor {
+        let (outer, n, inner) = self.axis_dims(axis);
+        let mut shape = self.shape.clone();
+        shape[axis] = 1;
+
+        let values = self.data();
+        let data = (0..outer)
+            .flat_map(|o| (0..inner).map(move |i| (o, i)))
+            .map(|(o, i)| {
+                (0..n)
+                    .map(|a| values[(o * n + a) * inner + i])
+                    .fold(init, &f)
+            })
+            .collect();
+        Tensor::new(shape, data)
+    }
+
+    /// View with axes `a` and `b` swapped, sharing the storage.
+    fn swap_axes(&self, a: usize, b: usize) -> Tensor {
+        let mut transposed = self.clone();
+        transposed.shape.swap(a, b);
+        transposed.strides.swap(a, b);
+        transposed.contiguous = OnceCell::new();
+        transposed
+    }
+
+    fn concat(parts: &[&Tensor], axis: usize) -> Tensor {
+        let mut shape = parts[0].shape.clone();
+        shape[axis] = parts.iter().map(|p| p.shape[axis]).sum();
+
+        let (outer, _, _) = parts[0].axis_dims(axis);
+        let data = (0..outer)
+            .flat_map(|o| {
+                parts.iter().flat_map(move |p| {
+                    let (_, n, inner) = p.axis_dims(axis);
+                    p.data()[o * n * inner..(o + 1) * n * inner].iter().cloned()
+                })
+            })
+            .collect();
+        Tensor::new(shape, data)
+    }
+
+    fn slice(&self, axis: usize, start: usize, end: usize) -> Tensor {
+        let (outer, n, inner) = self.axis_dims(axis);
+        let mut shape = self.shape.clone();
+        shape[axis] = end - start;
+
+        let values = self.data();
+        let data = (0..outer)
+            .flat_map(|o| values[(o * n + start) * inner..(o * n + end) * inner].iter())
+            .cloned()
+            .collect();
+        Tensor::new(shape, data)
+    }
+
+    /// Inverse of `slice`: places `self` at `start` along `axis` inside zeros of `shape`.
+    fn pad(&self, shape: &[usize], axis: usize, start: usize) -> Tensor {
+        let mut padded = Tensor::zeros(shape.to_vec());
+        let (outer, n, inner) = padded.axis_dims(axis);
+        let len = self.shape[axis] * inner;
+        let (values, data) = (self.data(), padded.data_mut());
+        for o in 0..outer {
+            let offset = (o * n + start) * inner;
+            data[offset..offset + len].copy_from_slice(&values[o * len..(o + 1) * len]);
+        }
+        padded
+    }
+
+    /// Swaps the last two dimensions.
+    fn transpose(&self) -> Tensor {
+        let rank = self.shape.len();
+        self.swap_axes(rank - 2, rank - 1)
+    }
+
+    /// Element `indices[j]` along `axis` at position `j`, or zero for `None`.
+    fn gather(&self, axis: usize, indices: &[Option<usize>]) -> Tensor {
+        let (outer, n, inner) = self.axis_dims(axis);
+        let mut shape = self.shape.clone();
+        shape[axis] = indices.len();
+
+        let values = self.data();
+        let data = (0..outer)
+            .flat_map(|o| indices.iter().map(move |index| (o, index)))
+            .flat_map(|(o, index)| {
+                (0..inner).map(move |i| match index {
+                    Some(a) => values[(o * n + a) * inner + i],
+                    None => 0.,
+                })
+            })
+            .collect();
+        Tensor::new(shape, data)
+    }
+
+    /// Inverse of `gather`: adds `self` back into zeros of `shape` at the gathered positions.
+    fn scatter_add(&self, shape: &[usize], axis: usize, indices: &[Option<usize>]) -> Tensor {
+        let mut scattered = Tensor::zeros(shape.to_vec());
+        let (outer, n, inner) = scattered.axis_dims(axis);
+        let (values, data) = (self.data(), scattered.data_mut());
+        for o in 0..outer {
+            for (j, index) in indices.iter().enumerate() {
+                if let Some(a) = index {
+                    for i in 0..inner {
+                        data[(o * n + a) * inner + i] +=
+                            values[(o * indices.len() + j) * inner + i];
+                    }
+                }
+            }
+        }
+        scattered
+    }
+}
+
+fn strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+/// NumPy-style broadcasting: shapes are aligned from the right and each dimension must either
+/// match or be 1.
+fn broadcast_shape(left: &[usize], right: &[usize]) -> Vec<usize> {
+    let len = left.len().max(right.len());
+    let dim = |shape: &[usize], i: usize| {
+        if i < len - shape.len() {
+            1
+        } else {
+            shape[i - (len - shape.len())]
+        }
+    };
+
+    (0..len)
+        .map(|i| match (dim(left, i), dim(right, i)) {
+            (l, r) if l == r => l,
+            (1, r) => r,
+            (l, 1) => l,
+            _ => panic!("Cannot broadcast shapes {:?} and {:?}", left, right),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub enum TensorOperation {
+    MatMul,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Relu,
+    LeakyRelu(f64),
+    Tanh,
+    Sigmoid,
+    Exp,
+    /// Elementwise power with a constant exponent.
+    Pow(f64),
+    Sum {
+        axis: usize,
+        keepdim: bool,
+    },
+    Mean {
+        axis: usize,
+        keepdim: bool,
+    },
+    Max {
+        axis: usize,
+        keepdim: bool,
+    },
+    Reshape(Vec<usize>),
+    Transpose(usize, usize),
+    Concat {
+        axis: usize,
+    },
+    Slice {
+        axis: usize,
+        start: usize,
+        end: usize,
+    },
+    /// Picks elements along `axis` by index, repeating them as needed, with `None` standing
+    /// for zeros, e.g. to unfold image patches with their padding.
+    Gather {
+        axis: usize,
+        indices: Vec<Option<usize>>,
+    },
+}
+
+impl TensorOperation {
+    fn output_shape(&self, shapes: &[&[usize]]) -> Vec<usize> {
+        match self {
+            TensorOperation::MatMul => {
+                let (l, r) = (shapes[0], shapes[1]);
+                let rank = l.len();
+                if rank < 2
+                    || r.len() != rank
+                    || l[..rank - 2] != r[..rank - 2]
+                    || l[rank - 1] != r[rank - 2]
+                {
+                    panic!("Cannot matmul shapes {:?} and {:?}", l, r)
+                }
+                let mut shape = l.to_vec();
+                shape[rank - 1] = r[rank - 1];
+                shape
+            }
+            TensorOperation::Add
+            | TensorOperation::Sub
+            | TensorOperation::Mul
+            | TensorOperation::Div => broadcast_shape(shapes[0], shapes[1]),
+            TensorOperation::Relu
+            | TensorOperation::LeakyRelu(_)
+            | TensorOperation::Tanh
+            | TensorOperation::Sigmoid
+            | TensorOperation::Exp
+            | TensorOperation::Pow(_) => shapes[0].to_vec(),
+            TensorOperation::Sum { axis, keepdim }
+            | TensorOperation::Mean { axis, keepdim }
+            | TensorOperation::Max { axis, keepdim } => {
+                if *axis >= shapes[0].len() {
+                    panic!("Axis {} out of range for shape {:?}", axis, shapes[0])
+                }
+                let mut shape = shapes[0].to_vec();
+                if *keepdim {
+                    shape[*axis] = 1;
+                } else {
+                    shape.remove(*axis);
+                }
+                shape
+            }
+            TensorOperation::Reshape(shape) => {
+                let size: usize = shape.iter().product();
+                if size != shapes[0].iter().product::<usize>() {
+                    panic!("Cannot reshape {:?} into {:?}", shapes[0], shape)
+                }
+                shape.clone()
+            }
+            TensorOperation::Transpose(a, b) => {
+                if *a >= shapes[0].len() || *b >= shapes[0].len() {
+                    panic!("Cannot transpose axes {} and {} of {:?}", a, b, shapes[0])
+                }
+                let mut shape = shapes[0].to_vec();
+                shape.swap(*a, *b);
+                shape
+            }
+            TensorOperation::Concat { axis } => {
+                let mut shape = shapes[0].to_vec();
+                shapes.iter().for_each(|s| {
+                    let compatible = s.len() == shape.len()
+                        && (0..s.len()).all(|i| i == *axis || s[i] == shape[i]);
+                    if *axis >= s.len() || !compatible {
+                        panic!("Cannot concat {:?} along axis {}", shapes, axis)
+                    }
+                });
+                shape[*axis] = shapes.iter().map(|s| s[*axis]).sum();
+                shape
+            }
+            TensorOperation::Slice { axis, start, end } => {
+                if *axis >= shapes[0].len() || start >= end || *end > shapes[0][*axis] {
+                    panic!(
+                        "Cannot slice {}..{} along axis {} of {:?}",
+                        start, end, axis, shapes[0]
+                    )
+                }
+                let mut shape = shapes[0].to_vec();
+                shape[*axis] = end - start;
+                shape
+            }
+            TensorOperation::Gather { axis, indices } => {
+                let size = shapes[0].get(*axis).copied().unwrap_or(0);
+                if *axis >= shapes[0].len() || indices.iter().flatten().any(|i| *i >= size) {
+                    panic!("Cannot gather along axis {} of {:?}", axis, shapes[0])
+                }
+                let mut shape = shapes[0].to_vec();
+                shape[*axis] = indices.len();
+                shape
+            }
+        }
+    }
+
+    fn apply(&self, operands: &[&Tensor]) -> Tensor {
+        let value = match self {
+            TensorOperation::MatMul => operands[0].matmul(operands[1]),
+            TensorOperation::Add => operands[0].zip_map(operands[1], |l, r| l + r),
+            TensorOperation::Sub => operands[0].zip_map(operands[1], |l, r| l - r),
+            TensorOperation::Mul => operands[0].zip_map(operands[1], |l, r| l * r),
+            TensorOperation::Div => operands[0].zip_map(operands[1], |l, r| l / r),
+            TensorOperation::Relu => operands[0].map(|v| v.max(0.)),
+            TensorOperation::LeakyRelu(slope) => {
+                operands[0].map(|v| if v < 0. { slope * v } else { v })
+            }
+            TensorOperation::Tanh => operands[0].map(f64::tanh),
+            TensorOperation::Sigmoid => operands[0].map(|v| 1. / (1. + (-v).exp())),
+            TensorOperation::Exp => operands[0].map(f64::exp),
+            TensorOperation::Pow(exponent) => operands[0].map(|v| v.powf(*exponent)),
+            TensorOperation::Sum { axis, .. } => operands[0].fold_axis(*axis, 0., |s, v| s + v),
+            TensorOperation::Mean { axis, .. } => {
+                let n = operands[0].shape[*axis] as f64;
+                operands[0]
+                    .fold_axis(*axis, 0., |s, v| s + v)
+                    .map(|s| s / n)
+            }
+            TensorOperation::Max { axis, .. } => {
+                operands[0].fold_axis(*axis, f64::NEG_INFINITY, f64::max)
+            }
+            TensorOperation::Reshape(shape) => operands[0].reshape(shape.clone()),
+            TensorOperation::Transpose(a, b) => operands[0].swap_axes(*a, *b),
+            TensorOperation::Concat { axis } => Tensor::concat(operands, *axis),
+            TensorOperation::Slice { axis, start, end } => operands[0].slice(*axis, *start, *end),
+            TensorOperation::Gather { axis, indices } => operands[0].gather(*axis, indices),
+        };
+
+        let shapes: Vec<&[usize]> = operands.iter().map(|o| o.shape()).collect();
+        let shape = self.output_shape(&shapes);
+        match value.shape == shape {
+            true => value,
+            false => value.reshape(shape),
+        }
+    }
+
+    /// Gradients of each operand given the gradient of the operation output. Gradients of
+    /// broadcast operands are summed back down to the operand's shape.
+    fn backward(&self, operands: &[&Tensor], grad: &Tensor) -> Vec<Tensor> {
+        let grads = match self {
+            TensorOperation::MatMul => vec![
+                grad.matmul(&operands[1].transpose()),
+                operands[0].transpose().matmul(grad),
+            ],
+            TensorOperation::Add => vec![grad.clone(), grad.clone()],
+            TensorOperation::Sub => vec![grad.clone(), grad.map(|g| -g)],
+            TensorOperation::Mul => vec![
+                grad.zip_map(operands[1], |g, r| g * r),
+                grad.zip_map(operands[0], |g, l| g * l),
+            ],
+            TensorOperation::Div => vec![
+                grad.zip_map(operands[1], |g, r| g / r),
+                grad.zip_map(operands[0], |g, l| g * l)
+                    .zip_map(operands[1], |gl, r| -gl / (r * r)),
+            ],
+            TensorOperation::Relu => {
+                vec![grad.zip_map(operands[0], |g, v| if v > 0. { g } else { 0. })]
+            }
+            TensorOperation::LeakyRelu(slope) => {
+                vec![grad.zip_map(operands[0], |g, v| if v < 0. { slope * g } else { g })]
+            }
+            TensorOperation::Tanh => vec![grad.zip_map(operands[0], |g, v| {
+                let t = v.tanh();
+                g * (1. - t * t)
+            })],
+            TensorOperation::Sigmoid => vec![grad.zip_map(operands[0], |g, v| {
+                let s = 1. / (1. + (-v).exp());
+                g * s * (1. - s)
+            })],
+            TensorOperation::Exp => vec![grad.zip_map(operands[0], |g, v| g * v.exp())],
+            TensorOperation::Pow(exponent) => {
+                vec![grad.zip_map(operands[0], |g, v| g * exponent * v.powf(exponent - 1.))]
+            }
+            TensorOperation::Sum { axis, .. } | TensorOperation::Mean { axis, .. } => {
+                let mut kept = operands[0].shape.clone();
+                kept[*axis] = 1;
+                let scale = match self {
+                    TensorOperation::Mean { .. } => 1. / operands[0].shape[*axis] as f64,
+                    _ => 1.,
+                };
+                let grad = grad.reshape(kept).map(|g| g * scale);
+                vec![grad.broadcast_to(operands[0].shape())]
+            }
+            TensorOperation::Max { axis, .. } => {
+                // Route the gradient to the first maximal element along the axis.
+                let input = operands[0];
+                let (outer, n, inner) = input.axis_dims(*axis);
+                let (values, grads) = (input.data(), grad.data());
+                let mut routed = Tensor::zeros(input.shape.clone());
+                let data = routed.data_mut();
+                for o in 0..outer {
+                    for i in 0..inner {
+                        let index = |a: usize| (o * n + a) * inner + i;
+                        let argmax = (0..n)
+                            .max_by(|l, r| {
+                                values[index(*l)]
+                                    .total_cmp(&values[index(*r)])
+                                    .then(r.cmp(l))
+                            })
+                            .unwrap();
+                        data[index(argmax)] = grads[o * inner + i];
+                    }
+                }
+                vec![routed]
+            }
+            TensorOperation::Reshape(_) => {
+                vec![grad.reshape(operands[0].shape.clone())]
+            }
+            TensorOperation::Transpose(a, b) => vec![grad.swap_axes(*a, *b)],
+            TensorOperation::Concat { axis } => {
+                let mut start = 0;
+                operands
+                    .iter()
+                    .map(|o| {
+                        start += o.shape[*axis];
+                        grad.slice(*axis, start - o.shape[*axis], start)
+                    })
+                    .collect()
+            }
+            TensorOperation::Slice { axis, start, .. } => {
+                vec![grad.pad(operands[0].shape(), *axis, *start)]
+            }
+            TensorOperation::Gather { axis, indices } => {
+                vec![grad.scatter_add(operands[0].shape(), *axis, indices)]
+            }
+        };
+
+        grads
+            .iter()
+            .zip(operands.iter())
+            .map(|(g, o)| g.reduce_to(o.shape()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorGraphBuilderNode {
+    operation: TensorOperation,
+    operands: Vec<NodeId>,
+    shape: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TensorNode {
+    Operation(TensorGraphBuilderNode),
+    Immediate(Tensor),
+    /// Trainable value, the only kind of node `update_weights` hands to the optimiser.
+    Parameter(Tensor),
+    Input(Vec<usize>),
+    /// Standard normal samples, redrawn from the graph's RNG on every evaluation.
+    Normal(Vec<usize>),
+    /// Inverted dropout mask keeping each element with the given probability: `1 / keep` or 0,
+    /// redrawn from the graph's RNG on every evaluation.
+    Mask(Vec<usize>, f64),
+}
+
+impl TensorNode {
+    fn shape(&self) -> &[usize] {
+        match self {
+            TensorNode::Operation(n) => &n.shape,
+            TensorNode::Immediate(t) | TensorNode::Parameter(t) => t.shape(),
+            TensorNode::Input(shape) | TensorNode::Normal(shape) | TensorNode::Mask(shape, _) => {
+                shape
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorGraphBuilder<'a> {
+    pub root: NodeId,
+    nodes: HashMap<NodeId, TensorNode>,
+    ids: Rc<RefCell<&'a mut IdGenerator>>,
+}
+
+impl<'a> TensorGraphBuilder<'a> {
+    pub fn new(ids: Rc<RefCell<&'a mut IdGenerator>>) -> TensorGraphBuilder<'a> {
+        TensorGraphBuilder {
+            root: NodeId(0),
+            nodes: HashMap::new(),
+            ids,
+        }
+    }
+
+    fn combine(
+        operation: TensorOperation,
+        operands: Vec<&TensorGraphBuilder<'a>>,
+    ) -> TensorGraphBuilder<'a> {
+        let shapes: Vec<&[usize]> = operands.iter().map(|o| o.shape()).collect();
+        let shape = operation.output_shape(&shapes);
+        let new_root = TensorGraphBuilderNode {
+            operation,
+            operands: operands.iter().map(|o| o.root).collect(),
+            shape,
+        };
+
+        let mut nodes = operands[0].nodes.clone();
+        operands[1..]
+            .iter()
+            .for_each(|o| nodes.extend(o.nodes.iter().map(|(id, n)| (*id, n.clone()))));
+
+        let id = operands[0].ids.borrow_mut().get_id();
+        nodes.insert(id, TensorNode::Operation(new_root));
+
+        TensorGraphBuilder {
+            root: id,
+            nodes,
+            ids: operands[0].ids.clone(),
+        }
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.nodes[&self.root].shape()
+    }
+
+    pub fn create_input(&self, shape: Vec<usize>) -> (NodeId, TensorGraphBuilder<'a>) {
+        let id = self.ids.borrow_mut().get_id();
+
+        (
+            id,
+            TensorGraphBuilder {
+                root: id,
+                nodes: HashMap::from([(id, TensorNode::Input(shape))]),
+                ids: self.ids.clone(),
+            },
+        )
+    }
+
+    /// Creates a tensor-valued constant, which `RunnableTensorGraph::set_state` can overwrite.
+    pub fn immediate(&self, value: Tensor) -> TensorGraphBuilder<'a> {
+        self.leaf(TensorNode::Immediate(value))
+    }
+
+    /// Creates a tensor-valued parameter, which is trained by `update_weights`.
+    pub fn parameter(&self, value: Tensor) -> TensorGraphBuilder<'a> {
+        self.leaf(TensorNode::Parameter(value))
+    }
+
+    fn leaf(&self, node: TensorNode) -> TensorGraphBuilder<'a> {
+        let id = self.ids.borrow_mut().get_id();
+
+        TensorGraphBuilder {
+            root: id,
+            nodes: HashMap::from([(id, node)]),
+            ids: self.ids.clone(),
+        }
+    }
+
+    /// Reparameterised Gaussian sample `mu + sigma * eps`, where `eps` is redrawn on every
+    /// evaluation, so gradients flow to both `mu` and `sigma`.
+    pub fn gaussian(
+        mu: &TensorGraphBuilder<'a>,
+        sigma: &TensorGraphBuilder<'a>,
+    ) -> TensorGraphBuilder<'a> {
+        let eps = mu.leaf(TensorNode::Normal(mu.shape().to_vec()));
+        mu + &(sigma * &eps)
+    }
+
+    /// Inverted dropout: zeroes each element with probability `p` and scales the others by
+    /// `1 / (1 - p)`, with a new mask on every evaluation.
+    pub fn dropout(&self, p: f64) -> TensorGraphBuilder<'a> {
+        if !(0. ..1.).contains(&p) {
+            panic!("Dropout probability must be in [0, 1), but got {p}")
+        }
+
+        let mask = self.leaf(TensorNode::Mask(self.shape().to_vec(), 1. - p));
+        self * &mask
+    }
+
+    pub fn matmul(&self, rhs: &TensorGraphBuilder<'a>) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::MatMul, vec![self, rhs])
+    }
+
+    pub fn relu(&self) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Relu, vec![self])
+    }
+
+    pub fn leaky_relu(&self, slope: f64) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::LeakyRelu(slope), vec![self])
+    }
+
+    pub fn tanh(&self) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Tanh, vec![self])
+    }
+
+    pub fn sigmoid(&self) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Sigmoid, vec![self])
+    }
+
+    pub fn exp(&self) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Exp, vec![self])
+    }
+
+    pub fn pow(&self, exponent: f64) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Pow(exponent), vec![self])
+    }
+
+    pub fn sum(&self, axis: usize, keepdim: bool) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Sum { axis, keepdim }, vec![self])
+    }
+
+    pub fn mean(&self, axis: usize, keepdim: bool) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Mean { axis, keepdim }, vec![self])
+    }
+
+    pub fn max(&self, axis: usize, keepdim: bool) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Max { axis, keepdim }, vec![self])
+    }
+
+    pub fn reshape(&self, shape: Vec<usize>) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Reshape(shape), vec![self])
+    }
+
+    /// Swaps two axes; `transpose(0, 1)` is the usual matrix transpose.
+    pub fn transpose(&self, a: usize, b: usize) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Transpose(a, b), vec![self])
+    }
+
+    pub fn concat(parts: &[&TensorGraphBuilder<'a>], axis: usize) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Concat { axis }, parts.to_vec())
+    }
+
+    /// Elements `start..end` along `axis`.
+    pub fn slice(&self, axis: usize, start: usize, end: usize) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Slice { axis, start, end }, vec![self])
+    }
+
+    /// Elements at `indices` along `axis`, with zeros for `None`.
+    pub fn gather(&self, axis: usize, indices: Vec<Option<usize>>) -> TensorGraphBuilder<'a> {
+        Self::combine(TensorOperation::Gather { axis, indices }, vec![self])
+    }
+}
+
+impl<'a> Add<&TensorGraphBuilder<'a>> for &TensorGraphBuilder<'a> {
+    type Output = TensorGraphBuilder<'a>;
+
+    fn add(self, rhs: &TensorGraphBuilder<'a>) -> Self::Output {
+        TensorGraphBuilder::combine(TensorOperation::Add, vec![self, rhs])
+    }
+}
+
+impl<'a> Sub<&TensorGraphBuilder<'a>> for &TensorGraphBuilder<'a> {
+    type Output = TensorGraphBuilder<'a>;
+
+    fn sub(self, rhs: &TensorGraphBuilder<'a>) -> Self::Output {
+        TensorGraphBuilder::combine(TensorOperation::Sub, vec![self, rhs])
+    }
+}
+
+impl<'a> Mul<&TensorGraphBuilder<'a>> for &TensorGraphBuilder<'a> {
+    type Output = TensorGraphBuilder<'a>;
+
+    fn mul(self, rhs: &TensorGraphBuilder<'a>) -> Self::Output {
+        TensorGraphBuilder::combine(TensorOperation::Mul, vec![self, rhs])
+    }
+}
+
+impl<'a> Div<&TensorGraphBuilder<'a>> for &TensorGraphBuilder<'a> {
+    type Output = TensorGraphBuilder<'a>;
+
+    fn div(self, rhs: &TensorGraphBuilder<'a>) -> Self::Output {
+        TensorGraphBuilder::combine(TensorOperation::Div, vec![self, rhs])
+    }
+}
+
+#[derive(Debug)]
+pub struct RunnableTensorGraph {
+    nodes: Vec<Option<TensorNode>>,
+    values: Vec<Tensor>,
+    gradients: Vec<Tensor>,
+    rng: StdRng,
+}
+
+impl RunnableTensorGraph {
+    pub fn new(graphs: Vec<&TensorGraphBuilder>) -> RunnableTensorGraph {
+        let size = graphs
+            .iter()
+            .flat_map(|g| g.nodes.keys())
+            .map(|id| id.0 + 1)
+            .max()
+            .unwrap_or(0);
+
+        let mut nodes: Vec<Option<TensorNode>> = vec![None; size];
+        graphs
+            .iter()
+            .flat_map(|g| g.nodes.iter())
+            .for_each(|(id, node)| nodes[id.0] = Some(node.clone()));
+
+        let values = nodes
+            .iter()
+            .map(|n| match n {
+                Some(TensorNode::Immediate(t) | TensorNode::Parameter(t)) => t.clone(),
+                Some(n) => Tensor::zeros(n.shape().to_vec()),
+                None => Tensor::zeros(vec![0]),
+            })
+            .collect();
+        let gradients = nodes
+            .iter()
+            .map(|n| Tensor::zeros(n.as_ref().map_or(vec![0], |n| n.shape().to_vec())))
+            .collect();
+
+        RunnableTensorGraph {
+            nodes,
+            values,
+            gradients,
+            rng: StdRng::from_rng(thread_rng()).unwrap(),
+        }
+    }
+
+    /// Reseeds the RNG used to draw samples for random nodes.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_input(&mut self, inp: NodeId, val: Tensor) {
+        match &self.nodes[inp.0] {
+            Some(TensorNode::Input(shape)) if *shape == val.shape => self.values[inp.0] = val,
+            Some(TensorNode::Input(shape)) => panic!(
+                "Input {:?} expects shape {:?}, but got {:?}",
+                inp, shape, val.shape
+            ),
+            n => panic!("This is not an Input node: {:?} {:?}", inp, n),
+        }
+    }
+
+    /// Overwrites the value of a Parameter or Immediate node, which must keep its shape.
+    pub fn set_state(&mut self, id: NodeId, val: Tensor) {
+        match &self.nodes[id.0] {
+            Some(TensorNode::Parameter(t) | TensorNode::Immediate(t)) if t.shape == val.shape => {
+                self.values[id.0] = val
+            }
+            Some(TensorNode::Parameter(t) | TensorNode::Immediate(t)) => panic!(
+                "Node {:?} expects shape {:?}, but got {:?}",
+                id, t.shape, val.shape
+            ),
+            n => panic!(
+                "This is not a Parameter or Immediate node: {:?} {:?}",
+                id, n
+            ),
+        }
+    }
+
+    pub fn value(&self, id: NodeId) -> &Tensor {
+        &self.values[id.0]
+    }
+
+    pub fn gradient(&self, id: NodeId) -> &Tensor {
+        &self.gradients[id.0]
+    }
+
+    pub fn evaluate(&mut self, outputs: &[NodeId]) -> Vec<Tensor> {
+        for id in 0..self.nodes.len() {
+            match &self.nodes[id] {
+                Some(TensorNode::Operation(n)) => {
+                    let operands: Vec<&Tensor> =
+                        n.operands.iter().map(|o| &self.values[o.0]).collect();
+                    self.values[id] = n.operation.apply(&operands);
+                }
+                Some(TensorNode::Normal(_)) => {
+                    let rng = &mut self.rng;
+                    self.values[id]
+                        .data_mut()
+                        .iter_mut()
+                        .for_each(|v| *v = Util::standard_normal(rng));
+                }
+                Some(TensorNode::Mask(_, keep)) => {
+                    let (rng, keep) = (&mut self.rng, *keep);
+                    self.values[id].data_mut().iter_mut().for_each(|v| {
+                        *v = if rng.gen::<f64>() < keep {
+                            1. / keep
+                        } else {
+                            0.
+                        }
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        outputs.iter().map(|id| self.values[id.0].clone()).collect()
+    }
+
+    pub fn zero_grads(&mut self) {
+        self.gradients
+            .iter_mut()
+            .for_each(|g| g.data_mut().iter_mut().for_each(|v| *v = 0.));
+    }
+
+    pub fn backwards(&mut self, out_grads: Vec<(NodeId, Tensor)>) {
+        out_grads.into_iter().for_each(|(root, grad)| {
+            self.gradients[root.0] = self.gradients[root.0].zip_map(&grad, |l, r| l + r);
+        });
+
+        for id in (0..self.nodes.len()).rev() {
+            if let Some(TensorNode::Operation(n)) = &self.nodes[id] {
+                let operands: Vec<&Tensor> = n.operands.iter().map(|o| &self.values[o.0]).collect();
+                let grads = n.operation.backward(&operands, &self.gradients[id]);
+
+                n.operands.iter().zip(grads).for_each(|(o, g)| {
+                    self.gradients[o.0] = self.gradients[o.0].zip_map(&g, |l, r| l + r);
+                });
+            }
+        }
+    }
+
+    fn parameter_ids(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|id| matches!(self.nodes[*id], Some(TensorNode::Parameter(_))))
+            .collect()
+    }
+
+    /// Flattens every parameter into the optimiser's `Data` view, then writes the update back.
+    pub fn update_weights(&mut self, optimiser: &mut impl Optimiser) {
+        let ids = self.parameter_ids();
+
+        let mut data: Vec<Data> = ids
+            .iter()
+            .flat_map(|id| {
+                self.values[*id]
+                    .data()
+                    .iter()
+                    .zip(self.gradients[*id].data().iter())
+                    .map(|(v, g)| Data {
+                        value: *v,
+                        gradient: *g,
+                    })
+            })
+            .collect();
+
+        optimiser.optimise(&mut data);
+
+        let mut updated = data.into_iter();
+        ids.iter().for_each(|id| {
+            self.values[*id]
+                .data_mut()
+                .iter_mut()
+                .zip(updated.by_ref())
+                .for_each(|(v, d)| *v = d.value)
+        });
+    }
+
+    pub fn num_parameters(&self) -> usize {
+        self.parameter_ids()
+            .iter()
+            .map(|id| self.values[*id].data().len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{optimiser::LearningRateOptimiser, tensor::*};
+
+    #[test]
+    fn test_matmul_forward_backward() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input(vec![2, 3]);
+        let w = graph.parameter(Tensor::new(vec![3, 1], vec![1., -1., 2.]));
+        let w_id = w.root;
+
+        let y = x.matmul(&w).relu();
+        assert_eq!(y.shape(), &[2, 1]);
+
+        let mut g = RunnableTensorGraph::new(vec![&y]);
+        g.set_input(
+            x_id,
+            Tensor::from_rows(&[vec![1., 2., 3.], vec![3., 2., -1.]]),
+        );
+
+        let out = g.evaluate(&[y.root]);
+        assert_eq!(out[0].rows(), vec![vec![5.], vec![0.]]);
+
+        g.backwards(vec![(y.root, Tensor::new(vec![2, 1], vec![1., 1.]))]);
+        assert_eq!(g.gradient(w_id).data(), &[1., 2., 3.]);
+        assert_eq!(
+            g.gradient(x_id).rows(),
+            vec![vec![1., -1., 2.], vec![0., 0., 0.]]
+        );
+    }
+
+    #[test]
+    fn test_elementwise() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (a_id, a) = graph.create_input(vec![2]);
+        let (b_id, b) = graph.create_input(vec![2]);
+
+        let c = &(&a * &b) - &(&a / &b);
+        let mut g = RunnableTensorGraph::new(vec![&c]);
+        g.set_input(a_id, Tensor::new(vec![2], vec![2., 3.]));
+        g.set_input(b_id, Tensor::new(vec![2], vec![4., 1.]));
+
+        assert_eq!(g.evaluate(&[c.root])[0].data(), &[7.5, 0.]);
+
+        g.backwards(vec![(c.root, Tensor::new(vec![2], vec![1., 1.]))]);
+        assert_eq!(g.gradient(a_id).data(), &[3.75, 0.]);
+        assert_eq!(g.gradient(b_id).data(), &[2.125, 6.]);
+    }
+
+    #[test]
+    fn test_broadcasting() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input(vec![2, 3]);
+        let bias = graph.immediate(Tensor::new(vec![3], vec![1., 2., 3.]));
+        let scale = graph.immediate(Tensor::scalar(2.));
+
+        let y = &(&x + &bias) * &scale;
+        assert_eq!(y.shape(), &[2, 3]);
+
+        let mut g = RunnableTensorGraph::new(vec![&y]);
+        g.set_input(
+            x_id,
+            Tensor::from_rows(&[vec![0., 0., 0.], vec![1., 1., 1.]]),
+        );
+        assert_eq!(
+            g.evaluate(&[y.root])[0].rows(),
+            vec![vec![2., 4., 6.], vec![4., 6., 8.]]
+        );
+
+        g.backwards(vec![(y.root, Tensor::new(vec![2, 3], vec![1.; 6]))]);
+        assert_eq!(g.gradient(bias.root).data(), &[4., 4., 4.]);
+        assert_eq!(g.gradient(scale.root).data(), &[15.]);
+    }
+
+    #[test]
+    fn test_axis_reductions() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input(vec![2, 3]);
+
+        let sum = x.sum(1, false);
+        let mean = x.mean(0, true);
+        let max = x.max(1, true);
+        assert_eq!(sum.shape(), &[2]);
+        assert_eq!(mean.shape(), &[1, 3]);
+        assert_eq!(max.shape(), &[2, 1]);
+
+        let mut g = RunnableTensorGraph::new(vec![&sum, &mean, &max]);
+        g.set_input(
+            x_id,
+            Tensor::from_rows(&[vec![1., 5., 3.], vec![4., 2., 6.]]),
+        );
+
+        let out = g.evaluate(&[sum.root, mean.root, max.root]);
+        assert_eq!(out[0].data(), &[9., 12.]);
+        assert_eq!(out[1].data(), &[2.5, 3.5, 4.5]);
+        assert_eq!(out[2].data(), &[5., 6.]);
+
+        g.backwards(vec![
+            (sum.root, Tensor::new(vec![2], vec![1., 2.])),
+            (mean.root, Tensor::new(vec![1, 3], vec![2., 2., 2.])),
+            (max.root, Tensor::new(vec![2, 1], vec![10., 10.])),
+        ]);
+        assert_eq!(
+            g.gradient(x_id).rows(),
+            vec![vec![2., 12., 2.], vec![3., 3., 13.]]
+        );
+    }
+
+    #[test]
+    fn test_shape_operations() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input(vec![2, 3]);
+        let (y_id, y) = graph.create_input(vec![1, 3]);
+
+        let stacked = TensorGraphBuilder::concat(&[&x, &y], 0);
+        let t = stacked.transpose(0, 1);
+        let s = t.slice(1, 1, 3);
+        let r = s.reshape(vec![6]);
+        assert_eq!(stacked.shape(), &[3, 3]);
+        assert_eq!(t.shape(), &[3, 3]);
+        assert_eq!(r.shape(), &[6]);
+
+        let mut g = RunnableTensorGraph::new(vec![&r]);
+        g.set_input(
+            x_id,
+            Tensor::from_rows(&[vec![1., 2., 3.], vec![4., 5., 6.]]),
+        );
+        g.set_input(y_id, Tensor::from_rows(&[vec![7., 8., 9.]]));
+
+        assert_eq!(g.evaluate(&[r.root])[0].data(), &[4., 7., 5., 8., 6., 9.]);
+
+        g.backwards(vec![(
+            r.root,
+            Tensor::new(vec![6], vec![1., 2., 3., 4., 5., 6.]),
+        )]);
+        assert_eq!(
+            g.gradient(x_id).rows(),
+            vec![vec![0., 0., 0.], vec![1., 3., 5.]]
+        );
+        assert_eq!(g.gradient(y_id).rows(), vec![vec![2., 4., 6.]]);
+    }
+
+    #[test]
+    fn test_views() {
+        let x = Tensor::new(vec![2, 3], vec![1., 2., 3., 4., 5., 6.]);
+        let r = TensorOperation::Reshape(vec![3, 2]).apply(&[&x]);
+        let t = TensorOperation::Transpose(0, 1).apply(&[&x]);
+        assert!(Rc::ptr_eq(&x.storage, &r.storage));
+        assert!(Rc::ptr_eq(&x.storage, &t.storage));
+        assert_eq!(t.rows(), vec![vec![1., 4.], vec![2., 5.], vec![3., 6.]]);
+
+        // Strided reads give the same product as a row-major copy of the view.
+        let copy = Tensor::new(t.shape.clone(), t.data().to_vec());
+        assert_eq!(t.matmul(&x), copy.matmul(&x));
+        assert_eq!(x.matmul(&t), x.matmul(&copy));
+
+        let b = Tensor::new(vec![2, 2, 3], (0..12).map(|v| v as f64).collect());
+        let bt = b.swap_axes(0, 2);
+        let copy = Tensor::new(bt.shape.clone(), bt.data().to_vec());
+        assert_eq!(
+            bt.matmul(&b.swap_axes(0, 1)),
+            copy.matmul(&b.swap_axes(0, 1))
+        );
+
+        // Writing through a view leaves the shared storage untouched.
+        let mut t = t;
+        t.data_mut()[0] = 0.;
+        assert_eq!(x.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_eq!(t.data(), &[0., 4., 2., 5., 3., 6.]);
+    }
+
+    #[test]
+    fn test_batched_matmul() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (a_id, a) = graph.create_input(vec![2, 1, 2]);
+        let (b_id, b) = graph.create_input(vec![2, 2, 1]);
+
+        let c = a.matmul(&b);
+        assert_eq!(c.shape(), &[2, 1, 1]);
+
+        let mut g = RunnableTensorGraph::new(vec![&c]);
+        g.set_input(a_id, Tensor::new(vec![2, 1, 2], vec![1., 2., 3., 4.]));
+        g.set_input(b_id, Tensor::new(vec![2, 2, 1], vec![5., 6., 7., 8.]));
+        assert_eq!(g.evaluate(&[c.root])[0].data(), &[17., 53.]);
+
+        g.backwards(vec![(c.root, Tensor::new(vec![2, 1, 1], vec![1., 2.]))]);
+        assert_eq!(g.gradient(a_id).data(), &[5., 6., 14., 16.]);
+        assert_eq!(g.gradient(b_id).data(), &[1., 2., 6., 8.]);
+    }
+
+    #[test]
+    fn test_gather() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input(vec![2, 3]);
+
+        let y = x.gather(1, vec![Some(2), None, Some(0), Some(2)]);
+        assert_eq!(y.shape(), &[2, 4]);
+
+        let mut g = RunnableTensorGraph::new(vec![&y]);
+        g.set_input(
+            x_id,
+            Tensor::from_rows(&[vec![1., 2., 3.], vec![4., 5., 6.]]),
+        );
+        assert_eq!(
+            g.evaluate(&[y.root])[0].rows(),
+            vec![vec![3., 0., 1., 3.], vec![6., 0., 4., 6.]]
+        );
+
+        g.backwards(vec![(y.root, Tensor::new(vec![2, 4], vec![1.; 8]))]);
+        assert_eq!(
+            g.gradient(x_id).rows(),
+            vec![vec![1., 0., 2.], vec![1., 0., 2.]]
+        );
+    }
+
+    #[test]
+    fn test_parameters() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let w = graph.parameter(Tensor::new(vec![2], vec![1., 2.]));
+        let scale = graph.immediate(Tensor::scalar(3.));
+        let y = (&w * &scale).sum(0, false);
+
+        let mut g = RunnableTensorGraph::new(vec![&y]);
+        assert_eq!(g.num_parameters(), 2);
+        g.evaluate(&[y.root]);
+        g.backwards(vec![(y.root, Tensor::scalar(1.))]);
+        g.update_weights(&mut LearningRateOptimiser::new(0.5));
+        assert_eq!(g.value(w.root).data(), &[-0.5, 0.5]);
+        assert_eq!(g.value(scale.root).data(), &[3.]);
+
+        g.set_state(scale.root, Tensor::scalar(-1.));
+        assert_eq!(g.evaluate(&[y.root])[0].data(), &[0.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expects shape [2], but got [3]")]
+    fn test_set_state_shape() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let w = graph.parameter(Tensor::zeros(vec![2]));
+        let mut g = RunnableTensorGraph::new(vec![&w]);
+        g.set_state(w.root, Tensor::zeros(vec![3]));
+    }
+
+    #[test]
+    fn test_random_nodes() {
+        let ids = &mut IdGenerator::new();
+        let ids = Rc::new(RefCell::new(ids));
+
+        let graph = TensorGraphBuilder::new(ids);
+        let (x_id, x) = graph.create_input(vec![1000]);
+        let dropped = x.dropout(0.5);
+        let noisy = TensorGraphBuilder::gaussian(&x, &graph.immediate(Tensor::scalar(2.)));
+
+        let mut g = RunnableTensorGraph::new(vec![&dropped, &noisy]);
+        g.set_input(x_id, Tensor::new(vec![1000], vec![1.; 1000]));
+        g.seed(1);
+        let first = g.evaluate(&[dropped.root, noisy.root]);
+        assert!(first[0].data().iter().all(|v| *v == 0. || *v == 2.));
+        assert!((first[0].data().iter().sum::<f64>() / 1000. - 1.).abs() < 0.1);
+        let noise: Vec<f64> = first[1].data().iter().map(|v| v - 1.).collect();
+        let std = (noise.iter().map(|n| n * n).sum::<f64>() / 1000.).sqrt();
+        assert!((std - 2.).abs() < 0.2);
+
+        // New samples on every evaluation, reproducible from the seed.
+        assert_ne!(g.evaluate(&[dropped.root, noisy.root]), first);
+        g.seed(1);
+        assert_eq!(g.evaluate(&[dropped.root, noisy.root]), first);
+    }
+}
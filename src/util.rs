@@ -1,6 +1,15 @@
+use rand::Rng;
+
 pub struct Util {}
 
 impl Util {
+    /// Draws a sample from N(0, 1) using the Box-Muller transform.
+    pub fn standard_normal(rng: &mut impl Rng) -> f64 {
+        let u1: f64 = 1. - rng.gen::<f64>();
+        let u2: f64 = rng.gen();
+        (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+    }
+
     pub fn argmax(v: &[f64]) -> usize {
         let (max, _) = v
             .iter()
@@ -10,6 +19,24 @@ impl Util {
             .unwrap();
         max
     }
+
+    /// Vector of `num_classes` zeros with a one at `label`.
+    pub fn one_hot(label: usize, num_classes: usize) -> Vec<f64> {
+        if label >= num_classes {
+            panic!("Expected a label below {}, but got {}", num_classes, label)
+        }
+        let mut v = vec![0.; num_classes];
+        v[label] = 1.;
+        v
+    }
+
+    /// Softmax of `v`, shifted by its maximum so that large values don't overflow.
+    pub fn softmax(v: &[f64]) -> Vec<f64> {
+        let max = v.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let exps: Vec<f64> = v.iter().map(|x| (x - max).exp()).collect();
+        let sum: f64 = exps.iter().sum();
+        exps.iter().map(|e| e / sum).collect()
+    }
 }
 
 pub trait Mean {